@@ -0,0 +1,148 @@
+//! Results the parent decodes by borrowing from them. See [`fork_map_arena`].
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Codec, Fork, ForkBuilder, ForkError};
+
+/// Like [`fork_map`](crate::fork_map), but the result is kept as the bytes the child sent, for
+/// decoding into values that borrow from them, rather than decoded into owned values right away.
+///
+/// A result made of many small records, each with a string or two in it, otherwise costs an
+/// allocation for every string the parent decodes. Here the child serializes all of them into one
+/// buffer, and the parent receives that one buffer, which the returned [`ForkArena`] owns; what
+/// [`decode`](ForkArena::decode) returns can borrow its strings and byte slices straight out of
+/// it, so decoding them copies nothing. The borrow checker sees to it that none of them outlive
+/// the arena.
+///
+/// The child can return any type that serializes the same way, borrowed or not. Strings that
+/// JSON escapes (with quotes or backslashes in them, say) can't be borrowed, so a `&str` field
+/// fails to decode for those; a `Cow<str>` field marked `#[serde(borrow)]` borrows when it can
+/// and copies when it has to. `Codec::Cbor` has no way to lend anything out, and fails with
+/// [`ForkError::Unsupported`] before forking.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_arena;
+/// use serde::{Deserialize, Serialize};
+/// use std::borrow::Cow;
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Record<'a> {
+///     id: u32,
+///     name: &'a str,
+///     #[serde(borrow)]
+///     note: Cow<'a, str>,
+/// }
+///
+/// let arena = unsafe {
+///     fork_map_arena(|| {
+///         let notes = ["plain", "\"quoted\""];
+///         let records: Vec<Record> = (0..10_000)
+///             .map(|id| Record {
+///                 id,
+///                 name: "widget",
+///                 note: Cow::Borrowed(notes[id as usize % 2]),
+///             })
+///             .collect();
+///         Ok(records)
+///     })
+/// }
+/// .unwrap();
+///
+/// let records: Vec<Record> = arena.decode().unwrap();
+/// assert_eq!(records.len(), 10_000);
+/// assert_eq!(records[1].id, 1);
+/// // Borrowed from the arena, not copied out of it
+/// let bytes = arena.as_bytes().as_ptr_range();
+/// assert!(bytes.contains(&records[7].name.as_ptr()));
+/// assert!(matches!(records[0].note, Cow::Borrowed("plain")));
+/// // Other than the one that had to be unescaped
+/// assert!(matches!(&records[1].note, Cow::Owned(note) if note == "\"quoted\""));
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`](crate::fork_map).
+pub unsafe fn fork_map_arena<F, S>(func: F) -> anyhow::Result<ForkArena>
+where
+    F: Fn() -> anyhow::Result<S> + Send + Sync,
+    S: Serialize + Send,
+{
+    Fork::builder().run_arena(func)
+}
+
+/// A result as the child encoded it, for decoding into values that borrow from it. Returned by
+/// [`fork_map_arena`].
+pub struct ForkArena {
+    /// The child's, for errors decoding its result.
+    pid: u32,
+    bytes: Vec<u8>,
+    codec: Codec,
+}
+
+impl ForkArena {
+    /// Decodes the result into a `T`, which can borrow from the arena. Decoding again decodes it
+    /// afresh, so the same arena can be decoded as more than one type.
+    pub fn decode<'a, T: Deserialize<'a>>(&'a self) -> Result<T, ForkError> {
+        self.codec.decode_borrowed(self.pid, &self.bytes)
+    }
+
+    /// The result, encoded with the builder's [`codec`](ForkBuilder::codec).
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The result, still encoded.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl fmt::Debug for ForkArena {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ForkArena")
+            .field("len", &self.bytes.len())
+            .field("codec", &self.codec)
+            .finish()
+    }
+}
+
+impl ForkBuilder {
+    /// Like [`run`](Self::run), but returns the result undecoded, for borrowing from. See
+    /// [`fork_map_arena`].
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_arena<F, S>(self, func: F) -> anyhow::Result<ForkArena>
+    where
+        F: Fn() -> anyhow::Result<S> + Send + Sync,
+        S: Serialize + Send,
+    {
+        #[cfg(feature = "testing")]
+        if let Some(error) = crate::testing::take() {
+            return Err(error.into());
+        }
+        let codec = self.codec;
+        if !codec.can_borrow() {
+            return Err(ForkError::Unsupported {
+                feature: "borrowing from a result in Codec::Cbor",
+            }
+            .into());
+        }
+        #[cfg(all(unix, not(feature = "fallback")))]
+        if !self.inline {
+            let child = self.fork_child(|_| func())?;
+            let pid = child.pid();
+            let bytes = child.wait_encoded()?;
+            return Ok(ForkArena { pid, bytes, codec });
+        }
+        let frame = self.frame_in_process(func)?;
+        let pid = std::process::id();
+        let bytes = self.decode_frame(pid, &frame, |body| Ok(body.to_vec()))?;
+        Ok(ForkArena { pid, bytes, codec })
+    }
+}
@@ -0,0 +1,58 @@
+//! Keeping the child from inheriting locks that some other thread was holding when it forked.
+//!
+//! Only the forking thread makes it into the child, so a lock held by any other thread at that
+//! moment stays held there forever, and the child hangs as soon as it wants it. The allocator
+//! sees to its own locks (glibc's `malloc` takes them all around `fork()`, as do jemalloc and
+//! macOS's), but not to the standard output and error locks that `print!` takes, nor to this
+//! crate's own statics. So `pthread_atfork` handlers take those too, right before any fork in
+//! the process, by this crate or not, and give them back right after, in both processes.
+
+use std::cell::RefCell;
+use std::io::{StderrLock, StdoutLock};
+use std::sync::{MutexGuard, Once};
+
+use crate::{limit, sys};
+
+/// The locks, in the order they're taken. Anything that takes more than one of them at a time
+/// takes them in this order too: a caller may be printing when it calls into this crate, the
+/// detached children's reaper gives back permits and releases `SIGCHLD` as it reaps, and
+/// nothing takes another lock while holding either of those.
+struct Held {
+    _stdout: StdoutLock<'static>,
+    _stderr: StderrLock<'static>,
+    #[cfg(feature = "serde")]
+    _detached: MutexGuard<'static, crate::detach::Detached>,
+    _limit: MutexGuard<'static, limit::State>,
+    _sigchld: MutexGuard<'static, sys::SigchldHold>,
+}
+
+thread_local! {
+    /// Held by the forking thread, from before the fork until after it, in the child too, whose
+    /// only thread is its copy of that one.
+    static HELD: RefCell<Option<Held>> = const { RefCell::new(None) };
+}
+
+/// Installs the handlers, the first time this crate forks.
+pub(crate) fn register() {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| unsafe {
+        libc::pthread_atfork(Some(prepare), Some(release), Some(release));
+    });
+}
+
+extern "C" fn prepare() {
+    let held = Held {
+        _stdout: std::io::stdout().lock(),
+        _stderr: std::io::stderr().lock(),
+        #[cfg(feature = "serde")]
+        _detached: crate::detach::detached(),
+        _limit: limit::state(),
+        _sigchld: sys::sigchld_hold(),
+    };
+    HELD.with(|slot| *slot.borrow_mut() = Some(held));
+}
+
+/// Runs in both the parent and the child.
+extern "C" fn release() {
+    HELD.with(|slot| slot.borrow_mut().take());
+}
@@ -0,0 +1,1708 @@
+use std::cell::Cell;
+use std::fmt;
+#[cfg(unix)]
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::limit::{self, Permit};
+use crate::multi::Running;
+use crate::protocol::{self, Tag};
+use crate::{
+    Codec, Compression, ForkError, ForkHandle, ForkId, ForkMapIter, ForkMapIterUnordered,
+    ForkStats, RetryPolicy, Signal, Transport,
+};
+
+/// Entry point for configuring how a child process is forked.
+///
+/// [`fork_map`](crate::fork_map) is equivalent to `Fork::builder().run(func)`; the builder exists
+/// for when you need the child set up differently before your closure runs.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::Fork;
+/// # if !fork_map::FORKS { return }
+///
+/// let (pid, pgid) = unsafe {
+///     Fork::builder()
+///         .new_process_group(true)
+///         .run(|| Ok((libc::getpid(), libc::getpgrp())))
+///         .unwrap()
+/// };
+/// // The child leads its own process group
+/// assert_eq!(pid, pgid);
+/// assert_ne!(pgid, unsafe { libc::getpgrp() });
+/// ```
+pub struct Fork;
+
+impl Fork {
+    /// Creates a builder with the default configuration, which matches [`fork_map`](crate::fork_map).
+    pub fn builder() -> ForkBuilder {
+        ForkBuilder::default()
+    }
+}
+
+/// Options applied to the child between `fork()` and running your closure.
+///
+/// Create one with [`Fork::builder`].
+#[derive(Debug, Clone, Default)]
+pub struct ForkBuilder {
+    pub(crate) codec: Codec,
+    pub(crate) compression: Compression,
+    pub(crate) transport: Transport,
+    pub(crate) new_process_group: bool,
+    pub(crate) new_session: bool,
+    pub(crate) cgroup: Option<PathBuf>,
+    pub(crate) current_dir: Option<PathBuf>,
+    pub(crate) reap_descendants: Option<Duration>,
+    #[cfg(target_os = "macos")]
+    pub(crate) sandbox_profile: Option<String>,
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    pub(crate) seccomp: Option<crate::SeccompProfile>,
+    pub(crate) pre_exec: Hooks<dyn Fn() -> anyhow::Result<()> + Send + Sync>,
+    pub(crate) on_fork: Hooks<dyn Fn(u32) + Send + Sync>,
+    pub(crate) inline: bool,
+    pub(crate) retries: Option<RetryPolicy>,
+    pub(crate) flush_stdio: bool,
+    pub(crate) max_concurrent: Option<usize>,
+    pub(crate) max_result_bytes: Option<usize>,
+    pub(crate) max_rss: Option<u64>,
+    pub(crate) max_rss_interval: Option<Duration>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) result_fd: Option<i32>,
+    pub(crate) pause_on_crash: bool,
+    pub(crate) non_blocking: bool,
+    pub(crate) id: Option<String>,
+    pub(crate) pipe_size: Option<usize>,
+    pub(crate) preallocate_result: Option<usize>,
+    pub(crate) kill_signal: Signal,
+    pub(crate) kill_grace: Duration,
+    pub(crate) capture_stdout: bool,
+    pub(crate) null_stdio: bool,
+    /// The inverse of [`reset_signals`](Self::reset_signals), so it defaults to resetting them.
+    pub(crate) keep_signals: bool,
+    /// Set by [`detach`](Self::detach) rather than an option of its own: the child sends nothing
+    /// back, so it gets no pipe to send it on.
+    #[cfg(all(unix, not(feature = "fallback")))]
+    pub(crate) detached: bool,
+}
+
+/// Callbacks like [`pre_exec`](ForkBuilder::pre_exec), in the order they were added.
+pub(crate) struct Hooks<F: ?Sized>(pub(crate) Vec<Arc<F>>);
+
+impl<F: ?Sized> Clone for Hooks<F> {
+    fn clone(&self) -> Self {
+        Hooks(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> Default for Hooks<F> {
+    fn default() -> Self {
+        Hooks(vec![])
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Hooks<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{} hook(s)]", self.0.len())
+    }
+}
+
+impl ForkBuilder {
+    /// Picks the format the result is serialized with on its way back from the child. Defaults
+    /// to [`Codec::Json`].
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Compresses the result in the child before sending it to the parent. Defaults to
+    /// [`Compression::None`].
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Picks what carries messages between the parent and the child. Defaults to
+    /// [`Transport::Pipe`].
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// On Linux, grows the result pipe's buffer to `bytes` (`fcntl(F_SETPIPE_SZ)`), so a big
+    /// result goes through in fewer, larger writes, instead of the child waking the parent for
+    /// every 64 KiB and then waiting for it to make room. Defaults to 1 MiB, which is also the
+    /// most an unprivileged process gets by default; asking for more than
+    /// `/proc/sys/fs/pipe-max-size` gets that instead, and if even that fails, the pipe is left
+    /// as it was. Either way, [`ForkStats::pipe_size`] says what it ended up as. 0 leaves it
+    /// alone.
+    ///
+    /// Only a pipe has a buffer to grow, so this does nothing with
+    /// [`Transport::Socket`], or anywhere but Linux.
+    ///
+    /// ```
+    /// use fork_map::Fork;
+    /// # if !fork_map::FORKS || !cfg!(target_os = "linux") { return }
+    ///
+    /// let pipe_size = |builder: fork_map::ForkBuilder| unsafe {
+    ///     let (big, stats) = builder.run_with_stats(|| Ok(vec![7u8; 4 << 20])).unwrap();
+    ///     assert!(big.len() == 4 << 20 && big.iter().all(|&byte| byte == 7));
+    ///     stats.pipe_size.unwrap()
+    /// };
+    /// let max: usize = std::fs::read_to_string("/proc/sys/fs/pipe-max-size")
+    ///     .unwrap()
+    ///     .trim()
+    ///     .parse()
+    ///     .unwrap();
+    /// assert_eq!(pipe_size(Fork::builder()), max.min(1 << 20));
+    /// assert_eq!(pipe_size(Fork::builder().pipe_size(4096)), 4096);
+    /// // As much as we're allowed, or untouched if even that's too much
+    /// assert!(pipe_size(Fork::builder().pipe_size(1 << 30)) <= max.max(1 << 16));
+    /// ```
+    pub fn pipe_size(mut self, bytes: usize) -> Self {
+        self.pipe_size = Some(bytes);
+        self
+    }
+
+    /// Has the child make no allocations of its own on the way into the closure and, for a
+    /// result that fits in `bytes`, on the way out. The buffer the result is serialized into is
+    /// allocated before the fork, and the closure runs on the thread that forked, rather than on
+    /// a fresh one with a stack as big as the main thread's.
+    ///
+    /// This is for parents whose other threads allocate heavily, with an allocator that doesn't
+    /// take its locks around `fork()` the way glibc's `malloc`, jemalloc and macOS's do: a child
+    /// of theirs can hang the moment it allocates, if it inherited a lock some other thread was
+    /// holding. With this, only the closure itself has to stay away from the allocator, and a
+    /// result bigger than `bytes` grows the buffer, allocating after all. It doesn't apply to
+    /// [compressed](Self::compression) results, or with a [`transport`](Self::transport) other
+    /// than the pipe, and the closure has only as much stack as the forking thread had.
+    ///
+    /// ```
+    /// use fork_map::Fork;
+    ///
+    /// let builder = Fork::builder().preallocate_result(1 << 10);
+    /// let small = unsafe { builder.clone().run(|| Ok([1u64, 2, 3])) }.unwrap();
+    /// assert_eq!(small, [1, 2, 3]);
+    /// // One that doesn't fit still gets through
+    /// let big = unsafe { builder.clone().run(|| Ok(vec![7u8; 1 << 20])) }.unwrap();
+    /// assert_eq!(big.len(), 1 << 20);
+    ///
+    /// # if !fork_map::FORKS { return }
+    /// // Panics are still caught, on the forking thread
+    /// let err = unsafe { builder.run(|| -> anyhow::Result<()> { panic!("not now") }) };
+    /// assert!(err.unwrap_err().to_string().contains("not now"));
+    /// ```
+    pub fn preallocate_result(mut self, bytes: usize) -> Self {
+        self.preallocate_result = Some(bytes);
+        self
+    }
+
+    /// Moves the child into a new process group of its own (`setpgid(0, 0)`).
+    ///
+    /// The child stays in the parent's session and keeps the parent's controlling terminal, but
+    /// job-control signals sent to the parent's group (such as `SIGINT` from Ctrl-C in a
+    /// terminal) no longer reach it. Anything the child spawns joins its group, so
+    /// `killpg(pid, sig)` signals the whole subtree at once. The parent also calls
+    /// `setpgid(pid, pid)` after forking so the group exists by the time you could observe the pid.
+    pub fn new_process_group(mut self, enable: bool) -> Self {
+        self.new_process_group = enable;
+        self
+    }
+
+    /// Moves the child into a new session (`setsid()`), which also makes it the leader of a new
+    /// process group.
+    ///
+    /// Compared to [`new_process_group`](Self::new_process_group), this additionally detaches the
+    /// child from the parent's controlling terminal, so terminal-generated signals (`SIGHUP` on
+    /// hangup, `SIGINT`, `SIGTSTP`) can't reach it at all, and it can't read from or write to the
+    /// terminal as a foreground job. Takes precedence over `new_process_group`.
+    ///
+    /// ```
+    /// use fork_map::Fork;
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// let (pid, sid) = unsafe {
+    ///     Fork::builder()
+    ///         .new_session(true)
+    ///         .run(|| Ok((libc::getpid(), libc::getsid(0))))
+    ///         .unwrap()
+    /// };
+    /// assert_eq!(pid, sid);
+    /// ```
+    pub fn new_session(mut self, enable: bool) -> Self {
+        self.new_session = enable;
+        self
+    }
+
+    /// Changes the child's working directory to `dir` before the closure runs, so relative paths
+    /// in it are relative to that. A directory that can't be changed to fails the child with
+    /// [`ForkError::Io`].
+    ///
+    /// ```
+    /// use fork_map::Fork;
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// let dir = unsafe {
+    ///     Fork::builder()
+    ///         .current_dir("/")
+    ///         .run(|| Ok(std::env::current_dir()?))
+    ///         .unwrap()
+    /// };
+    /// assert_eq!(dir, std::path::Path::new("/"));
+    /// // Only the child's
+    /// assert_ne!(std::env::current_dir().unwrap(), dir);
+    /// ```
+    pub fn current_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    /// Places the child into the cgroup v2 group at `path` (for example
+    /// `/sys/fs/cgroup/jobs/worker`) before your closure runs, so the group's `memory.max`,
+    /// `cpu.max` and friends apply to it.
+    ///
+    /// The parent must be allowed to write the group's `cgroup.procs`; otherwise this fails with
+    /// [`ForkError::Cgroup`](crate::ForkError::Cgroup) before forking. If the child is then
+    /// killed by the group's OOM killer, the result is
+    /// [`ForkError::OutOfMemory`](crate::ForkError::OutOfMemory).
+    ///
+    /// cgroups only exist on Linux; elsewhere this fails with
+    /// [`ForkError::Unsupported`](crate::ForkError::Unsupported) before forking.
+    ///
+    /// ```
+    /// use fork_map::{Fork, ForkError};
+    /// # use std::path::PathBuf;
+    /// # fn cgroup2_root() -> Option<PathBuf> {
+    /// #     let mounts = std::fs::read_to_string("/proc/self/mounts").ok()?;
+    /// #     let mount = mounts.lines().find(|l| l.split(' ').nth(2) == Some("cgroup2"))?;
+    /// #     Some(PathBuf::from(mount.split(' ').nth(1)?))
+    /// # }
+    /// # if !fork_map::FORKS { return }
+    /// # let Some(root) = cgroup2_root() else { return };
+    ///
+    /// let group = root.join(format!("fork-map-doctest-{}", std::process::id()));
+    /// # if std::fs::create_dir(&group).is_err() { return }
+    /// let cgroup = unsafe {
+    ///     Fork::builder()
+    ///         .cgroup(&group)
+    ///         .run(|| Ok(std::fs::read_to_string("/proc/self/cgroup")?))
+    ///         .unwrap()
+    /// };
+    /// assert!(cgroup.trim_end().ends_with(group.file_name().unwrap().to_str().unwrap()));
+    ///
+    /// // With the memory controller enabled, a child that goes over memory.max is reported
+    /// if std::fs::write(group.join("memory.max"), "16M").is_ok() {
+    ///     let _ = std::fs::write(group.join("memory.swap.max"), "0");
+    ///     let err = unsafe {
+    ///         Fork::builder()
+    ///             .cgroup(&group)
+    ///             .run(|| Ok(vec![1u8; 256 << 20].len()))
+    ///             .unwrap_err()
+    ///     };
+    ///     assert!(matches!(err.downcast_ref(), Some(ForkError::OutOfMemory { .. })));
+    /// }
+    /// # std::fs::remove_dir(&group).unwrap();
+    /// ```
+    pub fn cgroup(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cgroup = Some(path.into());
+        self
+    }
+
+    /// Has the child clean up after any processes it started, and any they started in turn, before
+    /// it exits: by the time the result comes back, they're all gone, rather than left running
+    /// with nobody to wait for them. Whatever of them is still around when the closure returns
+    /// gets `grace` to exit, after which it's killed with `SIGKILL`, and the child reaps them all.
+    /// How many there were goes in the [stats](crate::ForkStats::stray_descendants).
+    ///
+    /// The child makes itself a subreaper (`prctl(PR_SET_CHILD_SUBREAPER)`) before the closure
+    /// runs, so a process whose parent exits first is reparented to it rather than to init, and
+    /// can't get away by double forking. Only for closures; it's not applied to the workers of a
+    /// [pool](crate::ForkPool), or to a command run with [`exec`](Self::exec).
+    ///
+    /// Subreapers only exist on Linux; elsewhere this fails with
+    /// [`ForkError::Unsupported`](crate::ForkError::Unsupported) before forking.
+    ///
+    /// ```
+    /// use fork_map::Fork;
+    /// use std::process::Command;
+    /// use std::time::{Duration, Instant};
+    /// # if !fork_map::FORKS || !cfg!(target_os = "linux") { return }
+    ///
+    /// let start = Instant::now();
+    /// let (sleep, stats) = unsafe {
+    ///     Fork::builder()
+    ///         .reap_descendants(Duration::from_millis(100))
+    ///         .run_with_stats(|| {
+    ///             // The shell is gone before we are, leaving the sleep an orphan
+    ///             let script = "sleep 60 >/dev/null 2>&1 & echo $!";
+    ///             let shell = Command::new("sh").args(["-c", script]).output()?;
+    ///             Ok(String::from_utf8(shell.stdout)?.trim().parse::<u32>()?)
+    ///         })
+    ///         .unwrap()
+    /// };
+    /// assert_eq!(stats.stray_descendants, 1);
+    /// assert!(start.elapsed() < Duration::from_secs(5));
+    /// // Killed and reaped, so not even a zombie
+    /// let comm = std::fs::read_to_string(format!("/proc/{}/comm", sleep));
+    /// assert!(comm.map_or(true, |comm| comm != "sleep\n"));
+    ///
+    /// // Anything done within the grace period is only waited for
+    /// let (_, stats) = unsafe {
+    ///     Fork::builder()
+    ///         .reap_descendants(Duration::from_secs(5))
+    ///         .run_with_stats(|| Ok(Command::new("sleep").arg("0.1").spawn()?.id()))
+    ///         .unwrap()
+    /// };
+    /// assert_eq!(stats.stray_descendants, 1);
+    /// assert!(start.elapsed() < Duration::from_secs(5));
+    /// ```
+    pub fn reap_descendants(mut self, grace: Duration) -> Self {
+        self.reap_descendants = Some(grace);
+        self
+    }
+
+    /// Applies a macOS sandbox profile, written in the SBPL language `sandbox-exec` uses, to the
+    /// child before your closure runs.
+    ///
+    /// Some useful starting points:
+    /// * `(version 1) (allow default) (deny network*)` cuts off all network access
+    /// * `(version 1) (allow default) (deny file-write*)` makes the whole filesystem read-only
+    /// * `(version 1) (allow default) (deny file-read-data (require-not (subpath "/some/root")))`
+    ///   only lets the child read files under `/some/root`
+    ///
+    /// Failing to apply the profile (for example, a syntax error in it) fails the job, and your
+    /// closure is never run unsandboxed.
+    ///
+    /// Only available on macOS, so using it elsewhere is a compile error rather than silently
+    /// running without a sandbox. See [`cgroup`](Self::cgroup) for the closest Linux equivalent.
+    ///
+    /// ```
+    /// use fork_map::Fork;
+    ///
+    /// let profile = r#"(version 1) (allow default)
+    ///     (deny file-read-data (require-not (subpath "/private/tmp")))"#;
+    /// let result = unsafe {
+    ///     Fork::builder()
+    ///         .sandbox_profile(profile)
+    ///         .run(|| Ok(std::fs::read_to_string("/etc/hosts")?))
+    /// };
+    /// assert!(result.is_err());
+    /// ```
+    #[cfg(target_os = "macos")]
+    pub fn sandbox_profile(mut self, profile: impl Into<String>) -> Self {
+        self.sandbox_profile = Some(profile.into());
+        self
+    }
+
+    /// Restricts the system calls the child can make to those `profile` allows, with a
+    /// seccomp-bpf filter, before your closure runs. A call that isn't allowed kills the child
+    /// with `SIGSYS` on the spot, and the parent reports it as [`ForkError::SyscallBlocked`],
+    /// so a parser that's been taken over by its input can't open files or sockets, or `exec`
+    /// something else, only send back whatever result it likes.
+    ///
+    /// The filter is applied after the [`pre_exec`](Self::pre_exec) hooks, so they can still
+    /// open what the closure is to read, and failing to apply it fails the job without running
+    /// the closure. It stays in place for everything the child starts, so forking from the
+    /// closure isn't allowed, and a [`ForkPool`](crate::ForkPool)'s workers apply it once, for
+    /// all of their jobs. It needs Linux 4.14 or later.
+    ///
+    /// Only available on Linux on x86_64 and aarch64, so using it elsewhere is a compile error
+    /// rather than silently running unrestricted.
+    ///
+    /// ```
+    /// use fork_map::{Fork, ForkError, SeccompProfile};
+    /// use std::io::Read;
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// // Opened beforehand, since opening files isn't allowed in the child
+    /// let untrusted = std::fs::File::open("Cargo.toml").unwrap();
+    /// let parse = || {
+    ///     let mut input = vec![0u8; 1 << 16];
+    ///     let len = (&untrusted).read(&mut input)?;
+    ///     input.truncate(len);
+    ///     Ok(String::from_utf8(input)?.lines().count())
+    /// };
+    /// let lines = unsafe { Fork::builder().seccomp(SeccompProfile::new()).run(parse) };
+    /// assert!(lines.unwrap() > 0);
+    ///
+    /// // Phoning home takes a socket, which this profile doesn't allow
+    /// let err = unsafe {
+    ///     Fork::builder()
+    ///         .seccomp(SeccompProfile::new())
+    ///         .run(|| Ok(std::net::TcpStream::connect("127.0.0.1:9").is_ok()))
+    ///         .unwrap_err()
+    /// };
+    /// assert!(matches!(err.downcast_ref(), Some(ForkError::SyscallBlocked { .. })));
+    /// ```
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    pub fn seccomp(mut self, profile: crate::SeccompProfile) -> Self {
+        self.seccomp = Some(profile);
+        self
+    }
+
+    /// Registers a hook to run in the child after the other options have been applied, just
+    /// before your closure. Mirrors [`CommandExt::pre_exec`](std::os::unix::process::CommandExt::pre_exec);
+    /// hooks run in the order they were added.
+    ///
+    /// If a hook returns an error, the remaining hooks and your closure are skipped and the
+    /// error is returned from [`run`](Self::run), just like an error from the closure would be.
+    /// On macOS, the [`sandbox_profile`](Self::sandbox_profile) is applied after the hooks, so
+    /// they aren't restricted by it.
+    ///
+    /// The hook runs in the forked child, so everything in [`fork_map`](crate::fork_map)'s
+    /// safety section applies to it, and more harshly: only the forking thread exists in the
+    /// child, so any lock another thread held at the time of the fork (including the allocator's
+    /// or stdio's) stays locked forever. Stick to async-signal-safe calls like `chdir`, `dup2`,
+    /// `setrlimit` or `signal` where you can.
+    ///
+    /// ```
+    /// use fork_map::Fork;
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// let dir = unsafe {
+    ///     Fork::builder()
+    ///         .pre_exec(|| Ok(std::env::set_current_dir("/")?))
+    ///         .run(|| Ok(std::env::current_dir()?))
+    ///         .unwrap()
+    /// };
+    /// assert_eq!(dir, std::path::Path::new("/"));
+    ///
+    /// let err = unsafe {
+    ///     Fork::builder()
+    ///         .pre_exec(|| Err(anyhow::anyhow!("not today")))
+    ///         .run(|| -> anyhow::Result<()> { panic!("never runs") })
+    ///         .unwrap_err()
+    /// };
+    /// assert_eq!(err.to_string(), "not today");
+    /// ```
+    pub fn pre_exec<F>(mut self, hook: F) -> Self
+    where
+        F: Fn() -> anyhow::Result<()> + Send + Sync + 'static,
+    {
+        self.pre_exec.0.push(Arc::new(hook));
+        self
+    }
+
+    /// Registers a callback to run in the parent right after forking, with the child's pid.
+    /// Callbacks run in the order they were added.
+    ///
+    /// The child is held back until every callback has returned, so this is the place to attach
+    /// a profiler or debugger to it, or to write its pid into a job log, before your closure
+    /// starts. When [`FORKS`](crate::FORKS) is `false`, the callbacks get the pid of the current
+    /// process instead. Use [`spawn`](Self::spawn) if you'd rather have the pid on a handle.
+    ///
+    /// ```
+    /// use fork_map::Fork;
+    /// use std::sync::atomic::{AtomicU32, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let forked = Arc::new(AtomicU32::new(0));
+    /// let pid = unsafe {
+    ///     Fork::builder()
+    ///         .on_fork({
+    ///             let forked = forked.clone();
+    ///             move |pid| forked.store(pid, Ordering::SeqCst)
+    ///         })
+    ///         .run(|| Ok(std::process::id()))
+    ///         .unwrap()
+    /// };
+    /// assert_eq!(forked.load(Ordering::SeqCst), pid);
+    /// ```
+    pub fn on_fork<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(u32) + Send + Sync + 'static,
+    {
+        self.on_fork.0.push(Arc::new(callback));
+        self
+    }
+
+    /// Skips forking and runs the closure in the calling process, for debugging it with
+    /// breakpoints and backtraces that work normally.
+    ///
+    /// The result still makes the round-trip through the [`codec`](Self::codec) (and
+    /// [`compression`](Self::compression)), so serialization bugs show up here too. Everything
+    /// else the child would have been isolated from is now shared: memory leaks, global state,
+    /// crashes. This behaves exactly like the `fallback` feature, including failing with
+    /// [`ForkError::Unsupported`] if any options that need a child process are set.
+    ///
+    /// ```
+    /// use fork_map::Fork;
+    ///
+    /// let pid = unsafe {
+    ///     Fork::builder()
+    ///         .inline(true)
+    ///         .run(|| Ok(std::process::id()))
+    ///         .unwrap()
+    /// };
+    /// assert_eq!(pid, std::process::id());
+    /// ```
+    pub fn inline(mut self, enable: bool) -> Self {
+        self.inline = enable;
+        self
+    }
+
+    /// Stops the child instead of letting it die when it crashes with `SIGSEGV`, `SIGBUS`,
+    /// `SIGILL`, `SIGFPE` or `SIGABRT`, and prints its pid to standard error, so you can attach a
+    /// debugger (`gdb -p <pid>`) and look around while everything is still there. Once it's sent
+    /// `SIGCONT`, it dies of the original signal, and the parent sees the crash as usual.
+    ///
+    /// Meant for crashes too rare to reproduce on demand. The parent waits for a stopped child
+    /// like for any other, unless there's a [`timeout`](Self::timeout). Setting the
+    /// `FORK_MAP_PAUSE_ON_CRASH` environment variable to `1` turns this on for every child,
+    /// without changing any code.
+    ///
+    /// ```
+    /// use fork_map::{interpret_status, ExitOutcome, Fork, ForkError};
+    /// use std::sync::atomic::{AtomicU32, Ordering};
+    /// use std::sync::Arc;
+    /// use std::time::Duration;
+    /// # if !fork_map::FORKS || !cfg!(target_os = "linux") { return }
+    ///
+    /// let pid = Arc::new(AtomicU32::new(0));
+    /// let debugger = {
+    ///     let pid = pid.clone();
+    ///     std::thread::spawn(move || loop {
+    ///         // Standing in for someone attaching, looking around, and moving on
+    ///         let child = pid.load(Ordering::SeqCst);
+    ///         let stat = std::fs::read_to_string(format!("/proc/{}/stat", child));
+    ///         if stat.is_ok_and(|stat| stat.contains(") T ")) {
+    ///             unsafe { libc::kill(child as i32, libc::SIGCONT) };
+    ///             return;
+    ///         }
+    ///         std::thread::sleep(Duration::from_millis(10));
+    ///     })
+    /// };
+    /// let err = unsafe {
+    ///     Fork::builder()
+    ///         .pause_on_crash(true)
+    ///         .on_fork(move |child| pid.store(child, Ordering::SeqCst))
+    ///         .run(|| -> anyhow::Result<()> {
+    ///             libc::raise(libc::SIGSEGV);
+    ///             Ok(())
+    ///         })
+    ///         .unwrap_err()
+    /// };
+    /// debugger.join().unwrap();
+    /// match err.downcast_ref() {
+    ///     Some(ForkError::ChildFailed { status, .. }) => {
+    ///         assert_eq!(interpret_status(*status), ExitOutcome::Signaled(libc::SIGSEGV));
+    ///     }
+    ///     _ => panic!("unexpected error {:?}", err),
+    /// }
+    /// ```
+    pub fn pause_on_crash(mut self, enable: bool) -> Self {
+        self.pause_on_crash = enable;
+        self
+    }
+
+    /// Whether the child puts every signal back to its default action and unblocks them all
+    /// before running the closure, like a freshly started program. Defaults to `true`.
+    ///
+    /// Otherwise it has whatever the forking thread had: the handlers of a parent that cleans up
+    /// on `SIGTERM`, say, which might do the wrong thing in the child, and the signals it
+    /// ignores or blocks. Those last two are also inherited by any program the child runs, which
+    /// leaves it unable to be interrupted, or unaware its output has gone away. Rust itself
+    /// ignores `SIGPIPE` in every program, and signal-handling libraries often block signals on
+    /// every thread but their own.
+    ///
+    /// Signals being [forwarded](crate::install_signal_forwarding) are still forwarded to the
+    /// child's own children, before the default action. The child ignores `SIGPIPE` again
+    /// once the closure returns, so if the parent has gone away by the time the result is sent,
+    /// it exits with [`EXIT_PARENT_GONE`](crate::EXIT_PARENT_GONE) rather than dying of it. When
+    /// [`FORKS`](crate::FORKS) is `false`, there's no child, and nothing is reset.
+    ///
+    /// ```
+    /// use fork_map::Fork;
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// unsafe fn observe() -> anyhow::Result<(bool, bool)> {
+    ///     let pipe = libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+    ///     let mut mask: libc::sigset_t = std::mem::zeroed();
+    ///     libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &mut mask);
+    ///     Ok((pipe == libc::SIG_IGN, libc::sigismember(&mask, libc::SIGUSR1) == 1))
+    /// }
+    ///
+    /// // Whether SIGPIPE is ignored, and whether SIGUSR1 is blocked
+    /// let (reset, kept) = unsafe {
+    ///     libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+    ///     let mut usr1: libc::sigset_t = std::mem::zeroed();
+    ///     libc::sigemptyset(&mut usr1);
+    ///     libc::sigaddset(&mut usr1, libc::SIGUSR1);
+    ///     libc::pthread_sigmask(libc::SIG_BLOCK, &usr1, std::ptr::null_mut());
+    ///     let observed = (
+    ///         Fork::builder().run(|| observe()).unwrap(),
+    ///         Fork::builder().reset_signals(false).run(|| observe()).unwrap(),
+    ///     );
+    ///     libc::pthread_sigmask(libc::SIG_UNBLOCK, &usr1, std::ptr::null_mut());
+    ///     observed
+    /// };
+    /// assert_eq!(reset, (false, false));
+    /// assert_eq!(kept, (true, true));
+    /// ```
+    pub fn reset_signals(mut self, enable: bool) -> Self {
+        self.keep_signals = !enable;
+        self
+    }
+
+    /// Flushes Rust's `stdout` and `stderr` and all C stdio streams (`fflush(NULL)`) right before
+    /// forking. See [`fork_map_after_flush`](crate::fork_map_after_flush) for why you'd want to.
+    pub fn flush_stdio(mut self, enable: bool) -> Self {
+        self.flush_stdio = enable;
+        self
+    }
+
+    /// Fails with [`ForkError::WouldBlock`] rather than waiting when the
+    /// [concurrency limit](crate::set_max_concurrent_forks) has been reached. See
+    /// [`try_fork_map`](crate::try_fork_map).
+    pub fn non_blocking(mut self, enable: bool) -> Self {
+        self.non_blocking = enable;
+        self
+    }
+
+    /// Caps the size of the result the parent is willing to receive, as a safety valve for its
+    /// memory when a child's result could grow without bound.
+    ///
+    /// The limit applies to the serialized (and [`compression`](Self::compression)ed) body. The
+    /// size is announced before the body, so an oversized result is rejected before any of it
+    /// is read; the child is killed, and the result is [`ForkError::ResultTooLarge`].
+    ///
+    /// ```
+    /// use fork_map::{Fork, ForkError};
+    ///
+    /// let err = unsafe {
+    ///     Fork::builder()
+    ///         .max_result_bytes(1 << 20)
+    ///         .run(|| Ok(vec![0u8; 4 << 20]))
+    ///         .unwrap_err()
+    /// };
+    /// assert!(matches!(
+    ///     err.downcast_ref(),
+    ///     Some(ForkError::ResultTooLarge { limit: 1048576, .. })
+    /// ));
+    /// ```
+    pub fn max_result_bytes(mut self, limit: usize) -> Self {
+        self.max_result_bytes = Some(limit);
+        self
+    }
+
+    /// Kills the child if its resident set grows past `bytes`, and fails with
+    /// [`ForkError::MemoryLimitExceeded`]. Unlike an `RLIMIT_AS` set in
+    /// [`pre_exec`](Self::pre_exec), this counts only the memory the child actually uses, not
+    /// what it has mapped, and it works the same on macOS, where that limit isn't enforced.
+    ///
+    /// The parent checks every [`max_rss_interval`](Self::max_rss_interval) while it waits for
+    /// the result, for every child it's waiting on at once with [`map_iter`](Self::map_iter)
+    /// and the like, reading `/proc/<pid>/statm` on Linux and asking `proc_pidinfo` on macOS, so
+    /// a child that allocates quickly can get that far past the limit before it's caught. The
+    /// pages it shares with the parent count too, until either of them writes to them. Only
+    /// available on Linux and macOS, and only for closures, so it fails with
+    /// [`ForkError::Unsupported`] elsewhere, for [`exec`](Self::exec), and when
+    /// [`FORKS`](crate::FORKS) is `false`.
+    ///
+    /// ```
+    /// use fork_map::{Fork, ForkError};
+    /// use std::time::{Duration, Instant};
+    /// # if !fork_map::FORKS || !cfg!(any(target_os = "linux", target_os = "macos")) { return }
+    ///
+    /// let start = Instant::now();
+    /// let err = unsafe {
+    ///     Fork::builder()
+    ///         .max_rss(100 << 20)
+    ///         .max_rss_interval(Duration::from_millis(20))
+    ///         .run(|| {
+    ///             let big = vec![1u8; 500 << 20];
+    ///             std::thread::sleep(Duration::from_secs(10));
+    ///             Ok(big.len())
+    ///         })
+    ///         .unwrap_err()
+    /// };
+    /// match err.downcast_ref() {
+    ///     Some(&ForkError::MemoryLimitExceeded { observed, limit, .. }) => {
+    ///         assert_eq!(limit, 100 << 20);
+    ///         assert!(observed > limit);
+    ///     }
+    ///     _ => panic!("unexpected error {:?}", err),
+    /// }
+    /// assert!(start.elapsed() < Duration::from_secs(5));
+    /// ```
+    pub fn max_rss(mut self, bytes: u64) -> Self {
+        self.max_rss = Some(bytes);
+        self
+    }
+
+    /// How often the parent checks the child's memory against [`max_rss`](Self::max_rss).
+    /// Defaults to 100ms. Each check reads a small file or makes one system call, so this can
+    /// be short, at the cost of waking the parent more often.
+    pub fn max_rss_interval(mut self, interval: Duration) -> Self {
+        self.max_rss_interval = Some(interval);
+        self
+    }
+
+    /// Kills the child if it hasn't finished `timeout` after it was forked, and fails with
+    /// [`ForkError::Timeout`].
+    ///
+    /// What counts is the result: if the whole result has arrived by the deadline, it's
+    /// returned even though the child had to be killed on its way out, for example because of
+    /// slow cleanup in an `atexit` handler. Needs a child to kill, so with
+    /// [`inline`](Self::inline) or without [`FORKS`](crate::FORKS) this fails with
+    /// [`ForkError::Unsupported`].
+    ///
+    /// ```
+    /// use fork_map::{Fork, ForkError};
+    /// use std::time::{Duration, Instant};
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// let err = unsafe {
+    ///     Fork::builder()
+    ///         .timeout(Duration::from_millis(100))
+    ///         .run(|| {
+    ///             std::thread::sleep(Duration::from_secs(10));
+    ///             Ok(())
+    ///         })
+    ///         .unwrap_err()
+    /// };
+    /// assert!(matches!(err.downcast_ref(), Some(ForkError::Timeout { .. })));
+    ///
+    /// // Done in time, but slow to exit
+    /// extern "C" fn linger() {
+    ///     std::thread::sleep(Duration::from_secs(10));
+    /// }
+    /// let start = Instant::now();
+    /// let result = unsafe {
+    ///     Fork::builder()
+    ///         .timeout(Duration::from_millis(500))
+    ///         .run(|| {
+    ///             libc::atexit(linger);
+    ///             Ok(42)
+    ///         })
+    ///         .unwrap()
+    /// };
+    /// assert_eq!(result, 42);
+    /// assert!(start.elapsed() < Duration::from_secs(5));
+    /// ```
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sends the child's standard output to a pipe, which the handle from [`spawn`](Self::spawn)
+    /// reads from with [`stdout`](ForkHandle::stdout) while the child runs. See there.
+    pub fn capture_stdout(mut self, enable: bool) -> Self {
+        self.capture_stdout = enable;
+        self
+    }
+
+    /// Points the child's standard input, output and error at `/dev/null`, for a child that
+    /// has no business reading the parent's terminal or cluttering its output. Standard output
+    /// still goes to the parent if it's [captured](Self::capture_stdout).
+    ///
+    /// ```
+    /// use fork_map::Fork;
+    /// use std::io::Read;
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// let read = unsafe {
+    ///     Fork::builder()
+    ///         .null_stdio(true)
+    ///         .run(|| {
+    ///             println!("nobody will see this");
+    ///             let mut input = vec![];
+    ///             Ok(std::io::stdin().read_to_end(&mut input)?)
+    ///         })
+    ///         .unwrap()
+    /// };
+    /// // Nothing to read, rather than waiting on whoever's at the terminal
+    /// assert_eq!(read, 0);
+    /// ```
+    pub fn null_stdio(mut self, enable: bool) -> Self {
+        self.null_stdio = enable;
+        self
+    }
+
+    /// Picks the signal a child is killed with when it has to be stopped early: when it
+    /// [times out](Self::timeout), sends more than [`max_result_bytes`](Self::max_result_bytes),
+    /// outgrows [`max_rss`](Self::max_rss), loses a [race](crate::fork_map_race), or is
+    /// abandoned by a
+    /// [scope](crate::fork_scope) or an iterator that's dropped. Defaults to [`Signal::Kill`].
+    ///
+    /// Anything else can be caught, which gives the child a chance to release what it holds
+    /// outside of its own memory, like file locks or temporary files, but also to ignore it, so
+    /// set a [`kill_grace`](Self::kill_grace) too.
+    pub fn kill_signal(mut self, signal: Signal) -> Self {
+        self.kill_signal = signal;
+        self
+    }
+
+    /// How long a child gets to exit after the [`kill_signal`](Self::kill_signal), before it's
+    /// killed with `SIGKILL` after all. Defaults to zero, which means it gets the one signal and
+    /// is then waited for however long it takes. Children stopped together, like the losers of
+    /// a race, are signalled together and share the one grace period.
+    ///
+    /// ```
+    /// use fork_map::{Fork, ForkError, Signal};
+    /// use std::ffi::CString;
+    /// use std::sync::OnceLock;
+    /// use std::time::{Duration, Instant};
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// static LOCK_FILE: OnceLock<CString> = OnceLock::new();
+    /// let path = std::env::temp_dir().join(format!("fork-map-grace-{}", std::process::id()));
+    /// LOCK_FILE.set(CString::new(path.to_str().unwrap()).unwrap()).unwrap();
+    ///
+    /// extern "C" fn clean_up(_: libc::c_int) {
+    ///     unsafe {
+    ///         libc::unlink(LOCK_FILE.get().unwrap().as_ptr());
+    ///         libc::_exit(1);
+    ///     }
+    /// }
+    /// let builder = Fork::builder()
+    ///     .timeout(Duration::from_millis(100))
+    ///     .kill_signal(Signal::Term)
+    ///     .kill_grace(Duration::from_secs(1));
+    /// let err = unsafe {
+    ///     builder.clone().run(|| {
+    ///         std::fs::write(&path, b"locked")?;
+    ///         libc::signal(libc::SIGTERM, clean_up as libc::sighandler_t);
+    ///         std::thread::sleep(Duration::from_secs(10));
+    ///         Ok(())
+    ///     })
+    /// }
+    /// .unwrap_err();
+    /// assert!(matches!(err.downcast_ref(), Some(ForkError::Timeout { .. })));
+    /// assert!(!path.exists());
+    ///
+    /// // One that won't listen gets killed once its time is up
+    /// let start = Instant::now();
+    /// let err = unsafe {
+    ///     builder.run(|| {
+    ///         libc::signal(libc::SIGTERM, libc::SIG_IGN);
+    ///         std::thread::sleep(Duration::from_secs(10));
+    ///         Ok(())
+    ///     })
+    /// }
+    /// .unwrap_err();
+    /// assert!(matches!(err.downcast_ref(), Some(ForkError::Timeout { .. })));
+    /// assert!(start.elapsed() >= Duration::from_secs(1));
+    /// assert!(start.elapsed() < Duration::from_secs(5));
+    /// ```
+    pub fn kill_grace(mut self, grace: Duration) -> Self {
+        self.kill_grace = grace;
+        self
+    }
+
+    /// Has a command run by [`exec`](Self::exec) write its result to file descriptor `fd`
+    /// instead of standard output, which is then left however the command was set up (inherited,
+    /// unless you said otherwise).
+    ///
+    /// The write end of a pipe is `dup2`'d onto `fd` between `fork()` and `exec()`, and is the
+    /// only descriptor of ours the command inherits. It's read and decoded exactly like standard
+    /// output would have been, so the command writes its result there encoded with the
+    /// [`codec`](Self::codec), and is done once it has exited. This frees standard output up for
+    /// logs, or for programs that insist on printing things. Only [`exec`](Self::exec) uses this,
+    /// and it needs `dup2`, so without [`FORKS`](crate::FORKS) it fails with
+    /// [`ForkError::Unsupported`].
+    ///
+    /// ```
+    /// use fork_map::Fork;
+    /// use std::process::Command;
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// let mut chatty = Command::new("sh");
+    /// chatty.args(["-c", "read x; echo 'thinking...'; echo $((x + 1)) >&3"]);
+    /// let builder = Fork::builder().result_fd(3);
+    /// let result: u64 = unsafe { builder.exec(&mut chatty, &41) }.unwrap();
+    /// assert_eq!(result, 42);
+    /// ```
+    pub fn result_fd(mut self, fd: i32) -> Self {
+        self.result_fd = Some(fd);
+        self
+    }
+
+    /// Caps how many children the iterator APIs like [`map_iter`](Self::map_iter) keep running
+    /// at once. Defaults to [`std::thread::available_parallelism`].
+    pub fn max_concurrent(mut self, limit: usize) -> Self {
+        self.max_concurrent = Some(limit);
+        self
+    }
+
+    /// Tags the job with `id`, like the index of the item it's working on, which is attached to
+    /// any error it fails with as a [`ForkId`] (see there for an example), so failures can be told
+    /// apart when many jobs run at once.
+    ///
+    /// Applies to [`run`](Self::run) and its variants, and to [`run_par`](Self::run_par), which
+    /// wraps each item's error in a [`ForkError::Job`] with this id, or the item's position if
+    /// there isn't one. The iterator APIs, which also return a [`ForkError`] for each item,
+    /// return them in the order of the items without an id.
+    pub fn id(mut self, id: impl fmt::Display) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    /// Runs the closure again in a fresh child when an attempt fails in a way `policy` considers
+    /// transient, like the child crashing. See [`fork_map_retry`](crate::fork_map_retry).
+    ///
+    /// Applies to [`run`](Self::run) and its variants, which return the result (and statistics)
+    /// of the attempt that succeeded. [`spawn`](Self::spawn) always makes one attempt.
+    pub fn retries(mut self, policy: RetryPolicy) -> Self {
+        self.retries = Some(policy);
+        self
+    }
+
+    /// Forks, applies the configured options in the child, and then runs `func` there.
+    /// Waits for the child to terminate and returns the result of `func`.
+    ///
+    /// If the child can't be set up as requested, `func` is never run and the setup failure is
+    /// returned instead.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run<F, R>(self, func: F) -> anyhow::Result<R>
+    where
+        F: Fn() -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        self.run_with_stats(func).map(|(result, _)| result)
+    }
+
+    /// Like [`run`](Self::run), but also returns the wall-clock time from just before `fork()`
+    /// until the child was reaped. See [`fork_map_timed`](crate::fork_map_timed).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_timed<F, R>(self, func: F) -> anyhow::Result<(R, Duration)>
+    where
+        F: Fn() -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        self.run_with_stats(func)
+            .map(|(result, stats)| (result, stats.timings.reaped))
+    }
+
+    /// Like [`run`](Self::run), but also returns the resources the child used and how long each
+    /// phase took. See [`fork_map_with_stats`](crate::fork_map_with_stats).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_with_stats<F, R>(self, func: F) -> anyhow::Result<(R, ForkStats)>
+    where
+        F: Fn() -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        if self.retries.is_none() {
+            let id = self.id.clone();
+            return tag(id, self.spawn(func).and_then(ForkHandle::join_with_stats));
+        }
+        self.with_retries(|| {
+            self.clone()
+                .spawn(&func)
+                .and_then(ForkHandle::join_with_stats)
+        })
+    }
+
+    /// Like [`run`](Self::run), but the closure also picks the code the child exits with, which
+    /// is returned along with its result. See
+    /// [`fork_map_with_exit_code`](crate::fork_map_with_exit_code).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_with_exit_code<F, R>(self, func: F) -> anyhow::Result<(R, i32)>
+    where
+        F: Fn() -> anyhow::Result<(R, i32)> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        self.with_retries(|| self.clone().exit_code_once(&func))
+    }
+
+    unsafe fn exit_code_once<F, R>(self, func: &F) -> anyhow::Result<(R, i32)>
+    where
+        F: Fn() -> anyhow::Result<(R, i32)> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        #[cfg(feature = "testing")]
+        if let Some(error) = crate::testing::take() {
+            return Err(error.into());
+        }
+        #[cfg(all(unix, not(feature = "fallback")))]
+        if !self.inline {
+            return self.run_forked_with_exit_code(func);
+        }
+        // Without a child to exit, the code is just part of the result
+        self.run_in_process(func)?.join()
+    }
+
+    /// Like [`run`](Self::run), but `func` gets `input`, which is sent to the child rather than
+    /// copied into it by `fork()`. See [`fork_map_with_input`](crate::fork_map_with_input).
+    ///
+    /// The input goes through the [`codec`](Self::codec) like the result does.
+    /// [`retries`](Self::retries) don't apply, since `func` can only be called once.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_with_input<I, F, R>(self, input: I, func: F) -> anyhow::Result<R>
+    where
+        I: Serialize + for<'a> Deserialize<'a>,
+        F: FnOnce(I) -> anyhow::Result<R> + Send,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        #[cfg(feature = "testing")]
+        if let Some(error) = crate::testing::take() {
+            return Err(error.into());
+        }
+        let codec = self.codec;
+        let input = codec.encode(&input)?;
+        #[cfg(all(unix, not(feature = "fallback")))]
+        if !self.inline {
+            return self.run_forked_with_input(input, func);
+        }
+        // Without a child the input still makes the same trip through the codec
+        let func = Cell::new(Some(func));
+        self.run_in_process(|| {
+            let func = func.take().expect("closure called twice");
+            func(codec.decode(std::process::id(), &input)?)
+        })?
+        .join()
+    }
+
+    /// Like [`run`](Self::run), but the result comes back over `read` and `write`, the two ends
+    /// of a pipe or socket of the caller's, rather than a pipe of our own. See
+    /// [`fork_map_on_fds`](crate::fork_map_on_fds).
+    ///
+    /// [`retries`](Self::retries) don't apply, since the descriptors can only be used once.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    #[cfg(unix)]
+    pub unsafe fn run_on_fds<F, R>(
+        self,
+        read: OwnedFd,
+        write: OwnedFd,
+        func: F,
+    ) -> anyhow::Result<R>
+    where
+        F: Fn() -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        #[cfg(feature = "testing")]
+        if let Some(error) = crate::testing::take() {
+            return Err(error.into());
+        }
+        #[cfg(all(unix, not(feature = "fallback")))]
+        if !self.inline {
+            let child = self.fork_child_on(Some((read, write)), |_| func())?;
+            return child.wait().map(|(result, _)| result);
+        }
+        // There's nothing for them to carry
+        drop((read, write));
+        self.run_in_process(func)?.join()
+    }
+
+    /// Calls `attempt` until it succeeds or the [`retries`](Self::retries) policy gives up, and
+    /// tags the error with the [`id`](Self::id) if it does.
+    pub(crate) fn with_retries<T>(
+        &self,
+        attempt: impl FnMut() -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        tag(self.id.clone(), self.retry(attempt))
+    }
+
+    fn retry<T>(&self, mut attempt: impl FnMut() -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let Some(policy) = &self.retries else {
+            return attempt();
+        };
+        let mut attempts = 1;
+        loop {
+            let error = match attempt() {
+                Ok(result) => return Ok(result),
+                Err(error) => error,
+            };
+            if !policy.should_retry(&error) {
+                return Err(error);
+            }
+            if attempts == policy.max_attempts {
+                return Err(ForkError::RetriesExhausted {
+                    attempts,
+                    last: error,
+                }
+                .into());
+            }
+            std::thread::sleep(policy.delay(attempts));
+            attempts += 1;
+        }
+    }
+
+    /// Like [`run`](Self::run), but with the closure's own errors kept apart from the
+    /// machinery's, in a result of their own. See [`fork_map_nested`](crate::fork_map_nested).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_nested<F, R, E>(self, func: F) -> Result<Result<R, E>, ForkError>
+    where
+        F: Fn() -> Result<R, E> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+        E: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        // The closure's error is just another value on the way back, so whatever fails is ours
+        self.run(|| Ok(func())).map_err(ForkError::from_anyhow)
+    }
+
+    /// Like [`run`](Self::run), but the child's result is sent back as an `S` and received as
+    /// a `D`. See [`fork_map_into`](crate::fork_map_into).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_into<S, D, F>(self, func: F) -> anyhow::Result<D>
+    where
+        F: Fn() -> anyhow::Result<S> + Send + Sync,
+        S: Serialize + Send,
+        D: for<'a> Deserialize<'a>,
+    {
+        self.with_retries(|| self.clone().spawn_into(&func)?.join())
+    }
+
+    /// Forks and starts running `func` in the child like [`run`](Self::run), but returns right
+    /// away with a handle instead of waiting for the result.
+    ///
+    /// The handle gives you the child's pid while it runs, and [`ForkHandle::join`] waits for the
+    /// result. Until you join, the child blocks once it has filled the pipe with its result.
+    /// Errors setting up the fork are returned here; anything that goes wrong after is returned
+    /// from `join`.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map). `func` has already been copied into the child when
+    /// this returns, so it's fine for it to borrow from the caller.
+    pub unsafe fn spawn<F, R>(self, func: F) -> anyhow::Result<ForkHandle<R>>
+    where
+        F: Fn() -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        self.spawn_into(func)
+    }
+
+    /// Like [`spawn`](Self::spawn), with the child sending back an `S` and the handle decoding
+    /// it as a `D`. See [`run_into`](Self::run_into).
+    pub(crate) unsafe fn spawn_into<F, S, D>(self, func: F) -> anyhow::Result<ForkHandle<D>>
+    where
+        F: Fn() -> anyhow::Result<S> + Send + Sync,
+        S: Serialize + Send,
+        D: for<'a> Deserialize<'a>,
+    {
+        #[cfg(feature = "testing")]
+        if let Some(error) = crate::testing::take() {
+            return Err(error.into());
+        }
+        #[cfg(all(unix, not(feature = "fallback")))]
+        if !self.inline {
+            return self.spawn_forked(func);
+        }
+        self.run_in_process(func)
+    }
+
+    /// Forks `n` children at once, runs `func(index)` in each, and collects their results in
+    /// index order. See [`fork_map_n`](crate::fork_map_n).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_n<F, R>(self, n: usize, func: F) -> Vec<Result<R, ForkError>>
+    where
+        F: Fn(usize) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        self.max_concurrent(n).run_batched(0..n, func)
+    }
+
+    /// Forks `n` children that all run `func`, returns the first successful result, and kills
+    /// the rest. See [`fork_map_race`](crate::fork_map_race).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_race<F, R>(self, n: usize, func: F) -> anyhow::Result<R>
+    where
+        F: Fn() -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        let mut running = Running::new();
+        let mut errors = vec![];
+        for _ in 0..n.max(1) {
+            match self.clone().spawn(&func) {
+                Ok(handle) => running.push((), handle),
+                Err(e) => errors.push(ForkError::from_anyhow(e)),
+            }
+        }
+        while let Some(((), result)) = running.next() {
+            match result {
+                Ok((result, _)) => {
+                    // The losers are reaped as `running` is dropped
+                    running.kill_all();
+                    return Ok(result);
+                }
+                Err(e) => errors.push(ForkError::from_anyhow(e)),
+            }
+        }
+        Err(ForkError::AllFailed { errors }.into())
+    }
+
+    /// Runs `func` on each of `items` in a child of its own, with up to
+    /// [`max_concurrent`](Self::max_concurrent) running at once, and returns the results in the
+    /// order of the items. See [`fork_map_batched`](crate::fork_map_batched).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_batched<I, F, R>(self, items: I, func: F) -> Vec<Result<R, ForkError>>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        // Slotted by index, so the order children finish in can't leak into the output
+        let mut results: Vec<Option<Result<R, ForkError>>> = vec![];
+        self.for_each_completed(items, func, |index, result| {
+            if index >= results.len() {
+                results.resize_with(index + 1, || None);
+            }
+            results[index] = Some(result);
+        });
+        results
+            .into_iter()
+            .map(|result| result.expect("every item has a result"))
+            .collect()
+    }
+
+    /// Runs `func` on `items` a chunk of `chunk_size` at a time, with a child for each chunk, and
+    /// returns a result for each item, in the order of the items. See
+    /// [`fork_map_chunked`](crate::fork_map_chunked).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_chunked<I, F, R>(
+        self,
+        items: I,
+        chunk_size: usize,
+        func: F,
+    ) -> Vec<Result<R, ForkError>>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(Vec<I::Item>) -> anyhow::Result<Vec<R>> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        let mut items = items.into_iter();
+        let mut lens = vec![];
+        let chunks = std::iter::from_fn(|| {
+            let chunk: Vec<_> = items.by_ref().take(chunk_size.max(1)).collect();
+            lens.push(chunk.len());
+            (!chunk.is_empty()).then_some(chunk)
+        });
+        let chunk_results = self.run_batched(chunks, func);
+
+        let mut results = Vec::with_capacity(lens.iter().sum());
+        for (result, len) in chunk_results.into_iter().zip(lens) {
+            let source = match result {
+                Ok(values) if values.len() == len => {
+                    results.extend(values.into_iter().map(Ok));
+                    continue;
+                }
+                Ok(values) => ForkError::Closure(anyhow::anyhow!(
+                    "returned {} results for a chunk of {} items",
+                    values.len(),
+                    len
+                )),
+                Err(e) => e,
+            };
+            let items = results.len()..results.len() + len;
+            let source = Arc::new(source);
+            for _ in 0..len {
+                results.push(Err(ForkError::ChunkFailed {
+                    items: items.clone(),
+                    source: source.clone(),
+                }));
+            }
+        }
+        results
+    }
+
+    /// Like [`run_batched`](Self::run_batched), but instead of collecting the results, passes
+    /// each one to `on_complete` along with its item's index as soon as its child finishes. See
+    /// [`fork_map_for_each_completed`](crate::fork_map_for_each_completed).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn for_each_completed<I, F, R, C>(self, items: I, func: F, mut on_complete: C)
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+        C: FnMut(usize, Result<R, ForkError>),
+    {
+        for (index, result) in self.map_iter_unordered(items, func) {
+            on_complete(index, result);
+        }
+    }
+
+    /// Runs `func` on each of `items` in a child of its own, configured by this builder, with up
+    /// to [`max_concurrent`](Self::max_concurrent) children running at once. See
+    /// [`fork_map_iter`](crate::fork_map_iter).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map), for every call to the iterator's `next`.
+    ///
+    /// # Example
+    ///
+    /// Options apply to each child on its own, like [`max_rss`](Self::max_rss) here:
+    ///
+    /// ```
+    /// use fork_map::{Fork, ForkError};
+    /// use std::time::Duration;
+    /// # if !fork_map::FORKS || !cfg!(any(target_os = "linux", target_os = "macos")) { return }
+    ///
+    /// let builder = Fork::builder().max_rss(100 << 20);
+    /// let results: Vec<_> = unsafe {
+    ///     builder.map_iter([1usize, 500], |mib| {
+    ///         let big = vec![1u8; mib << 20];
+    ///         std::thread::sleep(Duration::from_secs(2));
+    ///         Ok(big.len())
+    ///     })
+    /// }
+    /// .collect();
+    /// assert_eq!(results[0].as_ref().unwrap(), &(1 << 20));
+    /// assert!(matches!(
+    ///     results[1],
+    ///     Err(ForkError::MemoryLimitExceeded { limit: 104857600, .. })
+    /// ));
+    /// ```
+    pub unsafe fn map_iter<I, F, R>(self, items: I, func: F) -> ForkMapIter<I::IntoIter, F, R>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        ForkMapIter::new(self, items.into_iter(), func)
+    }
+
+    /// Like [`map_iter`](Self::map_iter), but yields results as the children finish. See
+    /// [`fork_map_iter_unordered`](crate::fork_map_iter_unordered).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map), for every call to the iterator's `next`.
+    pub unsafe fn map_iter_unordered<I, F, R>(
+        self,
+        items: I,
+        func: F,
+    ) -> ForkMapIterUnordered<I::IntoIter, F, R>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        ForkMapIterUnordered::new(self, items.into_iter(), func)
+    }
+
+    /// Starts running `func` on each of `items` from a thread of its own, and returns a channel
+    /// the results arrive on as the children finish. See
+    /// [`fork_map_channel`](crate::fork_map_channel).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn map_channel<I, F, R>(
+        self,
+        items: I,
+        func: F,
+    ) -> Receiver<(usize, Result<R, ForkError>)>
+    where
+        I: IntoIterator,
+        I::IntoIter: Send + 'static,
+        I::Item: Send,
+        F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync + 'static,
+        R: Serialize + for<'a> Deserialize<'a> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let items = items.into_iter();
+        std::thread::spawn(move || {
+            // Safety: promised by our caller
+            unsafe { self.map_to_sender(items, func, sender) };
+        });
+        receiver
+    }
+
+    /// Runs `func` on each of `items`, and sends each result to `sender` as its child finishes,
+    /// from the calling thread. See [`fork_map_to_sender`](crate::fork_map_to_sender).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn map_to_sender<I, F, R>(
+        self,
+        items: I,
+        func: F,
+        sender: Sender<(usize, Result<R, ForkError>)>,
+    ) where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        let mut results = self.map_iter_unordered(items, func);
+        while let Some(result) = results.next() {
+            if sender.send(result).is_err() {
+                // Nobody's listening anymore
+                results.cancel();
+                break;
+            }
+        }
+    }
+
+    /// Runs `func(item)` in a child configured like this.
+    pub(crate) unsafe fn run_item<T, F, R>(&self, func: &F, item: T) -> anyhow::Result<R>
+    where
+        T: Send,
+        F: Fn(T) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        let item = Mutex::new(Some(item));
+        self.clone().run(|| func(take_once(&item)))
+    }
+
+    /// Like [`run_item`](Self::run_item), for callers that never fork here, because they're
+    /// [`inline`](Self::inline) or can't, so neither `func` nor the item has to be `Send`.
+    pub(crate) fn run_item_in_process<T, F, R>(&self, func: &F, item: T) -> anyhow::Result<R>
+    where
+        F: Fn(T) -> anyhow::Result<R>,
+        R: Serialize + for<'a> Deserialize<'a>,
+    {
+        #[cfg(feature = "testing")]
+        if let Some(error) = crate::testing::take() {
+            return Err(error.into());
+        }
+        let item = Cell::new(Some(item));
+        let result = self
+            .clone()
+            .run_in_process(|| func(item.take().expect("closure called twice")))
+            .and_then(ForkHandle::join);
+        tag(self.id.clone(), result)
+    }
+
+    /// Runs `func` right here instead, with the result making the same trip through the codec a
+    /// child's would.
+    pub(crate) fn run_in_process<F, S, D>(self, func: F) -> anyhow::Result<ForkHandle<D>>
+    where
+        F: Fn() -> anyhow::Result<S>,
+        S: Serialize,
+        D: for<'a> Deserialize<'a>,
+    {
+        let start = Instant::now();
+        let mut stats = ForkStats::default();
+        let frame = self.frame_in_process(func)?;
+        stats.timings.first_byte = start.elapsed();
+        stats.timings.eof = stats.timings.first_byte;
+        stats.timings.reaped = stats.timings.first_byte;
+        // This process stands in for the child, as it does for `on_fork`
+        let result = self
+            .decode_result(std::process::id(), &frame)
+            .map(|result| {
+                stats.timings.decoded = start.elapsed();
+                (result, stats)
+            });
+        Ok(ForkHandle::done(result))
+    }
+
+    /// Runs `func` right here, and returns the frame a child would have sent.
+    pub(crate) fn frame_in_process<F, S>(&self, func: F) -> anyhow::Result<Vec<u8>>
+    where
+        F: Fn() -> anyhow::Result<S>,
+        S: Serialize,
+    {
+        // Without a child there's nothing to apply these to, and ignoring them would be a lie
+        if let Some(feature) = self.child_only_option() {
+            return Err(ForkError::Unsupported { feature }.into());
+        }
+        if self.timeout.is_some() {
+            return Err(ForkError::Unsupported { feature: "timeout" }.into());
+        }
+
+        for callback in &self.on_fork.0 {
+            callback(std::process::id());
+        }
+        let frame = self.encode_result(func());
+        if let Some(limit) = self.max_result_bytes {
+            protocol::check_size(std::process::id(), &frame, limit)?;
+        }
+        Ok(frame)
+    }
+
+    /// Takes a permit for one more child under the concurrency limit, waiting for it unless
+    /// [`non_blocking`](Self::non_blocking).
+    pub(crate) fn permit(&self) -> Result<Permit, ForkError> {
+        if self.non_blocking {
+            return limit::try_acquire().ok_or(ForkError::WouldBlock);
+        }
+        Ok(limit::acquire())
+    }
+
+    /// The first option set that only applies to a child we forked ourselves, if any.
+    pub(crate) fn child_only_option(&self) -> Option<&'static str> {
+        if self.new_process_group {
+            return Some("new_process_group");
+        }
+        if self.new_session {
+            return Some("new_session");
+        }
+        if self.cgroup.is_some() {
+            return Some("cgroup");
+        }
+        if self.current_dir.is_some() {
+            return Some("current_dir");
+        }
+        if self.reap_descendants.is_some() {
+            return Some("reap_descendants");
+        }
+        if self.max_rss.is_some() {
+            return Some("max_rss");
+        }
+        #[cfg(target_os = "macos")]
+        if self.sandbox_profile.is_some() {
+            return Some("sandbox_profile");
+        }
+        #[cfg(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        if self.seccomp.is_some() {
+            return Some("seccomp");
+        }
+        if !self.pre_exec.0.is_empty() {
+            return Some("pre_exec");
+        }
+        if self.pause_on_crash {
+            return Some("pause_on_crash");
+        }
+        if self.null_stdio {
+            return Some("null_stdio");
+        }
+        None
+    }
+
+    /// Turns the closure's result into the frame sent to the parent.
+    pub(crate) fn encode_result<R: Serialize>(&self, result: anyhow::Result<R>) -> Vec<u8> {
+        let (tag, body) = match result {
+            Ok(value) => self.codec.encode(&value).map(|body| (Tag::Value, body)),
+            // Its own tag, so the parent gets it back as a ForkError rather than as a message
+            Err(e) if matches!(e.downcast_ref(), Some(ForkError::Cancelled)) => {
+                Ok((Tag::Cancelled, vec![]))
+            }
+            // serde_error walks source(), so the parent gets the whole chain of messages
+            Err(e) => self
+                .codec
+                .encode(&serde_error::Error::new(&*e))
+                .map(|body| (Tag::Error, body)),
+        }
+        // Sent as plain text, which can't fail to encode, so this isn't mistaken for a crash
+        .unwrap_or_else(|e| (Tag::SerializeFailed, format!("{:#}", e).into_bytes()));
+        protocol::frame(tag, self.compression, body)
+            .or_else(|e| {
+                let message = format!("failed to compress: {:#}", e).into_bytes();
+                protocol::frame(Tag::SerializeFailed, Compression::None, message)
+            })
+            // Can't fail uncompressed, but if it somehow did, the parent sees the child exit
+            // without reporting
+            .unwrap_or_default()
+    }
+
+    /// Turns a frame received from child `pid` back into the closure's result.
+    pub(crate) fn decode_result<R>(&self, pid: u32, frame: &[u8]) -> anyhow::Result<R>
+    where
+        R: for<'a> Deserialize<'a>,
+    {
+        self.decode_frame(pid, frame, |body| Ok(self.codec.decode::<R>(pid, body)?))
+    }
+
+    /// Like [`decode_result`](Self::decode_result), with `value` turning the body of a frame
+    /// with the closure's `Ok` value into the result.
+    pub(crate) fn decode_frame<R>(
+        &self,
+        pid: u32,
+        frame: &[u8],
+        value: impl FnOnce(&[u8]) -> anyhow::Result<R>,
+    ) -> anyhow::Result<R> {
+        match protocol::parse(pid, frame)? {
+            (Tag::Value, body) => value(&body),
+            (Tag::Error, body) => Err(self.codec.decode::<serde_error::Error>(pid, &body)?.into()),
+            (Tag::SerializeFailed, body) => Err(ForkError::ResultSerializeFailed {
+                pid,
+                message: String::from_utf8_lossy(&body).into_owned(),
+            }
+            .into()),
+            (Tag::Cancelled, _) => Err(ForkError::Cancelled.into()),
+            // Only streaming children send items, and never last
+            (Tag::Item, _) => {
+                Err(ForkError::decode(pid, &"expected a result, got an item", frame).into())
+            }
+            // Progress reports and file descriptors are taken off before the result
+            (Tag::Progress, _) => {
+                Err(
+                    ForkError::decode(pid, &"expected a result, got a progress report", frame)
+                        .into(),
+                )
+            }
+            (Tag::Fd, _) => {
+                Err(
+                    ForkError::decode(pid, &"expected a result, got a file descriptor", frame)
+                        .into(),
+                )
+            }
+            // Only ever where the result would be, and followed there
+            (Tag::Shared, _) => {
+                Err(ForkError::decode(pid, &"unexpected result in shared memory", frame).into())
+            }
+        }
+    }
+}
+
+/// Attaches the [`id`](ForkBuilder::id), if there is one, to an error.
+fn tag<T>(id: Option<String>, result: anyhow::Result<T>) -> anyhow::Result<T> {
+    match id {
+        Some(id) => result.context(ForkId(id)),
+        None => result,
+    }
+}
+
+/// Takes the item a child was given, which it only ever does once. The parent's copy is just
+/// dropped.
+pub(crate) fn take_once<T>(item: &Mutex<Option<T>>) -> T {
+    item.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take()
+        .expect("closure called twice")
+}
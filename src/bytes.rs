@@ -0,0 +1,116 @@
+//! The minimal core: raw bytes back from a child, with nothing but libc underneath.
+
+use crate::ForkError;
+
+/// Forks, runs `func` in the child, and returns the bytes it produced. Waits for the child to
+/// terminate first.
+///
+/// This is all there is when the crate is built with `default-features = false`, which drops
+/// the `serde`, `serde_json` and `anyhow` dependencies and leaves only `libc`. There's no
+/// serialization or framing: the bytes are sent back as they are, and whatever you need to
+/// encode in them (including errors) is up to you. A child that crashes or exits with a non-zero
+/// code is still reported as [`ForkError::ChildFailed`], but one that calls `exit(0)` itself
+/// before returning can't be told apart from one that returned nothing. If `func` panics, the
+/// child exits with [`EXIT_CLOSURE_PANICKED`](crate::EXIT_CLOSURE_PANICKED), which is a
+/// `ChildFailed` too.
+///
+/// The [`set_max_concurrent_forks`](crate::set_max_concurrent_forks) limit applies, and when
+/// [`FORKS`](crate::FORKS) is `false` this just calls `func`.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_bytes;
+///
+/// let bytes = unsafe { fork_map_bytes(|| b"hello from the child".to_vec()) }.unwrap();
+/// assert_eq!(bytes, b"hello from the child");
+///
+/// # if !fork_map::FORKS { return }
+/// use fork_map::{interpret_status, ExitOutcome, ForkError, EXIT_CLOSURE_PANICKED};
+///
+/// match unsafe { fork_map_bytes(|| panic!("no bytes today")) } {
+///     Err(ForkError::ChildFailed { status, .. }) => {
+///         assert_eq!(interpret_status(status), ExitOutcome::Exited(EXIT_CLOSURE_PANICKED));
+///     }
+///     other => panic!("unexpected result {:?}", other),
+/// }
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`](crate::fork_map), or if you only have this: due to the nature of
+/// `fork()`, this is very unsound. All of your memory is duplicated into a second process, and
+/// any threads other than the calling one don't exist in it, so locks they held stay locked.
+pub unsafe fn fork_map_bytes<F>(func: F) -> Result<Vec<u8>, ForkError>
+where
+    F: Fn() -> Vec<u8>,
+{
+    #[cfg(feature = "testing")]
+    if let Some(error) = crate::testing::take() {
+        return Err(error);
+    }
+    #[cfg(all(unix, not(feature = "fallback")))]
+    {
+        forked(func)
+    }
+    #[cfg(any(not(unix), feature = "fallback"))]
+    {
+        Ok(func())
+    }
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+unsafe fn forked<F>(func: F) -> Result<Vec<u8>, ForkError>
+where
+    F: Fn() -> Vec<u8>,
+{
+    use crate::{limit, sys};
+
+    // Held until the child is reaped
+    let _permit = limit::acquire();
+    let forking = limit::Forking::enter();
+
+    let pipe = sys::pipe()?;
+    let pid = match sys::fork() {
+        Ok(pid) => pid,
+        Err(e) => {
+            libc::close(pipe[0]);
+            libc::close(pipe[1]);
+            return Err(e);
+        }
+    };
+    if pid == 0 {
+        // Child
+        libc::close(pipe[0]);
+        let bytes = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(&func)) {
+            Ok(bytes) => bytes,
+            // Rather than unwinding into the copy of the caller, which would carry on from there
+            Err(_) => libc::_exit(crate::EXIT_CLOSURE_PANICKED),
+        };
+        sys::send_result_and_exit(pipe[1], &bytes, 0);
+    }
+
+    // Parent
+    let mut child = sys::ChildGuard::new(pid);
+    drop(forking);
+    libc::close(pipe[1]);
+    let mut received = vec![];
+    let read = loop {
+        match sys::read_some(pipe[0], &mut received) {
+            Ok(0) => break Ok(()),
+            Ok(_) => {}
+            Err(e) => break Err(e),
+        }
+    };
+    // Always reap the child even if reading failed
+    libc::close(pipe[0]);
+    let (status, _) = child.reap()?;
+    if status != 0 {
+        return Err(ForkError::ChildFailed {
+            pid: pid as u32,
+            status,
+        });
+    }
+    read?;
+    Ok(received)
+}
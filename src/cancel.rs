@@ -0,0 +1,168 @@
+//! Asking a child to stop, rather than killing it.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::ForkError;
+
+/// A flag the parent raises to ask a child to stop at its next convenient point.
+///
+/// The flag lives in memory shared with every child forked after the token was created, so a
+/// child sees [`cancel`](Self::cancel) called in the parent (or in another child) through its
+/// own copy of the token, which it can simply capture. Checking it is a single atomic load, cheap
+/// enough for a tight loop. Clones share the same flag, so one can be handed to another thread
+/// to cancel from while the thread that forked is blocked waiting for the result.
+///
+/// What stopping means is up to the closure: it can return what it has so far, or use
+/// [`check`](Self::check) to bail out with [`ForkError::Cancelled`], which makes it back to the
+/// parent as that same error rather than as the closure's.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map, CancelToken, ForkError};
+/// use std::time::{Duration, Instant};
+///
+/// let token = CancelToken::new();
+/// let canceller = token.clone();
+/// std::thread::spawn(move || {
+///     std::thread::sleep(Duration::from_millis(50));
+///     canceller.cancel();
+/// });
+///
+/// let start = Instant::now();
+/// let done = unsafe {
+///     fork_map(|| {
+///         // Would take 10 seconds if left alone
+///         let mut done = 0u64;
+///         while done < 10_000 && !token.is_cancelled() {
+///             std::thread::sleep(Duration::from_millis(1));
+///             done += 1;
+///         }
+///         Ok(done)
+///     })
+/// }
+/// .unwrap();
+/// assert!(done < 10_000);
+/// assert!(start.elapsed() < Duration::from_secs(5));
+///
+/// // Already cancelled, so this child stops at its first check
+/// let err = unsafe {
+///     fork_map(|| -> anyhow::Result<()> {
+///         loop {
+///             token.check()?;
+///             std::thread::sleep(Duration::from_millis(1));
+///         }
+///     })
+/// }
+/// .unwrap_err();
+/// assert!(matches!(err.downcast_ref(), Some(ForkError::Cancelled)));
+/// ```
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    flag: Arc<Flag>,
+}
+
+impl CancelToken {
+    /// A token that hasn't been cancelled yet.
+    ///
+    /// # Panics
+    ///
+    /// If the memory to share with children can't be mapped, like an allocation failing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks every child holding this token to stop. There's no taking it back.
+    pub fn cancel(&self) {
+        self.flag.get().store(true, Ordering::SeqCst);
+    }
+
+    /// Whether [`cancel`](Self::cancel) has been called on this token, or any of its clones.
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.get().load(Ordering::SeqCst)
+    }
+
+    /// Fails with [`ForkError::Cancelled`] if the token has been cancelled, for stopping with
+    /// `?`.
+    pub fn check(&self) -> Result<(), ForkError> {
+        if self.is_cancelled() {
+            return Err(ForkError::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancelToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+/// The flag itself, in a page of its own shared with children.
+#[cfg(all(unix, not(feature = "fallback")))]
+struct Flag(*mut AtomicBool);
+
+// Safety: it's only ever accessed atomically
+#[cfg(all(unix, not(feature = "fallback")))]
+unsafe impl Send for Flag {}
+#[cfg(all(unix, not(feature = "fallback")))]
+unsafe impl Sync for Flag {}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+impl Flag {
+    fn get(&self) -> &AtomicBool {
+        unsafe { &*self.0 }
+    }
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+impl Default for Flag {
+    fn default() -> Self {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                std::mem::size_of::<AtomicBool>(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANON,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            panic!(
+                "failed to map memory for a CancelToken: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        // Anonymous mappings start out zeroed, which is a false AtomicBool
+        Flag(ptr as *mut AtomicBool)
+    }
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+impl Drop for Flag {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(
+                self.0 as *mut libc::c_void,
+                std::mem::size_of::<AtomicBool>(),
+            )
+        };
+    }
+}
+
+/// Without children, there's nobody to share it with.
+#[cfg(any(not(unix), feature = "fallback"))]
+#[derive(Default)]
+struct Flag(AtomicBool);
+
+#[cfg(any(not(unix), feature = "fallback"))]
+impl Flag {
+    fn get(&self) -> &AtomicBool {
+        &self.0
+    }
+}
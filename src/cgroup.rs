@@ -0,0 +1,76 @@
+//! Moving children into a cgroup v2 group.
+//!
+//! `clone3(CLONE_INTO_CGROUP)` would start the child inside the group directly, but calling it
+//! raw skips everything libc does around `fork()` (atfork handlers, resetting malloc locks), which
+//! is exactly what keeps the child usable in a multithreaded parent. Instead the parent writes the
+//! child's pid into `cgroup.procs` and the child waits on a pipe until that has happened, so your
+//! closure never runs outside the group either way.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::ForkError;
+
+pub(crate) struct Cgroup {
+    path: PathBuf,
+    procs: File,
+    oom_kills: Option<u64>,
+}
+
+impl Cgroup {
+    /// Opens the group's `cgroup.procs` before forking, so permission problems are reported
+    /// without ever creating a child.
+    pub(crate) fn open(path: &Path) -> Result<Self, ForkError> {
+        let procs = OpenOptions::new()
+            .write(true)
+            .open(path.join("cgroup.procs"))
+            .map_err(|source| ForkError::Cgroup {
+                path: path.to_owned(),
+                source,
+            })?;
+        Ok(Self {
+            path: path.to_owned(),
+            procs,
+            oom_kills: oom_kills(path),
+        })
+    }
+
+    pub(crate) fn attach(&mut self, pid: libc::pid_t) -> Result<(), ForkError> {
+        // The kernel wants one pid per write(2)
+        self.procs
+            .write_all(pid.to_string().as_bytes())
+            .map_err(|source| ForkError::Cgroup {
+                path: self.path.clone(),
+                source,
+            })
+    }
+
+    /// For a child to move itself into the group, by writing `0` to it. Open until `self` is
+    /// dropped.
+    pub(crate) fn procs_fd(&self) -> libc::c_int {
+        self.procs.as_raw_fd()
+    }
+
+    /// Whether the group's OOM killer fired since it was opened. Only meaningful once the child
+    /// is known to have been killed, and can't tell which process it picked if others share it.
+    pub(crate) fn oom_killed(&self, pid: u32) -> Option<ForkError> {
+        match (self.oom_kills, oom_kills(&self.path)) {
+            (Some(before), Some(after)) if after > before => Some(ForkError::OutOfMemory {
+                pid,
+                cgroup: self.path.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// `oom_kill` count from memory.events, absent if the memory controller isn't enabled.
+fn oom_kills(path: &Path) -> Option<u64> {
+    let events = std::fs::read_to_string(path.join("memory.events")).ok()?;
+    events
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|count| count.trim().parse().ok())
+}
@@ -0,0 +1,223 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::ForkError;
+
+/// How results are serialized for the trip from the child back to the parent.
+///
+/// JSON is the default, and available unless you turn off the default `json` feature. The binary
+/// formats are smaller and faster, and are enabled with the cargo feature of the same name; the
+/// first one enabled becomes the default when JSON isn't. All of them are self-describing, so a parent
+/// tolerates a child whose result type has gained fields (or lost ones marked `#[serde(default)]`)
+/// as long as the field names still line up, which is what you want when parent and child
+/// binaries can briefly differ during a rolling deployment.
+///
+/// Whichever it is, a value it can't represent fails to decode with [`ForkError::Decode`] rather
+/// than coming back different; see each one for what those are. Beyond the formats themselves,
+/// serde can't hand a `u128` or `i128` through `#[serde(flatten)]` or an untagged or internally
+/// tagged enum, whatever the codec, so those fail to decode too.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{Codec, Fork};
+///
+/// let result = unsafe {
+///     Fork::builder()
+///         .codec(Codec::Json)
+///         .run(|| Ok(vec![1u32, 2, 3]))
+///         .unwrap()
+/// };
+/// assert_eq!(result, vec![1, 2, 3]);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Codec {
+    /// JSON via `serde_json`. Requires the `json` feature, which is on by default.
+    ///
+    /// Integers come back exactly, all the way up to `u128` and `i128`, and so do floats, but
+    /// JSON has no way to write NaN or the infinities: they're sent as `null`, and fail to
+    /// decode as a float. A result that can hold them should use one of the binary formats,
+    /// which take any float, or wrap them in something that decodes `null`, like an `Option`.
+    ///
+    /// ```
+    /// use fork_map::{Codec, Fork, ForkError};
+    ///
+    /// let run = |result: f64| unsafe {
+    ///     Fork::builder()
+    ///         .codec(Codec::Json)
+    ///         .run(move || Ok(result))
+    /// };
+    /// assert_eq!(run(0.1 + 0.2).unwrap(), 0.1 + 0.2);
+    /// let err = run(f64::NAN).unwrap_err();
+    /// assert!(matches!(err.downcast_ref(), Some(ForkError::Decode { .. })));
+    ///
+    /// let hash = unsafe { Fork::builder().codec(Codec::Json).run(|| Ok(u128::MAX - 7)) };
+    /// assert_eq!(hash.unwrap(), u128::MAX - 7);
+    /// ```
+    #[cfg(feature = "json")]
+    #[default]
+    Json,
+    /// CBOR via `ciborium`. Requires the `cbor` feature.
+    ///
+    /// ```
+    /// use fork_map::{Codec, Fork};
+    ///
+    /// let result = unsafe {
+    ///     Fork::builder()
+    ///         .codec(Codec::Cbor)
+    ///         .run(|| Ok(("bytes".to_string(), vec![0u8; 16])))
+    ///         .unwrap()
+    /// };
+    /// assert_eq!(result.1.len(), 16);
+    /// ```
+    ///
+    /// It's lossless for anything serde has a type for: integers of every width, and every float,
+    /// NaN and the infinities included, come back bit for bit, which makes it the one to pick
+    /// when the default JSON won't do.
+    ///
+    /// ```
+    /// use fork_map::{Codec, Fork};
+    /// use serde::{Deserialize, Serialize};
+    ///
+    /// #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    /// struct Digest {
+    ///     hash: u128,
+    ///     offset: i128,
+    ///     score: f64,
+    /// }
+    ///
+    /// let digest = unsafe {
+    ///     Fork::builder()
+    ///         .codec(Codec::Cbor)
+    ///         .run(|| {
+    ///             Ok(Digest {
+    ///                 hash: u128::MAX - 7,
+    ///                 offset: i128::MIN,
+    ///                 score: f64::NEG_INFINITY,
+    ///             })
+    ///         })
+    ///         .unwrap()
+    /// };
+    /// assert_eq!(digest.hash, u128::MAX - 7);
+    /// assert_eq!(digest.offset, i128::MIN);
+    /// assert_eq!(digest.score, f64::NEG_INFINITY);
+    ///
+    /// let nan = unsafe { Fork::builder().codec(Codec::Cbor).run(|| Ok(f64::NAN)) }.unwrap();
+    /// assert!(nan.is_nan());
+    /// ```
+    #[cfg(feature = "cbor")]
+    #[cfg_attr(not(feature = "json"), default)]
+    Cbor,
+    /// MessagePack via `rmp-serde`, with structs encoded as maps so that field names are kept.
+    /// Requires the `msgpack` feature.
+    ///
+    /// ```
+    /// use fork_map::{Codec, Fork};
+    ///
+    /// let result = unsafe {
+    ///     Fork::builder()
+    ///         .codec(Codec::MessagePack)
+    ///         .run(|| Ok(Some(1.5f64)))
+    ///         .unwrap()
+    /// };
+    /// assert_eq!(result, Some(1.5));
+    /// ```
+    #[cfg(feature = "msgpack")]
+    #[cfg_attr(not(any(feature = "json", feature = "cbor")), default)]
+    MessagePack,
+}
+
+impl Codec {
+    pub(crate) fn encode<T: Serialize>(self, value: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            #[cfg(feature = "json")]
+            Codec::Json => serde_json::to_vec(value)?,
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => {
+                let mut bytes = vec![];
+                ciborium::into_writer(value, &mut bytes)?;
+                bytes
+            }
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => rmp_serde::to_vec_named(value)?,
+        })
+    }
+
+    /// Like [`encode`](Self::encode), straight into `writer`.
+    #[cfg(all(unix, not(feature = "fallback")))]
+    pub(crate) fn encode_to<T: Serialize>(
+        self,
+        value: &T,
+        writer: impl std::io::Write,
+    ) -> anyhow::Result<()> {
+        match self {
+            #[cfg(feature = "json")]
+            Codec::Json => serde_json::to_writer(writer, value)?,
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => ciborium::into_writer(value, writer)?,
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => rmp_serde::encode::write_named(&mut { writer }, value)?,
+        }
+        Ok(())
+    }
+
+    /// Whether [`decode_borrowed`](Self::decode_borrowed) can lend out anything.
+    pub(crate) fn can_borrow(self) -> bool {
+        match self {
+            #[cfg(feature = "json")]
+            Codec::Json => true,
+            // ciborium only deserializes from a reader
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => false,
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => true,
+        }
+    }
+
+    /// Like [`decode`](Self::decode), but the value can borrow from `bytes`.
+    pub(crate) fn decode_borrowed<'a, T: Deserialize<'a>>(
+        self,
+        pid: u32,
+        bytes: &'a [u8],
+    ) -> Result<T, ForkError> {
+        match self {
+            #[cfg(feature = "json")]
+            Codec::Json => {
+                serde_json::from_slice(bytes).map_err(|e| ForkError::decode(pid, &e, bytes))
+            }
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => {
+                let _ = (pid, bytes);
+                Err(ForkError::Unsupported {
+                    feature: "borrowing from a result in Codec::Cbor",
+                })
+            }
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(|e| ForkError::decode(pid, &e, bytes))
+            }
+        }
+    }
+
+    pub(crate) fn decode<T: DeserializeOwned>(
+        self,
+        pid: u32,
+        bytes: &[u8],
+    ) -> Result<T, ForkError> {
+        match self {
+            #[cfg(feature = "json")]
+            Codec::Json => {
+                serde_json::from_slice(bytes).map_err(|e| ForkError::decode(pid, &e, bytes))
+            }
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => {
+                ciborium::from_reader(bytes).map_err(|e| ForkError::decode(pid, &e, bytes))
+            }
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(|e| ForkError::decode(pid, &e, bytes))
+            }
+        }
+    }
+}
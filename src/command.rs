@@ -0,0 +1,401 @@
+//! Running external commands that speak the codec on standard input and output.
+
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Fork, ForkBuilder, ForkError};
+
+/// How much of a command's standard error is kept for [`ForkError::CommandFailed`].
+const STDERR_TAIL: usize = 64 << 10;
+
+/// Runs `command` with `input` encoded with the default [`Codec`](crate::Codec) on its standard
+/// input, and decodes its standard output the same way once it exits. For helper programs that
+/// take their job as JSON and print their answer as JSON, or whatever the codec is.
+///
+/// Errors come back the same way they do from [`fork_map`](crate::fork_map): a command that
+/// exits with a non-zero code or is killed by a signal is [`ForkError::CommandFailed`], with the
+/// end of what it wrote to standard error, and output that can't be decoded is
+/// [`ForkError::Decode`]. Standard input, output and error are always piped, whatever `command`
+/// was set up with; everything else about it (arguments, environment, working directory) is up
+/// to you. See [`ForkBuilder::exec`] for timeouts and the other options.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_exec, interpret_status, ExitOutcome, ForkError};
+/// use std::process::Command;
+/// # if !cfg!(unix) { return }
+///
+/// // Reads a number, prints its double
+/// let mut double = Command::new("sh");
+/// double.args(["-c", "read x; echo $((x * 2))"]);
+/// let result: u64 = fork_exec(&mut double, &21).unwrap();
+/// assert_eq!(result, 42);
+///
+/// let mut failing = Command::new("sh");
+/// failing.args(["-c", "echo 'out of cheese' >&2; exit 3"]);
+/// let err = fork_exec::<_, u64>(&mut failing, &21).unwrap_err();
+/// match err.downcast_ref() {
+///     Some(ForkError::CommandFailed { status, stderr, .. }) => {
+///         assert_eq!(interpret_status(*status), ExitOutcome::Exited(3));
+///         assert_eq!(stderr, "out of cheese\n");
+///     }
+///     _ => panic!("unexpected error {:?}", err),
+/// }
+/// ```
+pub fn fork_exec<I, O>(command: &mut Command, input: &I) -> anyhow::Result<O>
+where
+    I: Serialize,
+    O: for<'a> Deserialize<'a>,
+{
+    // Safety: the default builder has no pre_exec hooks
+    unsafe { Fork::builder().exec(command, input) }
+}
+
+impl ForkBuilder {
+    /// Like [`fork_exec`], with the command run the way this builder would run a child.
+    ///
+    /// The [`codec`](Self::codec) is what the command reads and writes, and there's no
+    /// framing around it, so [`compression`](Self::compression) doesn't apply.
+    /// [`result_fd`](Self::result_fd) has the command write its result somewhere other than
+    /// standard output.
+    /// [`timeout`](Self::timeout) kills the command if it hasn't exited in time,
+    /// [`max_result_bytes`](Self::max_result_bytes) kills it if it prints too much,
+    /// [`retries`](Self::retries) runs it again (with the same input) if it fails, and
+    /// [`on_fork`](Self::on_fork) callbacks are passed its pid. Options that set up the child
+    /// ([`new_process_group`](Self::new_process_group), [`new_session`](Self::new_session),
+    /// [`cgroup`](Self::cgroup), [`pre_exec`](Self::pre_exec)) are applied between `fork()` and
+    /// `exec()`, and like for closures, fail with [`ForkError::Unsupported`] when
+    /// [`FORKS`](crate::FORKS) is `false`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use fork_map::{Fork, ForkError};
+    /// use std::process::Command;
+    /// use std::time::{Duration, Instant};
+    /// # if !cfg!(unix) { return }
+    ///
+    /// let mut stuck = Command::new("sleep");
+    /// stuck.arg("60");
+    /// let start = Instant::now();
+    /// let builder = Fork::builder().timeout(Duration::from_millis(100));
+    /// let err = unsafe { builder.exec::<_, ()>(&mut stuck, &()) }.unwrap_err();
+    /// assert!(matches!(err.downcast_ref(), Some(ForkError::Timeout { .. })));
+    /// assert!(start.elapsed() < Duration::from_secs(30));
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// [`pre_exec`](Self::pre_exec) hooks run in the child between `fork()` and `exec()`, with
+    /// the same restrictions as [`CommandExt::pre_exec`](std::os::unix::process::CommandExt::pre_exec).
+    /// Without any, this is safe.
+    pub unsafe fn exec<I, O>(&self, command: &mut Command, input: &I) -> anyhow::Result<O>
+    where
+        I: Serialize,
+        O: for<'a> Deserialize<'a>,
+    {
+        #[cfg(feature = "testing")]
+        if let Some(error) = crate::testing::take() {
+            return Err(error.into());
+        }
+        let input = self.codec.encode(input)?;
+        let (pid, output) = self.with_retries(|| self.exec_once(command, &input))?;
+        Ok(self.codec.decode(pid, &output)?)
+    }
+
+    /// Runs the command once and returns its pid and what it printed, if it exited cleanly.
+    unsafe fn exec_once(
+        &self,
+        command: &mut Command,
+        input: &[u8],
+    ) -> anyhow::Result<(u32, Vec<u8>)> {
+        command.stdin(Stdio::piped()).stderr(Stdio::piped());
+        if self.result_fd.is_none() {
+            command.stdout(Stdio::piped());
+        }
+        #[cfg(all(unix, not(feature = "fallback")))]
+        let mut setup = self.set_up_command(command)?;
+        #[cfg(any(not(unix), feature = "fallback"))]
+        if let Some(feature) = self.child_only_option() {
+            return Err(ForkError::Unsupported { feature }.into());
+        }
+        #[cfg(any(not(unix), feature = "fallback"))]
+        if self.result_fd.is_some() {
+            return Err(ForkError::Unsupported {
+                feature: "result_fd",
+            }
+            .into());
+        }
+
+        // Held until the command is reaped
+        let _permit = self.permit()?;
+        let start = Instant::now();
+        let spawned = command.spawn();
+        // Only the command should have the write end, so that it's done when the command is
+        #[cfg(all(unix, not(feature = "fallback")))]
+        let result_pipe = setup.result_pipe.take().map(|pipe| pipe.read);
+        let mut child = spawned.map_err(|source| ForkError::Io {
+            op: "spawn",
+            source,
+        })?;
+        let pid = child.id();
+        for callback in &self.on_fork.0 {
+            callback(pid);
+        }
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        #[cfg(all(unix, not(feature = "fallback")))]
+        let mut stdout: Box<dyn Read + Send> = match result_pipe {
+            Some(read) => Box::new(read),
+            None => Box::new(child.stdout.take().expect("stdout is piped")),
+        };
+        #[cfg(any(not(unix), feature = "fallback"))]
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let limit = self.max_result_bytes;
+        let printed = AtomicU64::new(0);
+        let (ended, output, stderr) = std::thread::scope(|s| {
+            // A command that doesn't read all of its input gets to exit anyway, and then
+            // writing fails, which is fine
+            s.spawn(move || {
+                let _ = stdin.write_all(input);
+            });
+            let output = s.spawn(|| read_output(&mut stdout, limit, &printed));
+            let stderr = s.spawn(move || read_tail(&mut stderr));
+            let ended = wait(&mut child, start, self.timeout, limit, &printed);
+            (ended, output.join(), stderr.join())
+        });
+        let output = output.unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        let stderr = stderr.unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+
+        match ended? {
+            Ended::Exited(status) if status.success() => {}
+            Ended::Exited(status) => {
+                #[cfg(all(target_os = "linux", not(feature = "fallback")))]
+                if let Some(e) = setup.cgroup.as_ref().and_then(|c| c.oom_killed(pid)) {
+                    return Err(e.into());
+                }
+                return Err(ForkError::CommandFailed {
+                    pid,
+                    status: raw_status(status),
+                    stderr: String::from_utf8_lossy(&stderr).into_owned(),
+                }
+                .into());
+            }
+            Ended::TimedOut(timeout) => return Err(ForkError::Timeout { pid, timeout }.into()),
+            Ended::TooLarge(limit) => {
+                return Err(ForkError::ResultTooLarge {
+                    pid,
+                    limit,
+                    size: printed.load(Ordering::SeqCst),
+                }
+                .into())
+            }
+        }
+        let output = output.map_err(|source| ForkError::Io { op: "read", source })?;
+        Ok((pid, output))
+    }
+
+    /// Has the command apply the child's options to itself before `exec()`.
+    #[cfg(all(unix, not(feature = "fallback")))]
+    unsafe fn set_up_command(&self, command: &mut Command) -> Result<CommandSetup, ForkError> {
+        use std::os::unix::process::CommandExt;
+
+        // Checked on while reading a closure's result, which a command's doesn't go through
+        if self.max_rss.is_some() {
+            return Err(ForkError::Unsupported { feature: "max_rss" });
+        }
+        #[cfg(target_os = "linux")]
+        let cgroup = match &self.cgroup {
+            Some(path) => Some(crate::cgroup::Cgroup::open(path)?),
+            None => None,
+        };
+        #[cfg(target_os = "linux")]
+        let procs = cgroup.as_ref().map(|cgroup| cgroup.procs_fd());
+        #[cfg(not(target_os = "linux"))]
+        if self.cgroup.is_some() {
+            return Err(ForkError::Unsupported { feature: "cgroup" });
+        }
+
+        let result_pipe = match self.result_fd {
+            Some(fd) => Some(ResultPipe::new(command, fd)?),
+            None => None,
+        };
+
+        if self.child_only_option().is_some() {
+            let builder = self.clone();
+            command.pre_exec(move || {
+                // Moving ourselves in, which is what 0 means, before anything else happens
+                #[cfg(target_os = "linux")]
+                if let Some(procs) = procs {
+                    if libc::write(procs, b"0".as_ptr() as *const libc::c_void, 1) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                // Command only passes errno on, so the message is lost either way
+                builder.setup_child().map_err(|e| match e.downcast() {
+                    Ok(ForkError::Io { source, .. }) => source,
+                    _ => io::Error::from_raw_os_error(libc::EINVAL),
+                })
+            });
+        }
+        Ok(CommandSetup {
+            #[cfg(target_os = "linux")]
+            cgroup,
+            result_pipe,
+        })
+    }
+}
+
+/// What has to stay around while the command runs.
+#[cfg(all(unix, not(feature = "fallback")))]
+struct CommandSetup {
+    #[cfg(target_os = "linux")]
+    cgroup: Option<crate::cgroup::Cgroup>,
+    /// Until the command has been spawned, for [`result_fd`](ForkBuilder::result_fd).
+    result_pipe: Option<ResultPipe>,
+}
+
+/// The pipe a command writes its result to, when it isn't standard output.
+#[cfg(all(unix, not(feature = "fallback")))]
+struct ResultPipe {
+    read: std::fs::File,
+    /// Dropped once the command has its copy.
+    _write: std::os::fd::OwnedFd,
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+impl ResultPipe {
+    /// Creates the pipe, and has `command` move the write end onto `fd` before `exec()`.
+    unsafe fn new(command: &mut Command, fd: libc::c_int) -> Result<Self, ForkError> {
+        use std::os::fd::FromRawFd;
+        use std::os::unix::process::CommandExt;
+
+        let pipe = crate::sys::pipe()?;
+        // Neither end should end up in anything else that gets exec'd meanwhile
+        libc::fcntl(pipe[0], libc::F_SETFD, libc::FD_CLOEXEC);
+        libc::fcntl(pipe[1], libc::F_SETFD, libc::FD_CLOEXEC);
+        let theirs = pipe[1];
+        command.pre_exec(move || {
+            // dup2 clears close-on-exec on the copy, but does nothing at all if it's already in
+            // place
+            let ret = if theirs == fd {
+                libc::fcntl(theirs, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(theirs, fd)
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+        Ok(ResultPipe {
+            read: std::fs::File::from_raw_fd(pipe[0]),
+            _write: std::os::fd::OwnedFd::from_raw_fd(pipe[1]),
+        })
+    }
+}
+
+/// How a command's run ended.
+enum Ended {
+    Exited(ExitStatus),
+    /// Killed for taking longer than this.
+    TimedOut(Duration),
+    /// Killed for printing more than this.
+    TooLarge(usize),
+}
+
+/// Waits for the command to exit, and kills it if it runs out of time or prints too much.
+fn wait(
+    child: &mut Child,
+    start: Instant,
+    timeout: Option<Duration>,
+    limit: Option<usize>,
+    printed: &AtomicU64,
+) -> Result<Ended, ForkError> {
+    let wait_error = |source| ForkError::Io { op: "wait", source };
+    let mut nap = Duration::from_millis(1);
+    let ended = loop {
+        if let Some(status) = child.try_wait().map_err(wait_error)? {
+            return Ok(Ended::Exited(status));
+        }
+        if let Some(limit) = limit {
+            if printed.load(Ordering::SeqCst) > limit as u64 {
+                break Ended::TooLarge(limit);
+            }
+        }
+        let left = timeout.map(|timeout| timeout.saturating_sub(start.elapsed()));
+        if let (Some(timeout), Some(Duration::ZERO)) = (timeout, left) {
+            break Ended::TimedOut(timeout);
+        }
+        // There's no waiting on a child with a timeout, so check back often at first
+        std::thread::sleep(left.map_or(nap, |left| nap.min(left)));
+        nap = (nap * 2).min(Duration::from_millis(50));
+    };
+    // Don't let a command we've stopped listening to keep running
+    let _ = child.kill();
+    child.wait().map_err(wait_error)?;
+    Ok(ended)
+}
+
+/// Reads all of the command's output, or stops once it's more than `limit`.
+fn read_output(
+    stdout: &mut impl Read,
+    limit: Option<usize>,
+    printed: &AtomicU64,
+) -> io::Result<Vec<u8>> {
+    let mut output = vec![];
+    let mut chunk = [0u8; 0x10000];
+    loop {
+        let count = match stdout.read(&mut chunk) {
+            Ok(0) => return Ok(output),
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        output.extend_from_slice(&chunk[..count]);
+        printed.store(output.len() as u64, Ordering::SeqCst);
+        if limit.is_some_and(|limit| output.len() > limit) {
+            return Ok(output);
+        }
+    }
+}
+
+/// Reads all of the command's standard error, keeping only the end.
+fn read_tail(stderr: &mut impl Read) -> Vec<u8> {
+    let mut tail = vec![];
+    let mut chunk = [0u8; 0x10000];
+    loop {
+        match stderr.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(count) => tail.extend_from_slice(&chunk[..count]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            // What we have is the best we can do
+            Err(_) => break,
+        }
+        if tail.len() > 2 * STDERR_TAIL {
+            tail.drain(..tail.len() - STDERR_TAIL);
+        }
+    }
+    if tail.len() > STDERR_TAIL {
+        tail.drain(..tail.len() - STDERR_TAIL);
+    }
+    tail
+}
+
+fn raw_status(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status.into_raw()
+    }
+    #[cfg(not(unix))]
+    {
+        status.code().unwrap_or(-1)
+    }
+}
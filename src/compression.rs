@@ -0,0 +1,101 @@
+use std::borrow::Cow;
+
+use crate::ForkError;
+
+/// Whether and how results are compressed for the trip from the child back to the parent.
+///
+/// Worth it for large, repetitive results (big JSON blobs, text) where moving the bytes costs
+/// more than compressing them. The choice is recorded in the frame the child sends, so the parent
+/// always knows whether to decompress. Each algorithm is enabled with the cargo feature of the
+/// same name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// Send results as they are.
+    #[default]
+    None,
+    /// gzip via `flate2`, with a level from 0 (none) to 9 (best). Requires the `gzip` feature.
+    ///
+    /// ```
+    /// use fork_map::{Compression, Fork};
+    ///
+    /// let result = unsafe {
+    ///     Fork::builder()
+    ///         .compression(Compression::Gzip(6))
+    ///         .run(|| Ok(vec![0u64; 0]))
+    ///         .unwrap()
+    /// };
+    /// assert!(result.is_empty());
+    /// ```
+    #[cfg(feature = "gzip")]
+    Gzip(u32),
+    /// Zstandard via `zstd`, with a level from 1 to 22, or 0 for zstd's default. Requires the
+    /// `zstd` feature.
+    ///
+    /// ```
+    /// use fork_map::{Compression, Fork};
+    ///
+    /// let result = unsafe {
+    ///     Fork::builder()
+    ///         .compression(Compression::Zstd(3))
+    ///         .run(|| Ok("abc".repeat(100_000)))
+    ///         .unwrap()
+    /// };
+    /// assert_eq!(result.len(), 300_000);
+    /// ```
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+impl Compression {
+    /// How this is identified in the frame header.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            #[cfg(feature = "gzip")]
+            Compression::Gzip(_) => 1,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => 2,
+        }
+    }
+
+    pub(crate) fn compress(self, body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Compression::None => body,
+            #[cfg(feature = "gzip")]
+            Compression::Gzip(level) => {
+                use std::io::Write;
+                let mut encoder =
+                    flate2::write::GzEncoder::new(vec![], flate2::Compression::new(level));
+                encoder.write_all(&body)?;
+                encoder.finish()?
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => zstd::encode_all(body.as_slice(), level)?,
+        })
+    }
+
+    /// Undoes whichever compression the header `id` says was used.
+    pub(crate) fn decompress(pid: u32, id: u8, body: &[u8]) -> Result<Cow<'_, [u8]>, ForkError> {
+        let failed = |e: &dyn std::fmt::Display| {
+            ForkError::decode(pid, &format!("failed to decompress result: {}", e), body)
+        };
+        match id {
+            0 => Ok(Cow::Borrowed(body)),
+            #[cfg(feature = "gzip")]
+            1 => {
+                use std::io::Read;
+                let mut decompressed = vec![];
+                flate2::read::GzDecoder::new(body)
+                    .read_to_end(&mut decompressed)
+                    .map_err(|e| failed(&e))?;
+                Ok(Cow::Owned(decompressed))
+            }
+            #[cfg(feature = "zstd")]
+            2 => zstd::decode_all(body)
+                .map(Cow::Owned)
+                .map_err(|e| failed(&e)),
+            _ => Err(failed(&format!("unknown compression {}", id))),
+        }
+    }
+}
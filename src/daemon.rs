@@ -0,0 +1,131 @@
+//! Daemons, started with the classic double fork. See [`fork_daemon`].
+
+use crate::{Fork, ForkBuilder, ForkError};
+
+#[cfg(all(unix, not(feature = "fallback")))]
+use crate::{status, sys, Transport};
+
+/// Starts `func` running in a daemon: a grandchild of this process that's nobody's child by the
+/// time this returns, with its pid.
+///
+/// It's the classic double fork. A child starts a new session (see
+/// [`new_session`](ForkBuilder::new_session)), which leaves the controlling terminal behind,
+/// and forks the daemon, which, not being a session leader, can never pick up another. The
+/// child sends back the daemon's pid and exits, and is reaped here, so the daemon's parent is
+/// init (or the nearest subreaper) and there's nothing of it left for this process to wait
+/// for. The daemon's working directory is `/`, so it doesn't keep a mounted filesystem busy, and
+/// its standard input, output and error are `/dev/null`; it has nothing else open of this
+/// crate's.
+///
+/// Whatever `func` returns goes nowhere but the daemon's exit code, which init collects. To
+/// start one from a different directory, use [`ForkBuilder::daemon`], where it's up to the
+/// builder's [`current_dir`](ForkBuilder::current_dir).
+///
+/// When [`FORKS`](crate::FORKS) is `false`, there's no daemonizing, and this fails with
+/// [`ForkError::Unsupported`](crate::ForkError::Unsupported).
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_daemon;
+/// use std::time::{Duration, Instant};
+/// # if !fork_map::FORKS { return }
+///
+/// let path = std::env::temp_dir().join(format!("fork-map-daemon-{}", std::process::id()));
+/// let start = Instant::now();
+/// let pid = unsafe {
+///     fork_daemon(|| {
+///         let cwd = std::env::current_dir()?;
+///         let (pid, ppid, sid) = (libc::getpid(), libc::getppid(), libc::getsid(0));
+///         std::fs::write(&path, format!("{} {} {} {}", pid, ppid, sid, cwd.display()))?;
+///         loop {
+///             std::thread::sleep(Duration::from_secs(1));
+///         }
+///     })
+/// }
+/// .unwrap();
+/// // Back as soon as the daemon was started, not when it's done, which is never
+/// assert!(start.elapsed() < Duration::from_secs(1));
+///
+/// let mut written = String::new();
+/// while written.is_empty() && start.elapsed() < Duration::from_secs(5) {
+///     std::thread::sleep(Duration::from_millis(10));
+///     written = std::fs::read_to_string(&path).unwrap_or_default();
+/// }
+/// let fields: Vec<&str> = written.split(' ').collect();
+/// assert_eq!(fields[0], pid.to_string());
+/// // Not our child, and not in our session
+/// assert_ne!(fields[1], std::process::id().to_string());
+/// assert_ne!(fields[2], unsafe { libc::getsid(0) }.to_string());
+/// assert_eq!(fields[3], "/");
+/// // Nor is there anything left for us to reap
+/// let waited = unsafe { libc::waitpid(-1, std::ptr::null_mut(), libc::WNOHANG) };
+/// assert_eq!(waited, -1);
+///
+/// unsafe { libc::kill(pid as i32, libc::SIGKILL) };
+/// std::fs::remove_file(&path).unwrap();
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`](crate::fork_map).
+pub unsafe fn fork_daemon<F>(func: F) -> anyhow::Result<u32>
+where
+    F: Fn() -> anyhow::Result<()> + Send + Sync,
+{
+    Fork::builder().current_dir("/").daemon(func)
+}
+
+impl ForkBuilder {
+    /// Like [`fork_daemon`], with the first child configured like this, and the daemon forked
+    /// from it. It always gets a [new session](Self::new_session) and
+    /// [`/dev/null`](Self::null_stdio) for its standard streams, and options about the result,
+    /// like [`capture_stdout`](Self::capture_stdout), are for getting its pid back.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn daemon<F>(self, func: F) -> anyhow::Result<u32>
+    where
+        F: Fn() -> anyhow::Result<()> + Send + Sync,
+    {
+        #[cfg(feature = "testing")]
+        if let Some(error) = crate::testing::take() {
+            return Err(error.into());
+        }
+        #[cfg(all(unix, not(feature = "fallback")))]
+        if !self.inline {
+            let mut builder = self;
+            builder.new_session = true;
+            builder.null_stdio = true;
+            builder.capture_stdout = false;
+            builder.transport = Transport::Pipe;
+            let child = builder.fork_child(|pipe| {
+                let pid = sys::fork()?;
+                if pid == 0 {
+                    // The pid goes back from the child, which the parent waits on, not us
+                    libc::close(pipe);
+                    run_daemon(func);
+                }
+                Ok(pid as u32)
+            })?;
+            return child.wait().map(|(pid, _)| pid);
+        }
+        let _ = (self, func);
+        Err(ForkError::Unsupported {
+            feature: "fork_daemon",
+        }
+        .into())
+    }
+}
+
+/// Runs in the daemon: runs `func`, and exits with a code saying how it went.
+#[cfg(all(unix, not(feature = "fallback")))]
+unsafe fn run_daemon(func: impl Fn() -> anyhow::Result<()>) -> ! {
+    let code = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(func)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(_)) => crate::EXIT_CLOSURE_FAILED,
+        Err(_) => crate::EXIT_CLOSURE_PANICKED,
+    };
+    libc::exit(status::exit_code().unwrap_or(code));
+}
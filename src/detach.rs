@@ -0,0 +1,248 @@
+//! Children nobody waits for, reaped in the background. See [`fork_detach`].
+
+use std::time::{Duration, Instant};
+
+use crate::{Fork, ForkBuilder};
+
+#[cfg(all(unix, not(feature = "fallback")))]
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+#[cfg(all(unix, not(feature = "fallback")))]
+use crate::forked::Child;
+
+/// How often the reaper checks on the detached children still running.
+#[cfg(all(unix, not(feature = "fallback")))]
+const SWEEP_INTERVAL: Duration = Duration::from_millis(50);
+
+#[cfg(all(unix, not(feature = "fallback")))]
+pub(crate) struct Detached {
+    children: Vec<Child>,
+    /// The process these are the children of. A child that detaches children of its own
+    /// starts from its parent's copy of this, with none of them its own, and no reaper thread.
+    owner: u32,
+    reaper: bool,
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+static DETACHED: Mutex<Detached> = Mutex::new(Detached {
+    children: vec![],
+    owner: 0,
+    reaper: false,
+});
+/// Signalled when a child is detached, and when detached children are reaped.
+#[cfg(all(unix, not(feature = "fallback")))]
+static CHANGED: Condvar = Condvar::new();
+
+#[cfg(all(unix, not(feature = "fallback")))]
+pub(crate) fn detached() -> MutexGuard<'static, Detached> {
+    let mut detached = DETACHED.lock().unwrap_or_else(|e| e.into_inner());
+    let pid = std::process::id();
+    if detached.owner != pid {
+        // Not ours to reap or close, since they're the parent's
+        std::mem::forget(std::mem::take(&mut detached.children));
+        detached.owner = pid;
+        detached.reaper = false;
+    }
+    detached
+}
+
+/// Reaps whichever detached children have exited, and lets anyone waiting know.
+#[cfg(all(unix, not(feature = "fallback")))]
+fn sweep(detached: &mut Detached) {
+    let running = detached.children.len();
+    detached.children.retain_mut(|child| !child.try_reap());
+    if detached.children.len() < running {
+        CHANGED.notify_all();
+    }
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+fn reap_forever() {
+    let mut detached = detached();
+    loop {
+        while detached.children.is_empty() {
+            detached = CHANGED.wait(detached).unwrap_or_else(|e| e.into_inner());
+        }
+        sweep(&mut detached);
+        drop(detached);
+        std::thread::sleep(SWEEP_INTERVAL);
+        detached = self::detached();
+    }
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+fn adopt(child: Child) {
+    let mut detached = detached();
+    detached.children.push(child);
+    if !detached.reaper {
+        // If there's no thread to be had, whoever waits for them reaps them instead
+        detached.reaper = std::thread::Builder::new()
+            .name("fork-map-reaper".to_string())
+            .spawn(reap_forever)
+            .is_ok();
+    }
+    CHANGED.notify_all();
+}
+
+/// Forks, and runs `func` in a child process that nobody waits for: it returns as soon as the
+/// child is running, with its pid, and the child is reaped in the background once it exits.
+///
+/// This is for side effects the caller doesn't need to hear back about, like writing a cache
+/// file or sending off a metric. The child gets no pipe to send a result on, and its standard
+/// input is `/dev/null`; to quiet its output too, use [`ForkBuilder::null_stdio`] with
+/// [`ForkBuilder::detach`]. Whatever `func` returns, including an error, goes nowhere, other
+/// than into the child's exit code.
+///
+/// The reaping is done by a thread of this crate's, started the first time a child is
+/// detached, which checks on each detached child by its own pid, so it never reaps a child the
+/// rest of the program is waiting for. Being a thread, it counts against
+/// [`fork_map_safe`](crate::fork_map_safe) from then on. Detached children count against
+/// [`set_max_concurrent_forks`](crate::set_max_concurrent_forks) until they're reaped. See
+/// [`detached_count`] and [`wait_all_detached`] for keeping track of them.
+///
+/// When [`FORKS`](crate::FORKS) is `false`, `func` runs before this returns, and the pid is the
+/// calling process's.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_detach, wait_all_detached};
+/// use std::time::{Duration, Instant};
+///
+/// let dir = std::env::temp_dir().join(format!("fork-map-detach-{}", std::process::id()));
+/// std::fs::create_dir_all(&dir).unwrap();
+/// let cache = dir.join("cache");
+///
+/// let start = Instant::now();
+/// let pid = unsafe {
+///     fork_detach(|| {
+///         std::thread::sleep(Duration::from_millis(200));
+///         std::fs::write(&cache, "warm")?;
+///         Ok(())
+///     })
+/// }
+/// .unwrap();
+/// # if fork_map::FORKS {
+/// // Back before the child is done, which nobody waits for
+/// assert!(start.elapsed() < Duration::from_millis(200));
+/// assert!(!cache.exists());
+/// # }
+///
+/// // Until it's time to shut down
+/// assert!(wait_all_detached(Duration::from_secs(5)));
+/// assert_eq!(std::fs::read_to_string(&cache).unwrap(), "warm");
+/// # if fork_map::FORKS {
+/// // Reaped, so not a zombie
+/// let waited = unsafe { libc::waitpid(pid as i32, std::ptr::null_mut(), libc::WNOHANG) };
+/// assert_eq!(waited, -1);
+/// # }
+/// # let _ = pid;
+/// std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`](crate::fork_map).
+pub unsafe fn fork_detach<F>(func: F) -> anyhow::Result<u32>
+where
+    F: Fn() -> anyhow::Result<()> + Send + Sync,
+{
+    Fork::builder().detach(func)
+}
+
+/// How many [detached](fork_detach) children are still running, or have exited and are yet to
+/// be reaped.
+///
+/// ```
+/// use fork_map::{detached_count, fork_detach, wait_all_detached};
+/// use std::time::Duration;
+/// # if !fork_map::FORKS { return }
+///
+/// for _ in 0..3 {
+///     unsafe {
+///         fork_detach(|| {
+///             std::thread::sleep(Duration::from_millis(100));
+///             Ok(())
+///         })
+///     }
+///     .unwrap();
+/// }
+/// assert_eq!(detached_count(), 3);
+/// assert!(wait_all_detached(Duration::from_secs(5)));
+/// assert_eq!(detached_count(), 0);
+/// ```
+pub fn detached_count() -> usize {
+    #[cfg(all(unix, not(feature = "fallback")))]
+    {
+        detached().children.len()
+    }
+    #[cfg(any(not(unix), feature = "fallback"))]
+    {
+        0
+    }
+}
+
+/// Waits up to `timeout` for every [detached](fork_detach) child to exit, and reaps them, for an
+/// orderly shutdown. Returns whether they all did; any that didn't are left running, to be
+/// reaped in the background as usual.
+pub fn wait_all_detached(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    #[cfg(all(unix, not(feature = "fallback")))]
+    {
+        let mut detached = detached();
+        loop {
+            sweep(&mut detached);
+            if detached.children.is_empty() {
+                return true;
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return false;
+            }
+            // The reaper wakes us when it reaps one, if there is a reaper
+            let nap = left.min(SWEEP_INTERVAL);
+            detached = CHANGED
+                .wait_timeout(detached, nap)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+    #[cfg(any(not(unix), feature = "fallback"))]
+    {
+        let _ = deadline;
+        true
+    }
+}
+
+impl ForkBuilder {
+    /// Like [`fork_detach`], with the child configured like this. Options about the result,
+    /// like [`timeout`](Self::timeout) and [`capture_stdout`](Self::capture_stdout), have nothing
+    /// to apply to, and are ignored.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn detach<F>(mut self, func: F) -> anyhow::Result<u32>
+    where
+        F: Fn() -> anyhow::Result<()> + Send + Sync,
+    {
+        #[cfg(feature = "testing")]
+        if let Some(error) = crate::testing::take() {
+            return Err(error.into());
+        }
+        self.timeout = None;
+        self.capture_stdout = false;
+        #[cfg(all(unix, not(feature = "fallback")))]
+        if !self.inline {
+            self.detached = true;
+            let mut child = self.fork_child(|_| func())?;
+            child.close_pipes();
+            let pid = child.pid();
+            adopt(child);
+            return Ok(pid);
+        }
+        // Nobody would have heard about how it went from a child either
+        let _ = self.run_in_process::<_, (), ()>(func)?.join();
+        Ok(std::process::id())
+    }
+}
@@ -0,0 +1,715 @@
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Errors from the fork machinery itself, as opposed to errors returned by your closure.
+///
+/// These are returned wrapped in an [`anyhow::Error`], so use
+/// [`downcast_ref`](anyhow::Error::downcast_ref) to tell them apart:
+///
+/// ```
+/// use fork_map::{fork_map, ForkError};
+///
+/// let err = unsafe { fork_map(|| -> anyhow::Result<()> { anyhow::bail!("no luck") }) }.unwrap_err();
+/// // The closure failed, not the machinery
+/// assert!(err.downcast_ref::<ForkError>().is_none());
+/// assert_eq!(err.to_string(), "no luck");
+/// ```
+///
+/// The closure's error keeps its whole [`source`](std::error::Error::source) chain on the way
+/// back, including `anyhow` context, so `{:#}` and [`chain`](anyhow::Error::chain) work the same
+/// in the parent as they would have in the child. Only the messages make the trip though, not
+/// the original types (so no downcasting to them) or backtraces.
+///
+/// ```
+/// use anyhow::Context;
+/// use fork_map::fork_map;
+///
+/// let err = unsafe {
+///     fork_map(|| -> anyhow::Result<Vec<u8>> {
+///         Ok(std::fs::read("/nonexistent/config").context("failed to load config")?)
+///     })
+/// }
+/// .unwrap_err();
+/// let chain: Vec<String> = err.chain().map(|e| e.to_string()).collect();
+/// assert_eq!(chain.len(), 2);
+/// assert_eq!(chain[0], "failed to load config");
+/// assert!(format!("{:#}", err).starts_with("failed to load config: "));
+/// ```
+///
+/// APIs that return a result per item, like [`fork_map_iter`](crate::fork_map_iter), use
+/// `Result<R, ForkError>` instead, with the closure's errors wrapped in [`ForkError::Closure`].
+/// And [`fork_map_nested`](crate::fork_map_nested) returns the closure's result inside a
+/// `Result<_, ForkError>` of its own, so there's nothing to tell apart.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ForkError {
+    /// A system call failed. `op` names the call, and `source` carries the errno it set.
+    ///
+    /// ```
+    /// use fork_map::{fork_map_on_fds, ForkError};
+    /// use std::fs::{File, OpenOptions};
+    /// use std::os::fd::OwnedFd;
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// // The child writes its result somewhere that takes it, and the parent reads it from
+    /// // somewhere that can't be read
+    /// let read = OwnedFd::from(File::open("/").unwrap());
+    /// let write = OwnedFd::from(OpenOptions::new().write(true).open("/dev/null").unwrap());
+    /// let err = unsafe { fork_map_on_fds(read, write, || Ok(())) }.unwrap_err();
+    /// match err.downcast_ref() {
+    ///     Some(ForkError::Io { op, source }) => {
+    ///         assert_eq!(*op, "read");
+    ///         assert_eq!(source.raw_os_error(), Some(libc::EISDIR));
+    ///     }
+    ///     _ => panic!("unexpected error {:?}", err),
+    /// }
+    /// ```
+    Io { op: &'static str, source: io::Error },
+    /// The requested option doesn't exist on this platform, for example cgroups outside of
+    /// Linux. Nothing was forked.
+    Unsupported { feature: &'static str },
+    /// The child couldn't be moved into the requested cgroup. The child is killed before it
+    /// runs your closure.
+    Cgroup { path: PathBuf, source: io::Error },
+    /// The child was killed by the OOM killer of the cgroup it was placed in.
+    OutOfMemory { pid: u32, cgroup: PathBuf },
+    /// The child was killed with `SIGSYS` for making a system call its
+    /// [seccomp profile](crate::ForkBuilder::seccomp) doesn't allow. Which one isn't known to the
+    /// parent; `dmesg` or the audit log has it, or run the closure under `strace -f`.
+    SyscallBlocked { pid: u32 },
+    /// The child didn't exit cleanly: it exited with a non-zero code before sending its whole
+    /// result, or was killed by a signal.
+    /// `status` is the raw status from `waitpid`, which [`interpret_status`](crate::interpret_status)
+    /// decodes, as the message does:
+    ///
+    /// ```
+    /// use fork_map::{fork_map, interpret_status, ExitOutcome, ForkError};
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// let outcome = |err: anyhow::Error| match err.downcast_ref() {
+    ///     Some(&ForkError::ChildFailed { status, .. }) => {
+    ///         (interpret_status(status), err.to_string())
+    ///     }
+    ///     _ => panic!("unexpected error {:?}", err),
+    /// };
+    ///
+    /// let err = unsafe { fork_map(|| -> anyhow::Result<()> { libc::exit(3) }) }.unwrap_err();
+    /// let (exited, message) = outcome(err);
+    /// assert_eq!(exited, ExitOutcome::Exited(3));
+    /// assert!(message.ends_with("exited with code 3"));
+    ///
+    /// let err = unsafe { fork_map(|| -> anyhow::Result<()> { libc::abort() }) }.unwrap_err();
+    /// let (aborted, message) = outcome(err);
+    /// assert_eq!(aborted, ExitOutcome::Signaled(libc::SIGABRT));
+    /// assert!(message.ends_with(&format!("killed by signal {}", libc::SIGABRT)));
+    ///
+    /// assert_eq!(unsafe { fork_map(|| Ok(3)) }.unwrap(), 3);
+    /// ```
+    ChildFailed { pid: u32, status: i32 },
+    /// A command run by [`fork_exec`](crate::fork_exec) didn't exit cleanly. Like
+    /// [`ChildFailed`](Self::ChildFailed), plus the end of what it wrote to standard error.
+    CommandFailed {
+        pid: u32,
+        status: i32,
+        /// The last 64 KiB or so of the command's standard error, lossily decoded as UTF-8.
+        stderr: String,
+    },
+    /// Every attempt allowed by the [`RetryPolicy`](crate::RetryPolicy) failed. `last` is the
+    /// error from the final attempt.
+    #[cfg(feature = "serde")]
+    RetriesExhausted { attempts: u32, last: anyhow::Error },
+    /// Every child started by [`fork_map_race`](crate::fork_map_race) failed. `errors` has each
+    /// one's error, in the order they failed, with errors from the closure wrapped in
+    /// [`ForkError::Closure`].
+    AllFailed { errors: Vec<ForkError> },
+    /// The child running a chunk of items for [`fork_map_chunked`](crate::fork_map_chunked)
+    /// failed, or returned an error, so every item in the chunk gets this. `items` are their
+    /// indices, and `source` is what went wrong, shared between them.
+    ChunkFailed {
+        items: Range<usize>,
+        source: Arc<ForkError>,
+    },
+    /// The closure stopped early because its [`CancelToken`](crate::CancelToken) was
+    /// cancelled, by returning the error from [`check`](crate::CancelToken::check).
+    Cancelled,
+    /// A [`non_blocking`](crate::ForkBuilder::non_blocking) fork would have had to wait for
+    /// room under the [concurrency limit](crate::set_max_concurrent_forks). Nothing was forked,
+    /// so it's fine to try again later.
+    WouldBlock,
+    /// `fork()` failed with `EAGAIN`: this user has as many processes as `RLIMIT_NPROC` allows,
+    /// or the system (or the cgroup's `pids.max`) has run out of pids or threads. Nothing was
+    /// forked, and it's worth trying again once some processes have exited, after backing off,
+    /// or with fewer at a time (see [`set_max_concurrent_forks`](crate::set_max_concurrent_forks)).
+    ///
+    /// ```
+    /// use fork_map::{fork_map, ForkError};
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// // A child that's not allowed any processes of its own tries to fork one
+    /// let limited = unsafe {
+    ///     fork_map(|| {
+    ///         // Root can have as many as it likes, so be nobody
+    ///         if libc::geteuid() == 0 && libc::setuid(65534) != 0 {
+    ///             anyhow::bail!("setuid failed");
+    ///         }
+    ///         let none = libc::rlimit {
+    ///             rlim_cur: 0,
+    ///             rlim_max: 0,
+    ///         };
+    ///         libc::setrlimit(libc::RLIMIT_NPROC, &none);
+    ///         let err = fork_map(|| Ok(())).unwrap_err();
+    ///         Ok(matches!(err.downcast_ref(), Some(ForkError::TooManyProcesses)))
+    ///     })
+    /// }
+    /// .unwrap();
+    /// assert!(limited);
+    /// ```
+    ///
+    /// Nothing is left open by a fork that failed, so backing off and trying again can go on
+    /// for as long as it takes:
+    ///
+    /// ```
+    /// use fork_map::{Fork, Transport};
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// fn open_fds() -> usize {
+    ///     (0..1024).filter(|&fd| unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0).count()
+    /// }
+    ///
+    /// let unchanged = unsafe {
+    ///     fork_map::fork_map(|| {
+    ///         if libc::geteuid() == 0 && libc::setuid(65534) != 0 {
+    ///             anyhow::bail!("setuid failed");
+    ///         }
+    ///         let none = libc::rlimit {
+    ///             rlim_cur: 0,
+    ///             rlim_max: 0,
+    ///         };
+    ///         libc::setrlimit(libc::RLIMIT_NPROC, &none);
+    ///         let before = open_fds();
+    ///         for transport in [Transport::Pipe, Transport::Socket, Transport::SharedMemory] {
+    ///             for _ in 0..10 {
+    ///                 let builder = Fork::builder().transport(transport).capture_stdout(true);
+    ///                 assert!(builder.run(|| Ok(())).is_err());
+    ///             }
+    ///         }
+    ///         Ok(open_fds() == before)
+    ///     })
+    /// }
+    /// .unwrap();
+    /// assert!(unchanged);
+    /// ```
+    TooManyProcesses,
+    /// `fork()` failed with `ENOMEM`: the kernel couldn't allocate what a new process needs,
+    /// like a copy of the parent's page tables, which are big for a big parent. Nothing was
+    /// forked. Other errors from `fork()` are [`ForkError::Io`].
+    NoMemoryToFork,
+    /// [`fork_map_safe`](crate::fork_map_safe) was called from a process with other threads
+    /// running, whose locks the child would inherit. Nothing was forked.
+    MultiThreaded { threads: usize },
+    /// [`fork_map_via_server`](crate::fork_map_via_server) was called without a fork server
+    /// running, either because [`init_fork_server`](crate::init_fork_server) wasn't called or
+    /// the server has since been shut down or died.
+    NoForkServer,
+    /// The child exited without sending anything back, for example because it called `exit()`
+    /// itself. A closure that returns `Ok(())` does not
+    /// count, every result is sent back in a non-empty frame.
+    ///
+    /// ```
+    /// use fork_map::{fork_map, ForkError};
+    ///
+    /// assert_eq!(unsafe { fork_map(|| Ok(())) }.unwrap(), ());
+    /// # if !fork_map::FORKS { return }
+    /// let err = unsafe {
+    ///     fork_map(|| -> anyhow::Result<()> {
+    ///         libc::exit(0);
+    ///     })
+    /// }
+    /// .unwrap_err();
+    /// assert!(matches!(err.downcast_ref(), Some(ForkError::NoResult { .. })));
+    /// ```
+    NoResult { pid: u32 },
+    /// The [`ForkHandle`](crate::ForkHandle) was dropped without being joined, so the child's
+    /// result was never read. Only ever passed to [`on_reap`](crate::ForkHandle::on_reap)
+    /// callbacks.
+    ResultDiscarded,
+    /// The closure ran fine, but its result couldn't be serialized with the
+    /// [`Codec`](crate::Codec), so the child sent back the serializer's error instead. This is a
+    /// problem with the result type, not the child, so it isn't retried by default.
+    ///
+    /// ```
+    /// use fork_map::{fork_map, ForkError};
+    /// use std::collections::BTreeMap;
+    ///
+    /// // JSON object keys have to be strings
+    /// let err = unsafe { fork_map(|| Ok(BTreeMap::from([((1u8, 2u8), 3u8)]))) }.unwrap_err();
+    /// match err.downcast_ref() {
+    ///     Some(ForkError::ResultSerializeFailed { message, .. }) => {
+    ///         assert!(message.contains("key must be a string"));
+    ///     }
+    ///     _ => panic!("unexpected error {:?}", err),
+    /// }
+    /// ```
+    ResultSerializeFailed {
+        pid: u32,
+        /// What the serializer objected to.
+        message: String,
+    },
+    /// The child started sending a result but stopped partway through.
+    Truncated {
+        pid: u32,
+        /// How many bytes the result should have been, as far as the parent could tell.
+        expected: usize,
+        received: usize,
+    },
+    /// The child didn't send its whole result within its
+    /// [`timeout`](crate::ForkBuilder::timeout), so it was killed.
+    Timeout { pid: u32, timeout: Duration },
+    /// The child went longer than its watchdog's interval without a sign of life, so it was
+    /// killed. See [`fork_map_watchdog`](crate::fork_map_watchdog).
+    Stalled {
+        pid: u32,
+        /// How long it had been since the last tick (or the start) when the child was killed.
+        last_heartbeat_age: Duration,
+    },
+    /// The child's result was bigger than
+    /// [`max_result_bytes`](crate::ForkBuilder::max_result_bytes) allows, so the parent stopped
+    /// reading it and killed the child. `size` is how big the child said the result is, or how
+    /// much of it arrived if that's more.
+    ResultTooLarge { pid: u32, limit: usize, size: u64 },
+    /// The child's resident set grew past [`max_rss`](crate::ForkBuilder::max_rss), so it was
+    /// killed.
+    MemoryLimitExceeded {
+        pid: u32,
+        /// How many bytes it had resident when it was caught.
+        observed: u64,
+        limit: u64,
+    },
+    /// The child sent more bytes than its result frame said it would, which means something
+    /// other than this crate wrote to the result pipe, or the child was built from different code.
+    /// The result is rejected rather than trusting either part.
+    ///
+    /// ```
+    /// use fork_map::{Fork, ForkError, Transport};
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// fn sockets() -> impl Iterator<Item = libc::c_int> {
+    ///     (3..1024).filter(|&fd| {
+    ///         let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    ///         let found = unsafe { libc::fstat(fd, &mut stat) } == 0;
+    ///         found && stat.st_mode & libc::S_IFMT == libc::S_IFSOCK
+    ///     })
+    /// }
+    ///
+    /// // Runs as the child exits, after it has sent its result and closed its end, so it writes
+    /// // through copies the closure kept
+    /// extern "C" fn scribble() {
+    ///     for fd in sockets() {
+    ///         unsafe { libc::write(fd, b"junk".as_ptr().cast(), 4) };
+    ///     }
+    /// }
+    ///
+    /// let err = unsafe {
+    ///     Fork::builder().transport(Transport::Socket).run(|| {
+    ///         for fd in sockets().collect::<Vec<_>>() {
+    ///             libc::dup(fd);
+    ///         }
+    ///         libc::atexit(scribble);
+    ///         Ok(1)
+    ///     })
+    /// }
+    /// .unwrap_err();
+    /// match err.downcast_ref() {
+    ///     Some(ForkError::ProtocolViolation { extra, .. }) => assert_eq!(*extra, 4),
+    ///     other => panic!("expected a protocol violation, got {:?}", other),
+    /// }
+    /// ```
+    ProtocolViolation {
+        /// The child that sent them.
+        pid: u32,
+        /// How many bytes the frame accounted for.
+        expected: usize,
+        /// How many more came after those.
+        extra: usize,
+    },
+    /// The child exited without sending its whole result, but the result pipe never reached EOF,
+    /// because some other process still has it open: usually a grandchild the closure forked or
+    /// spawned, which inherited the pipe along with everything else. Rather than wait for that
+    /// one to exit too, the parent gives up on the rest shortly after the child is gone. A child
+    /// that did send its whole result gets it back, leak or no leak.
+    ///
+    /// ```
+    /// use fork_map::{fork_map, ForkError};
+    /// use std::time::{Duration, Instant};
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// // Starts a process that outlives the child, with the child's copy of the pipe
+    /// let linger = || unsafe {
+    ///     if libc::fork() == 0 {
+    ///         libc::sleep(1);
+    ///         libc::_exit(0);
+    ///     }
+    /// };
+    ///
+    /// let start = Instant::now();
+    /// let answer = unsafe {
+    ///     fork_map(|| {
+    ///         linger();
+    ///         Ok(42)
+    ///     })
+    /// }
+    /// .unwrap();
+    /// assert_eq!(answer, 42);
+    ///
+    /// let err = unsafe {
+    ///     fork_map(|| -> anyhow::Result<()> {
+    ///         linger();
+    ///         libc::_exit(0)
+    ///     })
+    /// }
+    /// .unwrap_err();
+    /// assert!(matches!(err.downcast_ref(), Some(ForkError::PipeLeak { .. })));
+    /// assert!(start.elapsed() < Duration::from_secs(1));
+    /// ```
+    PipeLeak { pid: u32 },
+    /// The parent received a result from the child but couldn't deserialize it, usually because
+    /// the two sides disagree about the shape of the result type.
+    ///
+    /// ```
+    /// use fork_map::{fork_map, ForkError};
+    /// use serde::{Deserialize, Serialize};
+    ///
+    /// // Written under one name and read under another, like a child built from stale code
+    /// #[derive(Serialize, Deserialize)]
+    /// struct Job {
+    ///     #[serde(rename(serialize = "id"))]
+    ///     ident: u64,
+    /// }
+    ///
+    /// let err = unsafe { fork_map(|| Ok(Job { ident: 7 })) }.err().unwrap();
+    /// match err.downcast_ref() {
+    ///     Some(ForkError::Decode { len, preview, .. }) => {
+    ///         assert_eq!(*len, 8);
+    ///         assert_eq!(preview, r#""{\"id\":7}""#);
+    ///     }
+    ///     _ => panic!("unexpected error {:?}", err),
+    /// }
+    /// ```
+    Decode {
+        pid: u32,
+        /// What the deserializer objected to.
+        message: String,
+        /// Total number of bytes received.
+        len: usize,
+        /// The start of the received bytes, as an escaped string if they're UTF-8, or hex if not.
+        preview: String,
+    },
+    /// The closure itself returned an error, which was sent back from the child. Only used where
+    /// a result per item is returned; elsewhere the closure's error is returned as it is. Its
+    /// [`source`](std::error::Error::source) chain is the closure error's.
+    #[cfg(feature = "serde")]
+    Closure(anyhow::Error),
+    /// The job tagged with `id` failed with `source`. Only used where a result per item is
+    /// returned, like from [`fork_map_par`](crate::fork_map_par), where the id is the item's
+    /// position unless the builder was given one; [`inner`](Self::inner) gets at `source`.
+    #[cfg(feature = "serde")]
+    Job { id: ForkId, source: Box<ForkError> },
+}
+
+impl ForkError {
+    /// What went wrong, from under the [`Job`](Self::Job) it's wrapped in, if it is.
+    pub fn inner(&self) -> &ForkError {
+        match self {
+            #[cfg(feature = "serde")]
+            ForkError::Job { source, .. } => source.inner(),
+            _ => self,
+        }
+    }
+
+    /// The [`ForkId`] the job was tagged with, if it's a [`Job`](Self::Job).
+    #[cfg(feature = "serde")]
+    pub fn id(&self) -> Option<&ForkId> {
+        match self {
+            ForkError::Job { id, .. } => Some(id),
+            _ => None,
+        }
+    }
+
+    #[cfg(all(unix, not(feature = "fallback")))]
+    pub(crate) fn last_os_error(op: &'static str) -> Self {
+        ForkError::Io {
+            op,
+            source: io::Error::last_os_error(),
+        }
+    }
+
+    /// What `errno` says went wrong with a `fork()` that returned -1.
+    #[cfg(all(unix, not(feature = "fallback")))]
+    pub(crate) fn fork_failed() -> Self {
+        let source = io::Error::last_os_error();
+        match source.raw_os_error() {
+            Some(libc::EAGAIN) => ForkError::TooManyProcesses,
+            Some(libc::ENOMEM) => ForkError::NoMemoryToFork,
+            _ => ForkError::Io { op: "fork", source },
+        }
+    }
+
+    /// Unwraps errors from the machinery, and wraps everything else as the closure's.
+    #[cfg(feature = "serde")]
+    pub(crate) fn from_anyhow(error: anyhow::Error) -> Self {
+        error.downcast().unwrap_or_else(ForkError::Closure)
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn decode(pid: u32, error: &dyn fmt::Display, bytes: &[u8]) -> Self {
+        ForkError::Decode {
+            pid,
+            message: error.to_string(),
+            len: bytes.len(),
+            preview: preview(bytes),
+        }
+    }
+}
+
+/// Enough of a payload to recognize it, without flooding the error message.
+#[cfg(feature = "serde")]
+fn preview(bytes: &[u8]) -> String {
+    const PREVIEW_LEN: usize = 128;
+    let head = &bytes[..bytes.len().min(PREVIEW_LEN)];
+    let mut preview = match std::str::from_utf8(head) {
+        Ok(text) => format!("{:?}", text),
+        // Truncation may have split a character, which isn't a reason to give up on the text
+        Err(e) if e.error_len().is_none() => {
+            let text = std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default();
+            format!("{:?}", text)
+        }
+        Err(_) => head.iter().map(|b| format!("{:02x}", b)).collect(),
+    };
+    if head.len() < bytes.len() {
+        preview.push_str("...");
+    }
+    preview
+}
+
+/// What a panic said, if it said it with a string, as `panic!` does.
+#[cfg(all(feature = "serde", unix, not(feature = "fallback")))]
+pub(crate) fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown payload")
+}
+
+impl fmt::Display for ForkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForkError::Io { op, source } => write!(f, "{} failed: {}", op, source),
+            ForkError::Unsupported { feature } => {
+                write!(f, "{} is not supported on this platform", feature)
+            }
+            ForkError::Cgroup { path, source } => {
+                write!(
+                    f,
+                    "failed to move child into cgroup {}: {}",
+                    path.display(),
+                    source
+                )?;
+                if source.kind() == io::ErrorKind::PermissionDenied {
+                    write!(
+                        f,
+                        " (the parent needs write access to its cgroup.procs, and to the \
+                         cgroup.procs of the common ancestor of both cgroups)"
+                    )?;
+                }
+                Ok(())
+            }
+            ForkError::OutOfMemory { pid, cgroup } => write!(
+                f,
+                "child {} was OOM-killed in cgroup {}",
+                pid,
+                cgroup.display()
+            ),
+            ForkError::SyscallBlocked { pid } => write!(
+                f,
+                "child {} was killed by its seccomp filter for a system call it doesn't allow",
+                pid
+            ),
+            ForkError::ChildFailed { pid, status } => {
+                let outcome = crate::interpret_status(*status);
+                write!(f, "child {} {}", pid, outcome)?;
+                if outcome == crate::ExitOutcome::Exited(crate::EXIT_SEND_FAILED) {
+                    write!(f, ", so it couldn't send its result")?;
+                }
+                Ok(())
+            }
+            ForkError::CommandFailed {
+                pid,
+                status,
+                stderr,
+            } => {
+                write!(f, "command {} {}", pid, crate::interpret_status(*status))?;
+                let stderr = stderr.trim_end();
+                if !stderr.is_empty() {
+                    write!(f, ", with stderr:\n{}", stderr)?;
+                }
+                Ok(())
+            }
+            #[cfg(feature = "serde")]
+            ForkError::RetriesExhausted { attempts, last } => {
+                write!(f, "gave up after {} attempts: {}", attempts, last)
+            }
+            ForkError::ChunkFailed { items, source } => write!(
+                f,
+                "the chunk of items {}..{} failed: {}",
+                items.start, items.end, source
+            ),
+            ForkError::AllFailed { errors } => {
+                write!(f, "all {} children failed", errors.len())?;
+                if let Some(first) = errors.first() {
+                    write!(f, ", the first with: {}", first)?;
+                }
+                Ok(())
+            }
+            ForkError::Cancelled => write!(f, "cancelled"),
+            ForkError::WouldBlock => write!(f, "the concurrent fork limit has been reached"),
+            ForkError::TooManyProcesses => {
+                write!(f, "fork failed: too many processes (EAGAIN)")
+            }
+            ForkError::NoMemoryToFork => write!(f, "fork failed: out of memory (ENOMEM)"),
+            ForkError::MultiThreaded { threads } => write!(
+                f,
+                "can't fork safely with {} threads running, only with one",
+                threads
+            ),
+            ForkError::NoForkServer => write!(f, "the fork server is not running"),
+            ForkError::NoResult { pid } => {
+                write!(f, "child {} exited without reporting a result", pid)
+            }
+            ForkError::ResultDiscarded => write!(f, "result was discarded without being read"),
+            ForkError::ResultSerializeFailed { pid, message } => {
+                write!(
+                    f,
+                    "child {} failed to serialize its result: {}",
+                    pid, message
+                )
+            }
+            ForkError::Truncated {
+                pid,
+                expected,
+                received,
+            } => write!(
+                f,
+                "result from child {} was truncated: expected {} bytes, received {}",
+                pid, expected, received
+            ),
+            ForkError::Timeout { pid, timeout } => {
+                write!(f, "child {} timed out after {:?}", pid, timeout)
+            }
+            ForkError::Stalled {
+                pid,
+                last_heartbeat_age,
+            } => write!(
+                f,
+                "child {} stalled, with no heartbeat for {:?}",
+                pid, last_heartbeat_age
+            ),
+            ForkError::ResultTooLarge { pid, limit, size } => write!(
+                f,
+                "result from child {} is {} bytes, over the limit of {}",
+                pid, size, limit
+            ),
+            ForkError::MemoryLimitExceeded {
+                pid,
+                observed,
+                limit,
+            } => write!(
+                f,
+                "child {} was killed for having {} bytes resident, over the limit of {}",
+                pid, observed, limit
+            ),
+            ForkError::ProtocolViolation {
+                pid,
+                expected,
+                extra,
+            } => write!(
+                f,
+                "child {} sent {} bytes after its {} byte result",
+                pid, extra, expected
+            ),
+            ForkError::PipeLeak { pid } => write!(
+                f,
+                "child {} exited without sending its whole result, and something else still \
+                 holds its result pipe open",
+                pid
+            ),
+            ForkError::Decode {
+                pid,
+                message,
+                len,
+                preview,
+            } => write!(
+                f,
+                "failed to decode result from child {}: {} (received {} bytes: {})",
+                pid, message, len, preview
+            ),
+            #[cfg(feature = "serde")]
+            ForkError::Closure(error) => write!(f, "{}", error),
+            #[cfg(feature = "serde")]
+            ForkError::Job { id, source } => write!(f, "{}: {}", id, source),
+        }
+    }
+}
+
+impl std::error::Error for ForkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            // Displayed as the closure's error itself, so its chain continues from there
+            #[cfg(feature = "serde")]
+            ForkError::Closure(error) => error.source(),
+            ForkError::ChunkFailed { source, .. } => Some(&**source),
+            #[cfg(feature = "serde")]
+            ForkError::Job { source, .. } => Some(&**source),
+            _ => None,
+        }
+    }
+}
+
+/// The id a job was tagged with using [`ForkBuilder::id`](crate::ForkBuilder::id), attached to
+/// its errors as context, so which job failed can be read back from them:
+///
+/// ```
+/// use fork_map::{Fork, ForkError, ForkId};
+///
+/// let err = unsafe {
+///     Fork::builder()
+///         .id(17)
+///         .run(|| -> anyhow::Result<()> { Err(ForkError::Cancelled.into()) })
+/// }
+/// .unwrap_err();
+/// assert_eq!(err.downcast_ref::<ForkId>().unwrap().as_str(), "17");
+/// assert_eq!(format!("{:#}", err), "fork 17: cancelled");
+/// // What went wrong can still be told apart as before
+/// assert!(matches!(err.downcast_ref(), Some(ForkError::Cancelled)));
+/// ```
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ForkId(pub(crate) String);
+
+#[cfg(feature = "serde")]
+impl ForkId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(feature = "serde")]
+impl fmt::Display for ForkId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "fork {}", self.0)
+    }
+}
@@ -0,0 +1,137 @@
+//! Keeping parts of the parent's memory out of its children.
+//!
+//! There's no registry of regions on our side: the kernel keeps the advice with the mapping it
+//! was given for, so it's there for every fork from then on, by this crate or not, and goes
+//! away with the mapping when it's unmapped.
+
+use crate::ForkError;
+
+#[cfg(target_os = "linux")]
+const DONTFORK: Option<libc::c_int> = Some(libc::MADV_DONTFORK);
+#[cfg(not(target_os = "linux"))]
+const DONTFORK: Option<libc::c_int> = None;
+#[cfg(target_os = "linux")]
+const WIPEONFORK: Option<libc::c_int> = Some(libc::MADV_WIPEONFORK);
+#[cfg(not(target_os = "linux"))]
+const WIPEONFORK: Option<libc::c_int> = None;
+
+/// Leaves the pages from `ptr` to `ptr + len` out of every child forked after this, with
+/// `madvise(MADV_DONTFORK)`: they aren't mapped in the child at all, so `fork()` doesn't have
+/// to copy their page tables, and they don't count towards the child's memory.
+///
+/// This is for big things the children never look at, like a cache the parent keeps, where
+/// forking a parent with tens of gigabytes of it mapped spends most of its time copying page
+/// tables that no child uses. If a child does touch the region after all, it crashes with
+/// `SIGSEGV`, which is reported as [`ForkError::ChildFailed`].
+///
+/// Only whole pages inside the region are left out, so the start is rounded up to a page
+/// boundary and the end down, and the allocator's bookkeeping just before a heap allocation is
+/// still there for the child. The region stays out of children until it's unmapped.
+///
+/// Fails with the error from `madvise`, for example `ENOMEM` if part of the region isn't
+/// mapped. Only available on Linux, and fails with [`ForkError::Unsupported`] elsewhere. When
+/// [`FORKS`](crate::FORKS) is `false`, the closure runs in this process with the region as it
+/// is, and this does nothing.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{exclude_region, fork_map_bytes, interpret_status, ExitOutcome, ForkError};
+/// # if !fork_map::FORKS || !cfg!(target_os = "linux") { return }
+///
+/// // A big cache, reserved but only partly filled in
+/// let len = 1 << 30;
+/// let cache = unsafe {
+///     let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE;
+///     let prot = libc::PROT_READ | libc::PROT_WRITE;
+///     libc::mmap(std::ptr::null_mut(), len, prot, flags, -1, 0) as *mut u8
+/// };
+/// unsafe { cache.write_bytes(1, 1 << 20) };
+/// unsafe { exclude_region(cache, len) }.unwrap();
+///
+/// // A child that doesn't look at it is none the wiser
+/// assert_eq!(unsafe { fork_map_bytes(|| vec![1, 2, 3]) }.unwrap(), [1, 2, 3]);
+///
+/// // One that does crashes
+/// let err = unsafe { fork_map_bytes(|| vec![*cache]) }.unwrap_err();
+/// match err {
+///     ForkError::ChildFailed { status, .. } => {
+///         assert_eq!(interpret_status(status), ExitOutcome::Signaled(libc::SIGSEGV));
+///     }
+///     _ => panic!("unexpected error {:?}", err),
+/// }
+/// // While the parent still has it
+/// assert_eq!(unsafe { *cache }, 1);
+/// ```
+///
+/// # Safety
+///
+/// The region is gone in the child, so nothing there may access it, including through
+/// references to anything in it, or by dropping something that owns part of it.
+pub unsafe fn exclude_region(ptr: *const u8, len: usize) -> Result<(), ForkError> {
+    advise(ptr, len, DONTFORK, "exclude_region")
+}
+
+/// Has the pages from `ptr` to `ptr + len` read as zeros in every child forked after this,
+/// with `madvise(MADV_WIPEONFORK)`, for secrets like keys that the children have no business
+/// with, but whose memory they might still read from.
+///
+/// Unlike with [`exclude_region`], the child can use the region as usual, as fresh memory that
+/// it fills in itself. The region must be a private anonymous mapping (made by [`mmap`](libc::mmap)
+/// with `MAP_PRIVATE | MAP_ANONYMOUS`), or this fails with `EINVAL`, and it's rounded to whole
+/// pages the same way. It needs Linux 4.14 or later, and fails with [`ForkError::Unsupported`]
+/// on other platforms. When [`FORKS`](crate::FORKS) is `false`, this does nothing.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_bytes, wipe_region_on_fork};
+/// # if !fork_map::FORKS || !cfg!(target_os = "linux") { return }
+///
+/// let len = 1 << 16;
+/// let key = unsafe {
+///     let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+///     let prot = libc::PROT_READ | libc::PROT_WRITE;
+///     let key = libc::mmap(std::ptr::null_mut(), len, prot, flags, -1, 0) as *mut u8;
+///     std::slice::from_raw_parts_mut(key, len)
+/// };
+/// key.fill(0x5a);
+/// unsafe { wipe_region_on_fork(key.as_ptr(), len) }.unwrap();
+///
+/// let seen = unsafe { fork_map_bytes(|| key.to_vec()) }.unwrap();
+/// assert!(seen.iter().all(|&b| b == 0));
+/// assert!(key.iter().all(|&b| b == 0x5a));
+/// ```
+///
+/// # Safety
+///
+/// Everything in the region reads as zeros in the child, so it must only hold data for which
+/// all zeros is a valid value, like bytes or integers, as far as the child is concerned.
+pub unsafe fn wipe_region_on_fork(ptr: *const u8, len: usize) -> Result<(), ForkError> {
+    advise(ptr, len, WIPEONFORK, "wipe_region_on_fork")
+}
+
+/// Gives the whole pages in the region `advice`, or fails with [`ForkError::Unsupported`] for
+/// `feature` if there's no such advice here.
+unsafe fn advise(
+    ptr: *const u8,
+    len: usize,
+    advice: Option<libc::c_int>,
+    feature: &'static str,
+) -> Result<(), ForkError> {
+    #[cfg(all(unix, not(feature = "fallback")))]
+    {
+        let Some(advice) = advice else {
+            return Err(ForkError::Unsupported { feature });
+        };
+        let page = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+        let start = (ptr as usize).next_multiple_of(page);
+        let end = (ptr as usize).saturating_add(len) / page * page;
+        if end > start && libc::madvise(start as *mut libc::c_void, end - start, advice) < 0 {
+            return Err(ForkError::last_os_error("madvise"));
+        }
+    }
+    #[cfg(any(not(unix), feature = "fallback"))]
+    let _ = (ptr, len, advice, feature);
+    Ok(())
+}
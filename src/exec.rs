@@ -0,0 +1,274 @@
+//! Running jobs in a freshly exec'd copy of the current binary.
+//!
+//! The child is started with [`Command`](std::process::Command) on the path from
+//! [`current_exe`](std::env::current_exe), with two environment variables saying which job to
+//! run and which file descriptor one end of a socket pair was passed on as. The parent sends the
+//! input over it the same way [`ForkPool`](crate::ForkPool) does, as a little-endian `u64` length
+//! followed by the input encoded with the default [`Codec`](crate::Codec), and the child answers
+//! with the usual frame (see `protocol`) and exits.
+
+// Without fork() jobs run in process, and the entry point has nothing to do
+#![cfg_attr(any(not(unix), feature = "fallback"), allow(dead_code))]
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Fork;
+#[cfg(all(unix, not(feature = "fallback")))]
+use crate::{limit, sys, ForkError};
+
+/// Which job the child should run, by [`ExecJob::NAME`].
+#[cfg(all(unix, not(feature = "fallback")))]
+const JOB_VAR: &str = "FORK_MAP_EXEC_JOB";
+/// Which file descriptor the child talks to the parent on.
+#[cfg(all(unix, not(feature = "fallback")))]
+const FD_VAR: &str = "FORK_MAP_EXEC_FD";
+/// Right after stdin, stdout and stderr.
+#[cfg(all(unix, not(feature = "fallback")))]
+const EXEC_FD: libc::c_int = 3;
+
+/// A job that [`exec_map`] can run in a fresh copy of the current binary.
+///
+/// A name has to be unique among the jobs passed to [`exec_entry_point`], since that's all the
+/// child gets to go on.
+pub trait ExecJob {
+    /// What the child is told to run.
+    const NAME: &'static str;
+    type Input: Serialize + for<'a> Deserialize<'a>;
+    type Output: Serialize + for<'a> Deserialize<'a>;
+
+    /// Runs the job, in the child.
+    fn run(input: Self::Input) -> anyhow::Result<Self::Output>;
+}
+
+/// A job registered with [`exec_entry_point`].
+#[derive(Clone, Copy)]
+pub struct ExecEntry {
+    name: &'static str,
+    run: fn(&[u8]) -> Vec<u8>,
+}
+
+impl ExecEntry {
+    /// The entry for job `J`.
+    pub fn of<J: ExecJob>() -> Self {
+        ExecEntry {
+            name: J::NAME,
+            run: run_job::<J>,
+        }
+    }
+}
+
+impl fmt::Debug for ExecEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExecEntry")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Decodes the input, runs the job and encodes its result, in the child.
+fn run_job<J: ExecJob>(input: &[u8]) -> Vec<u8> {
+    let builder = Fork::builder();
+    let input = builder.codec.decode::<J::Input>(std::process::id(), input);
+    builder.encode_result(input.map_err(anyhow::Error::from).and_then(J::run))
+}
+
+/// Takes over if this process was started by [`exec_map`], runs the job it was started for out
+/// of `jobs`, and exits. Otherwise returns right away.
+///
+/// Call this first thing in `main`: everything `main` does before it also happens in every
+/// child, which is the opposite of what you want from a clean process.
+pub fn exec_entry_point(jobs: &[ExecEntry]) {
+    #[cfg(all(unix, not(feature = "fallback")))]
+    {
+        let Some(name) = std::env::var_os(JOB_VAR) else {
+            return;
+        };
+        let fd = std::env::var(FD_VAR).ok().and_then(|fd| fd.parse().ok());
+        // So they don't fool processes this one starts in turn
+        std::env::remove_var(JOB_VAR);
+        std::env::remove_var(FD_VAR);
+        let Some(fd) = fd else {
+            // Not started by us after all, but by someone who knows the name
+            eprintln!("fork_map: {} is set, but {} isn't", JOB_VAR, FD_VAR);
+            std::process::exit(1);
+        };
+        unsafe { serve(fd, &name, jobs) }
+    }
+    #[cfg(any(not(unix), feature = "fallback"))]
+    let _ = jobs;
+}
+
+/// The child's side: answers the one job it was started for.
+#[cfg(all(unix, not(feature = "fallback")))]
+unsafe fn serve(fd: libc::c_int, name: &std::ffi::OsStr, jobs: &[ExecEntry]) -> ! {
+    let mut len = [0u8; 8];
+    if sys::read_exact(fd, &mut len).ok() != Some(len.len()) {
+        libc::exit(1);
+    }
+    let mut input = vec![0u8; u64::from_le_bytes(len) as usize];
+    if sys::read_exact(fd, &mut input).ok() != Some(input.len()) {
+        libc::exit(1);
+    }
+    let frame = match jobs.iter().find(|job| name == job.name) {
+        // A panic is answered like any other error, rather than unwinding into the rest of `main`
+        Some(job) => crate::forked::catch_panic(|| Ok((job.run)(&input)))
+            .unwrap_or_else(|e| Fork::builder().encode_result::<()>(Err(e))),
+        None => Fork::builder().encode_result::<()>(Err(anyhow::anyhow!(
+            "no job named {:?} was passed to exec_entry_point",
+            name
+        ))),
+    };
+    let _ = sys::send_all(fd, &frame);
+    libc::close(fd);
+    libc::exit(0);
+}
+
+/// Runs job `J` on `input` in a new process running the current binary from the start, and
+/// returns its result.
+///
+/// Unlike [`fork_map`](crate::fork_map), the child inherits none of the parent's memory: no
+/// secrets it has loaded, no allocator or lock state, no signal handlers, only what it gets in
+/// `input`. It starts out like any other run of your program, so it has to call
+/// [`exec_entry_point`] at the start of `main` with `J` among its jobs, which is where it takes
+/// the other road. Standard input, output and error are inherited, as are environment
+/// variables, but not command-line arguments.
+///
+/// The [`set_max_concurrent_forks`](crate::set_max_concurrent_forks) limit applies, and both the
+/// input and the result go through the default [`Codec`](crate::Codec). When
+/// [`FORKS`](crate::FORKS) is `false` there's no child, and the job runs in the calling process
+/// instead. A job that panics fails with the panic message, and its child exits rather than
+/// going on with the rest of `main`.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{exec_entry_point, exec_map, ExecEntry, ExecJob};
+/// use std::sync::atomic::{AtomicBool, Ordering};
+///
+/// static SECRET_LOADED: AtomicBool = AtomicBool::new(false);
+///
+/// struct Parse;
+///
+/// impl ExecJob for Parse {
+///     const NAME: &'static str = "parse";
+///     type Input = String;
+///     type Output = (u64, bool);
+///
+///     fn run(input: String) -> anyhow::Result<(u64, bool)> {
+///         if input == "panic" {
+///             panic!("asked to");
+///         }
+///         Ok((input.trim().parse()?, SECRET_LOADED.load(Ordering::SeqCst)))
+///     }
+/// }
+///
+/// // First thing in main
+/// exec_entry_point(&[ExecEntry::of::<Parse>()]);
+///
+/// SECRET_LOADED.store(true, Ordering::SeqCst);
+/// let (value, secret) = exec_map::<Parse>(" 42 ".to_string()).unwrap();
+/// assert_eq!(value, 42);
+/// // A fresh process never loaded it
+/// assert_eq!(secret, !fork_map::FORKS);
+/// assert!(exec_map::<Parse>("forty-two".to_string()).is_err());
+///
+/// # if !fork_map::FORKS { return }
+/// let err = exec_map::<Parse>("panic".to_string()).unwrap_err();
+/// assert!(err.to_string().contains("asked to"), "{}", err);
+/// ```
+pub fn exec_map<J: ExecJob>(input: J::Input) -> anyhow::Result<J::Output> {
+    #[cfg(all(unix, not(feature = "fallback")))]
+    {
+        exec_in_child::<J>(input)
+    }
+    #[cfg(any(not(unix), feature = "fallback"))]
+    {
+        Fork::builder().run_item_in_process(&J::run, input)
+    }
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+fn exec_in_child<J: ExecJob>(input: J::Input) -> anyhow::Result<J::Output> {
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+    use std::process::Command;
+
+    let builder = Fork::builder();
+    let body = builder.codec.encode(&input)?;
+    let mut job = (body.len() as u64).to_le_bytes().to_vec();
+    job.extend_from_slice(&body);
+    let exe = std::env::current_exe().map_err(|source| ForkError::Io {
+        op: "current_exe",
+        source,
+    })?;
+
+    // Held until the child is reaped
+    let _permit = limit::acquire();
+    let socket = unsafe { sys::socketpair() }?;
+    let ours = socket[0];
+    let theirs = socket[1];
+    unsafe {
+        // Neither end should end up in anything else that gets exec'd meanwhile
+        libc::fcntl(ours, libc::F_SETFD, libc::FD_CLOEXEC);
+        libc::fcntl(theirs, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+    let mut command = Command::new(exe);
+    command
+        .env(JOB_VAR, J::NAME)
+        .env(FD_VAR, EXEC_FD.to_string());
+    unsafe {
+        command.pre_exec(move || {
+            // Only async-signal-safe calls in here. dup2 clears close-on-exec on the copy, but
+            // does nothing at all if it's already in place.
+            let ret = if theirs == EXEC_FD {
+                libc::fcntl(theirs, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(theirs, EXEC_FD)
+            };
+            if ret < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    };
+    let spawned = command.spawn();
+    unsafe { libc::close(theirs) };
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(source) => {
+            unsafe { libc::close(ours) };
+            return Err(ForkError::Io { op: "exec", source }.into());
+        }
+    };
+
+    let sent = unsafe { sys::send_all(ours, &job) };
+    // Even if sending failed, the child may have sent why
+    let mut received = vec![];
+    let read = loop {
+        match unsafe { sys::read_some(ours, &mut received) } {
+            Ok(0) => break Ok(()),
+            Ok(_) => {}
+            Err(e) => break Err(e),
+        }
+    };
+    // Always reap the child even if talking to it failed
+    unsafe { libc::close(ours) };
+    let pid = child.id();
+    let status = child
+        .wait()
+        .map_err(|source| ForkError::Io { op: "wait", source })?;
+    if !status.success() {
+        return Err(ForkError::ChildFailed {
+            pid,
+            status: status.into_raw(),
+        }
+        .into());
+    }
+    read?;
+    if received.is_empty() {
+        sent?;
+        return Err(ForkError::NoResult { pid }.into());
+    }
+    builder.decode_result(pid, &received)
+}
@@ -0,0 +1,207 @@
+//! Forking from ordinary iterators, one item at a time.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Fork, ForkBuilder, ForkError};
+
+/// Adds [`fork_map`](Self::fork_map) and [`try_fork_map`](Self::try_fork_map) to everything
+/// that can be iterated over.
+///
+/// Each item is run in a child of its own, one after the other: the next child is only forked
+/// when you ask the iterator for its result, so stopping early (with `take`, `find`, or `?`
+/// in a `collect`) stops the forking too. Use [`fork_map_iter`](crate::fork_map_iter) if you
+/// want several children running at once.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{Fork, ForkError, ForkMapExt};
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+///
+/// // Count the children from the parent's side
+/// let forks = Arc::new(AtomicUsize::new(0));
+/// let builder = Fork::builder().on_fork({
+///     let forks = forks.clone();
+///     move |_| {
+///         forks.fetch_add(1, Ordering::SeqCst);
+///     }
+/// });
+///
+/// let squares = unsafe { (1..1000u64).fork_map_with(builder.clone(), |n| Ok(n * n)) };
+/// assert_eq!(forks.load(Ordering::SeqCst), 0);
+/// let squares: Vec<u64> = squares.take(3).collect::<Result<_, _>>().unwrap();
+/// assert_eq!(squares, [1, 4, 9]);
+/// assert_eq!(forks.load(Ordering::SeqCst), 3);
+///
+/// // try_fork_map stops at the first error, however hard you pull
+/// forks.store(0, Ordering::SeqCst);
+/// let results: Vec<Result<u64, ForkError>> = unsafe {
+///     (0..10u64).try_fork_map_with(builder, |n| {
+///         anyhow::ensure!(n != 2, "two is unlucky");
+///         Ok(n)
+///     })
+/// }
+/// .collect();
+/// assert_eq!(results.len(), 3);
+/// assert!(matches!(&results[2], Err(ForkError::Closure(e)) if e.to_string() == "two is unlucky"));
+/// assert_eq!(forks.load(Ordering::SeqCst), 3);
+/// ```
+pub trait ForkMapExt: IntoIterator + Sized {
+    /// Runs `func` on each item in a child process of its own, as the returned iterator is
+    /// advanced. Errors from `func` are wrapped in [`ForkError::Closure`].
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map), for every call to the iterator's `next`.
+    unsafe fn fork_map<F, R>(self, func: F) -> ForkMap<Self::IntoIter, F, R>
+    where
+        Self::Item: Send,
+        F: Fn(Self::Item) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        self.fork_map_with(Fork::builder(), func)
+    }
+
+    /// Like [`fork_map`](Self::fork_map), with each child configured by `builder`.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](Self::fork_map).
+    unsafe fn fork_map_with<F, R>(
+        self,
+        builder: ForkBuilder,
+        func: F,
+    ) -> ForkMap<Self::IntoIter, F, R>
+    where
+        Self::Item: Send,
+        F: Fn(Self::Item) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        ForkMap {
+            builder,
+            items: self.into_iter(),
+            func,
+            _result: PhantomData,
+        }
+    }
+
+    /// Like [`fork_map`](Self::fork_map), but the iterator ends right after yielding the first
+    /// error, so no more children are forked after one fails.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](Self::fork_map).
+    unsafe fn try_fork_map<F, R>(self, func: F) -> TryForkMap<Self::IntoIter, F, R>
+    where
+        Self::Item: Send,
+        F: Fn(Self::Item) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        self.try_fork_map_with(Fork::builder(), func)
+    }
+
+    /// Like [`try_fork_map`](Self::try_fork_map), with each child configured by `builder`.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](Self::fork_map).
+    unsafe fn try_fork_map_with<F, R>(
+        self,
+        builder: ForkBuilder,
+        func: F,
+    ) -> TryForkMap<Self::IntoIter, F, R>
+    where
+        Self::Item: Send,
+        F: Fn(Self::Item) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        TryForkMap {
+            inner: self.fork_map_with(builder, func),
+            failed: false,
+        }
+    }
+}
+
+impl<I: IntoIterator> ForkMapExt for I {}
+
+/// Iterator returned by [`ForkMapExt::fork_map`].
+pub struct ForkMap<I, F, R> {
+    builder: ForkBuilder,
+    items: I,
+    func: F,
+    _result: PhantomData<fn() -> R>,
+}
+
+impl<I, F, R> Iterator for ForkMap<I, F, R>
+where
+    I: Iterator,
+    I::Item: Send,
+    F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    type Item = Result<R, ForkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.items.next()?;
+        // Safety: promised by whoever created the iterator
+        let result = unsafe { self.builder.run_item(&self.func, item) };
+        Some(result.map_err(ForkError::from_anyhow))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.items.size_hint()
+    }
+}
+
+impl<I, F, R> fmt::Debug for ForkMap<I, F, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ForkMap")
+            .field("builder", &self.builder)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Iterator returned by [`ForkMapExt::try_fork_map`].
+pub struct TryForkMap<I, F, R> {
+    inner: ForkMap<I, F, R>,
+    failed: bool,
+}
+
+impl<I, F, R> Iterator for TryForkMap<I, F, R>
+where
+    I: Iterator,
+    I::Item: Send,
+    F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    type Item = Result<R, ForkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.inner.next()?;
+        self.failed = result.is_err();
+        Some(result)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.failed {
+            return (0, Some(0));
+        }
+        // Could stop after any item
+        (0, self.inner.size_hint().1)
+    }
+}
+
+impl<I, F, R> fmt::Debug for TryForkMap<I, F, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TryForkMap")
+            .field("failed", &self.failed)
+            .finish_non_exhaustive()
+    }
+}
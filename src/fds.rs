@@ -0,0 +1,210 @@
+//! Handing open file descriptors from a child back to the parent, instead of their contents.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::os::fd::{AsFd, OwnedFd};
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(all(unix, not(feature = "fallback")))]
+use crate::protocol::{self, Tag};
+#[cfg(all(unix, not(feature = "fallback")))]
+use crate::{Compression, Transport};
+use crate::{Fork, ForkBuilder, ForkError};
+
+/// What a child sends file descriptors to the parent with. See [`fork_map_with_fds`].
+pub struct FdSender {
+    /// The child's end of the socket, in a child.
+    #[cfg(all(unix, not(feature = "fallback")))]
+    socket: Option<libc::c_int>,
+    /// Copies for the parent otherwise, since it's the same process.
+    sent: Vec<OwnedFd>,
+}
+
+impl FdSender {
+    fn new() -> Self {
+        FdSender {
+            #[cfg(all(unix, not(feature = "fallback")))]
+            socket: None,
+            sent: vec![],
+        }
+    }
+
+    /// Sends the parent its own copy of `fd`, which stays open in the child too. Fails if the
+    /// parent has stopped listening.
+    pub fn send_fd(&mut self, fd: impl AsFd) -> Result<(), ForkError> {
+        #[cfg(all(unix, not(feature = "fallback")))]
+        if let Some(socket) = self.socket {
+            use std::os::fd::AsRawFd;
+
+            let frame = protocol::frame(Tag::Fd, Compression::None, vec![])
+                .map_err(ForkError::from_anyhow)?;
+            return unsafe { crate::sys::send_with_fd(socket, &frame, fd.as_fd().as_raw_fd()) };
+        }
+        let copy = fd
+            .as_fd()
+            .try_clone_to_owned()
+            .map_err(|source| ForkError::Io { op: "dup", source })?;
+        self.sent.push(copy);
+        Ok(())
+    }
+}
+
+impl fmt::Debug for FdSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FdSender").finish_non_exhaustive()
+    }
+}
+
+/// Forks, and runs `func` in a child process, which can hand open file descriptors to the parent
+/// with the [`FdSender`] it's given. Waits for the child to terminate and returns the result of
+/// `func`, along with the parent's copies of the descriptors, in the order they were sent.
+///
+/// This is for results that are already in a file (a temporary file, a `memfd`, a pipe still
+/// being written to), which would be a waste to read into memory only to serialize it. The
+/// descriptors go over a socket [`Transport`](crate::Transport) with `SCM_RIGHTS`, whatever the
+/// transport was set to, each in a frame of its own ahead of the result. They're close-on-exec
+/// in the parent. If anything fails, including `func` itself, the ones that did arrive are
+/// closed rather than returned.
+///
+/// When [`FORKS`](crate::FORKS) is `false`, [`send_fd`](FdSender::send_fd) `dup()`s them instead.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_with_fds;
+/// use std::fs::File;
+/// use std::io::{Read, Seek, Write};
+///
+/// let (len, fds) = unsafe {
+///     fork_map_with_fds(|sender| {
+///         let path = std::env::temp_dir().join(format!("fork-map-fds-{}", std::process::id()));
+///         let mut file = File::options()
+///             .read(true)
+///             .write(true)
+///             .create_new(true)
+///             .open(&path)?;
+///         // Nobody else will ever see it, and it's gone once the last descriptor is closed
+///         std::fs::remove_file(&path)?;
+///         file.write_all(b"marker from the child")?;
+///         sender.send_fd(&file)?;
+///         Ok(file.stream_position()?)
+///     })
+/// }
+/// .unwrap();
+///
+/// let mut file = File::from(fds.into_iter().next().unwrap());
+/// file.rewind().unwrap();
+/// let mut contents = String::new();
+/// file.read_to_string(&mut contents).unwrap();
+/// assert_eq!(contents, "marker from the child");
+/// assert_eq!(len, contents.len() as u64);
+///
+/// // A child that fails after sending some doesn't leak them into the parent
+/// if cfg!(target_os = "linux") {
+///     let open = || std::fs::read_dir("/proc/self/fd").unwrap().count();
+///     let before = open();
+///     for _ in 0..100 {
+///         let result = unsafe {
+///             fork_map_with_fds(|sender| -> anyhow::Result<()> {
+///                 sender.send_fd(std::io::stdin())?;
+///                 anyhow::bail!("failed after sending")
+///             })
+///         };
+///         assert!(result.is_err());
+///     }
+///     assert_eq!(open(), before);
+/// }
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`](crate::fork_map).
+pub unsafe fn fork_map_with_fds<F, R>(func: F) -> anyhow::Result<(R, Vec<OwnedFd>)>
+where
+    F: Fn(&mut FdSender) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run_with_fds(func)
+}
+
+impl ForkBuilder {
+    /// Like [`fork_map_with_fds`], with the child configured like this. If the child is
+    /// [retried](Self::retries), the descriptors from failed attempts are closed.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_with_fds<F, R>(self, func: F) -> anyhow::Result<(R, Vec<OwnedFd>)>
+    where
+        F: Fn(&mut FdSender) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        self.with_retries(|| self.clone().fds_once(&func))
+    }
+
+    unsafe fn fds_once<F, R>(self, func: &F) -> anyhow::Result<(R, Vec<OwnedFd>)>
+    where
+        F: Fn(&mut FdSender) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        #[cfg(feature = "testing")]
+        if let Some(error) = crate::testing::take() {
+            return Err(error.into());
+        }
+        #[cfg(all(unix, not(feature = "fallback")))]
+        if !self.inline {
+            return self.fds_forked(func);
+        }
+        let sent = RefCell::new(vec![]);
+        let result = self
+            .run_in_process(|| {
+                let mut sender = FdSender::new();
+                let result = func(&mut sender);
+                sent.borrow_mut().append(&mut sender.sent);
+                result
+            })?
+            .join()?;
+        Ok((result, sent.into_inner()))
+    }
+
+    #[cfg(all(unix, not(feature = "fallback")))]
+    unsafe fn fds_forked<F, R>(mut self, func: &F) -> anyhow::Result<(R, Vec<OwnedFd>)>
+    where
+        F: Fn(&mut FdSender) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        // Only a unix socket can carry them
+        self.transport = Transport::Socket;
+        let mut child = self.fork_child(|socket| {
+            let mut sender = FdSender::new();
+            sender.socket = Some(socket);
+            func(&mut sender)
+        })?;
+        // Owned from the moment they arrive, so every way out of here closes them
+        let mut fds = vec![];
+        let mut frames = 0;
+        let received = loop {
+            let eof = child.recv_more(&mut fds);
+            while child.take_frame(Tag::Fd, |_| Ok(())).is_some() {
+                frames += 1;
+            }
+            match eof {
+                Ok(true) => break Ok(()),
+                Ok(false) => {}
+                Err(e) => break Err(e),
+            }
+        };
+        let pid = child.pid();
+        let (result, _) = child.finish(received)?;
+        if frames != fds.len() {
+            let message = format!(
+                "{} file descriptors announced, {} received",
+                frames,
+                fds.len()
+            );
+            return Err(ForkError::decode(pid, &message, &[]).into());
+        }
+        Ok((result, fds))
+    }
+}
@@ -0,0 +1,1139 @@
+//! The real thing: running the closure in a forked child.
+
+use std::fmt;
+use std::io::{BufWriter, Write};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(target_os = "linux")]
+use crate::cgroup::Cgroup;
+use crate::limit::{self, Permit};
+use crate::protocol::{self, Tag};
+#[cfg(target_os = "linux")]
+use crate::subreaper;
+use crate::{
+    pause, status, sys, Compression, ForkBuilder, ForkError, ForkHandle, ForkStats, Transport,
+};
+
+/// What [`ForkBuilder::pipe_size`] asks for unless told otherwise.
+#[cfg(target_os = "linux")]
+const DEFAULT_PIPE_SIZE: usize = 1 << 20;
+
+/// How often a parent waiting on a quiet result pipe checks whether the child is still there.
+const EXIT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What [`ForkBuilder::max_rss_interval`] is unless told otherwise.
+const DEFAULT_RSS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long after the child has exited the result pipe has to reach EOF, before it's taken to be
+/// held open by some other process. See [`ForkError::PipeLeak`].
+const PIPE_LEAK_GRACE: Duration = Duration::from_millis(100);
+
+impl ForkBuilder {
+    /// Forks a child that sends back an `S`, which the handle decodes as a `D`.
+    pub(crate) unsafe fn spawn_forked<F, S, D>(self, func: F) -> anyhow::Result<ForkHandle<D>>
+    where
+        F: Fn() -> anyhow::Result<S> + Send + Sync,
+        S: Serialize + Send,
+        D: for<'a> Deserialize<'a>,
+    {
+        let child = self.fork_child(|_| func())?;
+        Ok(ForkHandle::forked(child.pid(), child))
+    }
+
+    /// Forks a child that exits with the code `func` returns along with its result, and waits
+    /// for it.
+    pub(crate) unsafe fn run_forked_with_exit_code<F, R>(self, func: F) -> anyhow::Result<(R, i32)>
+    where
+        F: Fn() -> anyhow::Result<(R, i32)> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        let builder = self.clone();
+        let child = self.fork_child::<_, ()>(|pipe| {
+            let (value, code) = func()?;
+            builder.send_result_and_exit(pipe, None, None, Ok(value), code);
+        })?;
+        child.wait_with_exit_code()
+    }
+
+    /// Forks a child that receives `input`, already encoded, over a socket and runs `func` on
+    /// it, and waits for it.
+    pub(crate) unsafe fn run_forked_with_input<I, F, R>(
+        self,
+        input: Vec<u8>,
+        func: F,
+    ) -> anyhow::Result<R>
+    where
+        I: for<'a> Deserialize<'a>,
+        F: FnOnce(I) -> anyhow::Result<R> + Send,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        // Over a socket of its own, unless the result already goes over one
+        let socket = match self.transport {
+            Transport::Pipe | Transport::SharedMemory | Transport::TempFile => {
+                Some(sys::socketpair()?)
+            }
+            Transport::Socket => None,
+        };
+        let codec = self.codec;
+        let child = self.fork_child(|fd| {
+            let Some([ours, theirs]) = socket else {
+                return func(codec.decode(std::process::id(), &receive_input(fd)?)?);
+            };
+            libc::close(ours);
+            let received = receive_input(theirs);
+            // So nothing the closure forks holds on to it
+            libc::close(theirs);
+            func(codec.decode(std::process::id(), &received?)?)
+        });
+        if let Some([_, theirs]) = socket {
+            libc::close(theirs);
+        }
+        let child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                if let Some([ours, _]) = socket {
+                    libc::close(ours);
+                }
+                return Err(e);
+            }
+        };
+        let ours = match socket {
+            Some([ours, _]) => ours,
+            // Our own copy, since the child's is closed when it's reaped, which may be while
+            // we're still sending
+            None => match libc::dup(child.pipe()) {
+                fd if fd >= 0 => fd,
+                _ => return Err(ForkError::last_os_error("dup").into()),
+            },
+        };
+
+        // Both at once, so a child blocked sending a big result can't stop us sending it a big
+        // input, or the other way around
+        let len = (input.len() as u64).to_le_bytes();
+        let result = std::thread::scope(|s| {
+            let sender = s.spawn(|| {
+                let _ = sys::send_all(ours, &len).and_then(|_| sys::send_all(ours, &input));
+            });
+            let result = child.wait();
+            // If the child went without reading it all, whatever's left can't go anywhere
+            libc::shutdown(ours, libc::SHUT_RDWR);
+            if let Err(panic) = sender.join() {
+                std::panic::resume_unwind(panic);
+            }
+            result
+        });
+        libc::close(ours);
+        result.map(|(result, _)| result)
+    }
+
+    /// Forks a child that runs `func`, which gets the write end of the result pipe for sending
+    /// anything it likes ahead of the frame with its result.
+    pub(crate) unsafe fn fork_child<F, S>(self, func: F) -> anyhow::Result<Child>
+    where
+        F: FnOnce(libc::c_int) -> anyhow::Result<S> + Send,
+        S: Serialize + Send,
+    {
+        self.fork_child_on(None, func)
+    }
+
+    /// Like [`fork_child`](Self::fork_child), with the result going over `given`, the read end
+    /// and the write end of a pipe or socket of the caller's, if there is one, rather than one of
+    /// our own.
+    pub(crate) unsafe fn fork_child_on<F, S>(
+        self,
+        given: Option<(OwnedFd, OwnedFd)>,
+        func: F,
+    ) -> anyhow::Result<Child>
+    where
+        F: FnOnce(libc::c_int) -> anyhow::Result<S> + Send,
+        S: Serialize + Send,
+    {
+        #[cfg(target_os = "linux")]
+        let mut cgroup = match &self.cgroup {
+            Some(path) => Some(Cgroup::open(path)?),
+            None => None,
+        };
+        #[cfg(not(target_os = "linux"))]
+        if self.cgroup.is_some() {
+            return Err(ForkError::Unsupported { feature: "cgroup" }.into());
+        }
+        #[cfg(not(target_os = "linux"))]
+        if self.reap_descendants.is_some() {
+            return Err(ForkError::Unsupported {
+                feature: "reap_descendants",
+            }
+            .into());
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        if self.max_rss.is_some() {
+            return Err(ForkError::Unsupported { feature: "max_rss" }.into());
+        }
+
+        if self.flush_stdio {
+            // Otherwise whatever is still buffered gets written by both processes
+            let _ = std::io::stdout().flush();
+            let _ = std::io::stderr().flush();
+            libc::fflush(std::ptr::null_mut());
+        }
+
+        let pause_on_crash = self.pause_on_crash || pause::env_enabled();
+
+        // Held until the child is reaped
+        let permit = self.permit()?;
+        let _forking = limit::Forking::enter();
+
+        // Everything from here on is owned until it's handed to the child or the parent's side,
+        // so none of it leaks if a later step fails, forking included
+
+        // The caller's is left the size they made it
+        #[cfg(target_os = "linux")]
+        let grow = given.is_none() && self.transport != Transport::Socket;
+        // Pipe for sending the result from child to parent, or a socket for sending anything
+        let pipe = match (given, self.transport) {
+            (Some((read, write)), _) => [read, write],
+            (None, Transport::Pipe | Transport::SharedMemory | Transport::TempFile) => {
+                owned(sys::pipe()?)
+            }
+            (None, Transport::Socket) => owned(sys::socketpair()?),
+        };
+        #[cfg(target_os = "linux")]
+        let pipe_size = match grow {
+            true => sys::grow_pipe(
+                pipe[0].as_raw_fd(),
+                self.pipe_size.unwrap_or(DEFAULT_PIPE_SIZE),
+            ),
+            false => None,
+        };
+        // And somewhere for the result to go instead
+        let shared = match self.transport {
+            Transport::SharedMemory => Some(sys::anonymous_file()?),
+            Transport::TempFile => Some(sys::temp_file()?),
+            Transport::Pipe | Transport::Socket => None,
+        };
+        // Pipe for the child's standard output, if the parent wants it
+        let stdout = match self.capture_stdout {
+            true => Some(owned(sys::pipe()?)),
+            false => None,
+        };
+        // Pipe for holding the child back until the parent has finished setting it up
+        let ready = owned(sys::pipe()?);
+        // Pipe for the child to say how many descendants it had to clean up after
+        #[cfg(target_os = "linux")]
+        let strays = match self.reap_descendants {
+            Some(_) => Some(owned(sys::pipe()?)),
+            None => None,
+        };
+
+        // So the child doesn't have to allocate one
+        let buffer = self
+            .preallocate_result
+            .map(|bytes| Vec::with_capacity(protocol::HEADER_LEN + bytes));
+
+        // Here we go
+        let start = Instant::now();
+        let pid = sys::fork()?;
+        if pid == 0 {
+            // Child
+            let [ours, theirs] = pipe;
+            drop(ours);
+            let pipe = theirs.into_raw_fd();
+            let [ready, release] = ready;
+            drop(release);
+            let mut go = 0u8;
+            let count = libc::read(
+                ready.as_raw_fd(),
+                &mut go as *mut u8 as *mut libc::c_void,
+                1,
+            );
+            drop(ready);
+            if count != 1 {
+                // Parent couldn't set us up and has already reported why
+                libc::exit(1);
+            }
+            if !self.keep_signals {
+                sys::reset_signals();
+            }
+            #[cfg(target_os = "linux")]
+            let strays = strays.map(|[_, theirs]| theirs);
+            if pause_on_crash {
+                pause::install();
+            }
+            if self.detached {
+                // There's nothing to send, and nobody listening
+                libc::close(pipe);
+            }
+            // Nobody's going to type anything at a detached child either
+            if self.null_stdio || self.detached {
+                sys::open_null(libc::STDIN_FILENO, libc::O_RDONLY);
+            }
+            if self.null_stdio {
+                sys::open_null(libc::STDOUT_FILENO, libc::O_WRONLY);
+                sys::open_null(libc::STDERR_FILENO, libc::O_WRONLY);
+            }
+            let captured = match stdout {
+                Some([ours, theirs]) => {
+                    let captured =
+                        sys::check("dup2", libc::dup2(theirs.as_raw_fd(), libc::STDOUT_FILENO));
+                    drop(ours);
+                    drop(theirs);
+                    captured.map(|_| ())
+                }
+                None => Ok(()),
+            };
+            status::reset_exit_code();
+            let result = captured
+                .map_err(anyhow::Error::from)
+                .and_then(|_| self.setup_child())
+                .and_then(|_| self.become_subreaper())
+                .and_then(|_| {
+                    // Starting a thread allocates
+                    if buffer.is_some() {
+                        catch_panic(|| func(pipe))
+                    } else {
+                        on_fresh_thread(|| func(pipe))
+                    }
+                });
+            #[cfg(target_os = "linux")]
+            if let (Some(grace), Some(theirs)) = (self.reap_descendants, strays) {
+                let strays = subreaper::reap_descendants(grace) as u64;
+                let _ = sys::write_all(theirs.as_raw_fd(), &strays.to_le_bytes());
+                drop(theirs);
+            }
+            let code = status::exit_code().unwrap_or(match &result {
+                Ok(_) => 0,
+                Err(e) if e.is::<Panicked>() => crate::EXIT_CLOSURE_PANICKED,
+                Err(_) => crate::EXIT_CLOSURE_FAILED,
+            });
+            if self.detached {
+                libc::exit(code);
+            }
+            self.send_result_and_exit(pipe, shared.as_ref(), buffer, result, code);
+        }
+
+        // Parent
+        // Killed if we don't get as far as letting it go, since it would wait for us forever
+        let mut guard =
+            sys::ChildGuard::new(pid).kill_with(self.kill_signal.as_raw(), self.kill_grace);
+        guard.kill_on_drop(true);
+        let mut stats = ForkStats::default();
+        stats.timings.forked = start.elapsed();
+        #[cfg(target_os = "linux")]
+        {
+            stats.pipe_size = pipe_size;
+        }
+        let [pipe, theirs] = pipe;
+        drop(theirs);
+        let [ready, release] = ready;
+        drop(ready);
+        #[cfg(target_os = "linux")]
+        let strays = strays.map(|[ours, _]| {
+            // It's read once the child has been reaped, by when it's there or it never will be
+            let flags = libc::fcntl(ours.as_raw_fd(), libc::F_GETFL);
+            libc::fcntl(ours.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK);
+            ours
+        });
+        let stdout = stdout.map(|[ours, _]| Stdout {
+            fd: ours,
+            buf: vec![],
+            pos: 0,
+            eof: false,
+        });
+        if self.new_process_group && !self.new_session {
+            // Also done here so there's no window where the child is still in our group. This
+            // may lose the race with the child's own setpgid, which is fine.
+            libc::setpgid(pid, pid);
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(cgroup) = &mut cgroup {
+            cgroup.attach(pid)?;
+        }
+        for callback in &self.on_fork.0 {
+            callback(pid as u32);
+        }
+        match sys::write_all(release.as_raw_fd(), &[1u8]) {
+            Ok(()) => {}
+            // Gone already, which reaping it will explain
+            Err(ForkError::Io { source, .. }) if source.raw_os_error() == Some(libc::EPIPE) => {}
+            // Otherwise it gives up and exits, and the guard reaps it
+            Err(e) => return Err(e.into()),
+        }
+        drop(release);
+        guard.kill_on_drop(false);
+
+        let child = Child {
+            pipe: pipe.into_raw_fd(),
+            deadline: self.timeout.map(|timeout| start + timeout),
+            received: vec![],
+            first_byte: None,
+            start,
+            rss_checked_at: start,
+            stats,
+            #[cfg(target_os = "linux")]
+            cgroup,
+            #[cfg(target_os = "linux")]
+            strays,
+            shared,
+            stdout,
+            result_eof: false,
+            builder: self,
+            guard,
+            _permit: permit,
+        };
+        Ok(child)
+    }
+
+    /// Runs in the child: sends the closure's result to the parent, through `shared` if there's
+    /// somewhere to share it, and exits with `code`.
+    ///
+    /// With a [preallocated](ForkBuilder::preallocate_result) `buffer`, a value that fits is
+    /// serialized into that, header and all, and sent in one go. One that doesn't fit grows it.
+    ///
+    /// A value that's going over the pipe uncompressed is serialized straight into it, rather
+    /// than into memory first, so a big result never exists in the child all at once, and the
+    /// parent starts reading it while the rest is being serialized. The length goes ahead of it,
+    /// so it's serialized twice, once only to count it.
+    unsafe fn send_result_and_exit<S: Serialize>(
+        &self,
+        pipe: libc::c_int,
+        shared: Option<&OwnedFd>,
+        buffer: Option<Vec<u8>>,
+        result: anyhow::Result<S>,
+        code: i32,
+    ) -> ! {
+        if let (Ok(value), None, Compression::None, Some(mut frame)) =
+            (&result, shared, self.compression, buffer)
+        {
+            frame.extend_from_slice(&[0; protocol::HEADER_LEN]);
+            if self.codec.encode_to(value, &mut frame).is_ok() {
+                let len = (frame.len() - protocol::HEADER_LEN) as u64;
+                frame[..protocol::HEADER_LEN].copy_from_slice(&protocol::header(
+                    Tag::Value,
+                    Compression::None,
+                    len,
+                ));
+                sys::send_result_and_exit(pipe, &frame, code);
+            }
+        }
+        if let (Ok(value), None, Compression::None) = (&result, shared, self.compression) {
+            let mut len = Counter(0);
+            // If it fails to, it fails again below, and that's reported as usual
+            if self.codec.encode_to(value, &mut len).is_ok() {
+                let header = protocol::header(Tag::Value, Compression::None, len.0);
+                sys::exit_after_sending(pipe, code, |pipe| {
+                    let mut writer = BufWriter::with_capacity(1 << 16, sys::FdWriter::new(pipe));
+                    let sent = writer
+                        .write_all(&header)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|_| self.codec.encode_to(value, &mut writer))
+                        .and_then(|_| Ok(writer.flush()?));
+                    // Past the header, there's no taking it back, so the parent finds out from
+                    // the frame being cut short
+                    sent.map_err(|e| {
+                        let error = writer.get_mut().error.take();
+                        error.unwrap_or_else(|| ForkError::from_anyhow(e))
+                    })
+                });
+            }
+        }
+        let frame = self.encode_result(result);
+        let frame = match shared {
+            Some(shared) => share(shared.as_raw_fd(), frame),
+            None => frame,
+        };
+        sys::send_result_and_exit(pipe, &frame, code);
+    }
+
+    /// Runs in the child, after [`setup_child`](Self::setup_child): makes it the subreaper for
+    /// everything it starts, if it's to [reap its descendants](ForkBuilder::reap_descendants).
+    unsafe fn become_subreaper(&self) -> anyhow::Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.reap_descendants.is_some() {
+            subreaper::become_subreaper()?;
+        }
+        Ok(())
+    }
+
+    /// Runs in the child, before the closure.
+    pub(crate) unsafe fn setup_child(&self) -> anyhow::Result<()> {
+        if self.new_session {
+            if libc::setsid() < 0 {
+                return Err(ForkError::last_os_error("setsid").into());
+            }
+        } else if self.new_process_group && libc::setpgid(0, 0) < 0 {
+            return Err(ForkError::last_os_error("setpgid").into());
+        }
+        if let Some(dir) = &self.current_dir {
+            std::env::set_current_dir(dir).map_err(|source| ForkError::Io {
+                op: "chdir",
+                source,
+            })?;
+        }
+        for hook in &self.pre_exec.0 {
+            hook()?;
+        }
+        // Last, since the sandbox may forbid the calls above
+        #[cfg(target_os = "macos")]
+        if let Some(profile) = &self.sandbox_profile {
+            crate::sandbox::apply(profile)?;
+        }
+        #[cfg(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        if let Some(profile) = &self.seccomp {
+            crate::seccomp::apply(profile)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs in the child: reads the input the parent sends, as a little-endian `u64` length followed
+/// by that many bytes.
+unsafe fn receive_input(fd: libc::c_int) -> anyhow::Result<Vec<u8>> {
+    let mut len = [0u8; 8];
+    let mut input = vec![];
+    if sys::read_exact(fd, &mut len)? == len.len() {
+        input.resize(u64::from_le_bytes(len) as usize, 0);
+        if sys::read_exact(fd, &mut input)? == input.len() {
+            return Ok(input);
+        }
+    }
+    anyhow::bail!("the parent stopped sending the input partway through")
+}
+
+/// Runs in the child: runs `func` on a new thread and waits for it, so it starts from a clean
+/// stack and fresh thread-locals rather than whatever the thread that forked was in the middle
+/// of. A panic comes back as an error, instead of unwinding into the copy of the caller.
+unsafe fn on_fresh_thread<F, S>(func: F) -> anyhow::Result<S>
+where
+    F: FnOnce() -> anyhow::Result<S> + Send,
+    S: Send,
+{
+    let mut func = Some(func);
+    let spawned = std::thread::scope(|s| {
+        let func = &mut func;
+        std::thread::Builder::new()
+            .stack_size(main_stack_size())
+            .spawn_scoped(s, move || func.take().unwrap()())
+            .map(|thread| thread.join())
+    });
+    match spawned {
+        Ok(Ok(result)) => result,
+        Ok(Err(panic)) => Err(Panicked(crate::error::panic_message(&*panic).to_string()).into()),
+        // Out of threads, so this one will have to do
+        Err(_) => func.take().unwrap()(),
+    }
+}
+
+/// Runs `func` in a child that carries on after it, like a worker between jobs, with a panic
+/// coming back as an error instead of unwinding out of the child's own loop.
+pub(crate) fn catch_panic<S>(func: impl FnOnce() -> anyhow::Result<S>) -> anyhow::Result<S> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(func)).unwrap_or_else(|panic| {
+        Err(Panicked(crate::error::panic_message(&*panic).to_string()).into())
+    })
+}
+
+/// Both ends of a fresh pipe or socket pair, closed when dropped.
+unsafe fn owned([read, write]: [libc::c_int; 2]) -> [OwnedFd; 2] {
+    [OwnedFd::from_raw_fd(read), OwnedFd::from_raw_fd(write)]
+}
+
+/// A panic in the closure, as an error.
+#[derive(Debug)]
+struct Panicked(String);
+
+impl fmt::Display for Panicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "closure panicked: {}", self.0)
+    }
+}
+
+impl std::error::Error for Panicked {}
+
+/// As much stack as the main thread gets, which is usually a lot more than a spawned thread, so
+/// a closure that recursed deeply before doesn't overflow now.
+unsafe fn main_stack_size() -> usize {
+    let mut limit: libc::rlimit = std::mem::zeroed();
+    if libc::getrlimit(libc::RLIMIT_STACK, &mut limit) == 0 && limit.rlim_cur != libc::RLIM_INFINITY
+    {
+        return limit.rlim_cur as usize;
+    }
+    8 << 20
+}
+
+/// A running child, as seen from the parent. Dropping it without calling [`wait`](Self::wait)
+/// still closes the pipe and reaps the child.
+pub(crate) struct Child {
+    /// Read end of the result pipe, or -1 once closed.
+    pipe: libc::c_int,
+    deadline: Option<Instant>,
+    received: Vec<u8>,
+    first_byte: Option<Instant>,
+    start: Instant,
+    /// When the child's memory was last checked against [`ForkBuilder::max_rss`].
+    rss_checked_at: Instant,
+    stats: ForkStats,
+    #[cfg(target_os = "linux")]
+    cgroup: Option<Cgroup>,
+    /// Where the child says how many descendants it cleaned up after, with
+    /// [`ForkBuilder::reap_descendants`].
+    #[cfg(target_os = "linux")]
+    strays: Option<OwnedFd>,
+    /// Where the result is, with [`Transport::SharedMemory`] or [`Transport::TempFile`].
+    shared: Option<OwnedFd>,
+    /// The child's standard output, if it was [captured](ForkBuilder::capture_stdout).
+    stdout: Option<Stdout>,
+    /// Whether the result pipe was read to EOF by [`read_stdout`](Self::read_stdout).
+    result_eof: bool,
+    builder: ForkBuilder,
+    /// Declared after everything that has to be closed first, so a child stuck writing to the
+    /// result pipe finds it closed, and exits to be reaped.
+    guard: sys::ChildGuard,
+    /// Declared last, so it's given back only after `drop` has reaped the child.
+    _permit: Permit,
+}
+
+impl Child {
+    pub(crate) fn pid(&self) -> u32 {
+        self.guard.pid() as u32
+    }
+
+    /// Closes everything the parent has open for hearing from the child, for a
+    /// [detached](ForkBuilder::detach) child that won't be sending anything.
+    pub(crate) fn close_pipes(&mut self) {
+        if self.pipe >= 0 {
+            unsafe { libc::close(self.pipe) };
+            self.pipe = -1;
+        }
+        self.shared = None;
+        self.stdout = None;
+    }
+
+    /// Reaps the child if it has exited, without waiting for it if it hasn't. Returns whether
+    /// it has been reaped, which it also counts as if there's no waiting on it at all.
+    pub(crate) fn try_reap(&mut self) -> bool {
+        !matches!(unsafe { self.guard.reap_until(Instant::now()) }, Ok(None))
+    }
+
+    /// Sends the child the builder's [`kill_signal`](ForkBuilder::kill_signal), and starts its
+    /// [grace](ForkBuilder::kill_grace) period if it has one. It still has to be reaped.
+    pub(crate) fn kill(&self) {
+        self.guard.kill();
+    }
+
+    /// Read end of the result pipe, for polling.
+    pub(crate) fn pipe(&self) -> libc::c_int {
+        self.pipe
+    }
+
+    /// Returns what the child has written to its standard output and hasn't been
+    /// [consumed](Self::consume_stdout) yet, waiting for more if that's nothing. Reads the result
+    /// meanwhile, so a child blocked on writing that doesn't hold it up. `None` at EOF, or if
+    /// standard output wasn't captured.
+    pub(crate) fn read_stdout(&mut self) -> Result<Option<&[u8]>, ForkError> {
+        loop {
+            let Some(stdout) = &mut self.stdout else {
+                return Ok(None);
+            };
+            if stdout.pos < stdout.buf.len() {
+                break;
+            }
+            if stdout.eof {
+                return Ok(None);
+            }
+            stdout.buf.clear();
+            stdout.pos = 0;
+            let mut fds = [
+                libc::pollfd {
+                    fd: stdout.fd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    // Ignored once it's done
+                    fd: if self.result_eof { -1 } else { self.pipe },
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+            self.poll_ready(&mut fds)?;
+            if fds[0].revents != 0 {
+                let stdout = self
+                    .stdout
+                    .as_mut()
+                    .expect("polled without captured stdout");
+                let count = unsafe { sys::read_some(fds[0].fd, &mut stdout.buf) }?;
+                stdout.eof = count == 0;
+            }
+            if fds[1].revents != 0 {
+                self.result_eof = self.read_some()?;
+            }
+        }
+        Ok(self.stdout.as_ref().map(|stdout| &stdout.buf[stdout.pos..]))
+    }
+
+    pub(crate) fn captures_stdout(&self) -> bool {
+        self.stdout.is_some()
+    }
+
+    /// Marks `count` bytes from [`read_stdout`](Self::read_stdout) as read.
+    pub(crate) fn consume_stdout(&mut self, count: usize) {
+        if let Some(stdout) = &mut self.stdout {
+            stdout.pos = (stdout.pos + count).min(stdout.buf.len());
+        }
+    }
+
+    /// Reads whatever the child has sent so far, blocking if it hasn't sent anything. Returns
+    /// whether the pipe has reached EOF.
+    ///
+    /// Reads no further than the end of the frame it's in the middle of: the rest of its header,
+    /// and then, once that says how long its body is, the rest of that, which `received` has
+    /// had room made for all at once.
+    pub(crate) fn read_some(&mut self) -> Result<bool, ForkError> {
+        let wanted = protocol::missing(&self.received);
+        let count = unsafe { sys::read_up_to(self.pipe, &mut self.received, wanted) }?;
+        self.got(count)
+    }
+
+    /// Like [`read_some`](Self::read_some), over a socket [`Transport`], with any file
+    /// descriptors that came along added to the end of `fds`.
+    pub(crate) fn recv_some(&mut self, fds: &mut Vec<OwnedFd>) -> Result<bool, ForkError> {
+        let wanted = protocol::missing(&self.received);
+        let count = unsafe { sys::recv_up_to(self.pipe, &mut self.received, wanted, fds) }?;
+        self.got(count)
+    }
+
+    fn got(&mut self, count: usize) -> Result<bool, ForkError> {
+        if count == 0 {
+            return Ok(true);
+        }
+        self.first_byte.get_or_insert_with(Instant::now);
+        if let Some(limit) = self.builder.max_result_bytes {
+            protocol::check_size(self.pid(), &self.received, limit)?;
+        }
+        Ok(false)
+    }
+
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Like [`read_some`](Self::read_some), but gives up with [`ForkError::Timeout`] if
+    /// nothing arrives before the deadline.
+    pub(crate) fn read_more(&mut self) -> Result<bool, ForkError> {
+        self.wait_readable()?;
+        self.read_some()
+    }
+
+    /// Like [`recv_some`](Self::recv_some), but gives up with [`ForkError::Timeout`] if
+    /// nothing arrives before the deadline.
+    pub(crate) fn recv_more(&mut self, fds: &mut Vec<OwnedFd>) -> Result<bool, ForkError> {
+        self.wait_readable()?;
+        self.recv_some(fds)
+    }
+
+    fn wait_readable(&mut self) -> Result<(), ForkError> {
+        if self.deadline.is_some() || self.builder.max_rss.is_some() {
+            let mut fds = [libc::pollfd {
+                fd: self.pipe,
+                events: libc::POLLIN,
+                revents: 0,
+            }];
+            self.poll_ready(&mut fds)?;
+        }
+        Ok(())
+    }
+
+    /// Polls `fds` until one of them is ready, failing with [`ForkError::Timeout`] if the
+    /// deadline passes first, or [`ForkError::MemoryLimitExceeded`] if the child outgrows
+    /// [`ForkBuilder::max_rss`] meanwhile.
+    fn poll_ready(&mut self, fds: &mut [libc::pollfd]) -> Result<(), ForkError> {
+        loop {
+            let wake_at = match (self.deadline, self.rss_check_at()) {
+                (Some(deadline), Some(check_at)) => Some(deadline.min(check_at)),
+                (deadline, check_at) => deadline.or(check_at),
+            };
+            let ready = unsafe { sys::poll(fds, wake_at) }?;
+            if ready == 0
+                && self
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                return Err(self.timeout_error());
+            }
+            self.check_rss()?;
+            if ready > 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// When the child's memory is next due to be checked, if it's checked at all.
+    pub(crate) fn rss_check_at(&self) -> Option<Instant> {
+        let interval = self
+            .builder
+            .max_rss_interval
+            .unwrap_or(DEFAULT_RSS_INTERVAL);
+        self.builder.max_rss.map(|_| self.rss_checked_at + interval)
+    }
+
+    /// Fails with [`ForkError::MemoryLimitExceeded`] if the child's memory is due to be
+    /// checked, and it has more resident than [`ForkBuilder::max_rss`] allows.
+    ///
+    /// There's no mistaking some other process for the child here, as there would be after
+    /// reaping it: until then its pid stays taken, by the zombie if it's exited, which has
+    /// nothing resident.
+    pub(crate) fn check_rss(&mut self) -> Result<(), ForkError> {
+        let (Some(limit), Some(check_at)) = (self.builder.max_rss, self.rss_check_at()) else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now < check_at {
+            return Ok(());
+        }
+        self.rss_checked_at = now;
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        if let Some(observed) = sys::resident_size(self.guard.pid()) {
+            if observed > limit {
+                return Err(ForkError::MemoryLimitExceeded {
+                    pid: self.pid(),
+                    observed,
+                    limit,
+                });
+            }
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        let _ = limit;
+        Ok(())
+    }
+
+    /// Takes the first frame received so far off the front, if it's a whole
+    /// [`Item`](Tag::Item), and decodes it.
+    pub(crate) fn take_item<T>(&mut self) -> Option<Result<T, ForkError>>
+    where
+        T: for<'a> Deserialize<'a>,
+    {
+        let codec = self.builder.codec;
+        let pid = self.pid();
+        self.take_frame(Tag::Item, |body| codec.decode(pid, body))
+    }
+
+    /// Takes the first frame received so far off the front, if it's a whole one tagged `tag`,
+    /// and passes its body to `parse`.
+    pub(crate) fn take_frame<T>(
+        &mut self,
+        tag: Tag,
+        parse: impl FnOnce(&[u8]) -> Result<T, ForkError>,
+    ) -> Option<Result<T, ForkError>> {
+        let len = protocol::complete_len(&self.received)?;
+        if self.received[0] != tag as u8 {
+            return None;
+        }
+        let pid = self.pid();
+        let parsed = protocol::parse(pid, &self.received[..len]).and_then(|(_, body)| parse(&body));
+        self.received.drain(..len);
+        Some(parsed)
+    }
+
+    /// Reads the result, reaps the child, and decodes the result.
+    pub(crate) fn wait<R>(mut self) -> anyhow::Result<(R, ForkStats)>
+    where
+        R: for<'a> Deserialize<'a>,
+    {
+        let received = self.read_all();
+        self.finish(received)
+    }
+
+    /// Like [`wait`](Self::wait), but returns the child's exit code with the result.
+    pub(crate) fn wait_with_exit_code<R>(mut self) -> anyhow::Result<(R, i32)>
+    where
+        R: for<'a> Deserialize<'a>,
+    {
+        let received = self.read_all();
+        self.finish(received)
+            .map(|(result, stats)| (result, stats.exit_code))
+    }
+
+    fn read_all(&mut self) -> Result<(), ForkError> {
+        // Output nobody read goes where it would have gone without being captured, so the child
+        // doesn't get stuck writing it
+        while let Some(output) = self.read_stdout()? {
+            let mut stdout = std::io::stdout().lock();
+            let _ = stdout.write_all(output).and_then(|_| stdout.flush());
+            let count = output.len();
+            self.consume_stdout(count);
+        }
+        if self.result_eof {
+            return Ok(());
+        }
+        // Reading has to come first: a child with more to write than fits in the pipe blocks
+        // until we do, so it would never exit for us to reap
+        while self.wait_for_result()? {
+            if self.read_some()? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the result pipe to be readable, and returns whether it is. If the child exits
+    /// without the pipe reaching EOF, something else has it open and may never close it, so
+    /// this only waits a little longer: it returns `false` if the child sent its whole result,
+    /// and [`ForkError::PipeLeak`] if not.
+    fn wait_for_result(&mut self) -> Result<bool, ForkError> {
+        let mut exited_at: Option<Instant> = None;
+        loop {
+            let mut check_at = Instant::now() + EXIT_CHECK_INTERVAL;
+            if let Some(exited_at) = exited_at {
+                check_at = check_at.min(exited_at + PIPE_LEAK_GRACE);
+            }
+            if let Some(deadline) = self.deadline {
+                check_at = check_at.min(deadline);
+            }
+            if let Some(rss_check_at) = self.rss_check_at() {
+                check_at = check_at.min(rss_check_at);
+            }
+            let mut fds = [libc::pollfd {
+                fd: self.pipe,
+                events: libc::POLLIN,
+                revents: 0,
+            }];
+            let ready = unsafe { sys::poll(&mut fds, Some(check_at)) }?;
+            self.check_rss()?;
+            if ready > 0 {
+                return Ok(true);
+            }
+            if self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                return Err(self.timeout_error());
+            }
+            match exited_at {
+                Some(exited_at) if exited_at.elapsed() >= PIPE_LEAK_GRACE => break,
+                Some(_) => {}
+                None if unsafe { sys::has_exited(self.guard.pid()) }? => {
+                    exited_at = Some(Instant::now())
+                }
+                None => {}
+            }
+        }
+        if protocol::is_complete(&self.received) {
+            return Ok(false);
+        }
+        Err(ForkError::PipeLeak { pid: self.pid() })
+    }
+
+    /// Like [`finish`](Self::finish), for when the deadline passed while the caller was
+    /// reading.
+    pub(crate) fn time_out<R>(self) -> anyhow::Result<(R, ForkStats)>
+    where
+        R: for<'a> Deserialize<'a>,
+    {
+        let error = self.timeout_error();
+        self.finish(Err(error))
+    }
+
+    fn timeout_error(&self) -> ForkError {
+        ForkError::Timeout {
+            pid: self.pid(),
+            timeout: self.builder.timeout.unwrap_or_default(),
+        }
+    }
+
+    /// Like [`wait`](Self::wait), but returns the closure's `Ok` value the way the codec encoded
+    /// it, without decoding it.
+    pub(crate) fn wait_encoded(mut self) -> anyhow::Result<Vec<u8>> {
+        let received = self.read_all();
+        let builder = self.builder.clone();
+        let decode = |child: &Self| {
+            let pid = child.pid();
+            child.decode_with(|frame| builder.decode_frame(pid, frame, |body| Ok(body.to_vec())))
+        };
+        self.finish_with(received, decode)
+            .map(|(encoded, _)| encoded)
+    }
+
+    /// Like [`wait`](Self::wait), for when the caller already called
+    /// [`read_some`](Self::read_some) until it hit EOF or failed.
+    ///
+    /// A child that exits normally after sending all of its result hasn't failed, whatever its
+    /// exit code, since that's the closure's to [pick](crate::set_exit_code). It goes in the
+    /// stats.
+    pub(crate) fn finish<R>(self, received: Result<(), ForkError>) -> anyhow::Result<(R, ForkStats)>
+    where
+        R: for<'a> Deserialize<'a>,
+    {
+        self.finish_with(received, Self::decode)
+    }
+
+    /// Like [`finish`](Self::finish), with `decode` turning what was received into the result.
+    fn finish_with<R>(
+        mut self,
+        mut received: Result<(), ForkError>,
+        decode: impl FnOnce(&Self) -> anyhow::Result<R>,
+    ) -> anyhow::Result<(R, ForkStats)> {
+        let start = self.start;
+        let mut stats = self.stats;
+
+        // Don't let a child we've stopped listening to keep running
+        let mut timed_out = matches!(received, Err(ForkError::Timeout { .. }));
+        let mut killed = timed_out
+            || matches!(
+                received,
+                Err(ForkError::ResultTooLarge { .. }
+                    | ForkError::MemoryLimitExceeded { .. }
+                    | ForkError::Stalled { .. })
+            );
+        if killed {
+            self.kill();
+        }
+
+        // Always reap the child even if reading failed
+        stats.timings.eof = start.elapsed();
+        unsafe { libc::close(self.pipe) };
+        self.pipe = -1;
+        let waited = match self.deadline {
+            // Having sent its result doesn't mean it's done, it may take its time cleaning up
+            Some(deadline) if !killed => match unsafe { self.guard.reap_until(deadline) } {
+                Ok(None) => {
+                    self.kill();
+                    timed_out = true;
+                    killed = true;
+                    unsafe { self.guard.reap() }
+                }
+                Ok(Some(waited)) => Ok(waited),
+                Err(e) => Err(e),
+            },
+            _ => unsafe { self.guard.reap() },
+        };
+        let (status, usage) = waited?;
+        stats.timings.reaped = start.elapsed();
+        stats.usage = (&usage).into();
+        #[cfg(target_os = "linux")]
+        if let Some(strays) = &self.strays {
+            let mut count = [0u8; 8];
+            let ptr = count.as_mut_ptr() as *mut libc::c_void;
+            if unsafe { libc::read(strays.as_raw_fd(), ptr, count.len()) } == count.len() as isize {
+                stats.stray_descendants = u64::from_le_bytes(count) as usize;
+            }
+        }
+
+        let pid = self.pid();
+        let exited = libc::WIFEXITED(status);
+        let code = if exited { libc::WEXITSTATUS(status) } else { 0 };
+        // Only once it's told us what it meant by it
+        let code_is_data = exited && received.is_ok() && protocol::is_complete(&self.received);
+        // It only went because we stopped reading, so why we stopped is the error
+        let parent_gone =
+            code == status::EXIT_PARENT_GONE && matches!(received, Err(ForkError::Io { .. }));
+        if status != 0 && !killed && !code_is_data && !parent_gone {
+            #[cfg(target_os = "linux")]
+            if let Some(e) = self.cgroup.as_ref().and_then(|c| c.oom_killed(pid)) {
+                return Err(e.into());
+            }
+            #[cfg(all(
+                target_os = "linux",
+                any(target_arch = "x86_64", target_arch = "aarch64")
+            ))]
+            if self.builder.seccomp.is_some()
+                && libc::WIFSIGNALED(status)
+                && libc::WTERMSIG(status) == libc::SIGSYS
+            {
+                return Err(ForkError::SyscallBlocked { pid }.into());
+            }
+            return Err(ForkError::ChildFailed { pid, status }.into());
+        }
+
+        // The result beat the clock, and only the child's exit didn't
+        if timed_out && protocol::is_complete(&self.received) {
+            received = Ok(());
+        }
+        received?;
+        stats.timings.first_byte = self.first_byte.map_or(stats.timings.eof, |t| t - start);
+        let result = decode(&self)?;
+        stats.timings.decoded = start.elapsed();
+        stats.exit_code = code;
+        Ok((result, stats))
+    }
+}
+
+impl Child {
+    /// Decodes the result, from shared memory if that's where the child says it is.
+    fn decode<R>(&self) -> anyhow::Result<R>
+    where
+        R: for<'a> Deserialize<'a>,
+    {
+        self.decode_with(|frame| self.builder.decode_result(self.pid(), frame))
+    }
+
+    /// Like [`decode`](Self::decode), with `decode` turning the frame with the result into it.
+    fn decode_with<R>(&self, decode: impl FnOnce(&[u8]) -> anyhow::Result<R>) -> anyhow::Result<R> {
+        let (Some(shared), Some(&tag)) = (&self.shared, self.received.first()) else {
+            return decode(&self.received);
+        };
+        if tag != Tag::Shared as u8 {
+            return decode(&self.received);
+        }
+        let (_, body) = protocol::parse(self.pid(), &self.received)?;
+        let len = match <[u8; 8]>::try_from(&body[..]) {
+            Ok(len) => u64::from_le_bytes(len) as usize,
+            Err(_) => {
+                return Err(
+                    ForkError::decode(self.pid(), &"bad shared memory length", &body).into(),
+                )
+            }
+        };
+        let mapping = unsafe { sys::Mapping::new(self.pid(), shared.as_raw_fd(), len) }?;
+        if let Some(limit) = self.builder.max_result_bytes {
+            protocol::check_size(self.pid(), mapping.as_slice(), limit)?;
+        }
+        decode(mapping.as_slice())
+    }
+}
+
+/// The parent's end of a child's captured standard output, and what's been read from it.
+struct Stdout {
+    fd: OwnedFd,
+    buf: Vec<u8>,
+    /// How much of `buf` has been consumed.
+    pos: usize,
+    eof: bool,
+}
+
+/// Counts what's written to it, and throws it away.
+struct Counter(u64);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs in the child: writes the result `frame` to shared memory, and returns the frame to send
+/// in its place, which is the same frame if that didn't work.
+unsafe fn share(shared: libc::c_int, frame: Vec<u8>) -> Vec<u8> {
+    let len = (frame.len() as u64).to_le_bytes().to_vec();
+    match sys::write_all(shared, &frame) {
+        Ok(()) => protocol::frame(Tag::Shared, Compression::None, len).unwrap_or(frame),
+        Err(_) => frame,
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        unsafe {
+            if self.pipe >= 0 {
+                libc::close(self.pipe);
+            }
+        }
+    }
+}
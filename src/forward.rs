@@ -0,0 +1,262 @@
+//! Passing signals the parent gets on to its children. See [`install_signal_forwarding`].
+//!
+//! The children being forwarded to are kept in a fixed table of pids, so the handler can go
+//! through it without taking a lock or allocating. A child is added when it's forked, with the
+//! forwarded signals blocked until it has been, and taken off once it has exited but before it's
+//! reaped, since until then its pid can't be given to some other process.
+
+#[cfg(all(unix, not(feature = "fallback"), feature = "serde"))]
+use std::sync::atomic::AtomicUsize;
+#[cfg(all(unix, not(feature = "fallback")))]
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+#[cfg(feature = "serde")]
+use crate::{ForkError, Signal};
+
+/// How many children can be forwarded to at once. Any forked while there are this many aren't.
+#[cfg(all(unix, not(feature = "fallback")))]
+const SLOTS: usize = 1024;
+
+/// One more than the highest signal number there's room for.
+#[cfg(all(unix, not(feature = "fallback")))]
+const SIGNALS: usize = 65;
+
+/// Whether any signal is being forwarded, so there's no bookkeeping for children otherwise.
+#[cfg(all(unix, not(feature = "fallback")))]
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+/// The pids of the children to forward to, 0 for a free slot.
+#[cfg(all(unix, not(feature = "fallback")))]
+static CHILDREN: [AtomicI32; SLOTS] = [const { AtomicI32::new(0) }; SLOTS];
+/// Which signals are being forwarded, by number.
+#[cfg(all(unix, not(feature = "fallback")))]
+static FORWARDING: [AtomicBool; SIGNALS] = [const { AtomicBool::new(false) }; SIGNALS];
+/// The handler each forwarded signal had before, to be called once it's been forwarded.
+#[cfg(all(unix, not(feature = "fallback"), feature = "serde"))]
+static PREVIOUS: [AtomicUsize; SIGNALS] = [const { AtomicUsize::new(0) }; SIGNALS];
+/// Whether that handler takes the three arguments of an `SA_SIGINFO` one.
+#[cfg(all(unix, not(feature = "fallback"), feature = "serde"))]
+static SIGINFO: [AtomicBool; SIGNALS] = [const { AtomicBool::new(false) }; SIGNALS];
+
+/// Passes `signals` on to every child of this crate's that's running when this process gets one
+/// of them, before whatever would have happened to this process goes ahead: a handler that was
+/// already installed is called, an ignored signal is still ignored, and one that would have
+/// killed this process still does, once it has been passed on.
+///
+/// Without this, a Ctrl-C in the terminal reaches the children anyway, since they're in the
+/// same process group, but a `SIGTERM` sent to the parent alone (by a service manager, or
+/// `kill`) doesn't, and the children run to completion after the parent has been told to stop.
+/// With it, they're told to stop too, and can clean up like the parent does.
+///
+/// Only children forked after this are forwarded to, by any of the ways this crate forks, up to
+/// 1024 at a time. A child forked from the closure of another is forwarded the signal by its
+/// parent in turn, and a child that installs a handler of its own for a signal gets the signal
+/// there; otherwise, signals being [reset](crate::ForkBuilder::reset_signals), the default
+/// action is taken. Installing it again for a signal that's already forwarded does nothing, and there's no
+/// uninstalling it.
+///
+/// `Signal::Kill` can't be caught, so it can't be forwarded either, and fails with
+/// [`ForkError::Io`]. When [`FORKS`](crate::FORKS) is `false`, there's nothing to forward to, and
+/// this does nothing.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{install_signal_forwarding, Fork, Signal};
+/// use std::sync::atomic::{AtomicBool, Ordering};
+/// use std::time::{Duration, Instant};
+/// # if !fork_map::FORKS { return }
+///
+/// static TERMINATED: AtomicBool = AtomicBool::new(false);
+/// extern "C" fn terminated(_: libc::c_int) {
+///     TERMINATED.store(true, Ordering::SeqCst);
+/// }
+/// // The program's own handler, which still gets its turn
+/// unsafe { libc::signal(libc::SIGTERM, terminated as libc::sighandler_t) };
+/// install_signal_forwarding(&[Signal::Int, Signal::Term]).unwrap();
+///
+/// let child = unsafe {
+///     Fork::builder().spawn(|| {
+///         // Signals start out at their defaults in the child, so it installs its own handler
+///         libc::signal(libc::SIGTERM, terminated as libc::sighandler_t);
+///         let deadline = Instant::now() + Duration::from_secs(60);
+///         while !TERMINATED.load(Ordering::SeqCst) && Instant::now() < deadline {
+///             std::thread::sleep(Duration::from_millis(10));
+///         }
+///         Ok(TERMINATED.load(Ordering::SeqCst))
+///     })
+/// }
+/// .unwrap();
+/// std::thread::sleep(Duration::from_millis(100));
+///
+/// let start = Instant::now();
+/// unsafe { libc::raise(libc::SIGTERM) };
+/// assert!(TERMINATED.load(Ordering::SeqCst));
+/// assert!(child.join().unwrap());
+/// assert!(start.elapsed() < Duration::from_secs(5));
+///
+/// assert!(install_signal_forwarding(&[Signal::Kill]).is_err());
+/// ```
+#[cfg(feature = "serde")]
+pub fn install_signal_forwarding(signals: &[Signal]) -> Result<(), ForkError> {
+    #[cfg(all(unix, not(feature = "fallback")))]
+    for signal in signals {
+        unsafe { install(signal.as_raw())? };
+    }
+    #[cfg(any(not(unix), feature = "fallback"))]
+    let _ = signals;
+    Ok(())
+}
+
+#[cfg(all(unix, not(feature = "fallback"), feature = "serde"))]
+unsafe fn install(signal: libc::c_int) -> Result<(), ForkError> {
+    let index = signal as usize;
+    assert!(index < SIGNALS, "signal {} out of range", signal);
+    if FORWARDING[index].load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    let mut previous: libc::sigaction = std::mem::zeroed();
+    if libc::sigaction(signal, std::ptr::null(), &mut previous) < 0 {
+        return Err(ForkError::last_os_error("sigaction"));
+    }
+    PREVIOUS[index].store(previous.sa_sigaction, Ordering::SeqCst);
+    SIGINFO[index].store(previous.sa_flags & libc::SA_SIGINFO != 0, Ordering::SeqCst);
+    // From before the handler is, so no child is forked in between without being added
+    INSTALLED.store(true, Ordering::SeqCst);
+
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = forward
+        as extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void)
+        as libc::sighandler_t;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART | (previous.sa_flags & libc::SA_ONSTACK);
+    libc::sigemptyset(&mut action.sa_mask);
+    if libc::sigaction(signal, &action, std::ptr::null_mut()) < 0 {
+        return Err(ForkError::last_os_error("sigaction"));
+    }
+    FORWARDING[index].store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// The handler. Nothing in it takes a lock or allocates.
+#[cfg(all(unix, not(feature = "fallback"), feature = "serde"))]
+extern "C" fn forward(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    for child in &CHILDREN {
+        let pid = child.load(Ordering::SeqCst);
+        if pid > 0 {
+            unsafe { libc::kill(pid, signal) };
+        }
+    }
+    let index = signal as usize;
+    match PREVIOUS[index].load(Ordering::SeqCst) {
+        libc::SIG_IGN => {}
+        libc::SIG_DFL => unsafe {
+            // The default action, as soon as this returns and the signal is unblocked
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        },
+        handler if SIGINFO[index].load(Ordering::SeqCst) => {
+            let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                unsafe { std::mem::transmute(handler) };
+            handler(signal, info, context);
+        }
+        handler => {
+            let handler: extern "C" fn(libc::c_int) = unsafe { std::mem::transmute(handler) };
+            handler(signal);
+        }
+    }
+}
+
+/// Blocks the forwarded signals on this thread, for forking a child and adding it before any
+/// of them can be handled. Returns the mask to put back with [`unblock`], if there was anything
+/// to block.
+#[cfg(all(unix, not(feature = "fallback")))]
+pub(crate) unsafe fn block() -> Option<libc::sigset_t> {
+    if !INSTALLED.load(Ordering::SeqCst) {
+        return None;
+    }
+    let mut forwarded: libc::sigset_t = std::mem::zeroed();
+    libc::sigemptyset(&mut forwarded);
+    for (signal, forwarding) in FORWARDING.iter().enumerate() {
+        if forwarding.load(Ordering::SeqCst) {
+            libc::sigaddset(&mut forwarded, signal as libc::c_int);
+        }
+    }
+    let mut mask: libc::sigset_t = std::mem::zeroed();
+    libc::pthread_sigmask(libc::SIG_BLOCK, &forwarded, &mut mask);
+    Some(mask)
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+pub(crate) unsafe fn unblock(mask: Option<libc::sigset_t>) {
+    if let Some(mask) = mask {
+        libc::pthread_sigmask(libc::SIG_SETMASK, &mask, std::ptr::null_mut());
+    }
+}
+
+/// Whether there are children to keep track of, in which case they're to be taken off with
+/// [`remove`] before they're reaped.
+#[cfg(all(unix, not(feature = "fallback")))]
+pub(crate) fn forwarding() -> bool {
+    INSTALLED.load(Ordering::SeqCst)
+}
+
+/// Runs in the parent, with the forwarded signals [blocked](block).
+#[cfg(all(unix, not(feature = "fallback")))]
+pub(crate) fn add(pid: libc::pid_t) {
+    if !forwarding() {
+        return;
+    }
+    for child in &CHILDREN {
+        if child
+            .compare_exchange(0, pid, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+pub(crate) fn remove(pid: libc::pid_t) {
+    if !forwarding() {
+        return;
+    }
+    for child in &CHILDREN {
+        if child
+            .compare_exchange(pid, 0, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            return;
+        }
+    }
+}
+
+/// Whether `signal` is being forwarded.
+#[cfg(all(unix, not(feature = "fallback"), feature = "serde"))]
+pub(crate) fn forwards(signal: libc::c_int) -> bool {
+    FORWARDING
+        .get(signal as usize)
+        .is_some_and(|forwarding| forwarding.load(Ordering::SeqCst))
+}
+
+/// Runs in a new child whose signals are being [reset](crate::ForkBuilder::reset_signals): it
+/// still forwards to children of its own, then takes the default action, rather than calling
+/// whatever handler its parent had.
+#[cfg(all(unix, not(feature = "fallback"), feature = "serde"))]
+pub(crate) fn forward_then_default() {
+    for (previous, siginfo) in PREVIOUS.iter().zip(&SIGINFO) {
+        previous.store(libc::SIG_DFL, Ordering::SeqCst);
+        siginfo.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Runs in a new child, which starts out with a copy of its parent's children, none of which
+/// are its own.
+#[cfg(all(unix, not(feature = "fallback")))]
+pub(crate) fn forget_all() {
+    if !forwarding() {
+        return;
+    }
+    for child in &CHILDREN {
+        child.store(0, Ordering::SeqCst);
+    }
+}
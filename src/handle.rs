@@ -0,0 +1,441 @@
+use std::fmt;
+use std::io::{self, BufRead};
+use std::marker::PhantomData;
+
+use serde::Deserialize;
+
+#[cfg(all(unix, not(feature = "fallback")))]
+use crate::forked::Child;
+use crate::{ForkError, ForkStats};
+
+/// A child started by [`ForkBuilder::spawn`](crate::ForkBuilder::spawn), which may still be
+/// running.
+///
+/// Dropping the handle without joining it closes the result pipe and waits for the child to
+/// exit, so no zombie is left behind. A child that is still writing its result then fails to, and
+/// the result is lost.
+///
+/// ```
+/// use fork_map::Fork;
+///
+/// let handle = unsafe { Fork::builder().spawn(|| Ok(std::process::id())) }.unwrap();
+/// let pid = handle.pid();
+/// assert_eq!(handle.join().unwrap(), pid);
+/// ```
+///
+/// Nor is one left behind when something goes wrong in the parent: whether reading the result
+/// fails, it won't decode, or the parent panics partway through, the child is reaped before the
+/// error or the panic gets back to the caller.
+///
+/// ```
+/// use fork_map::{Fork, ForkError};
+/// use serde::{Deserialize, Serialize};
+/// use std::panic::{catch_unwind, AssertUnwindSafe};
+/// use std::sync::atomic::{AtomicI32, Ordering};
+/// use std::sync::Arc;
+/// use std::time::Duration;
+/// # if !fork_map::FORKS { return }
+///
+/// let pid = Arc::new(AtomicI32::new(0));
+/// let remember = |pid: &Arc<AtomicI32>| {
+///     let pid = pid.clone();
+///     move |child| pid.store(child as i32, Ordering::SeqCst)
+/// };
+/// let builder = || Fork::builder().on_fork(remember(&pid));
+/// // No such child, rather than one that's still running or waiting to be reaped
+/// let gone = || unsafe {
+///     libc::waitpid(pid.load(Ordering::SeqCst), std::ptr::null_mut(), libc::WNOHANG) == -1
+/// };
+///
+/// // The result pipe closed under the parent while it's reading
+/// let fd = unsafe { libc::dup(0) };
+/// unsafe { libc::close(fd) };
+/// std::thread::spawn(move || {
+///     std::thread::sleep(Duration::from_millis(100));
+///     unsafe { libc::close(fd) };
+/// });
+/// let err = unsafe {
+///     builder().run(|| {
+///         std::thread::sleep(Duration::from_millis(300));
+///         Ok(())
+///     })
+/// }
+/// .unwrap_err();
+/// assert!(matches!(err.downcast_ref(), Some(ForkError::Io { .. })));
+/// assert!(gone());
+///
+/// // A result that won't decode
+/// #[derive(Serialize, Deserialize)]
+/// struct Job {
+///     #[serde(rename(serialize = "id"))]
+///     ident: u64,
+/// }
+/// let err = unsafe { builder().run(|| Ok(Job { ident: 7 })) }.err().unwrap();
+/// assert!(matches!(err.downcast_ref(), Some(ForkError::Decode { .. })));
+/// assert!(gone());
+///
+/// // A panic in the parent while the child is running
+/// let panicked = catch_unwind(AssertUnwindSafe(|| unsafe {
+///     builder().run_with_progress(
+///         |progress| {
+///             progress.report(1, 2);
+///             std::thread::sleep(Duration::from_millis(100));
+///             Ok(())
+///         },
+///         |_| panic!("not interested"),
+///     )
+/// }));
+/// assert!(panicked.is_err());
+/// assert!(gone());
+///
+/// // And one before the child has even been let go
+/// let panicked = catch_unwind(AssertUnwindSafe(|| unsafe {
+///     let remember = remember(&pid);
+///     Fork::builder()
+///         .on_fork(move |child| {
+///             remember(child);
+///             panic!("not today");
+///         })
+///         .run(|| Ok(()))
+/// }));
+/// assert!(panicked.is_err());
+/// assert!(gone());
+/// ```
+pub struct ForkHandle<R> {
+    pid: u32,
+    state: State<R>,
+    /// Declared after `state`, so a handle that's dropped reaps its child before this runs.
+    on_reap: OnReap<R>,
+}
+
+enum State<R> {
+    #[cfg(all(unix, not(feature = "fallback")))]
+    Forked(Box<Child>, PhantomData<fn() -> R>),
+    /// Ran in process, so the result is already here.
+    Done(anyhow::Result<(R, ForkStats)>),
+}
+
+impl<R> ForkHandle<R> {
+    #[cfg(all(unix, not(feature = "fallback")))]
+    pub(crate) fn forked(pid: u32, child: Child) -> Self {
+        ForkHandle {
+            pid,
+            state: State::Forked(Box::new(child), PhantomData),
+            on_reap: OnReap(None),
+        }
+    }
+
+    pub(crate) fn done(result: anyhow::Result<(R, ForkStats)>) -> Self {
+        ForkHandle {
+            pid: std::process::id(),
+            state: State::Done(result),
+            on_reap: OnReap(None),
+        }
+    }
+
+    /// Read end of the result pipe, or `None` if the result is already here.
+    #[cfg(all(unix, not(feature = "fallback")))]
+    pub(crate) fn pipe(&self) -> Option<libc::c_int> {
+        match &self.state {
+            State::Forked(child, _) => Some(child.pipe()),
+            State::Done(_) => None,
+        }
+    }
+
+    /// Kills the child with the builder's [`kill_signal`](crate::ForkBuilder::kill_signal), if
+    /// there is one. It still has to be reaped.
+    #[cfg(all(unix, not(feature = "fallback")))]
+    pub(crate) fn kill(&self) {
+        if let State::Forked(child, _) = &self.state {
+            child.kill();
+        }
+    }
+
+    /// The child's process id, or the current process's if there's no child because
+    /// [`FORKS`](crate::FORKS) is `false` or the builder was [`inline`](crate::ForkBuilder::inline).
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Runs `callback` in the parent once the child has been reaped, with the result about to
+    /// be returned, whichever way the child went: success, failure or timeout. It runs exactly
+    /// once, so it's the one place for teardown that belongs to this job, like releasing a slot
+    /// or removing a temporary file.
+    ///
+    /// If the handle is dropped without being joined, the callback still runs after the child
+    /// is reaped, with [`ForkError::ResultDiscarded`]. Calling this again adds another callback,
+    /// run after the ones added before it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use fork_map::{Fork, ForkError};
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let reaped = Arc::new(AtomicUsize::new(0));
+    /// let count = |reaped: &Arc<AtomicUsize>| {
+    ///     let reaped = reaped.clone();
+    ///     move |_: &Result<u32, ForkError>| {
+    ///         reaped.fetch_add(1, Ordering::SeqCst);
+    ///     }
+    /// };
+    ///
+    /// let ok = unsafe { Fork::builder().spawn(|| Ok(1)) }.unwrap().on_reap(count(&reaped));
+    /// assert_eq!(ok.join().unwrap(), 1);
+    ///
+    /// let failed = unsafe { Fork::builder().spawn(|| anyhow::bail!("no luck")) }
+    ///     .unwrap()
+    ///     .on_reap(|result: &Result<u32, ForkError>| {
+    ///         assert!(matches!(result, Err(ForkError::Closure(e)) if e.to_string() == "no luck"));
+    ///     })
+    ///     .on_reap(count(&reaped));
+    /// assert!(failed.join().is_err());
+    ///
+    /// let dropped = unsafe { Fork::builder().spawn(|| Ok(3)) }.unwrap().on_reap(count(&reaped));
+    /// drop(dropped);
+    /// assert_eq!(reaped.load(Ordering::SeqCst), 3);
+    /// ```
+    pub fn on_reap<F>(mut self, callback: F) -> Self
+    where
+        F: FnOnce(&Result<R, ForkError>) + Send + Sync + 'static,
+        R: 'static,
+    {
+        self.on_reap.0 = Some(match self.on_reap.0.take() {
+            Some(before) => Box::new(move |result: &Result<R, ForkError>| {
+                before(result);
+                callback(result);
+            }),
+            None => Box::new(callback),
+        });
+        self
+    }
+}
+
+type Callback<R> = Box<dyn FnOnce(&Result<R, ForkError>) + Send + Sync>;
+
+/// Callback for [`ForkHandle::on_reap`]. Runs on drop if it hasn't run yet.
+struct OnReap<R>(Option<Callback<R>>);
+
+impl<R> OnReap<R> {
+    /// Runs the callback, if any, on the result about to be returned.
+    fn run(mut self, result: anyhow::Result<(R, ForkStats)>) -> anyhow::Result<(R, ForkStats)> {
+        let Some(callback) = self.0.take() else {
+            return result;
+        };
+        let (result, stats) = match result {
+            Ok((value, stats)) => (Ok(value), Some(stats)),
+            Err(e) => (Err(ForkError::from_anyhow(e)), None),
+        };
+        callback(&result);
+        match result {
+            Ok(value) => Ok((value, stats.unwrap_or_default())),
+            // Keep the closure's own error as it was, rather than wrapped
+            Err(ForkError::Closure(e)) => Err(e),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl<R> Drop for OnReap<R> {
+    fn drop(&mut self) {
+        if let Some(callback) = self.0.take() {
+            callback(&Err(ForkError::ResultDiscarded));
+        }
+    }
+}
+
+impl<R> fmt::Debug for ForkHandle<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ForkHandle")
+            .field("pid", &self.pid)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R> ForkHandle<R> {
+    /// The child's standard output, as it's written, if it was
+    /// [captured](crate::ForkBuilder::capture_stdout). `None` if it wasn't, or when there's no
+    /// child because [`FORKS`](crate::FORKS) is `false` or the builder was
+    /// [`inline`](crate::ForkBuilder::inline).
+    ///
+    /// Reading waits for the child to write something, and ends when it closes its standard
+    /// output, which is usually when it exits. The result is read meanwhile, so a child with a
+    /// big one can't get stuck sending it while you wait for the rest of its output, and
+    /// [`join`](Self::join) still returns it as usual. Whatever hasn't been read by then goes to
+    /// the parent's standard output, as it would have without being captured.
+    ///
+    /// A [`timeout`](crate::ForkBuilder::timeout) applies to reading, too, which fails with
+    /// [`ForkError::Timeout`] wrapped in an [`io::Error`](std::io::Error).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use fork_map::Fork;
+    /// use std::io::BufRead;
+    /// use std::time::{Duration, Instant};
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// let start = Instant::now();
+    /// let mut handle = unsafe {
+    ///     Fork::builder().capture_stdout(true).spawn(|| {
+    ///         for step in 1..=3 {
+    ///             println!("step {}", step);
+    ///             std::thread::sleep(Duration::from_millis(200));
+    ///         }
+    ///         Ok(vec![7u8; 8 << 20])
+    ///     })
+    /// }
+    /// .unwrap();
+    /// let mut lines = handle.stdout().unwrap().lines();
+    /// assert_eq!(lines.next().unwrap().unwrap(), "step 1");
+    /// // Long before the child is done
+    /// assert!(start.elapsed() < Duration::from_millis(400));
+    /// let rest: Vec<_> = lines.map(Result::unwrap).collect();
+    /// assert_eq!(rest, ["step 2", "step 3"]);
+    ///
+    /// let big = handle.join().unwrap();
+    /// assert!(big.len() == 8 << 20 && big.iter().all(|&byte| byte == 7));
+    /// ```
+    pub fn stdout(&mut self) -> Option<ChildStdout<'_>> {
+        match &mut self.state {
+            #[cfg(all(unix, not(feature = "fallback")))]
+            State::Forked(child, _) if child.captures_stdout() => Some(ChildStdout { child }),
+            _ => None,
+        }
+    }
+}
+
+/// A child's standard output, from [`ForkHandle::stdout`].
+pub struct ChildStdout<'a> {
+    #[cfg(all(unix, not(feature = "fallback")))]
+    child: &'a mut Child,
+    #[cfg(any(not(unix), feature = "fallback"))]
+    _never: std::convert::Infallible,
+    #[cfg(any(not(unix), feature = "fallback"))]
+    _handle: PhantomData<&'a ()>,
+}
+
+impl io::Read for ChildStdout<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let count = available.len().min(buf.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.consume(count);
+        Ok(count)
+    }
+}
+
+impl BufRead for ChildStdout<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        #[cfg(all(unix, not(feature = "fallback")))]
+        match self.child.read_stdout() {
+            Ok(output) => Ok(output.unwrap_or_default()),
+            Err(e) => Err(io::Error::other(e)),
+        }
+        #[cfg(any(not(unix), feature = "fallback"))]
+        match self._never {}
+    }
+
+    fn consume(&mut self, count: usize) {
+        #[cfg(all(unix, not(feature = "fallback")))]
+        self.child.consume_stdout(count);
+        #[cfg(any(not(unix), feature = "fallback"))]
+        let _ = count;
+    }
+}
+
+impl fmt::Debug for ChildStdout<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChildStdout").finish_non_exhaustive()
+    }
+}
+
+impl<R> ForkHandle<R>
+where
+    R: for<'a> Deserialize<'a>,
+{
+    /// Waits for the child to terminate and returns the result of the closure.
+    pub fn join(self) -> anyhow::Result<R> {
+        self.join_with_stats().map(|(result, _)| result)
+    }
+
+    /// Like [`join`](Self::join), but also returns statistics about the run. See
+    /// [`fork_map_with_stats`](crate::fork_map_with_stats).
+    pub fn join_with_stats(self) -> anyhow::Result<(R, ForkStats)> {
+        let result = self.state.wait();
+        self.on_reap.run(result)
+    }
+
+    /// See [`Child::read_some`]. Only for handles with a [`pipe`](Self::pipe).
+    #[cfg(all(unix, not(feature = "fallback")))]
+    pub(crate) fn read_some(&mut self) -> Result<bool, crate::ForkError> {
+        match &mut self.state {
+            State::Forked(child, _) => child.read_some(),
+            State::Done(_) => Ok(true),
+        }
+    }
+
+    /// When the child runs out of time, if it has a [`timeout`](crate::ForkBuilder::timeout).
+    #[cfg(all(unix, not(feature = "fallback")))]
+    pub(crate) fn deadline(&self) -> Option<std::time::Instant> {
+        match &self.state {
+            State::Forked(child, _) => child.deadline(),
+            State::Done(_) => None,
+        }
+    }
+
+    /// See [`Child::rss_check_at`].
+    #[cfg(all(unix, not(feature = "fallback")))]
+    pub(crate) fn rss_check_at(&self) -> Option<std::time::Instant> {
+        match &self.state {
+            State::Forked(child, _) => child.rss_check_at(),
+            State::Done(_) => None,
+        }
+    }
+
+    /// See [`Child::check_rss`].
+    #[cfg(all(unix, not(feature = "fallback")))]
+    pub(crate) fn check_rss(&mut self) -> Result<(), crate::ForkError> {
+        match &mut self.state {
+            State::Forked(child, _) => child.check_rss(),
+            State::Done(_) => Ok(()),
+        }
+    }
+
+    /// See [`Child::time_out`].
+    #[cfg(all(unix, not(feature = "fallback")))]
+    pub(crate) fn time_out(self) -> anyhow::Result<(R, ForkStats)> {
+        let result = match self.state {
+            State::Forked(child, _) => child.time_out(),
+            State::Done(result) => result,
+        };
+        self.on_reap.run(result)
+    }
+
+    /// See [`Child::finish`].
+    #[cfg(all(unix, not(feature = "fallback")))]
+    pub(crate) fn finish(
+        self,
+        received: Result<(), crate::ForkError>,
+    ) -> anyhow::Result<(R, ForkStats)> {
+        let result = match self.state {
+            State::Forked(child, _) => child.finish(received),
+            State::Done(result) => result,
+        };
+        self.on_reap.run(result)
+    }
+}
+
+impl<R> State<R>
+where
+    R: for<'a> Deserialize<'a>,
+{
+    fn wait(self) -> anyhow::Result<(R, ForkStats)> {
+        match self {
+            #[cfg(all(unix, not(feature = "fallback")))]
+            State::Forked(child, _) => child.wait(),
+            State::Done(result) => result,
+        }
+    }
+}
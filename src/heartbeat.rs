@@ -0,0 +1,239 @@
+//! Killing children that stop making progress, rather than ones that take long.
+
+// Without fork() there's no watchdog, and so no heartbeat to send
+#![cfg_attr(any(not(unix), feature = "fallback"), allow(dead_code))]
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(all(unix, not(feature = "fallback")))]
+use crate::sys;
+use crate::{Fork, ForkBuilder, ForkError};
+
+/// What a child proves it's still making progress with. See [`fork_map_watchdog`].
+pub struct Heartbeat {
+    /// Write end of the heartbeat pipe.
+    pipe: libc::c_int,
+    /// How long after the last tick that went out the next one is worth sending.
+    every: Duration,
+    last_sent: Instant,
+}
+
+impl Heartbeat {
+    /// Tells the parent the child is still alive and well. Cheap enough to call from a loop:
+    /// ticks that come in quick succession only cost a clock read.
+    pub fn tick(&mut self) {
+        if self.last_sent.elapsed() < self.every {
+            return;
+        }
+        self.last_sent = Instant::now();
+        // A full pipe means the parent already has ticks it hasn't got to, so this one can go
+        #[cfg(all(unix, not(feature = "fallback")))]
+        unsafe {
+            libc::write(self.pipe, [0u8].as_ptr() as *const libc::c_void, 1)
+        };
+    }
+}
+
+impl fmt::Debug for Heartbeat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Heartbeat")
+            .field("every", &self.every)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Forks, and runs `func` in a child process that has to [`tick`](Heartbeat::tick) the
+/// [`Heartbeat`] it's given at least once every `interval`. Waits for the child to terminate and
+/// returns the result of `func`, unless it goes longer than that without a tick, in which case
+/// it's killed and the error is [`ForkError::Stalled`].
+///
+/// This is for jobs whose running time varies too much for a
+/// [`timeout`](ForkBuilder::timeout) to tell a slow child from a stuck one: as long as it keeps
+/// ticking, a child can take as long as it needs. Ticks travel over a pipe of their own. Sending
+/// the result counts as a tick too, so a child that finishes within `interval` never has to tick
+/// at all.
+///
+/// Needs a child to kill, so when [`FORKS`](crate::FORKS) is `false` this fails with
+/// [`ForkError::Unsupported`].
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_watchdog, ForkError};
+/// use std::time::{Duration, Instant};
+/// # if !fork_map::FORKS { return }
+///
+/// let interval = Duration::from_millis(100);
+/// let steps = unsafe {
+///     fork_map_watchdog(interval, |heartbeat| {
+///         // Takes longer than the interval, but never goes that long without a tick
+///         for _ in 0..30 {
+///             std::thread::sleep(Duration::from_millis(10));
+///             heartbeat.tick();
+///         }
+///         Ok(30)
+///     })
+/// }
+/// .unwrap();
+/// assert_eq!(steps, 30);
+///
+/// let start = Instant::now();
+/// let err = unsafe {
+///     fork_map_watchdog(interval, |heartbeat| -> anyhow::Result<()> {
+///         heartbeat.tick();
+///         std::thread::sleep(Duration::from_millis(30));
+///         heartbeat.tick();
+///         // Wedged
+///         loop {
+///             std::hint::spin_loop();
+///         }
+///     })
+/// }
+/// .unwrap_err();
+/// match err.downcast_ref() {
+///     Some(ForkError::Stalled {
+///         last_heartbeat_age, ..
+///     }) => assert!(*last_heartbeat_age >= interval),
+///     _ => panic!("unexpected error {:?}", err),
+/// }
+/// assert!(start.elapsed() < Duration::from_secs(5));
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`](crate::fork_map).
+pub unsafe fn fork_map_watchdog<F, R>(interval: Duration, func: F) -> anyhow::Result<R>
+where
+    F: Fn(&mut Heartbeat) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run_with_watchdog(interval, func)
+}
+
+impl ForkBuilder {
+    /// Like [`fork_map_watchdog`], with the child configured like this. A
+    /// [`timeout`](Self::timeout) still applies on top of the watchdog.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_with_watchdog<F, R>(self, interval: Duration, func: F) -> anyhow::Result<R>
+    where
+        F: Fn(&mut Heartbeat) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        self.with_retries(|| self.clone().watchdog_once(interval, &func))
+    }
+
+    unsafe fn watchdog_once<F, R>(self, interval: Duration, func: &F) -> anyhow::Result<R>
+    where
+        F: Fn(&mut Heartbeat) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        #[cfg(feature = "testing")]
+        if let Some(error) = crate::testing::take() {
+            return Err(error.into());
+        }
+        #[cfg(all(unix, not(feature = "fallback")))]
+        if !self.inline {
+            return self.watchdog_forked(interval, func);
+        }
+        let _ = (interval, func);
+        Err(ForkError::Unsupported {
+            feature: "watchdog",
+        }
+        .into())
+    }
+
+    #[cfg(all(unix, not(feature = "fallback")))]
+    unsafe fn watchdog_forked<F, R>(self, interval: Duration, func: &F) -> anyhow::Result<R>
+    where
+        F: Fn(&mut Heartbeat) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+        let beats = sys::pipe()?;
+        // Never block the child on a parent that's behind on reading
+        let flags = libc::fcntl(beats[1], libc::F_GETFL);
+        libc::fcntl(beats[1], libc::F_SETFL, flags | libc::O_NONBLOCK);
+        let child = self.fork_child(|_| {
+            libc::close(beats[0]);
+            let mut heartbeat = Heartbeat {
+                pipe: beats[1],
+                every: interval / 4,
+                last_sent: Instant::now(),
+            };
+            func(&mut heartbeat)
+        });
+        libc::close(beats[1]);
+        let beats = OwnedFd::from_raw_fd(beats[0]);
+        let mut child = child?;
+        // Until the child closes its end
+        let mut beats = Some(beats);
+        let mut last_beat = Instant::now();
+
+        let received = loop {
+            let stall_at = last_beat + interval;
+            let deadline = [child.deadline(), child.rss_check_at()]
+                .into_iter()
+                .flatten()
+                .fold(stall_at, Instant::min);
+            let mut fds = [
+                libc::pollfd {
+                    fd: child.pipe(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: beats.as_ref().map_or(-1, |beats| beats.as_raw_fd()),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+            let ready = match sys::poll(&mut fds, Some(deadline)) {
+                Ok(ready) => ready,
+                Err(e) => break Err(e),
+            };
+            if let Err(e) = child.check_rss() {
+                break Err(e);
+            }
+            if ready == 0 {
+                if child.deadline().is_some_and(|d| d <= Instant::now()) {
+                    return child.time_out().map(|(result, _)| result);
+                }
+                if last_beat.elapsed() >= interval {
+                    break Err(ForkError::Stalled {
+                        pid: child.pid(),
+                        last_heartbeat_age: last_beat.elapsed(),
+                    });
+                }
+                continue;
+            }
+            if let Some(fd) = &beats {
+                if fds[1].revents != 0 {
+                    // Only that they arrived matters
+                    let mut ticks = vec![];
+                    if !matches!(sys::read_some(fd.as_raw_fd(), &mut ticks), Ok(count) if count > 0)
+                    {
+                        beats = None;
+                    }
+                    last_beat = Instant::now();
+                }
+            }
+            if fds[0].revents != 0 {
+                last_beat = Instant::now();
+                match child.read_some() {
+                    Ok(true) => break Ok(()),
+                    Ok(false) => {}
+                    Err(e) => break Err(e),
+                }
+            }
+        };
+        drop(beats);
+        child.finish(received).map(|(result, _)| result)
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::iter::Enumerate;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::builder::take_once;
+use crate::multi::Running;
+use crate::{ForkBuilder, ForkError};
+
+/// What both iterators share: forks children for items as slots free up.
+struct Core<I: Iterator, F, R> {
+    builder: ForkBuilder,
+    items: Enumerate<I>,
+    func: F,
+    limit: usize,
+    running: Running<usize, R>,
+    /// Whether to stop starting children, even if there are items left.
+    items_done: bool,
+    /// Items whose child couldn't even be started.
+    failed: VecDeque<(usize, Result<R, ForkError>)>,
+}
+
+impl<I, F, R> Core<I, F, R>
+where
+    I: Iterator,
+    I::Item: Send,
+    F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    fn new(builder: ForkBuilder, items: I, func: F) -> Self {
+        let limit = builder
+            .max_concurrent
+            .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+            .unwrap_or(1)
+            .max(1);
+        Core {
+            builder,
+            items: items.enumerate(),
+            func,
+            limit,
+            running: Running::new(),
+            items_done: false,
+            failed: VecDeque::new(),
+        }
+    }
+
+    /// Starts children until `limit` are running or the items run out.
+    fn fill(&mut self) {
+        while !self.items_done && self.running.len() < self.limit {
+            let Some((index, item)) = self.items.next() else {
+                return;
+            };
+            let item = Mutex::new(Some(item));
+            let func = &self.func;
+            // Safety: promised by whoever created the iterator
+            let spawned = unsafe { self.builder.clone().spawn(|| func(take_once(&item))) };
+            match spawned {
+                Ok(handle) => self.running.push(index, handle),
+                Err(e) => self
+                    .failed
+                    .push_back((index, Err(ForkError::from_anyhow(e)))),
+            }
+        }
+    }
+
+    fn pids(&self) -> Vec<u32> {
+        match crate::FORKS && !self.builder.inline {
+            true => self.running.pids(),
+            false => vec![],
+        }
+    }
+
+    /// The next item to finish, in whatever order that happens.
+    fn next_completed(&mut self) -> Option<(usize, Result<R, ForkError>)> {
+        self.fill();
+        if let Some(failed) = self.failed.pop_front() {
+            return Some(failed);
+        }
+        let (index, result) = self.running.next()?;
+        Some((
+            index,
+            result
+                .map(|(result, _)| result)
+                .map_err(ForkError::from_anyhow),
+        ))
+    }
+}
+
+impl<I: Iterator, F, R> Drop for Core<I, F, R> {
+    fn drop(&mut self) {
+        // Nobody's going to ask for their results, so there's no waiting for them to finish
+        self.running.kill_all();
+    }
+}
+
+/// Iterator returned by [`fork_map_iter`](crate::fork_map_iter), yielding results in the order
+/// of the input items.
+pub struct ForkMapIter<I: Iterator, F, R> {
+    core: Core<I, F, R>,
+    next: usize,
+    finished: BTreeMap<usize, Result<R, ForkError>>,
+}
+
+impl<I: Iterator, F, R> ForkMapIter<I, F, R>
+where
+    I::Item: Send,
+    F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    pub(crate) fn new(builder: ForkBuilder, items: I, func: F) -> Self {
+        ForkMapIter {
+            core: Core::new(builder, items, func),
+            next: 0,
+            finished: BTreeMap::new(),
+        }
+    }
+
+    /// The pids of the children running for items whose results haven't been yielded yet. When
+    /// [`FORKS`](crate::FORKS) is `false`, there aren't any.
+    pub fn pids(&self) -> Vec<u32> {
+        self.core.pids()
+    }
+}
+
+impl<I: Iterator, F, R> Iterator for ForkMapIter<I, F, R>
+where
+    I::Item: Send,
+    F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    type Item = Result<R, ForkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(result) = self.finished.remove(&self.next) {
+                self.next += 1;
+                return Some(result);
+            }
+            let (index, result) = self.core.next_completed()?;
+            self.finished.insert(index, result);
+        }
+    }
+}
+
+impl<I: Iterator, F, R> fmt::Debug for ForkMapIter<I, F, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ForkMapIter")
+            .field("next", &self.next)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Iterator returned by [`fork_map_iter_unordered`](crate::fork_map_iter_unordered), yielding
+/// `(index, result)` pairs as soon as each child finishes.
+pub struct ForkMapIterUnordered<I: Iterator, F, R> {
+    core: Core<I, F, R>,
+}
+
+impl<I: Iterator, F, R> ForkMapIterUnordered<I, F, R>
+where
+    I::Item: Send,
+    F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    pub(crate) fn new(builder: ForkBuilder, items: I, func: F) -> Self {
+        ForkMapIterUnordered {
+            core: Core::new(builder, items, func),
+        }
+    }
+
+    /// The pids of the children running for items whose results haven't been yielded yet. When
+    /// [`FORKS`](crate::FORKS) is `false`, there aren't any.
+    pub fn pids(&self) -> Vec<u32> {
+        self.core.pids()
+    }
+
+    /// Stops early: no more children are started, and the running ones are killed.
+    pub(crate) fn cancel(&mut self) {
+        self.core.items_done = true;
+        self.core.running.kill_all();
+    }
+}
+
+impl<I: Iterator, F, R> Iterator for ForkMapIterUnordered<I, F, R>
+where
+    I::Item: Send,
+    F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    type Item = (usize, Result<R, ForkError>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.core.next_completed()
+    }
+}
+
+impl<I: Iterator, F, R> fmt::Debug for ForkMapIterUnordered<I, F, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ForkMapIterUnordered")
+            .finish_non_exhaustive()
+    }
+}
@@ -1,6 +1,186 @@
-use anyhow::anyhow;
+#[cfg(all(feature = "serde", unix))]
+use std::os::fd::OwnedFd;
+#[cfg(feature = "serde")]
+use std::time::Duration;
+
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "serde")]
+mod arena;
+#[cfg(all(unix, not(feature = "fallback")))]
+mod atfork;
+#[cfg(feature = "serde")]
+mod builder;
+mod bytes;
+#[cfg(feature = "serde")]
+mod cancel;
+#[cfg(all(feature = "serde", target_os = "linux", not(feature = "fallback")))]
+mod cgroup;
+#[cfg(feature = "serde")]
+mod codec;
+#[cfg(feature = "serde")]
+mod command;
+#[cfg(feature = "serde")]
+mod compression;
+mod error;
+#[cfg(feature = "serde")]
+mod daemon;
+#[cfg(feature = "serde")]
+mod detach;
+#[cfg(feature = "serde")]
+mod exec;
+mod exclude;
+#[cfg(feature = "serde")]
+mod ext;
+#[cfg(all(feature = "serde", unix))]
+mod fds;
+#[cfg(all(feature = "serde", unix, not(feature = "fallback")))]
+mod forked;
+mod forward;
+#[cfg(feature = "serde")]
+mod handle;
+#[cfg(feature = "serde")]
+mod heartbeat;
+#[cfg(feature = "serde")]
+mod iter;
+mod limit;
+#[cfg(feature = "serde")]
+mod multi;
+#[cfg(feature = "rayon")]
+mod par;
+#[cfg(all(feature = "serde", unix, not(feature = "fallback")))]
+mod pause;
+#[cfg(feature = "serde")]
+mod pool;
+#[cfg(feature = "serde")]
+mod progress;
+#[cfg(feature = "serde")]
+mod protocol;
+#[cfg(feature = "serde")]
+mod region;
+#[cfg(feature = "serde")]
+mod retry;
+mod rng;
+#[cfg(feature = "serde")]
+mod safe;
+#[cfg(all(feature = "serde", target_os = "macos", not(feature = "fallback")))]
+mod sandbox;
+#[cfg(feature = "serde")]
+mod scope;
+#[cfg(all(
+    feature = "serde",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod seccomp;
+#[cfg(feature = "serde")]
+mod server;
+#[cfg(feature = "serde")]
+mod signal;
+#[cfg(feature = "serde")]
+mod stats;
+mod status;
+#[cfg(feature = "serde")]
+mod stream;
+#[cfg(all(feature = "serde", target_os = "linux", not(feature = "fallback")))]
+mod subreaper;
+#[cfg(all(unix, not(feature = "fallback")))]
+mod sys;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "serde")]
+mod transport;
+
+#[cfg(feature = "serde")]
+pub use arena::{fork_map_arena, ForkArena};
+#[cfg(feature = "serde")]
+pub use builder::{Fork, ForkBuilder};
+pub use bytes::fork_map_bytes;
+#[cfg(feature = "serde")]
+pub use cancel::CancelToken;
+#[cfg(feature = "serde")]
+pub use codec::Codec;
+#[cfg(feature = "serde")]
+pub use command::fork_exec;
+#[cfg(feature = "serde")]
+pub use compression::Compression;
+pub use error::ForkError;
+#[cfg(feature = "serde")]
+pub use daemon::fork_daemon;
+#[cfg(feature = "serde")]
+pub use detach::{detached_count, fork_detach, wait_all_detached};
+#[cfg(feature = "serde")]
+pub use error::ForkId;
+pub use exclude::{exclude_region, wipe_region_on_fork};
+#[cfg(feature = "serde")]
+pub use exec::{exec_entry_point, exec_map, ExecEntry, ExecJob};
+#[cfg(feature = "serde")]
+pub use ext::{ForkMap, ForkMapExt, TryForkMap};
+#[cfg(all(feature = "serde", unix))]
+pub use fds::{fork_map_with_fds, FdSender};
+#[cfg(feature = "serde")]
+pub use forward::install_signal_forwarding;
+#[cfg(feature = "serde")]
+pub use handle::{ChildStdout, ForkHandle};
+#[cfg(feature = "serde")]
+pub use heartbeat::{fork_map_watchdog, Heartbeat};
+#[cfg(feature = "serde")]
+pub use iter::{ForkMapIter, ForkMapIterUnordered};
+pub use limit::set_max_concurrent_forks;
+#[cfg(feature = "rayon")]
+pub use par::ForkMapParallelExt;
+#[cfg(feature = "serde")]
+pub use pool::ForkPool;
+#[cfg(feature = "serde")]
+pub use progress::{fork_map_with_progress, Progress, ProgressUpdate};
+#[cfg(feature = "serde")]
+pub use region::{fork_map_with_region, Pod, SharedRegion};
+#[cfg(feature = "serde")]
+pub use retry::RetryPolicy;
+pub use rng::reseed_child_rng;
+#[cfg(feature = "serde")]
+pub use safe::fork_map_safe;
+#[cfg(feature = "serde")]
+pub use scope::{fork_scope, ForkScope, ScopeResults, ScopedForkHandle};
+#[cfg(all(
+    feature = "serde",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub use seccomp::SeccompProfile;
+#[cfg(feature = "serde")]
+pub use server::{fork_map_via_server, init_fork_server, shutdown_fork_server};
+#[cfg(feature = "serde")]
+pub use signal::Signal;
+#[cfg(feature = "serde")]
+pub use stats::{ChildUsage, ForkStats, ForkTimings};
+#[cfg(feature = "serde")]
+pub use status::set_exit_code;
+pub use status::{
+    interpret_status, ExitOutcome, EXIT_CLOSURE_FAILED, EXIT_CLOSURE_PANICKED, EXIT_PARENT_GONE,
+    EXIT_SEND_FAILED,
+};
+#[cfg(feature = "serde")]
+pub use stream::{fork_map_stream, ForkStream, StreamOutcome, Yielder};
+#[cfg(feature = "serde")]
+pub use transport::Transport;
+
+#[cfg(all(
+    feature = "serde",
+    not(any(feature = "json", feature = "cbor", feature = "msgpack"))
+))]
+compile_error!("the `serde` feature needs a codec: enable `json`, `cbor` or `msgpack`");
+
+/// Whether closures really run in a forked child.
+///
+/// This is `false` on platforms without `fork()`, like Windows, and when the `fallback` feature is
+/// enabled. In that case [`fork_map`] runs the closure in the calling process instead, still
+/// sending its result through the same serialization round-trip so that behavior stays as close
+/// as possible, and options that only make sense for a child process (like
+/// [`ForkBuilder::new_process_group`]) fail with [`ForkError::Unsupported`].
+pub const FORKS: bool = cfg!(all(unix, not(feature = "fallback")));
+
 /// Forks, and runs function F in a child process.
 /// Waits for the child to terminate and returns the result of F.
 ///
@@ -36,7 +216,7 @@ use serde::{Deserialize, Serialize};
 /// use rayon::prelude::*;
 ///
 /// pub fn main() {
-///     let my_big_list = [ /* ... */ ];
+///     let my_big_list = [1u64, 2, 3 /* ... */];
 ///
 ///     // Create a worker pool with rayon's into_par_iter
 ///     let results = my_big_list.into_par_iter().map(|item| {
@@ -54,6 +234,191 @@ use serde::{Deserialize, Serialize};
 /// }
 /// ```
 ///
+/// Results can be much bigger than a pipe's buffer, since the parent reads while the child is
+/// still writing, and only waits for it once it has everything:
+///
+/// ```
+/// use fork_map::fork_map;
+///
+/// let big = unsafe { fork_map(|| Ok(vec![7u8; 10 << 20])) }.unwrap();
+/// assert_eq!(big.len(), 10 << 20);
+/// assert!(big.iter().all(|&byte| byte == 7));
+/// ```
+///
+/// The parent reads the header the result comes with first, and then exactly as many bytes as
+/// it says follow, into a buffer made that size to begin with, rather than into one grown as
+/// they arrive. Whatever the size, none of it is lost or read twice:
+///
+/// ```
+/// use fork_map::fork_map;
+///
+/// // The header, a read's worth, and a pipe's worth, in JSON with its quotes
+/// for boundary in [10, 1 << 16, 1 << 20] {
+///     for len in [boundary - 3, boundary - 2, boundary - 1] {
+///         let text: String = (0..len).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+///         assert_eq!(unsafe { fork_map(|| Ok(text.clone())) }.unwrap(), text);
+///     }
+/// }
+/// ```
+///
+/// Nor does the child need room for all of it at once: the result is serialized straight into
+/// the pipe as the parent reads it, unless it's [compressed](ForkBuilder::compression) or goes
+/// through [shared memory](Transport::SharedMemory), which need it whole.
+///
+/// ```
+/// use fork_map::fork_map;
+/// # if !fork_map::FORKS || !cfg!(target_os = "linux") { return }
+///
+/// let big = unsafe {
+///     fork_map(|| {
+///         let big = vec![255u8; 8 << 20];
+///         // Four times as big in JSON, which is twice what it's allowed on top of that
+///         let statm = std::fs::read_to_string("/proc/self/statm")?;
+///         let pages: u64 = statm.split(' ').next().unwrap().parse()?;
+///         let limit = pages * libc::sysconf(libc::_SC_PAGESIZE) as u64 + (16 << 20);
+///         let limit = libc::rlimit {
+///             rlim_cur: limit,
+///             rlim_max: limit,
+///         };
+///         libc::setrlimit(libc::RLIMIT_AS, &limit);
+///         Ok(big)
+///     })
+/// }
+/// .unwrap();
+/// assert!(big.len() == 8 << 20 && big.iter().all(|&byte| byte == 255));
+/// ```
+///
+/// In the child, the closure runs on a new thread, with as much stack as the main thread, rather
+/// than on the copy of the one that forked, so its thread-locals start out fresh instead of
+/// carrying over whatever state the caller's had (a rayon worker's, say). A panic comes back as
+/// an error rather than unwinding into the copy of the caller:
+///
+/// ```
+/// use fork_map::fork_map;
+/// use std::cell::Cell;
+/// # if !fork_map::FORKS { return }
+///
+/// thread_local!(static DEPTH: Cell<u32> = const { Cell::new(0) });
+/// DEPTH.with(|depth| depth.set(3));
+/// assert_eq!(unsafe { fork_map(|| Ok(DEPTH.with(Cell::get))) }.unwrap(), 0);
+///
+/// let err = unsafe { fork_map(|| -> anyhow::Result<()> { panic!("oh no") }) }.unwrap_err();
+/// assert!(err.to_string().contains("closure panicked: oh no"));
+/// ```
+///
+/// So the closure has to be `Send` and `Sync`, and its result `Send`. One that holds on to
+/// something only the forking thread may use, like its lock on stdout, doesn't compile, rather
+/// than deadlocking in the child:
+///
+/// ```compile_fail
+/// use fork_map::fork_map;
+/// use std::cell::RefCell;
+/// use std::io::Write;
+///
+/// let out = RefCell::new(std::io::stdout().lock());
+/// let _ = unsafe {
+///     fork_map(|| {
+///         writeln!(out.borrow_mut(), "from the child")?;
+///         Ok(())
+///     })
+/// };
+/// ```
+///
+/// A child is waited for by its own pid, so children the rest of the program forked are left
+/// alone, and it works the same in a program that has set `SIGCHLD` to `SIG_IGN` (or handles it
+/// with `SA_NOCLDWAIT`) to have the kernel reap its children for it. That would leave no exit
+/// status to wait for, so while any child of this crate's is running, `SIGCHLD` is put back to
+/// its default, and the program's own setting is restored once the last of them has been
+/// reaped. In the meantime, the program's own children are left as zombies when they exit, like
+/// they would be without the setting, until they're reaped, and it's better not to change
+/// `SIGCHLD` then, since it would be changed back:
+///
+/// ```
+/// use fork_map::{fork_map, ForkError};
+/// # if !fork_map::FORKS { return }
+///
+/// unsafe { libc::signal(libc::SIGCHLD, libc::SIG_IGN) };
+/// assert_eq!(unsafe { fork_map(|| Ok(6 * 7)) }.unwrap(), 42);
+/// // The status isn't lost, either
+/// let err = unsafe { fork_map(|| -> anyhow::Result<()> { std::process::exit(3) }) }.unwrap_err();
+/// match err.downcast_ref() {
+///     Some(&ForkError::ChildFailed { status, .. }) => assert_eq!(libc::WEXITSTATUS(status), 3),
+///     other => panic!("{:?}", other),
+/// }
+/// assert_eq!(unsafe { libc::signal(libc::SIGCHLD, libc::SIG_DFL) }, libc::SIG_IGN);
+/// ```
+///
+/// The closure can call `fork_map` itself, or a library that does, as deep as it likes. Every
+/// child gets pipes of its own and waits for its own children, and what it inherited of its
+/// parent's bookkeeping is left alone, its parent's children being none of its business. Forks
+/// from inside a child don't wait for [`set_max_concurrent_forks`], which is counting the
+/// children of the process that set it:
+///
+/// ```
+/// use fork_map::{fork_map, set_max_concurrent_forks};
+/// # if !fork_map::FORKS { return }
+///
+/// set_max_concurrent_forks(Some(1));
+/// // Each one says who its parent is
+/// let (child, children) = unsafe {
+///     fork_map(|| {
+///         let children = (0..3).map(|_| {
+///             fork_map(|| {
+///                 let grandchildren = (0..3).map(|_| fork_map(|| Ok(libc::getppid())));
+///                 let grandchildren = grandchildren.collect::<anyhow::Result<Vec<_>>>()?;
+///                 Ok((libc::getppid(), libc::getpid(), grandchildren))
+///             })
+///         });
+///         Ok((libc::getpid(), children.collect::<anyhow::Result<Vec<_>>>()?))
+///     })
+/// }
+/// .unwrap();
+///
+/// assert_eq!(children.len(), 3);
+/// for (parent, pid, grandchildren) in children {
+///     assert_eq!(parent, child);
+///     assert_eq!(grandchildren, [pid; 3]);
+/// }
+/// ```
+///
+/// Forking from a program with other threads running, like a rayon worker does, means the child
+/// inherits whatever locks they were holding at the time, with nobody left to give them back.
+/// The allocator looks after its own, and this crate takes the standard output and error locks,
+/// and its own, around every fork in the process, so the child can allocate and print however
+/// busy the other threads were doing the same. With an allocator that doesn't look after its
+/// locks, [`ForkBuilder::preallocate_result`] keeps the child from allocating outside the
+/// closure. Other libraries' locks are still a hazard; see [`fork_map_safe`] and
+/// [`init_fork_server`] for ways around them.
+///
+/// ```
+/// use fork_map::fork_map;
+/// use std::sync::atomic::{AtomicBool, Ordering};
+///
+/// static DONE: AtomicBool = AtomicBool::new(false);
+/// std::thread::scope(|s| {
+///     for _ in 0..4 {
+///         s.spawn(|| {
+///             while !DONE.load(Ordering::Relaxed) {
+///                 // Allocating, under the lock that printing takes
+///                 let stderr = std::io::stderr().lock();
+///                 let lines: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+///                 drop((stderr, lines));
+///             }
+///         });
+///     }
+///     for i in 0..100 {
+///         let sum = unsafe {
+///             fork_map(|| {
+///                 eprint!("");
+///                 Ok((0..1000).map(|i| i.to_string()).collect::<Vec<_>>().len() + i)
+///             })
+///         };
+///         assert_eq!(sum.unwrap(), 1000 + i);
+///     }
+///     DONE.store(true, Ordering::Relaxed);
+/// });
+/// ```
+///
 /// # Safety
 ///
 /// Due to the nature of `fork()`, this function is very unsound and likely violates most of Rust's
@@ -61,59 +426,1167 @@ use serde::{Deserialize, Serialize};
 /// process, even though it calls `exit(0)` after your closure is executed. Any threads other than
 /// the one calling `fork_map` will not be present in the new process, so threaded lifetime
 /// guarantees are also violated. Don't even think about using async executors with this.
+#[cfg(feature = "serde")]
 pub unsafe fn fork_map<F, R>(func: F) -> anyhow::Result<R>
     where
-        F: Fn() -> anyhow::Result<R>,
-        R: Serialize + for<'a> Deserialize<'a>,
-{
-    // Pipe for sending the result from child to parent
-    let mut pipe: [libc::c_int; 2] = [0; 2];
-    libc::pipe(pipe.as_mut_ptr());
-
-    // Here we go
-    let pid = libc::fork();
-    if pid == 0 {
-        // Child
-        libc::close(pipe[0]);
-        let result = func().map_err(|e| serde_error::Error::new(&*e));
-        let ser = serde_json::to_string(&result).unwrap_or("".to_string());
-        libc::write(pipe[1], ser.as_ptr() as *const libc::c_void, ser.len());
-        libc::close(pipe[1]);
-        libc::exit(0);
-    }
-
-    // Parent
-    libc::close(pipe[1]);
-
-    // Read result from pipe
-    let mut des = vec![];
-    let des = loop {
-        const BUF_SIZE: usize = 0x1000;
-        let mut buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
-        let count = libc::read(pipe[0], buf.as_mut_ptr() as *mut libc::c_void, BUF_SIZE);
-        if count < 0 {
-            break Err(anyhow!("io error: {}", std::io::Error::last_os_error()));
-        }
-        des.extend_from_slice(&buf[0..(count as usize)]);
-        // EOF signalled by less than the max bytes
-        if (count as usize) < BUF_SIZE {
-            break Ok(des);
-        }
-    };
-
-    let mut status = 0;
-    libc::waitpid(pid, &mut status, 0);
-
-    if status != 0 {
-        return Err(anyhow!("Process returned non-zero status code {}", status));
-    }
-
-    des.and_then(|des| {
-        serde_json::from_slice::<Result<R, serde_error::Error>>(des.as_slice())
-            .map_err(|e| anyhow!("{}", e))
-            .and_then(|se| match se {
-                Ok(i) => Ok(i),
-                Err(e) => Err(anyhow::Error::from(e)),
-            })
-    })
+        F: Fn() -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run(func)
+}
+
+/// Like [`fork_map`], but the child's result doesn't have to be the type the parent gets back,
+/// only serialize into something that deserializes as it. The child can return a view that's
+/// cheap to build, like a struct of borrowed strings, and the parent gets the owned version.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_into;
+///
+/// let text = "the quick brown fox".to_string();
+/// // Slices of the child's copy of `text` on the way out, fresh strings on the way in
+/// let words: Vec<String> =
+///     unsafe { fork_map_into(|| Ok(text.split(' ').collect::<Vec<&str>>())) }.unwrap();
+/// assert_eq!(words, ["the", "quick", "brown", "fox"]);
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_into<S, D, F>(func: F) -> anyhow::Result<D>
+where
+    F: Fn() -> anyhow::Result<S> + Send + Sync,
+    S: Serialize + Send,
+    D: for<'a> Deserialize<'a>,
+{
+    Fork::builder().run_into(func)
+}
+
+/// Like [`fork_map`], but keeps the closure's own errors apart from the machinery's, the way
+/// [`JoinHandle::join`](std::thread::JoinHandle::join) keeps a thread's panic apart from what it
+/// returned. The outer result is for forking, sending the result back, and the child crashing or
+/// panicking; the inner one is whatever the closure returned, error and all, so telling "it
+/// failed" from "it couldn't run" takes a `match` rather than
+/// [downcasting](ForkError) or matching on messages.
+///
+/// The closure's error type is sent back like its value, so it has to be serializable too, and
+/// arrives as the type it was rather than as a message. Since it's a value as far as the
+/// machinery is concerned, a [retry policy](ForkBuilder::retries) only ever retries the outer
+/// errors.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_nested, ForkError};
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// enum ParseError {
+///     Empty,
+///     NotANumber(String),
+/// }
+///
+/// fn parse(text: &str) -> Result<Result<u64, ParseError>, ForkError> {
+///     unsafe {
+///         fork_map_nested(|| match text {
+///             "" => Err(ParseError::Empty),
+///             text => text.parse().map_err(|_| ParseError::NotANumber(text.to_string())),
+///         })
+///     }
+/// }
+///
+/// fn total(texts: &[&str]) -> Result<u64, ForkError> {
+///     let mut total = 0;
+///     for text in texts {
+///         // Out with any trouble forking, and on past any text that didn't parse
+///         match parse(text)? {
+///             Ok(number) => total += number,
+///             Err(error) => eprintln!("skipping {:?}: {:?}", text, error),
+///         }
+///     }
+///     Ok(total)
+/// }
+///
+/// assert_eq!(total(&["1", "", "two", "3"]).unwrap(), 4);
+/// assert_eq!(parse("two").unwrap(), Err(ParseError::NotANumber("two".to_string())));
+///
+/// # if !fork_map::FORKS { return }
+/// // A crash is the machinery's to report, since there's nothing the closure returned
+/// let crashed = unsafe {
+///     fork_map_nested(|| -> Result<(), ParseError> {
+///         libc::abort();
+///     })
+/// };
+/// assert!(matches!(crashed, Err(ForkError::ChildFailed { .. })));
+/// ```
+///
+/// And in a rayon pool, where the outer errors are usually worth stopping for and the inner ones
+/// are the results:
+///
+/// ```
+/// use fork_map::{fork_map_nested, ForkError};
+/// use rayon::prelude::*;
+///
+/// let results = (0..8u64)
+///     .into_par_iter()
+///     .map(|item| unsafe {
+///         fork_map_nested(|| match item % 3 {
+///             0 => Err(format!("{} is a multiple of three", item)),
+///             _ => Ok(item * 1234),
+///         })
+///     })
+///     .collect::<Result<Vec<_>, ForkError>>()
+///     .expect("every child ran");
+/// assert_eq!(results[1], Ok(1234));
+/// assert_eq!(results[3], Err("3 is a multiple of three".to_string()));
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_nested<F, R, E>(func: F) -> Result<Result<R, E>, ForkError>
+where
+    F: Fn() -> Result<R, E> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+    E: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run_nested(func)
+}
+
+/// Like [`fork_map`], but also returns how long the whole thing took: the wall-clock time from
+/// just before `fork()` until the child was reaped, which includes forking, your closure, and
+/// sending the result back.
+///
+/// Comparing this against the CPU time your closure actually used is a quick way to spot jobs
+/// that spend most of their time blocked.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_timed;
+/// use std::time::Duration;
+///
+/// let (result, elapsed) = unsafe {
+///     fork_map_timed(|| {
+///         std::thread::sleep(Duration::from_millis(50));
+///         Ok(42)
+///     })
+///     .unwrap()
+/// };
+/// assert_eq!(result, 42);
+/// assert!(elapsed >= Duration::from_millis(50));
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_timed<F, R>(func: F) -> anyhow::Result<(R, Duration)>
+where
+    F: Fn() -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run_timed(func)
+}
+
+/// Like [`fork_map`], but `input` is sent to the child explicitly instead of being captured by
+/// the closure, and `func` is called with it there.
+///
+/// The parent serializes `input` with the default [`Codec`] and writes it to a socket after
+/// forking, and the child reads and deserializes it before running `func`. That keeps big inputs
+/// out of what the closure captures, and makes the input one well-defined channel, which is
+/// what a child that doesn't share the parent's memory (say, a freshly exec'd one) would need
+/// anyway. The parent sends the input and reads the result at the same time, so both can be as
+/// big as you like. When [`FORKS`] is `false`, the input still makes the round trip through the
+/// codec.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_with_input;
+///
+/// let input = "a".repeat(100 << 20);
+/// let output = unsafe {
+///     fork_map_with_input(input, |input: String| Ok(input.to_uppercase()))
+/// }
+/// .unwrap();
+/// assert_eq!(output.len(), 100 << 20);
+/// assert!(output.bytes().all(|byte| byte == b'A'));
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_with_input<I, F, R>(input: I, func: F) -> anyhow::Result<R>
+where
+    I: Serialize + for<'a> Deserialize<'a>,
+    F: FnOnce(I) -> anyhow::Result<R> + Send,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run_with_input(input, func)
+}
+
+/// Like [`fork_map_with_input`], but with a plain `fn` rather than a closure, so it can't
+/// capture anything: all the child gets from the caller is `input`, sent over a socket.
+///
+/// This is the least there is to go wrong with forking, since nothing the function uses is
+/// borrowed from the parent's copy of the caller's stack. It still runs in a copy of the whole
+/// process, with statics as they were and other threads' locks as they were held (which the
+/// safety section of [`fork_map`] is mostly about), but it doesn't rely on any of that unless
+/// it goes looking. And because it would work as well in a child that doesn't share any of the
+/// parent's memory, it's the one to use for code that may later move to
+/// [`fork_map_via_server`], which takes the same arguments.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_fn;
+///
+/// fn times_ten(value: u64) -> anyhow::Result<u64> {
+///     // Do some obnoxious operation with `value`, that leaks memory or isn't thread-safe
+///     Ok(value * 10)
+/// }
+///
+/// assert_eq!(unsafe { fork_map_fn(4, times_ten) }.unwrap(), 40);
+/// // Closures that don't capture anything are `fn`s too
+/// let words = unsafe { fork_map_fn("a b c".to_string(), |text| Ok(text.split(' ').count())) };
+/// assert_eq!(words.unwrap(), 3);
+/// ```
+///
+/// # Safety
+///
+/// `func` runs in a forked child, where only the thread that forked exists, so it mustn't
+/// depend on locks or other state another thread may have been in the middle of, like a
+/// library's global lock. The rest of [`fork_map`]'s safety section is about what a closure
+/// captures, which doesn't apply.
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_fn<I, R>(input: I, func: fn(I) -> anyhow::Result<R>) -> anyhow::Result<R>
+where
+    I: Serialize + for<'a> Deserialize<'a>,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run_with_input(input, func)
+}
+
+/// Like [`fork_map`], but the result comes back over a pipe or socket you've opened yourself,
+/// rather than a pipe the crate opens for you: the child writes it to `write`, and the parent
+/// reads it from `read`. That's for fitting in with descriptors you manage anyway, like a pair
+/// from a pool of your own, or one end of a socket with something of yours at the other.
+///
+/// Both descriptors are handed over, and closed by the crate: `write` in the parent right after
+/// forking, and `read` in the child, so that each process is left with only its own end, and
+/// the rest once the result has been read, or whatever stopped it from being. For the parent
+/// to see the end of the result, nothing else can have `write` open. Keeping a copy of it (a
+/// [`try_clone`](OwnedFd::try_clone) held on to, or one inherited by some other child) holds
+/// it open, and the parent waits for the child to exit instead, which works as long as the
+/// whole result is in by then, but is slower. The usual [options](ForkBuilder) apply, via
+/// [`ForkBuilder::run_on_fds`]; a [`Transport`] other than [`Transport::Pipe`] decides where
+/// the result goes like it always does, with the length of a result in shared memory going
+/// over these, and a socket transport's socket ignored in favor of them.
+///
+/// When [`FORKS`] is `false`, the descriptors have nothing to carry, and are just closed.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_on_fds;
+/// use std::os::fd::{FromRawFd, OwnedFd};
+///
+/// let mut fds = [0; 2];
+/// let made = unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) };
+/// assert_eq!(made, 0);
+/// let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+///
+/// let result = unsafe { fork_map_on_fds(read, write, || Ok(vec![6u64 * 7; 100_000])) }.unwrap();
+/// assert!(result.len() == 100_000 && result.iter().all(|&n| n == 42));
+/// // Both closed once it's done
+/// assert_eq!(unsafe { libc::fcntl(fds[0], libc::F_GETFD) }, -1);
+/// assert_eq!(unsafe { libc::fcntl(fds[1], libc::F_GETFD) }, -1);
+///
+/// // With a copy of the write end kept open, it's the child exiting that says it's done
+/// let mut fds = [0; 2];
+/// assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+/// let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+/// let copy = write.try_clone().unwrap();
+/// let result = unsafe { fork_map_on_fds(read, write, || Ok("still fine".to_string())) };
+/// assert_eq!(result.unwrap(), "still fine");
+/// drop(copy);
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(all(feature = "serde", unix))]
+pub unsafe fn fork_map_on_fds<F, R>(read: OwnedFd, write: OwnedFd, func: F) -> anyhow::Result<R>
+where
+    F: Fn() -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run_on_fds(read, write, func)
+}
+
+/// Like [`fork_map`], but the closure also returns the code the child should exit with, and the
+/// parent gets back both the result and the code the child was seen exiting with.
+///
+/// For wrapping code that reports through its exit code as well as its output. A non-zero code
+/// is data here rather than a failure, as long as the child sends its result first: one that
+/// exits with a non-zero code before that, or is killed by a signal, is still
+/// [`ForkError::ChildFailed`]. Exit codes only have 8 bits, so the code is truncated to its
+/// lowest 8. When [`FORKS`] is `false`, the code is passed back as it is.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_with_exit_code, ForkError};
+///
+/// let (lines, code) = unsafe {
+///     fork_map_with_exit_code(|| {
+///         let lines = vec!["warning: deprecated flag".to_string()];
+///         // Succeeded, with warnings
+///         Ok((lines, 2))
+///     })
+///     .unwrap()
+/// };
+/// assert_eq!(lines.len(), 1);
+/// assert_eq!(code, 2);
+///
+/// # if !fork_map::FORKS { return }
+/// // Exiting before sending a result is still a failure
+/// let err = unsafe {
+///     fork_map_with_exit_code(|| -> anyhow::Result<((), i32)> { libc::exit(3) })
+/// }
+/// .unwrap_err();
+/// assert!(matches!(err.downcast_ref(), Some(ForkError::ChildFailed { .. })));
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_with_exit_code<F, R>(func: F) -> anyhow::Result<(R, i32)>
+where
+    F: Fn() -> anyhow::Result<(R, i32)> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run_with_exit_code(func)
+}
+
+/// Like [`fork_map`], but also returns statistics about the run: the CPU time and peak memory
+/// the child used, read from `wait4()` when it was reaped (see [`ChildUsage`] for exactly what's
+/// counted), and when each phase finished (see [`ForkTimings`]). Collecting them costs a few
+/// clock reads, so [`fork_map`] does it too and just throws them away.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_with_stats;
+/// # if !fork_map::FORKS { return }
+///
+/// const SIZE: usize = 100 << 20;
+/// let (len, stats) = unsafe {
+///     fork_map_with_stats(|| {
+///         // Touch every page so it's actually resident
+///         let big = vec![1u8; SIZE];
+///         Ok(big.iter().map(|&b| b as usize).sum::<usize>())
+///     })
+///     .unwrap()
+/// };
+/// assert_eq!(len, SIZE);
+/// assert!(stats.usage.max_rss >= SIZE as u64);
+/// assert!(stats.usage.max_rss < 4 * SIZE as u64);
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_with_stats<F, R>(func: F) -> anyhow::Result<(R, ForkStats)>
+where
+    F: Fn() -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run_with_stats(func)
+}
+
+/// Like [`fork_map`], but runs `func` again in a fresh child if an attempt fails in a way
+/// `policy` considers transient, like the child crashing or being OOM-killed. Errors returned by
+/// `func` itself aren't retried by default. See [`RetryPolicy`].
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_retry, ForkError, RetryPolicy};
+/// use std::time::Duration;
+/// # if !fork_map::FORKS { return }
+///
+/// // Children can't update the parent's memory, so count attempts in a file
+/// let counter = std::env::temp_dir().join(format!("fork-map-retry-{}", std::process::id()));
+/// let flaky = || {
+///     let attempts = std::fs::read_to_string(&counter).map_or(0, |s| s.parse().unwrap()) + 1;
+///     std::fs::write(&counter, attempts.to_string())?;
+///     if attempts < 3 {
+///         // Die the first two times
+///         unsafe { libc::kill(libc::getpid(), libc::SIGKILL) };
+///     }
+///     Ok(attempts)
+/// };
+///
+/// let policy = RetryPolicy::new(3).backoff(Duration::from_millis(10));
+/// assert_eq!(unsafe { fork_map_retry(policy, flaky) }.unwrap(), 3);
+///
+/// std::fs::remove_file(&counter).unwrap();
+/// let err = unsafe { fork_map_retry(RetryPolicy::new(2), flaky) }.unwrap_err();
+/// match err.downcast_ref() {
+///     Some(ForkError::RetriesExhausted { attempts, last }) => {
+///         assert_eq!(*attempts, 2);
+///         assert!(matches!(last.downcast_ref(), Some(ForkError::ChildFailed { .. })));
+///     }
+///     _ => panic!("unexpected error {:?}", err),
+/// }
+/// # std::fs::remove_file(&counter).unwrap();
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_retry<F, R>(policy: RetryPolicy, func: F) -> anyhow::Result<R>
+where
+    F: Fn() -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().retries(policy).run(func)
+}
+
+/// Like [`fork_map`], but flushes buffered standard output first, which you want whenever your
+/// program writes any.
+///
+/// Buffers get copied into the child like all other memory, so text that was printed but not
+/// yet flushed when you fork is in both processes' buffers, and shows up twice once both of them
+/// flush. Rust's `stdout` is only line buffered, so this takes a `print!` without a newline, but
+/// C's stdio (used by any C library you link) is fully buffered when it isn't writing to a
+/// terminal, and the child does flush it on its way out. This flushes Rust's `stdout` and
+/// `stderr` and then calls `fflush(NULL)` for every C stream, right before forking.
+///
+/// The same copying causes the more dangerous problem in [`fork_map`]'s safety section: a lock
+/// that another thread holds at the time of the fork stays locked forever in the child, since the
+/// thread that would unlock it doesn't exist there. That includes the locks around Rust's
+/// `stdout` and the allocator, so the child of a program that is printing or allocating on
+/// another thread can hang on its first `println!` or allocation. Nothing in this crate can
+/// detect that. If you control the other threads, have them stop at a known point (a barrier or
+/// a channel) while you fork; if you use a C library that keeps its own locks, it may provide
+/// `pthread_atfork` handlers for taking and releasing them around `fork()`.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_after_flush;
+///
+/// print!("starting... ");
+/// let result = unsafe { fork_map_after_flush(|| Ok(6 * 7)) }.unwrap();
+/// println!("{}", result);
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_after_flush<F, R>(func: F) -> anyhow::Result<R>
+where
+    F: Fn() -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().flush_stdio(true).run(func)
+}
+
+/// Like [`fork_map`], but fails with [`ForkError::WouldBlock`] right away, without forking,
+/// if the limit set with [`set_max_concurrent_forks`] has already been reached, instead of
+/// waiting for a running child to finish.
+///
+/// This is for schedulers that have better things to do with the thread than wait, like a rayon
+/// worker that could be running other tasks: they can put the job aside and try it again later.
+/// Without a limit, this never fails with `WouldBlock`.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{set_max_concurrent_forks, try_fork_map, Fork, ForkError};
+/// use std::sync::mpsc;
+/// use std::time::Duration;
+/// # if !fork_map::FORKS { return }
+///
+/// set_max_concurrent_forks(Some(1));
+/// let (forked, wait_for_fork) = mpsc::channel();
+/// std::thread::scope(|s| {
+///     // Takes the only slot for a while
+///     s.spawn(|| unsafe {
+///         Fork::builder()
+///             .on_fork(move |_| forked.send(()).unwrap())
+///             .run(|| {
+///                 std::thread::sleep(Duration::from_millis(500));
+///                 Ok(())
+///             })
+///             .unwrap()
+///     });
+///     wait_for_fork.recv().unwrap();
+///     let err = unsafe { try_fork_map(|| Ok(1)) }.unwrap_err();
+///     assert!(matches!(err.downcast_ref(), Some(ForkError::WouldBlock)));
+/// });
+/// // Once it's done, there's room again
+/// assert_eq!(unsafe { try_fork_map(|| Ok(1)) }.unwrap(), 1);
+/// set_max_concurrent_forks(None);
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn try_fork_map<F, R>(func: F) -> anyhow::Result<R>
+where
+    F: Fn() -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().non_blocking(true).run(func)
+}
+
+/// Runs `func` in `n` children at once, passing each one its index, and returns all of their
+/// results in index order.
+///
+/// All `n` children are forked up front, and the parent reads from whichever is ready rather
+/// than waiting on them one at a time, so a slow child doesn't hold up collecting the others.
+/// Each child's success or failure is independent, with errors from your closure wrapped in
+/// [`ForkError::Closure`].
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_n;
+///
+/// // Say, a Monte Carlo simulation seeded from the index (see `reseed_child_rng` for why it
+/// // can't just use a random number generator as is)
+/// let results = unsafe {
+///     fork_map_n(4, |index| {
+///         let seed = (index as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+///         Ok((index, seed % 1000))
+///     })
+/// };
+/// assert_eq!(results.len(), 4);
+/// for (index, result) in results.into_iter().enumerate() {
+///     assert_eq!(result.unwrap().0, index);
+/// }
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_n<F, R>(n: usize, func: F) -> Vec<Result<R, ForkError>>
+where
+    F: Fn(usize) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run_n(n, func)
+}
+
+/// Runs `func` in `n` children at once and returns whichever result comes back first, killing
+/// the other children and reaping them before returning. For hedging against the occasional
+/// child that gets stuck, when running the job more than once is harmless.
+///
+/// A child that fails doesn't end the race; the next one to succeed still wins. Only if they all
+/// fail is the error [`ForkError::AllFailed`], with every child's error. An `n` of 0 is treated
+/// as 1.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_race;
+/// use std::time::{Duration, Instant};
+/// # if !fork_map::FORKS { return }
+///
+/// // The first child to claim the file is quick, the others get stuck
+/// let claim = std::env::temp_dir().join(format!("fork-map-race-{}", std::process::id()));
+/// let start = Instant::now();
+/// let winner = unsafe {
+///     fork_map_race(4, || {
+///         if std::fs::File::options().write(true).create_new(true).open(&claim).is_err() {
+///             std::thread::sleep(Duration::from_secs(60));
+///         }
+///         Ok(std::process::id())
+///     })
+/// }
+/// .unwrap();
+/// assert_ne!(winner, std::process::id());
+/// assert!(start.elapsed() < Duration::from_secs(30));
+/// // None of the losers are left over, not even as zombies
+/// assert_eq!(unsafe { libc::waitpid(-1, std::ptr::null_mut(), libc::WNOHANG) }, -1);
+/// # std::fs::remove_file(&claim).unwrap();
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_race<F, R>(n: usize, func: F) -> anyhow::Result<R>
+where
+    F: Fn() -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run_race(n, func)
+}
+
+/// Runs `func` on each of `items` in a child of its own, with at most `max_concurrent` children
+/// running at a time, and returns the results in the order of the items.
+///
+/// A new child is forked as each one finishes, with the parent multiplexing the children's pipes
+/// from the calling thread rather than a thread per child, and no rayon needed. Every item gets
+/// its own result, so a child that crashes only fails its own slot while the rest of the batch
+/// carries on. Children finish in whatever order they finish in, but each result is stored in
+/// its item's slot, so the output always lines up with the input; use
+/// [`fork_map_for_each_completed`] to handle results in completion order instead.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_batched, ForkError};
+///
+/// let squares = unsafe { fork_map_batched(0..10u64, 3, |n| Ok(n * n)) };
+/// let squares: Vec<u64> = squares.into_iter().collect::<Result<_, _>>().unwrap();
+/// assert_eq!(squares, (0..10).map(|n| n * n).collect::<Vec<_>>());
+///
+/// // Nothing to do, or more room than items, are both fine
+/// assert!(unsafe { fork_map_batched(Vec::<u64>::new(), 3, |n| Ok(n)) }.is_empty());
+/// assert_eq!(unsafe { fork_map_batched([1u8], 64, |n| Ok(n)) }.len(), 1);
+///
+/// # if !fork_map::FORKS { return }
+/// // One child dies while the others are running
+/// let results = unsafe {
+///     fork_map_batched(0..4, 4, |n| {
+///         if n == 1 {
+///             libc::kill(libc::getpid(), libc::SIGKILL);
+///         }
+///         Ok(n)
+///     })
+/// };
+/// assert!(matches!(results[1], Err(ForkError::ChildFailed { .. })));
+/// assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 3);
+/// ```
+///
+/// Even when the last item is the first to finish:
+///
+/// ```
+/// use fork_map::fork_map_batched;
+/// use std::time::Duration;
+///
+/// let sleeps = [400u64, 300, 200, 100, 0];
+/// let results = unsafe {
+///     fork_map_batched(sleeps, sleeps.len(), |ms| {
+///         std::thread::sleep(Duration::from_millis(ms));
+///         Ok(ms)
+///     })
+/// };
+/// let results: Vec<u64> = results.into_iter().map(Result::unwrap).collect();
+/// assert_eq!(results, sleeps);
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_batched<I, F, R>(
+    items: I,
+    max_concurrent: usize,
+    func: F,
+) -> Vec<Result<R, ForkError>>
+where
+    I: IntoIterator,
+    I::Item: Send,
+    F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder()
+        .max_concurrent(max_concurrent)
+        .run_batched(items, func)
+}
+
+/// Like [`fork_map_batched`], but with a child for each chunk of `chunk_size` items rather than
+/// for each item, which `func` gets all at once and returns a result for each of, in the same
+/// order. Forking costs the same however little a child has to do, so for small items, that
+/// spreads it over the whole chunk, while keeping each chunk's memory to itself.
+///
+/// The results come back one for each item, in the order of the items, whichever order the
+/// chunks finish in. The last chunk has whatever's left over, which may be fewer than
+/// `chunk_size`. When a chunk's child fails, or `func` returns an error or the wrong number of
+/// results, every item in that chunk, and only those, gets a [`ForkError::ChunkFailed`] saying
+/// which items those were and why. A `chunk_size` of 0 is treated as 1.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_chunked, ForkError};
+///
+/// let results = unsafe {
+///     fork_map_chunked(0..10u64, 4, |chunk| {
+///         Ok(chunk.into_iter().map(|n| (n * n, std::process::id())).collect())
+///     })
+/// };
+/// let results: Vec<(u64, u32)> = results.into_iter().collect::<Result<_, _>>().unwrap();
+/// let squares: Vec<u64> = results.iter().map(|&(square, _)| square).collect();
+/// assert_eq!(squares, (0..10).map(|n| n * n).collect::<Vec<_>>());
+/// # if fork_map::FORKS {
+/// // Three children, the last with the two left over
+/// let mut pids: Vec<u32> = results.iter().map(|&(_, pid)| pid).collect();
+/// assert!(pids[..4].iter().all(|&pid| pid == pids[0]));
+/// assert!(pids[8..].iter().all(|&pid| pid == pids[8]));
+/// pids.dedup();
+/// assert_eq!(pids.len(), 3);
+/// # }
+///
+/// # if !fork_map::FORKS { return }
+/// // The chunk with 5 in it crashes, which only costs the items that were in it
+/// let results = unsafe {
+///     fork_map_chunked(0..10u64, 4, |chunk| {
+///         if chunk.contains(&5) {
+///             libc::abort();
+///         }
+///         Ok(chunk)
+///     })
+/// };
+/// assert_eq!(results.len(), 10);
+/// for (n, result) in results.iter().enumerate() {
+///     match result {
+///         Ok(value) => assert_eq!(*value, n as u64),
+///         Err(ForkError::ChunkFailed { items, source }) => {
+///             assert_eq!(*items, 4..8);
+///             assert!(matches!(**source, ForkError::ChildFailed { .. }));
+///         }
+///         Err(e) => panic!("unexpected error {:?}", e),
+///     }
+/// }
+/// assert_eq!(results.iter().filter(|result| result.is_err()).count(), 4);
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_chunked<I, F, R>(
+    items: I,
+    chunk_size: usize,
+    func: F,
+) -> Vec<Result<R, ForkError>>
+where
+    I: IntoIterator,
+    I::Item: Send,
+    F: Fn(Vec<I::Item>) -> anyhow::Result<Vec<R>> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run_chunked(items, chunk_size, func)
+}
+
+/// Like [`fork_map_batched`], but calls `on_complete` with each item's index and result as soon
+/// as its child finishes, so you can start on finished results while the rest are still running.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_for_each_completed;
+/// use std::time::Duration;
+///
+/// let sleeps = [300u64, 0];
+/// let mut order = vec![];
+/// unsafe {
+///     fork_map_for_each_completed(
+///         sleeps,
+///         2,
+///         |ms| {
+///             std::thread::sleep(Duration::from_millis(ms));
+///             Ok(ms)
+///         },
+///         |index, result| {
+///             assert_eq!(result.unwrap(), sleeps[index]);
+///             order.push(index);
+///         },
+///     )
+/// };
+/// if fork_map::FORKS {
+///     assert_eq!(order, [1, 0]);
+/// }
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_for_each_completed<I, F, R, C>(
+    items: I,
+    max_concurrent: usize,
+    func: F,
+    on_complete: C,
+) where
+    I: IntoIterator,
+    I::Item: Send,
+    F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+    C: FnMut(usize, Result<R, ForkError>),
+{
+    Fork::builder()
+        .max_concurrent(max_concurrent)
+        .for_each_completed(items, func, on_complete)
+}
+
+/// Runs `func` on each of `items`, each in a child process of its own, and yields the results in
+/// the same order as the items.
+///
+/// Up to [`std::thread::available_parallelism`] children run at once (use
+/// [`ForkBuilder::max_concurrent`] to change that), and a new one is forked for the next item as
+/// each one finishes. Children are only started as you iterate. Each item gets its own result,
+/// so one failing doesn't stop the rest; errors your closure returns come back as
+/// [`ForkError::Closure`]. Since results are yielded in order, one slow item holds back the
+/// results after it; see [`fork_map_iter_unordered`] if you'd rather get them as they're done.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_iter, ForkError};
+///
+/// let results: Vec<_> = unsafe {
+///     fork_map_iter(1..=4u64, |n| {
+///         if n == 3 {
+///             anyhow::bail!("three is right out");
+///         }
+///         Ok(n * 10)
+///     })
+/// }
+/// .collect();
+/// assert_eq!(results[0].as_ref().unwrap(), &10);
+/// assert!(matches!(&results[2], Err(ForkError::Closure(e)) if e.to_string() == "three is right out"));
+/// assert_eq!(results[3].as_ref().unwrap(), &40);
+/// ```
+///
+/// Dropping the iterator before it's done, having found what you were looking for, kills the
+/// children still running (with the builder's [`kill_signal`](ForkBuilder::kill_signal)) and
+/// reaps them, so none are left running or as zombies once it's dropped:
+///
+/// ```
+/// use fork_map::Fork;
+/// use std::time::{Duration, Instant};
+/// # if !fork_map::FORKS { return }
+///
+/// let mut results = unsafe {
+///     Fork::builder().max_concurrent(8).map_iter(0..100u64, |n| {
+///         if n > 0 {
+///             std::thread::sleep(Duration::from_secs(60));
+///         }
+///         Ok(n)
+///     })
+/// };
+/// assert_eq!(results.next().unwrap().unwrap(), 0);
+/// let pids = results.pids();
+/// assert_eq!(pids.len(), 7);
+///
+/// let start = Instant::now();
+/// drop(results);
+/// assert!(start.elapsed() < Duration::from_secs(5));
+/// for pid in pids {
+///     let waited = unsafe { libc::waitpid(pid as i32, std::ptr::null_mut(), libc::WNOHANG) };
+///     assert_eq!(waited, -1);
+/// }
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`], for every call to the iterator's `next`.
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_iter<I, F, R>(items: I, func: F) -> ForkMapIter<I::IntoIter, F, R>
+where
+    I: IntoIterator,
+    I::Item: Send,
+    F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().map_iter(items, func)
+}
+
+/// Like [`fork_map_iter`], but yields `(index, result)` pairs in the order the children finish,
+/// where `index` is the position of the item the result is for, so a slow item doesn't hold back
+/// the others.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::Fork;
+/// use std::time::Duration;
+///
+/// // The first item takes the longest
+/// let sleeps = [300u64, 0];
+/// let order: Vec<usize> = unsafe {
+///     Fork::builder()
+///         .max_concurrent(2)
+///         .map_iter_unordered(sleeps, |ms| {
+///             std::thread::sleep(Duration::from_millis(ms));
+///             Ok(ms)
+///         })
+/// }
+/// .map(|(index, result)| {
+///     assert_eq!(result.unwrap(), sleeps[index]);
+///     index
+/// })
+/// .collect();
+/// if fork_map::FORKS {
+///     assert_eq!(order, [1, 0]);
+/// }
+/// ```
+///
+/// Children are waited for by their own pid, so children the rest of your program started are
+/// left for it to reap, even when they exit in the middle of it all:
+///
+/// ```
+/// use fork_map::Fork;
+/// use std::process::Command;
+/// use std::time::Duration;
+/// # if !fork_map::FORKS { return }
+///
+/// let mut ours = Command::new("true").spawn().unwrap();
+/// let results = unsafe {
+///     Fork::builder()
+///         .timeout(Duration::from_millis(200))
+///         .map_iter_unordered([0u64, 100, 1000], |ms| {
+///             std::thread::sleep(Duration::from_millis(ms));
+///             Ok(ms)
+///         })
+/// };
+/// assert_eq!(results.filter(|(_, r)| r.is_ok()).count(), 2);
+/// assert!(ours.wait().unwrap().success());
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`], for every call to the iterator's `next`.
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_iter_unordered<I, F, R>(
+    items: I,
+    func: F,
+) -> ForkMapIterUnordered<I::IntoIter, F, R>
+where
+    I: IntoIterator,
+    I::Item: Send,
+    F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().map_iter_unordered(items, func)
+}
+
+/// Runs `func` on each of `items` in a child of its own, from a rayon thread pool sized so that
+/// the number of children running at once is the number of threads, and returns the results in
+/// the order of the items. Requires the `rayon` feature.
+///
+/// This packages the `into_par_iter().map(|item| unsafe { fork_map(...) })` pattern, with a pool
+/// of its own so it doesn't oversubscribe (or get starved by) the global one. The pool has
+/// [`std::thread::available_parallelism`] threads; use [`ForkBuilder::max_concurrent`] with
+/// [`ForkBuilder::run_par`] to pick the size, or [`fork_map_par_in`] to use a pool you already
+/// have.
+///
+/// Every item gets a result of its own, whatever happened to the others, so a batch where some
+/// items are expected to fail can use what succeeded and look into what didn't afterwards.
+/// Errors from `func` are wrapped in [`ForkError::Closure`], and every error in a
+/// [`ForkError::Job`] whose [`ForkId`] is the item's position in `items`, which says which item
+/// it came from wherever the error ends up. Collect the results into
+/// `Result<Vec<R>, ForkError>` to give up on the whole batch at the first failure instead.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_par, ForkError};
+///
+/// let results = unsafe { fork_map_par(vec![1u64, 2, 3, 4, 5], |n| Ok(n * 1234)) };
+/// let results: Vec<u64> = results.into_iter().collect::<Result<_, ForkError>>().unwrap();
+/// assert_eq!(results, [1234, 2468, 3702, 4936, 6170]);
+///
+/// # if !fork_map::FORKS { return }
+/// // One item failing, or its child crashing, doesn't stop the rest
+/// let results = unsafe {
+///     fork_map_par(0..6u32, |n| {
+///         anyhow::ensure!(n != 1, "no ones");
+///         if n == 4 {
+///             libc::kill(libc::getpid(), libc::SIGKILL);
+///         }
+///         Ok(n * 10)
+///     })
+/// };
+/// let err = results[1].as_ref().unwrap_err();
+/// assert!(matches!(err.inner(), ForkError::Closure(e) if e.to_string() == "no ones"));
+/// assert_eq!(err.to_string(), "fork 1: no ones");
+/// let err = results[4].as_ref().unwrap_err();
+/// assert!(matches!(err.inner(), ForkError::ChildFailed { .. }));
+/// assert_eq!(err.id().unwrap().as_str(), "4");
+/// let succeeded: Vec<u32> = results.into_iter().filter_map(Result::ok).collect();
+/// assert_eq!(succeeded, [0, 20, 30, 50]);
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "rayon")]
+pub unsafe fn fork_map_par<I, F, R>(items: I, func: F) -> Vec<Result<R, ForkError>>
+where
+    I: rayon::iter::IntoParallelIterator + Send,
+    I::Iter: rayon::iter::IndexedParallelIterator,
+    I::Item: Send,
+    F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run_par(items, func)
+}
+
+/// Like [`fork_map_par`], but runs the jobs from `pool`, so there are as many children running
+/// at once as `pool` has threads. Requires the `rayon` feature.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_par_in;
+///
+/// let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+/// let results = unsafe { fork_map_par_in(&pool, 0..8u32, |n| Ok(n + 1)) };
+/// assert_eq!(results.len(), 8);
+/// assert_eq!(*results[7].as_ref().unwrap(), 8);
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "rayon")]
+pub unsafe fn fork_map_par_in<I, F, R>(
+    pool: &rayon::ThreadPool,
+    items: I,
+    func: F,
+) -> Vec<Result<R, ForkError>>
+where
+    I: rayon::iter::IntoParallelIterator + Send,
+    I::Iter: rayon::iter::IndexedParallelIterator,
+    I::Item: Send,
+    F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run_par_in(pool, items, func)
+}
+
+/// Runs `func` on each of `items` in a child of its own, with at most `max_concurrent` running
+/// at a time, and sends each result down the returned channel as soon as its child finishes,
+/// along with the index of its item.
+///
+/// The children are started and waited on from a coordinator thread, so this returns right
+/// away. The channel is closed once every item's result has been sent. If you drop the receiver
+/// early, the coordinator notices the next time a child finishes: it stops starting new children,
+/// kills the ones still running, and reaps them.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_channel;
+///
+/// let results = unsafe { fork_map_channel(0..6u64, 3, |n| Ok(n * n)) };
+/// let mut squares = vec![0; 6];
+/// for (index, result) in results {
+///     squares[index] = result.unwrap();
+/// }
+/// assert_eq!(squares, [0, 1, 4, 9, 16, 25]);
+///
+/// // Only need the first one
+/// let results = unsafe {
+///     fork_map_channel(0..100u64, 4, |n| {
+///         std::thread::sleep(std::time::Duration::from_millis(20));
+///         Ok(n)
+///     })
+/// };
+/// let (_, first) = results.recv().unwrap();
+/// assert!(first.is_ok());
+/// drop(results);
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_channel<I, F, R>(
+    items: I,
+    max_concurrent: usize,
+    func: F,
+) -> std::sync::mpsc::Receiver<(usize, Result<R, ForkError>)>
+where
+    I: IntoIterator,
+    I::IntoIter: Send + 'static,
+    I::Item: Send,
+    F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync + 'static,
+    R: Serialize + for<'a> Deserialize<'a> + Send + 'static,
+{
+    Fork::builder()
+        .max_concurrent(max_concurrent)
+        .map_channel(items, func)
+}
+
+/// Like [`fork_map_channel`], but sends the results to a channel you already have, and does the
+/// work from the calling thread, returning once every child has been reaped.
+///
+/// For feeding results into an existing pipeline, with a consumer on another thread handling
+/// them as they come in. `sender` is dropped on return, so unless you kept a clone, the consumer
+/// sees the channel close after the last result. If the receiver goes away early, the remaining
+/// children are killed and reaped instead of being waited for, and nothing panics.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_to_sender, ForkError};
+/// use std::sync::mpsc;
+///
+/// let (sender, receiver) = mpsc::channel::<(usize, Result<u64, ForkError>)>();
+/// let consumer = std::thread::spawn(move || {
+///     let mut total = 0;
+///     for (_, result) in receiver {
+///         total += result.unwrap();
+///     }
+///     total
+/// });
+/// unsafe { fork_map_to_sender(1..=10u64, 4, |n| Ok(n * n), sender) };
+/// assert_eq!(consumer.join().unwrap(), 385);
+///
+/// // A consumer that gives up after the first result
+/// let (sender, receiver) = mpsc::channel::<(usize, Result<u64, ForkError>)>();
+/// let consumer = std::thread::spawn(move || receiver.recv().unwrap());
+/// unsafe {
+///     fork_map_to_sender(
+///         0..100u64,
+///         4,
+///         |n| {
+///             std::thread::sleep(std::time::Duration::from_millis(20));
+///             Ok(n)
+///         },
+///         sender,
+///     )
+/// };
+/// assert!(consumer.join().unwrap().1.is_ok());
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`].
+#[cfg(feature = "serde")]
+pub unsafe fn fork_map_to_sender<I, F, R>(
+    items: I,
+    max_concurrent: usize,
+    func: F,
+    sender: std::sync::mpsc::Sender<(usize, Result<R, ForkError>)>,
+) where
+    I: IntoIterator,
+    I::Item: Send,
+    F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder()
+        .max_concurrent(max_concurrent)
+        .map_to_sender(items, func, sender)
 }
@@ -0,0 +1,185 @@
+//! The process-wide cap on running children, see [`set_max_concurrent_forks`].
+//!
+//! Callers wait their turn in the order they arrived, using a ticket like a deli counter, so a
+//! steady stream of new callers can't starve one that has been waiting. A permit is taken right
+//! before `fork()` and given back once the child has been reaped.
+
+// Without fork() there's nothing to limit, but the setting still exists
+#![cfg_attr(any(not(unix), feature = "fallback"), allow(dead_code))]
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+pub(crate) struct State {
+    limit: Option<usize>,
+    /// Permits currently handed out.
+    active: usize,
+    /// The ticket the next caller to arrive gets.
+    next_ticket: u64,
+    /// The ticket whose turn it is.
+    serving: u64,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    limit: None,
+    active: 0,
+    next_ticket: 0,
+    serving: 0,
+});
+static TURN: Condvar = Condvar::new();
+
+thread_local! {
+    /// Whether this thread is between taking a permit and handing its child over, which is when
+    /// callbacks like `on_fork` run.
+    static FORKING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether this process is a child of this crate's, which has its own process to fork from, and
+/// a copy of [`STATE`] counting its parent's children, maybe locked by a thread it doesn't have.
+static CHILD: AtomicBool = AtomicBool::new(false);
+
+/// Caps how many children every `fork_map` API in this process combined may have running at
+/// once, or lifts the cap with `None`, which is the default.
+///
+/// Forking a large parent briefly commits a lot of memory, so a burst of forks from a big thread
+/// pool can fail with `EAGAIN` on a machine that's tight on memory even though every child on its
+/// own would be fine. With a limit, callers past it block until a running child has been reaped,
+/// and are let through in the order they arrived. A limit of 0 is treated as 1. To fail instead
+/// of blocking, see [`try_fork_map`](crate::try_fork_map).
+///
+/// Children already running when the limit is set count against it. Forks made from inside a
+/// child, or from an [`on_fork`](crate::ForkBuilder::on_fork) callback, don't wait, since that
+/// could wait forever on the very child that's being set up.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_bytes, set_max_concurrent_forks};
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::time::Duration;
+/// # if !fork_map::FORKS { return }
+///
+/// // Shared with the children, to see how many are running at once
+/// let counters = unsafe {
+///     libc::mmap(
+///         std::ptr::null_mut(),
+///         2 * std::mem::size_of::<AtomicUsize>(),
+///         libc::PROT_READ | libc::PROT_WRITE,
+///         libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+///         -1,
+///         0,
+///     )
+/// };
+/// assert_ne!(counters, libc::MAP_FAILED);
+/// let (running, peak) = unsafe {
+///     let counters = counters as *const AtomicUsize;
+///     (&*counters, &*counters.add(1))
+/// };
+///
+/// set_max_concurrent_forks(Some(4));
+/// std::thread::scope(|s| {
+///     for _ in 0..200 {
+///         s.spawn(|| unsafe {
+///             fork_map_bytes(|| {
+///                 let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+///                 peak.fetch_max(now, Ordering::SeqCst);
+///                 std::thread::sleep(Duration::from_millis(2));
+///                 running.fetch_sub(1, Ordering::SeqCst);
+///                 vec![]
+///             })
+///             .unwrap()
+///         });
+///     }
+/// });
+/// assert!(peak.load(Ordering::SeqCst) <= 4);
+/// ```
+pub fn set_max_concurrent_forks(limit: Option<usize>) {
+    state().limit = limit.map(|limit| limit.max(1));
+    // Whoever's turn it is may be able to go now
+    TURN.notify_all();
+}
+
+/// A claim on one of the running children allowed by the limit, given back when dropped.
+pub(crate) struct Permit {
+    counted: bool,
+}
+
+/// Waits until there's room for another child under the limit.
+pub(crate) fn acquire() -> Permit {
+    if exempt() {
+        return Permit { counted: false };
+    }
+    let mut state = state();
+    let ticket = state.next_ticket;
+    state.next_ticket += 1;
+    while ticket != state.serving || state.limit.is_some_and(|limit| state.active >= limit) {
+        state = TURN.wait(state).unwrap_or_else(|e| e.into_inner());
+    }
+    state.serving += 1;
+    state.active += 1;
+    // Let the next in line check whether there's room for them too
+    TURN.notify_all();
+    Permit { counted: true }
+}
+
+/// Like [`acquire`], but gives up right away rather than waiting, whether for room under the
+/// limit or for callers that were already waiting for it.
+#[cfg(feature = "serde")]
+pub(crate) fn try_acquire() -> Option<Permit> {
+    if exempt() {
+        return Some(Permit { counted: false });
+    }
+    let mut state = state();
+    if state.next_ticket != state.serving || state.limit.is_some_and(|limit| state.active >= limit)
+    {
+        return None;
+    }
+    // Takes a ticket and is served in one go, so nobody waiting can tell
+    state.next_ticket += 1;
+    state.serving += 1;
+    state.active += 1;
+    Some(Permit { counted: true })
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if self.counted {
+            state().active -= 1;
+            TURN.notify_all();
+        }
+    }
+}
+
+fn exempt() -> bool {
+    FORKING.with(Cell::get) || CHILD.load(Ordering::SeqCst)
+}
+
+/// Runs in a new child, right after it's forked.
+pub(crate) fn forked() {
+    CHILD.store(true, Ordering::SeqCst);
+}
+
+/// Marks this thread as in the middle of forking until dropped.
+pub(crate) struct Forking {
+    was: bool,
+}
+
+impl Forking {
+    pub(crate) fn enter() -> Self {
+        Forking {
+            was: FORKING.with(|forking| forking.replace(true)),
+        }
+    }
+}
+
+impl Drop for Forking {
+    fn drop(&mut self) {
+        FORKING.with(|forking| forking.set(self.was));
+    }
+}
+
+pub(crate) fn state() -> MutexGuard<'static, State> {
+    // Nothing panics while holding the lock, but don't take everyone down if it somehow did
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
@@ -0,0 +1,138 @@
+//! Waiting on several children at once, for the APIs that run more than one.
+//!
+//! The result pipes are multiplexed with `poll()` from the calling thread, and each child is
+//! reaped by its own pid once its pipe hits EOF, so children the rest of the program forked are
+//! never touched. Timeouts are no exception: rather than polling `waitpid(-1, WNOHANG)` to see
+//! who exited, we only ever poll the pids of the children we're tracking here.
+
+#[cfg(all(unix, not(feature = "fallback")))]
+use std::time::Instant;
+
+use serde::Deserialize;
+
+#[cfg(all(unix, not(feature = "fallback")))]
+use crate::sys;
+use crate::{ForkHandle, ForkStats};
+
+/// The children currently running, each tagged with a key (like its input's index).
+pub(crate) struct Running<K, R> {
+    slots: Vec<Slot<K, R>>,
+}
+
+struct Slot<K, R> {
+    key: K,
+    handle: ForkHandle<R>,
+}
+
+impl<K, R> Running<K, R> {
+    pub(crate) fn new() -> Self {
+        Running { slots: vec![] }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub(crate) fn push(&mut self, key: K, handle: ForkHandle<R>) {
+        self.slots.push(Slot { key, handle });
+    }
+
+    /// The pids of the children still running, or yet to be waited for.
+    pub(crate) fn pids(&self) -> Vec<u32> {
+        self.slots.iter().map(|slot| slot.handle.pid()).collect()
+    }
+
+    /// Kills every child still running. They're reaped when dropped.
+    pub(crate) fn kill_all(&self) {
+        #[cfg(all(unix, not(feature = "fallback")))]
+        for slot in &self.slots {
+            slot.handle.kill();
+        }
+    }
+}
+
+impl<K, R> Running<K, R>
+where
+    R: for<'a> Deserialize<'a>,
+{
+    /// Waits for whichever child finishes first and returns its result, or `None` if nothing is
+    /// running.
+    pub(crate) fn next(&mut self) -> Option<(K, anyhow::Result<(R, ForkStats)>)> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        #[cfg(all(unix, not(feature = "fallback")))]
+        {
+            Some(self.next_forked())
+        }
+        #[cfg(any(not(unix), feature = "fallback"))]
+        {
+            // Ran in process, so they're all done already
+            let slot = self.slots.remove(0);
+            Some((slot.key, slot.handle.join_with_stats()))
+        }
+    }
+
+    #[cfg(all(unix, not(feature = "fallback")))]
+    fn next_forked(&mut self) -> (K, anyhow::Result<(R, ForkStats)>) {
+        // Inline handles are done already
+        if let Some(i) = self.slots.iter().position(|s| s.handle.pipe().is_none()) {
+            let slot = self.slots.remove(i);
+            return (slot.key, slot.handle.join_with_stats());
+        }
+        loop {
+            // Whoever runs out of time first also decides how long we can wait
+            let deadline = self.slots.iter().filter_map(|s| s.handle.deadline()).min();
+            if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                let i = self
+                    .slots
+                    .iter()
+                    .position(|s| s.handle.deadline() == deadline);
+                let slot = self.slots.remove(i.unwrap());
+                return (slot.key, slot.handle.time_out());
+            }
+            let mut fds: Vec<libc::pollfd> = self
+                .slots
+                .iter()
+                .map(|slot| libc::pollfd {
+                    fd: slot.handle.pipe().unwrap(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                })
+                .collect();
+            // And whoever's memory is due to be checked first, how long we can go without
+            let check_at = self
+                .slots
+                .iter()
+                .filter_map(|s| s.handle.rss_check_at())
+                .min();
+            let wake_at = match (deadline, check_at) {
+                (Some(deadline), Some(check_at)) => Some(deadline.min(check_at)),
+                (deadline, check_at) => deadline.or(check_at),
+            };
+            if let Err(e) = unsafe { sys::poll(&mut fds, wake_at) } {
+                // Can't tell who's ready, so give up on one of them rather than spinning
+                let slot = self.slots.remove(0);
+                return (slot.key, slot.handle.finish(Err(e)));
+            }
+            for i in 0..self.slots.len() {
+                if let Err(e) = self.slots[i].handle.check_rss() {
+                    let slot = self.slots.remove(i);
+                    return (slot.key, slot.handle.finish(Err(e)));
+                }
+            }
+            for (i, fd) in fds.iter().enumerate() {
+                if fd.revents == 0 {
+                    continue;
+                }
+                let finished = match self.slots[i].handle.read_some() {
+                    Ok(true) => Ok(()),
+                    Ok(false) => continue,
+                    Err(e) => Err(e),
+                };
+                let slot = self.slots.remove(i);
+                return (slot.key, slot.handle.finish(finished));
+            }
+        }
+    }
+}
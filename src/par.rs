@@ -0,0 +1,167 @@
+//! Running jobs from a rayon thread pool, one child per job.
+
+use rayon::iter::Map;
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use serde::{Deserialize, Serialize};
+
+use crate::{Fork, ForkBuilder, ForkError, ForkId};
+
+impl ForkBuilder {
+    /// Runs `func` on each of `items` in a child of its own, from a rayon thread pool with one
+    /// thread per allowed child, and returns the results in the order of the items, each on its
+    /// own. See [`fork_map_par`](crate::fork_map_par).
+    ///
+    /// The pool has [`max_concurrent`](Self::max_concurrent) threads, which defaults to
+    /// [`std::thread::available_parallelism`].
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_par<I, F, R>(self, items: I, func: F) -> Vec<Result<R, ForkError>>
+    where
+        I: IntoParallelIterator + Send,
+        I::Iter: IndexedParallelIterator,
+        F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        let threads = self
+            .max_concurrent
+            .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+            .unwrap_or(1);
+        match rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+            Ok(pool) => self.run_par_in(&pool, items, func),
+            // Better oversubscribed than not at all
+            Err(_) => self.run_par_here(items, func),
+        }
+    }
+
+    /// Like [`run_par`](Self::run_par), but runs the jobs from `pool`, so the number of
+    /// children running at once is the number of threads in `pool`.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_par_in<I, F, R>(
+        self,
+        pool: &ThreadPool,
+        items: I,
+        func: F,
+    ) -> Vec<Result<R, ForkError>>
+    where
+        I: IntoParallelIterator + Send,
+        I::Iter: IndexedParallelIterator,
+        F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        pool.install(|| self.run_par_here(items, func))
+    }
+
+    /// Runs the jobs from whichever pool we're in, tagging each one's error with the
+    /// [`id`](Self::id), or the item's position if there isn't one.
+    unsafe fn run_par_here<I, F, R>(mut self, items: I, func: F) -> Vec<Result<R, ForkError>>
+    where
+        I: IntoParallelIterator + Send,
+        I::Iter: IndexedParallelIterator,
+        F: Fn(I::Item) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        // Taken off, so the error isn't tagged twice
+        let id = self.id.take();
+        items
+            .into_par_iter()
+            .enumerate()
+            .map(|(index, item)| {
+                // Safety: promised by our caller
+                unsafe { self.run_item(&func, item) }.map_err(|e| ForkError::Job {
+                    id: ForkId(id.clone().unwrap_or_else(|| index.to_string())),
+                    source: Box::new(ForkError::from_anyhow(e)),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Adds [`fork_map`](Self::fork_map) to rayon's parallel iterators. Requires the `rayon`
+/// feature.
+///
+/// This packages the `into_par_iter().map(|item| unsafe { fork_map(...) })` pattern: each item
+/// is moved into a child of its own, from whichever rayon thread picks it up, so there are as
+/// many children running at once as the pool has threads. It's a plain `map` underneath, so
+/// indexed iterators stay indexed and `collect` keeps the order of the items.
+///
+/// Each item gets a `Result<R, ForkError>`, with errors from your closure wrapped in
+/// [`ForkError::Closure`]. Collect into `Vec<Result<R, ForkError>>` to get every item's result,
+/// or into `Result<Vec<R>, ForkError>` to stop at the first error; rayon then stops handing out
+/// items that haven't started yet, but children that are already running still finish.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{ForkError, ForkMapParallelExt};
+/// use rayon::prelude::*;
+///
+/// let pool = rayon::ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+/// pool.install(|| {
+///     let doubled: Result<Vec<u64>, ForkError> =
+///         unsafe { (0..20u64).into_par_iter().fork_map(|n| Ok(n * 2)) }.collect();
+///     assert_eq!(doubled.unwrap(), (0..20).map(|n| n * 2).collect::<Vec<_>>());
+///
+///     # if !fork_map::FORKS { return }
+///     // One child crashes, the rest are unaffected
+///     let results: Vec<Result<u64, ForkError>> = unsafe {
+///         (0..20u64).into_par_iter().fork_map(|n| {
+///             if n == 7 {
+///                 libc::kill(libc::getpid(), libc::SIGKILL);
+///             }
+///             Ok(n)
+///         })
+///     }
+///     .collect();
+///     assert!(matches!(results[7], Err(ForkError::ChildFailed { .. })));
+///     for (n, result) in results.iter().enumerate().filter(|&(n, _)| n != 7) {
+///         assert_eq!(*result.as_ref().unwrap(), n as u64);
+///     }
+/// });
+/// ```
+pub trait ForkMapParallelExt: ParallelIterator {
+    /// Runs `func` on each item in a child process of its own. See the
+    /// [trait documentation](ForkMapParallelExt).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map), for every item: each child is forked from a rayon
+    /// worker thread, and the pool's other threads don't exist in it.
+    unsafe fn fork_map<F, R>(
+        self,
+        func: F,
+    ) -> Map<Self, impl Fn(Self::Item) -> Result<R, ForkError> + Send + Sync>
+    where
+        F: Fn(Self::Item) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        self.fork_map_with(Fork::builder(), func)
+    }
+
+    /// Like [`fork_map`](Self::fork_map), with each child configured by `builder`.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](Self::fork_map).
+    unsafe fn fork_map_with<F, R>(
+        self,
+        builder: ForkBuilder,
+        func: F,
+    ) -> Map<Self, impl Fn(Self::Item) -> Result<R, ForkError> + Send + Sync>
+    where
+        F: Fn(Self::Item) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        self.map(move |item| {
+            // Safety: promised by our caller
+            unsafe { builder.run_item(&func, item) }.map_err(ForkError::from_anyhow)
+        })
+    }
+}
+
+impl<I: ParallelIterator> ForkMapParallelExt for I {}
@@ -0,0 +1,90 @@
+//! Stopping a crashing child in its tracks, so a debugger can be attached before it's gone.
+
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// Setting this to `1` has every child pause on crashing, as if
+/// [`pause_on_crash`](crate::ForkBuilder::pause_on_crash) was set.
+pub(crate) const ENV_VAR: &str = "FORK_MAP_PAUSE_ON_CRASH";
+
+/// The signals a crash usually ends in.
+const FATAL_SIGNALS: [libc::c_int; 5] = [
+    libc::SIGSEGV,
+    libc::SIGBUS,
+    libc::SIGILL,
+    libc::SIGFPE,
+    libc::SIGABRT,
+];
+
+/// The start of the message, with the pid already in it, since formatting isn't safe in a signal
+/// handler.
+static PREFIX: AtomicPtr<u8> = AtomicPtr::new(std::ptr::null_mut());
+static PREFIX_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the environment asks for it, checked in the parent, since reading the environment
+/// right after `fork()` can deadlock.
+pub(crate) fn env_enabled() -> bool {
+    std::env::var_os(ENV_VAR).is_some_and(|value| value == "1")
+}
+
+/// Runs in the child: installs a handler for the fatal signals that stops the child with
+/// `SIGSTOP`, after saying so on standard error. Once it's continued, it dies of the original
+/// signal as it would have.
+pub(crate) unsafe fn install() {
+    let prefix = format!("fork_map: child {} got signal ", libc::getpid()).into_bytes();
+    let prefix = prefix.leak();
+    PREFIX_LEN.store(prefix.len(), Ordering::SeqCst);
+    PREFIX.store(prefix.as_mut_ptr(), Ordering::SeqCst);
+
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = on_fatal_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // Back to the default once it has fired, so the signal can finish the job. On the alternate
+    // stack if there is one, or a stack overflow would have nowhere to run this.
+    action.sa_flags = libc::SA_RESETHAND | libc::SA_ONSTACK;
+    libc::sigemptyset(&mut action.sa_mask);
+    for signal in FATAL_SIGNALS {
+        libc::sigaction(signal, &action, std::ptr::null_mut());
+    }
+}
+
+extern "C" fn on_fatal_signal(signal: libc::c_int) {
+    unsafe {
+        let prefix = std::slice::from_raw_parts(
+            PREFIX.load(Ordering::SeqCst),
+            PREFIX_LEN.load(Ordering::SeqCst),
+        );
+        write_stderr(prefix);
+        let mut digits = [0u8; 12];
+        write_stderr(format_int(signal, &mut digits));
+        write_stderr(b", stopped so a debugger can attach (send SIGCONT to let it die)\n");
+        libc::raise(libc::SIGSTOP);
+        // Blocked until we return, and then fatal
+        libc::raise(signal);
+    }
+}
+
+unsafe fn write_stderr(bytes: &[u8]) {
+    libc::write(
+        libc::STDERR_FILENO,
+        bytes.as_ptr() as *const libc::c_void,
+        bytes.len(),
+    );
+}
+
+/// Formats `value` into the end of `buf` without allocating.
+fn format_int(value: libc::c_int, buf: &mut [u8; 12]) -> &[u8] {
+    let mut start = buf.len();
+    let mut rest = value.unsigned_abs();
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    if value < 0 {
+        start -= 1;
+        buf[start] = b'-';
+    }
+    &buf[start..]
+}
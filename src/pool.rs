@@ -0,0 +1,428 @@
+//! Long-lived worker children that run many jobs each.
+//!
+//! Each worker is connected to the parent by a socket pair. For every job, the parent sends the
+//! input as a little-endian `u64` length followed by the input encoded with the builder's
+//! [`Codec`](crate::Codec), and the worker answers with the same frame a one-off child sends
+//! (see `protocol`), which says its own length, so the socket stays usable for the next job.
+
+use std::fmt;
+#[cfg(all(unix, not(feature = "fallback")))]
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(all(unix, not(feature = "fallback")))]
+use crate::{forked, protocol, sys, ForkError};
+use crate::{Fork, ForkBuilder};
+
+/// A fixed set of worker children, forked up front, that run jobs through a `handler` function
+/// without forking for each one.
+///
+/// Forking a parent with a large address space takes a while, mostly spent copying page tables,
+/// which can dwarf a small job. A pool pays that once per worker: create it early, while the
+/// parent is still small, and each [`run`](Self::run) only costs a round-trip over a socket.
+/// The price is that a job can't be a closure capturing whatever it likes, since it has to be
+/// sent to a process that already exists. Instead, the pool has a plain `fn` handler, and each
+/// job is an input it's called with, serialized on the way there.
+///
+/// Each call to `run` takes an idle worker, waiting for one if they're all busy, so the pool can
+/// be shared between threads to keep all of its workers busy. Workers keep whatever state the
+/// handler leaves behind (leaked memory included) from one job to the next. A handler that
+/// panics fails only that job, with the panic message, and the worker carries on. A worker that
+/// crashes fails only the job it was running, with [`ForkError::ChildFailed`], and is replaced
+/// by a fresh one forked from the parent as it is by then. Dropping the pool kills and reaps the
+/// workers.
+///
+/// When [`FORKS`](crate::FORKS) is `false`, or the builder is
+/// [`inline`](ForkBuilder::inline), there are no workers and each job runs in the calling
+/// process instead.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{ForkError, ForkPool};
+///
+/// fn parse(input: String) -> anyhow::Result<u64> {
+///     if input == "crash" {
+///         unsafe { libc::kill(libc::getpid(), libc::SIGKILL) };
+///     }
+///     if input == "panic" {
+///         panic!("asked to");
+///     }
+///     Ok(input.parse()?)
+/// }
+///
+/// let pool = unsafe { ForkPool::new(2, parse) }.unwrap();
+/// assert_eq!(pool.run("42".to_string()).unwrap(), 42);
+/// assert!(pool.run("forty-two".to_string()).is_err());
+///
+/// // From several threads at once
+/// std::thread::scope(|s| {
+///     for n in 0..8u64 {
+///         let pool = &pool;
+///         s.spawn(move || assert_eq!(pool.run(n.to_string()).unwrap(), n));
+///     }
+/// });
+///
+/// # if !fork_map::FORKS { return }
+/// let pids = pool.pids();
+/// let err = pool.run("crash".to_string()).unwrap_err();
+/// assert!(matches!(err.downcast_ref(), Some(ForkError::ChildFailed { .. })));
+/// // The crashed worker was replaced
+/// assert_eq!(pool.run("7".to_string()).unwrap(), 7);
+/// assert_eq!(pool.pids().len(), 2);
+/// assert_ne!(pool.pids(), pids);
+///
+/// // A panic is only an error, from a worker that's still there
+/// let pids = pool.pids();
+/// let err = pool.run("panic".to_string()).unwrap_err();
+/// assert!(err.to_string().contains("asked to"), "{}", err);
+/// assert_eq!(pool.pids(), pids);
+/// assert_eq!(pool.run("8".to_string()).unwrap(), 8);
+/// ```
+///
+/// # Several kinds of jobs
+///
+/// One pool can run jobs of different kinds: make the input an enum with a variant per kind,
+/// and the handler a function that dispatches on it. If the kinds return different things, so
+/// can the output.
+///
+/// ```
+/// use fork_map::ForkPool;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// enum Task {
+///     Hash(Vec<u8>),
+///     Resize { width: u32, height: u32, scale: u32 },
+/// }
+///
+/// #[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// enum Output {
+///     Hash(u64),
+///     Size(u32, u32),
+/// }
+///
+/// fn run(task: Task) -> anyhow::Result<Output> {
+///     Ok(match task {
+///         Task::Hash(bytes) => Output::Hash(bytes.iter().map(|&b| b as u64).sum()),
+///         Task::Resize { width, height, scale } => {
+///             anyhow::ensure!(scale > 0, "can't scale by zero");
+///             Output::Size(width / scale, height / scale)
+///         }
+///     })
+/// }
+///
+/// let pool = unsafe { ForkPool::new(2, run) }.unwrap();
+/// assert_eq!(pool.run(Task::Hash(vec![1, 2, 3])).unwrap(), Output::Hash(6));
+/// let resize = Task::Resize { width: 640, height: 480, scale: 2 };
+/// assert_eq!(pool.run(resize).unwrap(), Output::Size(320, 240));
+/// let resize = Task::Resize { width: 640, height: 480, scale: 0 };
+/// assert_eq!(pool.run(resize).unwrap_err().to_string(), "can't scale by zero");
+/// ```
+pub struct ForkPool<I, R> {
+    builder: ForkBuilder,
+    handler: fn(I) -> anyhow::Result<R>,
+    size: usize,
+    #[cfg(all(unix, not(feature = "fallback")))]
+    workers: Mutex<Workers>,
+    #[cfg(all(unix, not(feature = "fallback")))]
+    returned: Condvar,
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+struct Workers {
+    idle: Vec<Worker>,
+    /// Workers alive, whether idle or busy.
+    alive: usize,
+}
+
+impl<I, R> ForkPool<I, R>
+where
+    I: Serialize + for<'a> Deserialize<'a>,
+    R: Serialize + for<'a> Deserialize<'a>,
+{
+    /// Forks `size` workers (at least 1) that run jobs with `handler`.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map), for each worker. Replacements for crashed workers
+    /// are forked from whichever thread calls [`run`](Self::run) next, so this also applies to
+    /// every call to `run`.
+    pub unsafe fn new(size: usize, handler: fn(I) -> anyhow::Result<R>) -> anyhow::Result<Self> {
+        Self::with_builder(Fork::builder(), size, handler)
+    }
+
+    /// Like [`new`](Self::new), with each worker set up by `builder` before it runs its first
+    /// job. Options about the result, like the [`codec`](ForkBuilder::codec), apply to every
+    /// job; [`timeout`](ForkBuilder::timeout) and [`retries`](ForkBuilder::retries), which are
+    /// about one-off children, don't apply at all.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`new`](Self::new).
+    pub unsafe fn with_builder(
+        builder: ForkBuilder,
+        size: usize,
+        handler: fn(I) -> anyhow::Result<R>,
+    ) -> anyhow::Result<Self> {
+        let pool = ForkPool {
+            builder,
+            handler,
+            size: size.max(1),
+            #[cfg(all(unix, not(feature = "fallback")))]
+            workers: Mutex::new(Workers {
+                idle: vec![],
+                alive: 0,
+            }),
+            #[cfg(all(unix, not(feature = "fallback")))]
+            returned: Condvar::new(),
+        };
+        #[cfg(all(unix, not(feature = "fallback")))]
+        if !pool.builder.inline {
+            let mut workers = pool.lock();
+            while workers.alive < pool.size {
+                let worker = pool.fork_worker()?;
+                workers.idle.push(worker);
+                workers.alive += 1;
+            }
+        }
+        Ok(pool)
+    }
+
+    /// Runs the handler on `input` in an idle worker, waiting for one to become idle if they're
+    /// all busy, and returns its result.
+    pub fn run(&self, input: I) -> anyhow::Result<R> {
+        #[cfg(all(unix, not(feature = "fallback")))]
+        if !self.builder.inline {
+            return self.run_in_worker(input);
+        }
+        self.builder.run_item_in_process(&self.handler, input)
+    }
+
+    /// The process ids of the workers that are currently idle.
+    pub fn pids(&self) -> Vec<u32> {
+        #[cfg(all(unix, not(feature = "fallback")))]
+        {
+            let workers = self.lock();
+            workers.idle.iter().map(|w| w.pid as u32).collect()
+        }
+        #[cfg(any(not(unix), feature = "fallback"))]
+        {
+            vec![]
+        }
+    }
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+impl<I, R> ForkPool<I, R>
+where
+    I: Serialize + for<'a> Deserialize<'a>,
+    R: Serialize + for<'a> Deserialize<'a>,
+{
+    fn lock(&self) -> MutexGuard<'_, Workers> {
+        // Nothing panics while holding the lock, but don't take everyone down if it somehow did
+        self.workers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn run_in_worker(&self, input: I) -> anyhow::Result<R> {
+        let body = self.builder.codec.encode(&input)?;
+        let mut job = (body.len() as u64).to_le_bytes().to_vec();
+        job.extend_from_slice(&body);
+
+        let mut worker = self.take_worker()?;
+        match worker.call(&job, self.builder.max_result_bytes) {
+            Ok(frame) => {
+                let pid = worker.pid as u32;
+                self.return_worker(worker);
+                self.builder.decode_result(pid, &frame)
+            }
+            Err(error) => {
+                let error = worker.bury(error);
+                self.replace_worker();
+                Err(error.into())
+            }
+        }
+    }
+
+    /// Takes an idle worker, forking one if there's room for one, or waiting for one otherwise.
+    fn take_worker(&self) -> Result<Worker, ForkError> {
+        let mut workers = self.lock();
+        loop {
+            if let Some(worker) = workers.idle.pop() {
+                return Ok(worker);
+            }
+            if workers.alive < self.size {
+                // Only replacements that failed to fork leave room
+                // Safety: promised by whoever created the pool
+                let worker = unsafe { self.fork_worker() }?;
+                workers.alive += 1;
+                return Ok(worker);
+            }
+            workers = self
+                .returned
+                .wait(workers)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    fn return_worker(&self, worker: Worker) {
+        self.lock().idle.push(worker);
+        self.returned.notify_one();
+    }
+
+    /// Forks a replacement for a worker that died.
+    fn replace_worker(&self) {
+        // Forking under the lock, so no other new worker can inherit this one's end of its socket
+        let mut workers = self.lock();
+        // Safety: promised by whoever created the pool
+        match unsafe { self.fork_worker() } {
+            Ok(worker) => workers.idle.push(worker),
+            // Leave room for take_worker to try again when it's needed
+            Err(_) => workers.alive -= 1,
+        }
+        self.returned.notify_one();
+    }
+
+    unsafe fn fork_worker(&self) -> Result<Worker, ForkError> {
+        let socket = sys::socketpair()?;
+        let pid = {
+            let _permit = crate::limit::acquire();
+            let _forking = crate::limit::Forking::enter();
+            match sys::fork() {
+                Ok(pid) => pid,
+                Err(e) => {
+                    libc::close(socket[0]);
+                    libc::close(socket[1]);
+                    return Err(e);
+                }
+            }
+        };
+        if pid == 0 {
+            // Child
+            libc::close(socket[0]);
+            self.serve(socket[1]);
+        }
+        libc::close(socket[1]);
+        Ok(Worker {
+            pid,
+            socket: socket[0],
+        })
+    }
+
+    /// The worker's side: answers jobs until the parent goes away.
+    unsafe fn serve(&self, socket: libc::c_int) -> ! {
+        // Jobs can't be run as requested, so each one is answered with why
+        let failed = self
+            .builder
+            .setup_child()
+            .err()
+            .map(|e| self.builder.encode_result::<R>(Err(e)));
+        loop {
+            let mut len = [0u8; 8];
+            if !matches!(sys::read_exact(socket, &mut len), Ok(8)) {
+                // The pool was dropped
+                libc::exit(0);
+            }
+            let mut body = vec![0u8; u64::from_le_bytes(len) as usize];
+            if sys::read_exact(socket, &mut body).ok() != Some(body.len()) {
+                libc::exit(1);
+            }
+            let frame = match &failed {
+                Some(frame) => frame.clone(),
+                None => {
+                    let input = self.builder.codec.decode::<I>(std::process::id(), &body);
+                    let result = input
+                        .map_err(anyhow::Error::from)
+                        .and_then(|input| forked::catch_panic(|| (self.handler)(input)));
+                    self.builder.encode_result(result)
+                }
+            };
+            if sys::send_all(socket, &frame).is_err() {
+                libc::exit(1);
+            }
+        }
+    }
+}
+
+impl<I, R> fmt::Debug for ForkPool<I, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ForkPool")
+            .field("size", &self.size)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A worker, as seen from the parent. Dropping it kills and reaps the worker.
+#[cfg(all(unix, not(feature = "fallback")))]
+struct Worker {
+    pid: libc::pid_t,
+    socket: libc::c_int,
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+impl Worker {
+    /// Sends a job and reads back the result frame.
+    fn call(&mut self, job: &[u8], max_result_bytes: Option<usize>) -> Result<Vec<u8>, ForkError> {
+        unsafe { sys::send_all(self.socket, job) }?;
+        let mut frame = vec![0u8; protocol::HEADER_LEN];
+        let got = unsafe { sys::read_exact(self.socket, &mut frame) }?;
+        let pid = self.pid as u32;
+        if got < frame.len() {
+            frame.truncate(got);
+            return Err(protocol::parse(pid, &frame)
+                .err()
+                .unwrap_or(ForkError::NoResult { pid }));
+        }
+        if let Some(limit) = max_result_bytes {
+            protocol::check_size(pid, &frame, limit)?;
+        }
+        let len = u64::from_le_bytes(frame[2..].try_into().unwrap()) as usize;
+        frame.resize(protocol::HEADER_LEN + len, 0);
+        let got = unsafe { sys::read_exact(self.socket, &mut frame[protocol::HEADER_LEN..]) }?;
+        if got < len {
+            return Err(ForkError::Truncated {
+                pid,
+                expected: frame.len(),
+                received: protocol::HEADER_LEN + got,
+            });
+        }
+        Ok(frame)
+    }
+
+    /// Reaps a worker that failed a job with `error`, and works out what really happened.
+    fn bury(self, error: ForkError) -> ForkError {
+        let pid = self.pid;
+        let socket = self.socket;
+        std::mem::forget(self);
+        unsafe {
+            libc::close(socket);
+            // If it's still around, it can't be trusted to be in sync with us anymore
+            let exited = matches!(
+                error,
+                ForkError::NoResult { .. } | ForkError::Truncated { .. }
+            );
+            if !exited {
+                libc::kill(pid, libc::SIGKILL);
+            }
+            match sys::wait4(pid) {
+                Ok((status, _)) if exited && status != 0 => ForkError::ChildFailed {
+                    pid: pid as u32,
+                    status,
+                },
+                _ => error,
+            }
+        }
+    }
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+impl Drop for Worker {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.socket);
+            libc::kill(self.pid, libc::SIGKILL);
+            let _ = sys::wait4(self.pid);
+        }
+    }
+}
@@ -0,0 +1,485 @@
+//! Progress reports from a child while it works, over a pipe of their own, or as frames of their
+//! own over a socket [`Transport`](crate::Transport).
+
+use std::cell::RefCell;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(all(unix, not(feature = "fallback")))]
+use crate::protocol::{self, Tag};
+#[cfg(all(unix, not(feature = "fallback")))]
+use crate::{Compression, Transport};
+use crate::{Fork, ForkBuilder};
+
+/// How often a child sends progress at most, so reporting on every step of a tight loop doesn't
+/// turn into a write for every step.
+const MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How finely [`Progress::report_fraction`] divides the work up.
+const FRACTION_TOTAL: u64 = 1_000_000;
+
+/// One report: `done` out of `total` units of work. See [`fork_map_with_progress`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ProgressUpdate {
+    pub done: u64,
+    pub total: u64,
+}
+
+impl ProgressUpdate {
+    /// How much of the work is done, from 0 to 1, for a progress bar. 1 when there's no work.
+    pub fn fraction(&self) -> f64 {
+        if self.total == 0 {
+            return 1.0;
+        }
+        (self.done as f64 / self.total as f64).min(1.0)
+    }
+
+    /// Size on the wire: `done` and then `total`, as little-endian `u64`s. Small enough that a
+    /// write to a pipe is atomic.
+    #[cfg(all(unix, not(feature = "fallback")))]
+    const LEN: usize = 16;
+
+    #[cfg(all(unix, not(feature = "fallback")))]
+    fn to_bytes(self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[..8].copy_from_slice(&self.done.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.total.to_le_bytes());
+        bytes
+    }
+
+    #[cfg(all(unix, not(feature = "fallback")))]
+    fn from_bytes(bytes: &[u8]) -> Self {
+        ProgressUpdate {
+            done: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            total: u64::from_le_bytes(bytes[8..Self::LEN].try_into().unwrap()),
+        }
+    }
+}
+
+/// What a child reports its progress with. See [`fork_map_with_progress`].
+pub struct Progress<'a> {
+    sink: Sink<'a>,
+    last_sent: Option<Instant>,
+    /// Reported, but held back since the last one went out too recently.
+    pending: Option<ProgressUpdate>,
+}
+
+enum Sink<'a> {
+    /// Write end of the progress pipe, in a child.
+    #[cfg(all(unix, not(feature = "fallback")))]
+    Pipe(libc::c_int),
+    /// The child's end of the socket the result goes over too, in a child.
+    #[cfg(all(unix, not(feature = "fallback")))]
+    Socket(libc::c_int),
+    /// The parent's callback itself, since it's the same process.
+    Callback(&'a mut dyn FnMut(ProgressUpdate)),
+}
+
+impl<'a> Progress<'a> {
+    fn new(sink: Sink<'a>) -> Self {
+        Progress {
+            sink,
+            last_sent: None,
+            pending: None,
+        }
+    }
+
+    /// Reports that `done` out of `total` units of work are done.
+    ///
+    /// Reports that come in quick succession are coalesced, so the parent may only see the
+    /// latest of them, but it always sees one where `done` has reached `total`, and the last one
+    /// reported before the closure returns.
+    ///
+    /// A report only waits for the parent if it's the last one, where `done` has reached
+    /// `total`. Any other that finds the parent too far behind to take it is held back like one
+    /// that came too soon, and goes out with the next report the parent can take, if it isn't
+    /// replaced first, so a slow parent costs it some reports rather than holding up the work.
+    pub fn report(&mut self, done: u64, total: u64) {
+        let update = ProgressUpdate { done, total };
+        let due = self
+            .last_sent
+            .is_none_or(|last_sent| last_sent.elapsed() >= MIN_INTERVAL);
+        if done >= total {
+            self.send(update, true);
+        } else if due {
+            self.send(update, false);
+        } else {
+            self.pending = Some(update);
+        }
+    }
+
+    /// Like [`report`](Self::report), with how much of the work is done as a fraction from 0 to
+    /// 1, which the parent gets back with [`ProgressUpdate::fraction`].
+    pub fn report_fraction(&mut self, fraction: f64) {
+        let done = (fraction.clamp(0.0, 1.0) * FRACTION_TOTAL as f64).round() as u64;
+        self.report(done, FRACTION_TOTAL);
+    }
+
+    /// Sends the report held back, if any.
+    fn flush(&mut self) {
+        if let Some(update) = self.pending {
+            self.send(update, true);
+        }
+    }
+
+    /// Sends `update`, or holds it back if the parent isn't ready for it, unless told to `wait`.
+    fn send(&mut self, update: ProgressUpdate, wait: bool) {
+        self.pending = None;
+        self.last_sent = Some(Instant::now());
+        let sent = match &mut self.sink {
+            #[cfg(all(unix, not(feature = "fallback")))]
+            Sink::Pipe(pipe) => unsafe { send_to(*pipe, &update.to_bytes(), wait) },
+            #[cfg(all(unix, not(feature = "fallback")))]
+            Sink::Socket(socket) => {
+                let body = update.to_bytes().to_vec();
+                match protocol::frame(Tag::Progress, Compression::None, body) {
+                    Ok(frame) => unsafe { send_to(*socket, &frame, wait) },
+                    Err(_) => true,
+                }
+            }
+            Sink::Callback(callback) => {
+                #[cfg(any(not(unix), feature = "fallback"))]
+                let _ = wait;
+                callback(update);
+                true
+            }
+        };
+        if !sent {
+            self.pending = Some(update);
+        }
+    }
+}
+
+/// Writes all of `bytes` to the child's end of the progress pipe or the socket, or none of them
+/// if that would mean waiting and it isn't to `wait`. Returns whether it didn't hold them back.
+/// If the parent has stopped listening, it won't be reading the result either, so that's left
+/// for the result to find out.
+#[cfg(all(unix, not(feature = "fallback")))]
+unsafe fn send_to(fd: libc::c_int, bytes: &[u8], wait: bool) -> bool {
+    if !wait {
+        let count = libc::send(
+            fd,
+            bytes.as_ptr() as *const libc::c_void,
+            bytes.len(),
+            libc::MSG_DONTWAIT,
+        );
+        match count {
+            // Not a socket, so the pipe it is, which is non-blocking already
+            -1 if std::io::Error::last_os_error().raw_os_error() == Some(libc::ENOTSOCK) => {
+                let count = libc::write(fd, bytes.as_ptr() as *const libc::c_void, bytes.len());
+                // Reports are small enough to go into a pipe all at once or not at all
+                return count != -1
+                    || std::io::Error::last_os_error().kind() != std::io::ErrorKind::WouldBlock;
+            }
+            -1 => return std::io::Error::last_os_error().kind() != std::io::ErrorKind::WouldBlock,
+            count if count as usize == bytes.len() => return true,
+            // Half a frame can't be taken back, so the rest has to follow
+            count => {
+                let _ = crate::sys::write_all(fd, &bytes[count as usize..]);
+                return true;
+            }
+        }
+    }
+    let _ = crate::sys::write_all(fd, bytes);
+    true
+}
+
+impl fmt::Debug for Progress<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Progress")
+            .field("pending", &self.pending)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Forks, and runs `func` in a child process, calling `on_progress` in the parent with each
+/// report the child makes through the [`Progress`] it's given. Waits for the child to terminate
+/// and returns the result of `func`.
+///
+/// Reports travel over a pipe of their own (or in frames of their own, over a socket
+/// [`Transport`](crate::Transport)), so they never get mixed up with the result, and
+/// `on_progress` is called as they arrive, while the parent waits for the result. Both the child
+/// and the parent coalesce reports that come in faster than they're useful, so `on_progress` may
+/// not see every one, but it does see them in order, and always the last. Nor does the child
+/// wait for a parent that's slow to take its reports: it skips ahead instead (see
+/// [`report`](Progress::report)), so a slow `on_progress` slows down the parent, not the work.
+///
+/// When [`FORKS`](crate::FORKS) is `false`, `on_progress` is called from
+/// [`report`](Progress::report) itself instead.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_map_with_progress;
+///
+/// let mut seen = vec![];
+/// let sum = unsafe {
+///     fork_map_with_progress(
+///         |progress| {
+///             let mut sum = 0u64;
+///             for done in 0..=100 {
+///                 sum += done;
+///                 progress.report(done, 100);
+///             }
+///             Ok(sum)
+///         },
+///         |update| seen.push(update.done),
+///     )
+/// }
+/// .unwrap();
+/// assert_eq!(sum, 5050);
+/// assert!(seen.windows(2).all(|pair| pair[0] <= pair[1]));
+/// assert_eq!(seen.last(), Some(&100));
+///
+/// // Or as a fraction, for a progress bar that takes its time drawing
+/// let mut shown = vec![];
+/// let steps = unsafe {
+///     fork_map_with_progress(
+///         |progress| {
+///             for step in 0..=100 {
+///                 progress.report_fraction(step as f64 / 100.0);
+///                 std::thread::sleep(std::time::Duration::from_millis(1));
+///             }
+///             Ok(100)
+///         },
+///         |update| {
+///             shown.push(update.fraction());
+///             std::thread::sleep(std::time::Duration::from_millis(20));
+///         },
+///     )
+/// }
+/// .unwrap();
+/// assert_eq!(steps, 100);
+/// assert!(shown.windows(2).all(|pair| pair[0] <= pair[1]));
+/// assert_eq!(shown.last(), Some(&1.0));
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`](crate::fork_map).
+pub unsafe fn fork_map_with_progress<F, R, P>(func: F, on_progress: P) -> anyhow::Result<R>
+where
+    F: Fn(&mut Progress) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+    P: FnMut(ProgressUpdate),
+{
+    Fork::builder().run_with_progress(func, on_progress)
+}
+
+impl ForkBuilder {
+    /// Like [`fork_map_with_progress`], with the child configured like this. If the child is
+    /// [retried](Self::retries), the reports start over with the next attempt.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_with_progress<F, R, P>(self, func: F, mut on_progress: P) -> anyhow::Result<R>
+    where
+        F: Fn(&mut Progress) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+        P: FnMut(ProgressUpdate),
+    {
+        self.with_retries(|| self.clone().progress_once(&func, &mut on_progress))
+    }
+
+    unsafe fn progress_once<F, R>(
+        self,
+        func: &F,
+        on_progress: &mut dyn FnMut(ProgressUpdate),
+    ) -> anyhow::Result<R>
+    where
+        F: Fn(&mut Progress) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        #[cfg(feature = "testing")]
+        if let Some(error) = crate::testing::take() {
+            return Err(error.into());
+        }
+        #[cfg(all(unix, not(feature = "fallback")))]
+        if !self.inline {
+            return self.progress_forked(func, on_progress);
+        }
+        let on_progress = RefCell::new(on_progress);
+        self.run_in_process(|| {
+            let mut on_progress = on_progress.borrow_mut();
+            let mut progress = Progress::new(Sink::Callback(&mut **on_progress));
+            let result = func(&mut progress);
+            progress.flush();
+            result
+        })?
+        .join()
+    }
+
+    #[cfg(all(unix, not(feature = "fallback")))]
+    unsafe fn progress_forked<F, R>(
+        self,
+        func: &F,
+        on_progress: &mut dyn FnMut(ProgressUpdate),
+    ) -> anyhow::Result<R>
+    where
+        F: Fn(&mut Progress) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        use crate::sys;
+
+        if self.transport == Transport::Socket {
+            return self.progress_over_socket(func, on_progress);
+        }
+        let updates = sys::pipe()?;
+        let child = self.fork_child(|_| {
+            libc::close(updates[0]);
+            // So a report can find out the parent is behind instead of waiting for it
+            let flags = libc::fcntl(updates[1], libc::F_GETFL);
+            libc::fcntl(updates[1], libc::F_SETFL, flags | libc::O_NONBLOCK);
+            let mut progress = Progress::new(Sink::Pipe(updates[1]));
+            let result = func(&mut progress);
+            // Ahead of the result, so the parent has it all by the time it's done
+            progress.flush();
+            result
+        });
+        libc::close(updates[1]);
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                libc::close(updates[0]);
+                return Err(e);
+            }
+        };
+        let mut updates = Updates {
+            fd: updates[0],
+            received: vec![],
+        };
+
+        let received = loop {
+            let mut fds = [child.pipe(), updates.fd].map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            });
+            let open = if updates.fd < 0 { 1 } else { 2 };
+            let wake_at = match (child.deadline(), child.rss_check_at()) {
+                (Some(deadline), Some(check_at)) => Some(deadline.min(check_at)),
+                (deadline, check_at) => deadline.or(check_at),
+            };
+            match sys::poll(&mut fds[..open], wake_at) {
+                Ok(0) if child.deadline().is_some_and(|d| d <= Instant::now()) => {
+                    return child.time_out().map(|(result, _)| result)
+                }
+                Ok(_) => {}
+                Err(e) => break Err(e),
+            }
+            if let Err(e) = child.check_rss() {
+                break Err(e);
+            }
+            if open == 2 && fds[1].revents != 0 {
+                updates.read(on_progress);
+            }
+            if fds[0].revents != 0 {
+                match child.read_some() {
+                    Ok(true) => break Ok(()),
+                    Ok(false) => {}
+                    Err(e) => break Err(e),
+                }
+            }
+        };
+        if received.is_ok() {
+            // The child sent its last reports before its result, so they're already in the pipe
+            let now = Some(Instant::now());
+            while updates.fd >= 0 {
+                let mut fds = [libc::pollfd {
+                    fd: updates.fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                }];
+                if !matches!(sys::poll(&mut fds, now), Ok(1)) {
+                    break;
+                }
+                updates.read(on_progress);
+            }
+        }
+        drop(updates);
+        child.finish(received).map(|(result, _)| result)
+    }
+
+    /// Like [`progress_forked`](Self::progress_forked), with reports coming in ahead of the
+    /// result over the same socket.
+    #[cfg(all(unix, not(feature = "fallback")))]
+    unsafe fn progress_over_socket<F, R>(
+        self,
+        func: &F,
+        on_progress: &mut dyn FnMut(ProgressUpdate),
+    ) -> anyhow::Result<R>
+    where
+        F: Fn(&mut Progress) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        let mut child = self.fork_child(|socket| {
+            let mut progress = Progress::new(Sink::Socket(socket));
+            let result = func(&mut progress);
+            progress.flush();
+            result
+        })?;
+        let received = loop {
+            let eof = child.read_more();
+            let mut latest = None;
+            while let Some(update) = child.take_frame(Tag::Progress, |body| {
+                Ok((body.len() == ProgressUpdate::LEN).then(|| ProgressUpdate::from_bytes(body)))
+            }) {
+                latest = update.ok().flatten().or(latest);
+            }
+            if let Some(update) = latest {
+                on_progress(update);
+            }
+            match eof {
+                Ok(true) => break Ok(()),
+                Ok(false) => {}
+                Err(e) => break Err(e),
+            }
+        };
+        child.finish(received).map(|(result, _)| result)
+    }
+}
+
+/// The parent's end of the progress pipe.
+#[cfg(all(unix, not(feature = "fallback")))]
+struct Updates {
+    /// Read end, or -1 once it has reached EOF or failed.
+    fd: libc::c_int,
+    /// Partial report left over from the last read.
+    received: Vec<u8>,
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+impl Updates {
+    /// Reads whatever reports have arrived, and passes the latest on. Closes the pipe once
+    /// there's nothing more to come.
+    fn read(&mut self, on_progress: &mut dyn FnMut(ProgressUpdate)) {
+        match unsafe { crate::sys::read_some(self.fd, &mut self.received) } {
+            Ok(count) if count > 0 => {}
+            // Reports are nice to have, so losing them doesn't fail the job
+            _ => {
+                unsafe { libc::close(self.fd) };
+                self.fd = -1;
+                return;
+            }
+        }
+        let whole = self.received.len() / ProgressUpdate::LEN * ProgressUpdate::LEN;
+        if whole == 0 {
+            return;
+        }
+        let latest = ProgressUpdate::from_bytes(&self.received[whole - ProgressUpdate::LEN..]);
+        self.received.drain(..whole);
+        on_progress(latest);
+    }
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+impl Drop for Updates {
+    fn drop(&mut self) {
+        if self.fd >= 0 {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Framing for what the child sends back to the parent.
+//!
+//! The child writes exactly one frame: a one byte [`Tag`], a byte identifying the
+//! [`Compression`] applied to the body, the body length as a little-endian `u64`, and then the
+//! body itself. Since the header is always there, even a result that encodes to nothing makes a
+//! non-empty frame, so reading zero bytes unambiguously means the child went away without
+//! reporting anything. Anything after the frame is an error, not padding. The exceptions are a
+//! streaming child (see `stream`), which writes any number of [`Item`](Tag::Item) frames before
+//! the frame with its result, and a child on a socket [`Transport`](crate::Transport), which can
+//! do the same with [`Progress`](Tag::Progress) and [`Fd`](Tag::Fd) frames.
+
+use std::borrow::Cow;
+
+use crate::{Compression, ForkError};
+
+pub(crate) const HEADER_LEN: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum Tag {
+    /// The body is the closure's `Ok` value.
+    Value = 1,
+    /// The body is the `serde_error::Error` the closure (or child setup) failed with.
+    Error = 2,
+    /// The body is the UTF-8 message of the error the closure's result failed to serialize with.
+    SerializeFailed = 3,
+    /// The body is one item a streaming child sent, ahead of its result.
+    Item = 4,
+    /// The closure stopped with [`ForkError::Cancelled`]. There's no body.
+    Cancelled = 5,
+    /// The body is a progress report (see `progress`), ahead of the result. Only sent over a
+    /// socket [`Transport`](crate::Transport).
+    Progress = 6,
+    /// A file descriptor (see `fds`) came with the first byte of the frame, ahead of the result.
+    /// There's no body.
+    Fd = 7,
+    /// The body is the length, as a little-endian `u64`, of the frame with the result, which is
+    /// in shared memory or a file instead (see
+    /// [`Transport::SharedMemory`](crate::Transport::SharedMemory)).
+    Shared = 8,
+}
+
+impl Tag {
+    fn from_u8(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Tag::Value),
+            2 => Some(Tag::Error),
+            3 => Some(Tag::SerializeFailed),
+            4 => Some(Tag::Item),
+            5 => Some(Tag::Cancelled),
+            6 => Some(Tag::Progress),
+            7 => Some(Tag::Fd),
+            8 => Some(Tag::Shared),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn frame(tag: Tag, compression: Compression, body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let body = compression.compress(body)?;
+    let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+    frame.extend_from_slice(&header(tag, compression, body.len() as u64));
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// The start of a frame with a body of `len` bytes, for sending the body separately.
+pub(crate) fn header(tag: Tag, compression: Compression, len: u64) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[0] = tag as u8;
+    header[1] = compression.id();
+    header[2..].copy_from_slice(&len.to_le_bytes());
+    header
+}
+
+/// Checks the (possibly still incomplete) frame in `received` from child `pid` against a limit
+/// on the body size, which the header lets us do before the body even arrives.
+pub(crate) fn check_size(pid: u32, received: &[u8], limit: usize) -> Result<(), ForkError> {
+    let size = match received.get(2..HEADER_LEN) {
+        Some(len) => u64::from_le_bytes(len.try_into().unwrap()),
+        None => 0,
+    };
+    let size = size.max(received.len().saturating_sub(HEADER_LEN) as u64);
+    if size > limit as u64 {
+        return Err(ForkError::ResultTooLarge { pid, limit, size });
+    }
+    Ok(())
+}
+
+/// How long the frame at the front of `received` is, if all of it is there.
+pub(crate) fn complete_len(received: &[u8]) -> Option<usize> {
+    let len = u64::from_le_bytes(received.get(2..HEADER_LEN)?.try_into().unwrap());
+    let len = HEADER_LEN.checked_add(usize::try_from(len).ok()?)?;
+    (received.len() >= len).then_some(len)
+}
+
+/// How many more bytes it takes to finish the last frame in `received`: the rest of its header,
+/// if that's not all there, or otherwise the rest of its body. Once every frame is whole, it's
+/// the header of the next one.
+#[cfg(all(unix, not(feature = "fallback")))]
+pub(crate) fn missing(received: &[u8]) -> usize {
+    let mut rest = received;
+    while let Some(len) = complete_len(rest) {
+        rest = &rest[len..];
+    }
+    match rest.get(2..HEADER_LEN) {
+        Some(len) => {
+            let len = u64::from_le_bytes(len.try_into().unwrap());
+            let len = usize::try_from(len).unwrap_or(usize::MAX);
+            len.saturating_add(HEADER_LEN) - rest.len()
+        }
+        None => HEADER_LEN - rest.len(),
+    }
+}
+
+/// Whether `received` holds a whole frame, so nothing more is needed from the child.
+#[cfg(all(unix, not(feature = "fallback")))]
+pub(crate) fn is_complete(received: &[u8]) -> bool {
+    match received.get(2..HEADER_LEN) {
+        Some(len) => {
+            let len = u64::from_le_bytes(len.try_into().unwrap());
+            (received.len() - HEADER_LEN) as u64 >= len
+        }
+        None => false,
+    }
+}
+
+/// Splits a frame received from child `pid` into its tag and (decompressed) body.
+pub(crate) fn parse(pid: u32, bytes: &[u8]) -> Result<(Tag, Cow<'_, [u8]>), ForkError> {
+    if bytes.is_empty() {
+        return Err(ForkError::NoResult { pid });
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(ForkError::Truncated {
+            pid,
+            expected: HEADER_LEN,
+            received: bytes.len(),
+        });
+    }
+    let tag = Tag::from_u8(bytes[0])
+        .ok_or_else(|| ForkError::decode(pid, &format!("unknown frame tag {}", bytes[0]), bytes))?;
+    let compression = bytes[1];
+    let len = u64::from_le_bytes(bytes[2..HEADER_LEN].try_into().unwrap()) as usize;
+    let body = &bytes[HEADER_LEN..];
+    if body.len() < len {
+        return Err(ForkError::Truncated {
+            pid,
+            expected: HEADER_LEN + len,
+            received: bytes.len(),
+        });
+    }
+    if body.len() > len {
+        return Err(ForkError::ProtocolViolation {
+            pid,
+            expected: HEADER_LEN + len,
+            extra: body.len() - len,
+        });
+    }
+    Ok((
+        tag,
+        Compression::decompress(pid, compression, &body[..len])?,
+    ))
+}
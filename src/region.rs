@@ -0,0 +1,242 @@
+//! Memory a child writes its results into directly, for results that don't need serializing.
+
+use std::alloc::Layout;
+use std::fmt;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Fork, ForkBuilder, ForkError};
+
+/// Types that are valid for any bit pattern, including all zeroes, and have no pointers, so
+/// they can be shared with a child as raw memory. See [`SharedRegion`].
+///
+/// # Safety
+///
+/// Implementing it for a type that doesn't meet those requirements is undefined behavior.
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! impl_pod {
+    ($($ty:ty),*) => {
+        $(unsafe impl Pod for $ty {})*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// A zeroed buffer of `len` values of `T`, in memory that a child forked by
+/// [`fork_map_with_region`] shares with the parent, so the child can write its results straight
+/// into it instead of serializing them.
+///
+/// Only one process ever has it at a time: the region is moved into `fork_map_with_region`,
+/// the child gets it as a `&mut [T]`, and it's handed back to the parent only once the child has
+/// been reaped. So reading it in the parent never races the child writing it, and what's there
+/// is everything the child wrote, even when the child crashed partway through. Outside of that,
+/// it derefs to a plain slice.
+///
+/// ```compile_fail
+/// use fork_map::{fork_map_with_region, SharedRegion};
+///
+/// let region = SharedRegion::<u8>::new(16).unwrap();
+/// let (_, result) = unsafe { fork_map_with_region(region, |out| Ok(out[0])) };
+/// // Gone into the call, so it can't be read while the child has it
+/// println!("{}", region[0]);
+/// ```
+///
+/// The memory is mapped with `MAP_SHARED | MAP_ANONYMOUS`, and shared with any process forked
+/// while it's mapped, which includes any the child forks itself: those can go on writing to it
+/// after the child is gone, so don't leave any running.
+///
+/// When [`FORKS`](crate::FORKS) is `false`, it's an ordinary allocation.
+pub struct SharedRegion<T: Pod> {
+    ptr: *mut T,
+    len: usize,
+}
+
+// Safety: it owns the memory, like a `Box<[T]>`
+unsafe impl<T: Pod + Send> Send for SharedRegion<T> {}
+unsafe impl<T: Pod + Sync> Sync for SharedRegion<T> {}
+
+impl<T: Pod> SharedRegion<T> {
+    /// Maps a region of `len` values, all zeroes.
+    pub fn new(len: usize) -> Result<Self, ForkError> {
+        let layout = Layout::array::<T>(len).map_err(|_| ForkError::Io {
+            op: "mmap",
+            source: io::ErrorKind::OutOfMemory.into(),
+        })?;
+        if layout.size() == 0 {
+            return Ok(SharedRegion {
+                ptr: NonNull::dangling().as_ptr(),
+                len,
+            });
+        }
+        Ok(SharedRegion {
+            ptr: unsafe { allocate(layout) }? as *mut T,
+            len,
+        })
+    }
+
+    fn layout(&self) -> Layout {
+        // Already checked when it was allocated
+        Layout::array::<T>(self.len).unwrap()
+    }
+}
+
+impl<T: Pod> Deref for SharedRegion<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl<T: Pod> DerefMut for SharedRegion<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl<T: Pod> Drop for SharedRegion<T> {
+    fn drop(&mut self) {
+        if self.layout().size() > 0 {
+            unsafe { free(self.ptr as *mut u8, self.layout()) };
+        }
+    }
+}
+
+impl<T: Pod> fmt::Debug for SharedRegion<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedRegion")
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+unsafe fn allocate(layout: Layout) -> Result<*mut u8, ForkError> {
+    // Page aligned, which is plenty
+    let ptr = libc::mmap(
+        std::ptr::null_mut(),
+        layout.size(),
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_SHARED | libc::MAP_ANON,
+        -1,
+        0,
+    );
+    if ptr == libc::MAP_FAILED {
+        return Err(ForkError::last_os_error("mmap"));
+    }
+    // Anonymous mappings start out zeroed
+    Ok(ptr as *mut u8)
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+unsafe fn free(ptr: *mut u8, layout: Layout) {
+    libc::munmap(ptr as *mut libc::c_void, layout.size());
+}
+
+/// Without children, there's nobody to share it with.
+#[cfg(any(not(unix), feature = "fallback"))]
+unsafe fn allocate(layout: Layout) -> Result<*mut u8, ForkError> {
+    let ptr = std::alloc::alloc_zeroed(layout);
+    if ptr.is_null() {
+        std::alloc::handle_alloc_error(layout);
+    }
+    Ok(ptr)
+}
+
+#[cfg(any(not(unix), feature = "fallback"))]
+unsafe fn free(ptr: *mut u8, layout: Layout) {
+    std::alloc::dealloc(ptr, layout);
+}
+
+/// Forks, and runs `func` in a child process with `region` to write into, and waits for the
+/// child to terminate. Returns the region, with whatever the child wrote to it, along with the
+/// result of `func`.
+///
+/// The region comes back whether or not the child succeeded, so if it crashed, the error says
+/// how and the region has what it wrote before it did.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_with_region, ForkError, SharedRegion};
+///
+/// let region = SharedRegion::<f32>::new(1 << 20).unwrap();
+/// let (region, result) = unsafe {
+///     fork_map_with_region(region, |out| {
+///         for (i, value) in out.iter_mut().enumerate() {
+///             *value = (i as f32).sqrt();
+///         }
+///         Ok(out.len())
+///     })
+/// };
+/// assert_eq!(result.unwrap(), 1 << 20);
+/// assert_eq!(region[16], 4.0);
+/// assert_eq!(region[1 << 10], 32.0);
+///
+/// # if !fork_map::FORKS { return }
+/// // A child that dies halfway through leaves the first half written
+/// let region = SharedRegion::<u64>::new(100).unwrap();
+/// let (region, result) = unsafe {
+///     fork_map_with_region(region, |out| {
+///         for (i, value) in out.iter_mut().enumerate().take(50) {
+///             *value = i as u64 + 1;
+///         }
+///         libc::kill(libc::getpid(), libc::SIGKILL);
+///         Ok(())
+///     })
+/// };
+/// let err = result.unwrap_err();
+/// assert!(matches!(err.downcast_ref(), Some(ForkError::ChildFailed { .. })));
+/// assert!(region[..50].iter().enumerate().all(|(i, &v)| v == i as u64 + 1));
+/// assert!(region[50..].iter().all(|&v| v == 0));
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`](crate::fork_map).
+pub unsafe fn fork_map_with_region<T, F, R>(
+    region: SharedRegion<T>,
+    func: F,
+) -> (SharedRegion<T>, anyhow::Result<R>)
+where
+    T: Pod,
+    F: Fn(&mut [T]) -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().run_with_region(region, func)
+}
+
+impl ForkBuilder {
+    /// Like [`fork_map_with_region`], with the child configured like this. If the child is
+    /// [retried](Self::retries), each attempt starts from what the ones before it left in the
+    /// region.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_with_region<T, F, R>(
+        self,
+        mut region: SharedRegion<T>,
+        func: F,
+    ) -> (SharedRegion<T>, anyhow::Result<R>)
+    where
+        T: Pod,
+        F: Fn(&mut [T]) -> anyhow::Result<R> + Send + Sync,
+        R: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        // As an address, so the closure can go to the thread the child runs it on. It's plain
+        // data in memory the child shares, so it's fine wherever it's used from.
+        let addr = region.as_mut_ptr() as usize;
+        let len = region.len();
+        // Safety: the region is ours until we return it, and by then the child has been reaped,
+        // so whoever runs this is the only one with access
+        let result = self.run(|| func(std::slice::from_raw_parts_mut(addr as *mut T, len)));
+        (region, result)
+    }
+}
@@ -0,0 +1,84 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::ForkError;
+
+type Predicate = Arc<dyn Fn(&anyhow::Error) -> bool + Send + Sync>;
+
+/// How [`ForkBuilder::retries`](crate::ForkBuilder::retries) and
+/// [`fork_map_retry`](crate::fork_map_retry) retry children that didn't make it.
+///
+/// By default only failures of the child process itself are retried: crashes and non-zero exits
+/// ([`ForkError::ChildFailed`], or [`ForkError::CommandFailed`] for commands), OOM kills
+/// ([`ForkError::OutOfMemory`]), and children that went away without sending (all of) their
+/// result ([`ForkError::NoResult`], [`ForkError::Truncated`]). An `Err` returned by your closure is its answer, not a crash, and
+/// is never retried unless you say so with [`retry_if`](Self::retry_if).
+///
+/// If every attempt fails, the error is [`ForkError::RetriesExhausted`], carrying the number of
+/// attempts and the last error.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    pub(crate) max_attempts: u32,
+    pub(crate) backoff: Duration,
+    pub(crate) retry_if: Option<Predicate>,
+}
+
+impl RetryPolicy {
+    /// Runs the child up to `max_attempts` times in total, retrying immediately. An
+    /// `max_attempts` of 0 is treated as 1.
+    pub fn new(max_attempts: u32) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            backoff: Duration::ZERO,
+            retry_if: None,
+        }
+    }
+
+    /// Sleeps for `delay` before the first retry, doubling it before each one after that.
+    pub fn backoff(mut self, delay: Duration) -> Self {
+        self.backoff = delay;
+        self
+    }
+
+    /// Replaces the default choice of which errors are worth retrying. `predicate` gets the
+    /// error of the attempt that just failed.
+    pub fn retry_if<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&anyhow::Error) -> bool + Send + Sync + 'static,
+    {
+        self.retry_if = Some(Arc::new(predicate));
+        self
+    }
+
+    pub(crate) fn should_retry(&self, error: &anyhow::Error) -> bool {
+        match &self.retry_if {
+            Some(predicate) => predicate(error),
+            None => matches!(
+                error.downcast_ref(),
+                Some(
+                    ForkError::ChildFailed { .. }
+                        | ForkError::CommandFailed { .. }
+                        | ForkError::OutOfMemory { .. }
+                        | ForkError::NoResult { .. }
+                        | ForkError::Truncated { .. }
+                )
+            ),
+        }
+    }
+
+    /// How long to wait before retrying after `attempt` (counting from 1) failed.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        self.backoff.saturating_mul(1 << (attempt - 1).min(31))
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff", &self.backoff)
+            .field("retry_if", &self.retry_if.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
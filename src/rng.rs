@@ -0,0 +1,68 @@
+//! Telling apart the random number generators of children forked from the same parent.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
+use std::time::SystemTime;
+
+/// Reseeds the C library's `rand()` from fresh OS entropy, and returns 32 more
+/// bytes of it for seeding any other random number generator the child uses.
+///
+/// A forked child starts with a copy of everything in the parent's memory, including the state
+/// of every random number generator, so without this, children forked from the same parent draw
+/// the exact same "random" numbers as each other (and as the parent, from the point of the
+/// fork). That's easy to miss in something like a Monte Carlo simulation split across children,
+/// which quietly runs the same trial many times over. This crate can't reach the generators of
+/// other crates, like `rand`'s `thread_rng`, which is what the returned seed is for: call this
+/// at the top of the closure, or in a [`pre_exec`](crate::ForkBuilder::pre_exec) hook, and seed
+/// the generator with it (`StdRng::from_seed`, for one).
+///
+/// The entropy comes from `/dev/urandom`. If that can't be read, it's mixed up from the pid and
+/// the time instead, which at least tells the children apart.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_bytes, reseed_child_rng};
+/// # if !fork_map::FORKS { return }
+///
+/// // Each child carries on with the parent's sequence, so they all draw the same number
+/// let draw = || unsafe { fork_map_bytes(|| libc::rand().to_ne_bytes().to_vec()) }.unwrap();
+/// let draws: Vec<_> = (0..4).map(|_| draw()).collect();
+/// assert!(draws.iter().all(|d| *d == draws[0]));
+///
+/// let seed = || unsafe { fork_map_bytes(|| reseed_child_rng().to_vec()) }.unwrap();
+/// let mut seeds: Vec<_> = (0..4).map(|_| seed()).collect();
+/// seeds.sort();
+/// seeds.dedup();
+/// assert_eq!(seeds.len(), 4);
+/// ```
+pub fn reseed_child_rng() -> [u8; 32] {
+    // The seed to return, and then one for libc
+    let mut entropy = [0; 36];
+    let read = std::fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut entropy));
+    if read.is_err() {
+        fallback_entropy(&mut entropy);
+    }
+    let reseed = u32::from_ne_bytes(entropy[32..].try_into().unwrap());
+    unsafe { libc::srand(reseed) };
+    entropy[..32].try_into().unwrap()
+}
+
+/// Fills `bytes` with whatever differs between processes and over time, hashed.
+fn fallback_entropy(bytes: &mut [u8]) {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    // Keyed the same in every child, so it's only the inputs that differ
+    let state = RandomState::new();
+    for (i, chunk) in bytes.chunks_mut(8).enumerate() {
+        let mut hasher = state.build_hasher();
+        hasher.write_u32(std::process::id());
+        hasher.write_u128(now.as_nanos());
+        hasher.write_usize(i);
+        let hash = hasher.finish().to_ne_bytes();
+        chunk.copy_from_slice(&hash[..chunk.len()]);
+    }
+}
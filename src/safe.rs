@@ -0,0 +1,79 @@
+//! A safe entry point, for when the process is in a state where forking can't go wrong.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Fork, ForkError};
+
+/// Like [`fork_map`](crate::fork_map), but safe to call, because it checks the one thing that
+/// makes forking unsound before it forks: that the calling thread is the only one in the
+/// process. With no other threads, there are no locks held by someone who won't be around in the
+/// child to release them, which is what the safety section of `fork_map` is about. If there are
+/// others, it fails with [`ForkError::MultiThreaded`] without forking.
+///
+/// That's as safe as fork gets, and it's the common case for a command-line tool that isolates
+/// a risky computation before it starts any threads of its own. Anything the closure changes
+/// stays in the child, since the child has a copy of the parent's memory, so it can't corrupt
+/// the parent's state however it behaves.
+///
+/// Like every child, it runs the closure on a fresh thread rather than the one that forked, so
+/// it starts from a clean stack and its own thread-locals, not whatever the caller was in the
+/// middle of, and a panic in it comes back as an error.
+///
+/// Threads are counted in `/proc/self/task`, so outside of Linux there's no telling, and this
+/// fails with [`ForkError::Unsupported`]; use the `unsafe` functions there. When
+/// [`FORKS`](crate::FORKS) is `false` there's no child and nothing to check, so it runs the
+/// closure in the parent like `fork_map` would.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_safe, ForkError};
+/// # if !fork_map::FORKS || !cfg!(target_os = "linux") { return }
+///
+/// // No unsafe block
+/// let result = fork_map_safe(|| Ok(6 * 7)).unwrap();
+/// assert_eq!(result, 42);
+///
+/// let err = fork_map_safe(|| -> anyhow::Result<()> { panic!("oh no") }).unwrap_err();
+/// assert!(err.to_string().contains("oh no"));
+///
+/// // But not once there's another thread
+/// let (stop, stopped) = std::sync::mpsc::channel::<()>();
+/// let other = std::thread::spawn(move || stopped.recv());
+/// let err = fork_map_safe(|| Ok(1)).unwrap_err();
+/// assert!(matches!(err.downcast_ref(), Some(ForkError::MultiThreaded { .. })));
+/// drop(stop);
+/// other.join().unwrap().unwrap_err();
+/// ```
+pub fn fork_map_safe<F, R>(func: F) -> anyhow::Result<R>
+where
+    F: Fn() -> anyhow::Result<R> + Send + Sync,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    if crate::FORKS {
+        let threads = thread_count()?;
+        if threads > 1 {
+            return Err(ForkError::MultiThreaded { threads }.into());
+        }
+    }
+    // Safety: the only thread that could hold a lock is the one forking, and it isn't holding
+    // any of ours, so nothing in the child is left locked
+    unsafe { Fork::builder().run(func) }
+}
+
+#[cfg(target_os = "linux")]
+fn thread_count() -> Result<usize, ForkError> {
+    std::fs::read_dir("/proc/self/task")
+        .map(Iterator::count)
+        .map_err(|source| ForkError::Io {
+            op: "read_dir",
+            source,
+        })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_count() -> Result<usize, ForkError> {
+    Err(ForkError::Unsupported {
+        feature: "fork_map_safe",
+    })
+}
@@ -0,0 +1,35 @@
+//! Applying a Seatbelt (SBPL) sandbox profile on macOS.
+
+use std::ffi::{CStr, CString};
+
+use anyhow::anyhow;
+
+extern "C" {
+    // From libsandbox, which is part of libSystem. Deprecated in the headers but still what
+    // sandbox-exec uses, and the only way to apply a custom profile to an existing process.
+    fn sandbox_init(
+        profile: *const libc::c_char,
+        flags: u64,
+        errorbuf: *mut *mut libc::c_char,
+    ) -> libc::c_int;
+    fn sandbox_free_error(errorbuf: *mut libc::c_char);
+}
+
+/// Applies `profile` to the calling process. There is no way back out of a sandbox once applied.
+pub(crate) unsafe fn apply(profile: &str) -> anyhow::Result<()> {
+    let profile =
+        CString::new(profile).map_err(|_| anyhow!("sandbox profile contains a nul byte"))?;
+    let mut error = std::ptr::null_mut();
+    // flags = 0 means `profile` is the profile text itself rather than a named profile
+    if sandbox_init(profile.as_ptr(), 0, &mut error) != 0 {
+        let message = if error.is_null() {
+            "unknown error".to_string()
+        } else {
+            let message = CStr::from_ptr(error).to_string_lossy().into_owned();
+            sandbox_free_error(error);
+            message
+        };
+        return Err(anyhow!("sandbox_init: {}", message));
+    }
+    Ok(())
+}
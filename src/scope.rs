@@ -0,0 +1,198 @@
+//! Children tied to a scope, like threads in [`std::thread::scope`].
+
+use std::cell::RefCell;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Fork, ForkBuilder, ForkError, ForkHandle};
+
+/// Runs `f` with a [`ForkScope`] to spawn children from, and makes sure none of them outlive
+/// the call.
+///
+/// If `f` returns `Ok`, every child you didn't [`join`](ScopedForkHandle::join) is waited for,
+/// and their results are returned alongside `f`'s, as `(index, result)` pairs in the order the
+/// children were spawned. If `f` returns an error or panics, the children still running are
+/// killed with the [`kill_signal`](crate::ForkBuilder::kill_signal) and reaped before the error
+/// is returned or the panic continues, so neither zombies nor children stuck writing to a pipe
+/// nobody reads are left behind.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::fork_scope;
+///
+/// let (sum, rest) = unsafe {
+///     fork_scope(|scope| {
+///         let first = scope.spawn(|| Ok(1u32));
+///         scope.spawn(|| Ok(2));
+///         scope.spawn(|| Ok(3));
+///         Ok(first.join()? + 10)
+///     })
+/// }
+/// .unwrap();
+/// assert_eq!(sum, 11);
+/// let rest: Vec<(usize, u32)> = rest.into_iter().map(|(i, r)| (i, r.unwrap())).collect();
+/// assert_eq!(rest, [(1, 2), (2, 3)]);
+/// ```
+///
+/// Leaving early kills what's left:
+///
+/// ```
+/// use fork_map::fork_scope;
+/// use std::time::{Duration, Instant};
+/// # if !fork_map::FORKS { return }
+///
+/// let stuck = || {
+///     std::thread::sleep(Duration::from_secs(60));
+///     Ok(())
+/// };
+/// let start = Instant::now();
+/// let err = unsafe {
+///     fork_scope(|scope| -> anyhow::Result<()> {
+///         scope.spawn(stuck);
+///         scope.spawn(stuck);
+///         anyhow::bail!("changed my mind")
+///     })
+/// }
+/// .unwrap_err();
+/// assert_eq!(err.to_string(), "changed my mind");
+///
+/// let panicked = std::panic::catch_unwind(|| unsafe {
+///     fork_scope(|scope| -> anyhow::Result<()> {
+///         scope.spawn(stuck);
+///         panic!("oh no")
+///     })
+/// });
+/// assert!(panicked.is_err());
+///
+/// assert!(start.elapsed() < Duration::from_secs(30));
+/// // Nothing left over, not even zombies
+/// assert_eq!(unsafe { libc::waitpid(-1, std::ptr::null_mut(), libc::WNOHANG) }, -1);
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`](crate::fork_map), for every call to [`ForkScope::spawn`].
+pub unsafe fn fork_scope<F, T, R>(f: F) -> anyhow::Result<(T, ScopeResults<R>)>
+where
+    F: for<'scope> FnOnce(&'scope ForkScope<R>) -> anyhow::Result<T>,
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    let scope = ForkScope {
+        handles: RefCell::new(vec![]),
+    };
+    // On an error or panic, dropping the scope kills and reaps the rest
+    let value = f(&scope)?;
+    Ok((value, scope.join_all()))
+}
+
+/// The results of the children [`fork_scope`] joined for you, as `(index, result)` pairs.
+pub type ScopeResults<R> = Vec<(usize, Result<R, ForkError>)>;
+
+/// Spawns children that can't outlive it. See [`fork_scope`].
+pub struct ForkScope<R> {
+    /// Indexed by spawn order; `None` once joined or if spawning failed.
+    handles: RefCell<Vec<Option<anyhow::Result<ForkHandle<R>>>>>,
+}
+
+impl<R> ForkScope<R>
+where
+    R: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    /// Forks and starts running `func` in a child, like [`ForkBuilder::spawn`]. An error
+    /// starting the child is returned when you join it.
+    pub fn spawn<F>(&self, func: F) -> ScopedForkHandle<'_, R>
+    where
+        F: Fn() -> anyhow::Result<R> + Send + Sync,
+    {
+        self.spawn_with(Fork::builder(), func)
+    }
+
+    /// Like [`spawn`](Self::spawn), with the child configured by `builder`.
+    pub fn spawn_with<F>(&self, builder: ForkBuilder, func: F) -> ScopedForkHandle<'_, R>
+    where
+        F: Fn() -> anyhow::Result<R> + Send + Sync,
+    {
+        // Safety: promised by whoever created the scope
+        let handle = unsafe { builder.spawn(func) };
+        let mut handles = self.handles.borrow_mut();
+        handles.push(Some(handle));
+        ScopedForkHandle {
+            scope: self,
+            index: handles.len() - 1,
+        }
+    }
+
+    fn join_all(&self) -> ScopeResults<R> {
+        let handles = std::mem::take(&mut *self.handles.borrow_mut());
+        handles
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, handle)| {
+                let result = handle?.and_then(ForkHandle::join);
+                Some((index, result.map_err(ForkError::from_anyhow)))
+            })
+            .collect()
+    }
+}
+
+impl<R> Drop for ForkScope<R> {
+    fn drop(&mut self) {
+        let handles = std::mem::take(self.handles.get_mut());
+        #[cfg(all(unix, not(feature = "fallback")))]
+        for handle in handles.iter().flatten().flatten() {
+            handle.kill();
+        }
+        // Dropping the handles reaps the children
+        drop(handles);
+    }
+}
+
+impl<R> fmt::Debug for ForkScope<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ForkScope").finish_non_exhaustive()
+    }
+}
+
+/// A child spawned in a [`ForkScope`], which can't outlive it.
+pub struct ScopedForkHandle<'scope, R> {
+    scope: &'scope ForkScope<R>,
+    index: usize,
+}
+
+impl<R> ScopedForkHandle<'_, R>
+where
+    R: for<'a> Deserialize<'a>,
+{
+    /// Where this child is in the order of the scope's children, as used for the results
+    /// [`fork_scope`] collects.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The child's process id, or `None` if it couldn't be started.
+    pub fn pid(&self) -> Option<u32> {
+        match &self.scope.handles.borrow()[self.index] {
+            Some(Ok(handle)) => Some(handle.pid()),
+            _ => None,
+        }
+    }
+
+    /// Waits for the child to terminate and returns the result of the closure, or the error
+    /// that kept it from starting.
+    pub fn join(self) -> anyhow::Result<R> {
+        let handle = self.scope.handles.borrow_mut()[self.index]
+            .take()
+            .expect("scoped handle joined twice");
+        handle?.join()
+    }
+}
+
+impl<R> fmt::Debug for ScopedForkHandle<'_, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScopedForkHandle")
+            .field("index", &self.index)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,190 @@
+//! Restricting the child's system calls with a seccomp-bpf filter on Linux.
+//!
+//! The filter is a plain allowlist: a BPF program comparing the syscall number against each
+//! allowed one in turn, killing the whole process on anything else. That's slower to evaluate
+//! than a binary search, but the lists are short, and it's simple enough to check by eye.
+
+// Without fork() there's no child to apply it to, only the options to reject
+#![cfg_attr(feature = "fallback", allow(dead_code))]
+
+/// The `AUDIT_ARCH_*` the filter was written for, so a syscall made through another ABI (32-bit
+/// `int 0x80` on x86_64, where the numbers mean different calls) is never let through.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Where the fields of `struct seccomp_data` are, for loading them.
+const NR: u32 = 0;
+const ARCH: u32 = 4;
+/// The low half of the first argument, which is all of `clone`'s flags that matter here.
+const ARG0: u32 = 16;
+/// The third argument, in two halves.
+const ARG2: u32 = 32;
+
+/// What the child needs after the filter is in place, to run the closure on a thread of its
+/// own, allocate, and send the result back and exit. Threads are made with `clone`, which is
+/// only allowed with `CLONE_THREAD`, so the filter can't be escaped by forking, and
+/// `prlimit64` only to read limits, for the size of the closure's stack.
+const BASELINE: &[libc::c_long] = &[
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_sendto,
+    libc::SYS_sendmsg,
+    libc::SYS_lseek,
+    libc::SYS_fcntl,
+    libc::SYS_close,
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sigaltstack,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_kill,
+    libc::SYS_tgkill,
+    libc::SYS_wait4,
+    libc::SYS_waitid,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_getrandom,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+];
+
+/// The system calls a child started with [`seccomp`](crate::ForkBuilder::seccomp) may make.
+/// Any other kills it with `SIGSYS` before the call happens, which the parent reports as
+/// [`ForkError::SyscallBlocked`](crate::ForkError::SyscallBlocked).
+///
+/// A new profile allows only what this crate needs to run the closure and send its result
+/// back: reading and writing file descriptors that are already open, memory allocation,
+/// threads, signals, waiting, clocks, random numbers and exiting. Notably, that leaves out
+/// opening files, sockets, `exec` and forking, so a closure that's been taken over can still
+/// write to any descriptor it inherited, but can't reach anything new. Close what it shouldn't
+/// have, or leave it out with [`null_stdio`](crate::ForkBuilder::null_stdio), before it runs.
+/// Whatever else the closure needs is added with [`allow`](Self::allow), as the `libc::SYS_*`
+/// numbers for the target.
+///
+/// ```
+/// use fork_map::SeccompProfile;
+///
+/// // Parsing files that are opened in the closure, without being able to write to them
+/// let profile = SeccompProfile::new().allow(&[libc::SYS_openat, libc::SYS_fstat]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SeccompProfile {
+    allowed: Vec<libc::c_long>,
+}
+
+impl SeccompProfile {
+    /// A profile allowing only what the child needs to run at all.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows `syscalls` too.
+    pub fn allow(mut self, syscalls: &[libc::c_long]) -> Self {
+        self.allowed.extend_from_slice(syscalls);
+        self
+    }
+
+    /// The BPF program. `clone3` fails with `ENOSYS`, rather than being allowed, because its
+    /// flags are in memory where the filter can't look at them, and libc falls back to `clone`.
+    fn program(&self) -> Vec<libc::sock_filter> {
+        let stmt = |code: u32, k: u32| unsafe { libc::BPF_STMT(code as u16, k) };
+        let jump = |k: libc::c_long, jt: u8, jf: u8| unsafe {
+            libc::BPF_JUMP(
+                (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+                k as u32,
+                jt,
+                jf,
+            )
+        };
+        let load = |offset: u32| stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset);
+        let ret = |action: u32| stmt(libc::BPF_RET | libc::BPF_K, action);
+
+        let mut program = vec![
+            load(ARCH),
+            jump(AUDIT_ARCH as libc::c_long, 1, 0),
+            ret(libc::SECCOMP_RET_KILL_PROCESS),
+            load(NR),
+        ];
+        // The x32 ABI's calls are the x86_64 ones with this bit set
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            unsafe {
+                libc::BPF_JUMP(
+                    (libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K) as u16,
+                    0x4000_0000,
+                    0,
+                    1,
+                )
+            },
+            ret(libc::SECCOMP_RET_KILL_PROCESS),
+        ]);
+        program.extend([
+            jump(libc::SYS_clone3, 0, 1),
+            ret(libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+            jump(libc::SYS_clone, 0, 5),
+            load(ARG0),
+            stmt(
+                libc::BPF_ALU | libc::BPF_AND | libc::BPF_K,
+                libc::CLONE_THREAD as u32,
+            ),
+            jump(libc::CLONE_THREAD as libc::c_long, 0, 1),
+            ret(libc::SECCOMP_RET_ALLOW),
+            ret(libc::SECCOMP_RET_KILL_PROCESS),
+            // Where the new limit would be is null
+            jump(libc::SYS_prlimit64, 0, 6),
+            load(ARG2),
+            jump(0, 0, 3),
+            load(ARG2 + 4),
+            jump(0, 0, 1),
+            ret(libc::SECCOMP_RET_ALLOW),
+            ret(libc::SECCOMP_RET_KILL_PROCESS),
+        ]);
+        for &syscall in BASELINE.iter().chain(&self.allowed) {
+            program.extend([jump(syscall, 0, 1), ret(libc::SECCOMP_RET_ALLOW)]);
+        }
+        program.push(ret(libc::SECCOMP_RET_KILL_PROCESS));
+        program
+    }
+}
+
+/// Applies `profile` to the calling process, and every thread it starts from then on. There's
+/// no undoing it.
+#[cfg(not(feature = "fallback"))]
+pub(crate) unsafe fn apply(profile: &SeccompProfile) -> Result<(), crate::ForkError> {
+    let program = profile.program();
+    let fprog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+    // Otherwise only root may install a filter, so that a setuid program can't be tricked by one
+    if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0 {
+        return Err(crate::ForkError::last_os_error("prctl"));
+    }
+    let filter = libc::SECCOMP_SET_MODE_FILTER;
+    if libc::syscall(
+        libc::SYS_seccomp,
+        filter,
+        0,
+        &fprog as *const libc::sock_fprog,
+    ) < 0
+    {
+        return Err(crate::ForkError::last_os_error("seccomp"));
+    }
+    Ok(())
+}
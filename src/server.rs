@@ -0,0 +1,427 @@
+//! A fork server: a broker forked early, that forks children on the parent's behalf later.
+//!
+//! The parent and the broker share a control socket. For each job, the parent sends a request
+//! made of the address of a monomorphized trampoline, the address of the handler, and the input
+//! encoded with the default [`Codec`](crate::Codec), as three little-endian `u64`s (trampoline,
+//! handler, input length) followed by the input. One end of a fresh socket pair comes along with
+//! the request as `SCM_RIGHTS`, and that's where the reply goes: the child's pid and raw wait
+//! status as a little-endian `u32` and `i32`, followed by the frame the child sent (see
+//! `protocol`). Since each job has its own reply socket, threads in the parent only take turns
+//! sending requests, and never have to sort out each other's replies.
+//!
+//! Addresses are only meaningful because the broker is a copy of the parent, with the same
+//! code at the same place.
+
+#[cfg(all(unix, not(feature = "fallback")))]
+use std::sync::{Mutex, MutexGuard};
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(all(unix, not(feature = "fallback")))]
+use crate::{forked, limit, sys};
+use crate::{Fork, ForkError};
+
+/// The broker, as seen from the parent.
+#[cfg(all(unix, not(feature = "fallback")))]
+struct Server {
+    pid: libc::pid_t,
+    control: libc::c_int,
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+static SERVER: Mutex<Option<Server>> = Mutex::new(None);
+
+/// Decodes the input, runs the handler and encodes its result, in the child.
+#[cfg(all(unix, not(feature = "fallback")))]
+type Trampoline = unsafe fn(handler: usize, input: &[u8]) -> Vec<u8>;
+
+#[cfg(all(unix, not(feature = "fallback")))]
+const REQUEST_HEADER_LEN: usize = 24;
+
+#[cfg(all(unix, not(feature = "fallback")))]
+const REPLY_HEADER_LEN: usize = 8;
+
+/// Starts the fork server used by [`fork_map_via_server`], if it isn't running already.
+///
+/// Call this as early as you can, ideally first thing in `main`, while the process is small and
+/// has no other threads. It forks a broker process that does nothing but wait for requests, and
+/// every child started through the server is forked from the broker instead of from your
+/// process. Those forks stay as cheap as they are now however big your process gets, and they
+/// can't inherit a lock some other thread of yours happened to be holding, since by then there
+/// were none.
+///
+/// The broker exits when [`shutdown_fork_server`] is called or your process exits, and kills
+/// whatever children it still has running on its way out.
+///
+/// # Safety
+///
+/// Same as [`fork_map`](crate::fork_map), for the broker: it's a copy of your process as it is
+/// right now, and every child it forks is a copy of that.
+pub unsafe fn init_fork_server() -> Result<(), ForkError> {
+    #[cfg(all(unix, not(feature = "fallback")))]
+    {
+        let mut server = lock();
+        if server.is_some() {
+            return Ok(());
+        }
+        let socket = sys::socketpair()?;
+        let pid = match sys::fork() {
+            Ok(pid) => pid,
+            Err(e) => {
+                libc::close(socket[0]);
+                libc::close(socket[1]);
+                return Err(e);
+            }
+        };
+        if pid == 0 {
+            // Broker: our copy of the lock was held by this thread, so it's ours to give back,
+            // and anything the children try to send through a server of their own fails cleanly
+            drop(server);
+            libc::close(socket[0]);
+            broker(socket[1]);
+        }
+        libc::close(socket[1]);
+        *server = Some(Server {
+            pid,
+            control: socket[0],
+        });
+    }
+    Ok(())
+}
+
+/// Stops the fork server started by [`init_fork_server`] and reaps the broker. Jobs still
+/// running in it fail with [`ForkError::ChildFailed`], since their children are killed, and
+/// jobs started afterwards fail with [`ForkError::NoForkServer`] until the server is started
+/// again. Does nothing if it isn't running.
+pub fn shutdown_fork_server() {
+    #[cfg(all(unix, not(feature = "fallback")))]
+    if let Some(server) = lock().take() {
+        unsafe {
+            // The broker takes EOF as the signal to leave
+            libc::close(server.control);
+            let _ = sys::wait4(server.pid);
+        }
+    }
+}
+
+/// Runs `handler(input)` in a child forked by the fork server, and returns its result.
+///
+/// This is [`ForkPool::run`](crate::ForkPool::run), but with a fresh child for every job, and
+/// with children forked from the small process your program was when it called
+/// [`init_fork_server`], rather than from the one it has grown into. The handler sees memory as
+/// it was back then: anything your program has set up since (including statics it has filled
+/// in) isn't there, and neither is any code loaded with `dlopen` since, so the handler can't be
+/// a function from such a library. It has to be a plain `fn`, not a closure, since there's no
+/// sending a closure's captures to a process that already exists; everything it needs has to
+/// come in `input`. [`fork_map_fn`](crate::fork_map_fn) takes the same arguments, and forks
+/// the process as it is now.
+///
+/// Jobs from different threads run at the same time, each in its own child. The
+/// [`set_max_concurrent_forks`](crate::set_max_concurrent_forks) limit applies, and both the
+/// input and the result go through the default [`Codec`](crate::Codec). A handler that panics
+/// fails with the panic message, as a closure passed to [`fork_map`](crate::fork_map) does.
+///
+/// When [`FORKS`](crate::FORKS) is `false` there's no server, and the handler runs in the
+/// calling process instead.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_via_server, init_fork_server, shutdown_fork_server, ForkError};
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// static LOADED: AtomicUsize = AtomicUsize::new(0);
+///
+/// fn count_words(text: String) -> anyhow::Result<(usize, usize)> {
+///     Ok((text.split_whitespace().count(), LOADED.load(Ordering::SeqCst)))
+/// }
+///
+/// fn crash(_: ()) -> anyhow::Result<()> {
+///     unsafe { libc::kill(libc::getpid(), libc::SIGKILL) };
+///     Ok(())
+/// }
+///
+/// fn panic(_: ()) -> anyhow::Result<()> {
+///     panic!("asked to");
+/// }
+///
+/// // First thing, while the process is still small
+/// unsafe { init_fork_server() }.unwrap();
+///
+/// // Much later, after loading gigabytes of state
+/// LOADED.store(1, Ordering::SeqCst);
+/// std::thread::scope(|s| {
+///     for n in 1..=8 {
+///         s.spawn(move || {
+///             let (words, loaded) = fork_map_via_server("word ".repeat(n), count_words).unwrap();
+///             assert_eq!(words, n);
+///             if fork_map::FORKS {
+///                 // Forked from the broker, which never saw it loaded
+///                 assert_eq!(loaded, 0);
+///             }
+///         });
+///     }
+/// });
+///
+/// # if !fork_map::FORKS { return }
+/// let err = fork_map_via_server((), crash).unwrap_err();
+/// assert!(matches!(err.downcast_ref(), Some(ForkError::ChildFailed { .. })));
+/// let err = fork_map_via_server((), panic).unwrap_err();
+/// assert!(err.to_string().contains("asked to"), "{}", err);
+///
+/// shutdown_fork_server();
+/// let err = fork_map_via_server("too late".to_string(), count_words).unwrap_err();
+/// assert!(matches!(err.downcast_ref(), Some(ForkError::NoForkServer)));
+/// ```
+pub fn fork_map_via_server<I, R>(input: I, handler: fn(I) -> anyhow::Result<R>) -> anyhow::Result<R>
+where
+    I: Serialize + for<'a> Deserialize<'a>,
+    R: Serialize + for<'a> Deserialize<'a>,
+{
+    #[cfg(all(unix, not(feature = "fallback")))]
+    {
+        via_server(input, handler)
+    }
+    #[cfg(any(not(unix), feature = "fallback"))]
+    {
+        Fork::builder().run_item_in_process(&handler, input)
+    }
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+fn via_server<I, R>(input: I, handler: fn(I) -> anyhow::Result<R>) -> anyhow::Result<R>
+where
+    I: Serialize + for<'a> Deserialize<'a>,
+    R: Serialize + for<'a> Deserialize<'a>,
+{
+    let builder = Fork::builder();
+    let body = builder.codec.encode(&input)?;
+    let trampoline = trampoline::<I, R> as Trampoline;
+    let mut request = Vec::with_capacity(REQUEST_HEADER_LEN + body.len());
+    request.extend_from_slice(&(trampoline as usize as u64).to_le_bytes());
+    request.extend_from_slice(&(handler as usize as u64).to_le_bytes());
+    request.extend_from_slice(&(body.len() as u64).to_le_bytes());
+    request.extend_from_slice(&body);
+
+    // Held until the child is reaped, which is before the broker replies
+    let _permit = limit::acquire();
+    unsafe {
+        let reply = sys::socketpair()?;
+        let sent = match &*lock() {
+            Some(server) => sys::send_with_fd(server.control, &request, reply[1]),
+            None => Err(ForkError::NoForkServer),
+        };
+        // The broker has its own copy now, so EOF means it's done with this job
+        libc::close(reply[1]);
+        if let Err(e) = sent {
+            libc::close(reply[0]);
+            return Err(match e {
+                // The broker is gone
+                ForkError::Io { .. } => ForkError::NoForkServer,
+                e => e,
+            }
+            .into());
+        }
+        let mut received = vec![];
+        let read = loop {
+            match sys::read_some(reply[0], &mut received) {
+                Ok(0) => break Ok(()),
+                Ok(_) => {}
+                Err(e) => break Err(e),
+            }
+        };
+        libc::close(reply[0]);
+        read?;
+
+        if received.len() < REPLY_HEADER_LEN {
+            // The broker died before it could reply
+            return Err(ForkError::NoForkServer.into());
+        }
+        let pid = u32::from_le_bytes(received[..4].try_into().unwrap());
+        let status = i32::from_le_bytes(received[4..8].try_into().unwrap());
+        if status != 0 {
+            return Err(ForkError::ChildFailed { pid, status }.into());
+        }
+        let frame = &received[REPLY_HEADER_LEN..];
+        if frame.is_empty() {
+            return Err(ForkError::NoResult { pid }.into());
+        }
+        builder.decode_result(pid, frame)
+    }
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+unsafe fn trampoline<I, R>(handler: usize, input: &[u8]) -> Vec<u8>
+where
+    I: for<'a> Deserialize<'a>,
+    R: Serialize,
+{
+    // Safety: the parent sent the address of a `fn(I) -> anyhow::Result<R>` along with this
+    let handler: fn(I) -> anyhow::Result<R> = std::mem::transmute(handler);
+    let builder = Fork::builder();
+    let input = builder.codec.decode::<I>(std::process::id(), input);
+    // A panic is sent back like any other error, rather than unwinding out of the broker's loop
+    let result = input
+        .map_err(anyhow::Error::from)
+        .and_then(|input| forked::catch_panic(|| handler(input)));
+    builder.encode_result(result)
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+fn lock() -> MutexGuard<'static, Option<Server>> {
+    // Nothing panics while holding the lock, but don't take everyone down if it somehow did
+    SERVER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A child the broker forked, whose output it's collecting.
+#[cfg(all(unix, not(feature = "fallback")))]
+struct Job {
+    pid: libc::pid_t,
+    output: libc::c_int,
+    reply: libc::c_int,
+    received: Vec<u8>,
+}
+
+/// The broker's side: forks a child for each request until the parent goes away.
+#[cfg(all(unix, not(feature = "fallback")))]
+unsafe fn broker(control: libc::c_int) -> ! {
+    let mut jobs: Vec<Job> = vec![];
+    loop {
+        let mut fds: Vec<libc::pollfd> = std::iter::once(control)
+            .chain(jobs.iter().map(|job| job.output))
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        if sys::poll(&mut fds, None).is_err() {
+            break;
+        }
+        // Backwards, so jobs that get removed are only replaced by ones already handled
+        for index in (0..jobs.len()).rev() {
+            if fds[index + 1].revents == 0 {
+                continue;
+            }
+            let job = &mut jobs[index];
+            if !matches!(sys::read_some(job.output, &mut job.received), Ok(count) if count > 0) {
+                reply(jobs.swap_remove(index));
+            }
+        }
+        if fds[0].revents != 0 {
+            // If not, the parent is gone or shut us down, or is no longer making sense
+            let Some(request) = receive(control) else {
+                break;
+            };
+            match start(control, &jobs, &request) {
+                Ok(job) => jobs.push(job),
+                Err(e) => refuse(request.reply, e),
+            }
+        }
+    }
+    for job in jobs {
+        libc::kill(job.pid, libc::SIGKILL);
+        reply(job);
+    }
+    libc::exit(0);
+}
+
+/// A job as the parent asked for it.
+#[cfg(all(unix, not(feature = "fallback")))]
+struct Request {
+    trampoline: Trampoline,
+    handler: usize,
+    input: Vec<u8>,
+    reply: libc::c_int,
+}
+
+/// Reads the next request, or returns `None` if there are no more.
+#[cfg(all(unix, not(feature = "fallback")))]
+unsafe fn receive(control: libc::c_int) -> Option<Request> {
+    let mut header = [0u8; REQUEST_HEADER_LEN];
+    let (got, reply) = sys::recv_with_fd(control, &mut header).ok()?;
+    let reply = match reply {
+        Some(reply) if got == REQUEST_HEADER_LEN => reply,
+        Some(reply) => {
+            libc::close(reply);
+            return None;
+        }
+        None => return None,
+    };
+    let field = |index: usize| {
+        let bytes = &header[index * 8..index * 8 + 8];
+        u64::from_le_bytes(bytes.try_into().unwrap()) as usize
+    };
+    let mut input = vec![0u8; field(2)];
+    if sys::read_exact(control, &mut input).ok() != Some(input.len()) {
+        libc::close(reply);
+        return None;
+    }
+    Some(Request {
+        // Safety: the parent sent the address of a `Trampoline`
+        trampoline: std::mem::transmute::<usize, Trampoline>(field(0)),
+        handler: field(1),
+        input,
+        reply,
+    })
+}
+
+/// Forks a child for `request`.
+#[cfg(all(unix, not(feature = "fallback")))]
+unsafe fn start(control: libc::c_int, jobs: &[Job], request: &Request) -> Result<Job, ForkError> {
+    let output = sys::pipe()?;
+    let pid = match sys::fork() {
+        Ok(pid) => pid,
+        Err(e) => {
+            libc::close(output[0]);
+            libc::close(output[1]);
+            return Err(e);
+        }
+    };
+    if pid == 0 {
+        // Child: nothing of the broker's is any of its business
+        libc::close(control);
+        libc::close(request.reply);
+        libc::close(output[0]);
+        for job in jobs {
+            libc::close(job.output);
+            libc::close(job.reply);
+        }
+        let frame = (request.trampoline)(request.handler, &request.input);
+        sys::send_result_and_exit(output[1], &frame, 0);
+    }
+    libc::close(output[1]);
+    Ok(Job {
+        pid,
+        output: output[0],
+        reply: request.reply,
+        received: vec![],
+    })
+}
+
+/// Tells the parent why its job never got a child.
+#[cfg(all(unix, not(feature = "fallback")))]
+unsafe fn refuse(reply: libc::c_int, error: ForkError) {
+    // There's no child, so no pid and nothing wrong with its status
+    let mut message = vec![0u8; REPLY_HEADER_LEN];
+    message.extend_from_slice(&Fork::builder().encode_result::<()>(Err(error.into())));
+    let _ = sys::send_all(reply, &message);
+    libc::close(reply);
+}
+
+/// Reaps a job's child and sends the parent its exit status and output.
+#[cfg(all(unix, not(feature = "fallback")))]
+unsafe fn reply(job: Job) {
+    libc::close(job.output);
+    let status = match sys::wait4(job.pid) {
+        Ok((status, _)) => status,
+        Err(_) => -1,
+    };
+    let mut message = Vec::with_capacity(REPLY_HEADER_LEN + job.received.len());
+    message.extend_from_slice(&(job.pid as u32).to_le_bytes());
+    message.extend_from_slice(&status.to_le_bytes());
+    message.extend_from_slice(&job.received);
+    // The parent may have given up on it, which is fine
+    let _ = sys::send_all(job.reply, &message);
+    libc::close(job.reply);
+}
@@ -0,0 +1,34 @@
+/// The signal a child is killed with when it has to be stopped early: when it times out, loses
+/// a [race](crate::fork_map_race), or is abandoned because something else failed. See
+/// [`kill_signal`](crate::ForkBuilder::kill_signal).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Signal {
+    /// `SIGKILL`, which can't be caught, so the child is gone right away.
+    #[default]
+    Kill,
+    /// `SIGTERM`, the usual request to clean up and exit.
+    Term,
+    /// `SIGINT`, as sent by Ctrl-C in a terminal.
+    Int,
+    /// `SIGHUP`.
+    Hup,
+    /// `SIGUSR1`, for a child with a handler of its own for it.
+    Usr1,
+    /// `SIGUSR2`, likewise.
+    Usr2,
+}
+
+impl Signal {
+    /// The signal's number, as `kill()` takes it.
+    pub fn as_raw(self) -> i32 {
+        match self {
+            Signal::Kill => libc::SIGKILL,
+            Signal::Term => libc::SIGTERM,
+            Signal::Int => libc::SIGINT,
+            Signal::Hup => libc::SIGHUP,
+            Signal::Usr1 => libc::SIGUSR1,
+            Signal::Usr2 => libc::SIGUSR2,
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+/// Resources a child used, as reported by `wait4()` when it was reaped.
+///
+/// The numbers cover the child and any of its own children it waited for, but nothing else the
+/// parent has forked. When [`FORKS`](crate::FORKS) is `false` there's no child to measure, and
+/// everything is zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ChildUsage {
+    /// CPU time spent in user mode.
+    pub user_time: Duration,
+    /// CPU time spent in the kernel on the child's behalf.
+    pub system_time: Duration,
+    /// Peak resident set size, in bytes.
+    ///
+    /// Linux and the BSDs report this in kilobytes and macOS in bytes; it's converted so it's
+    /// always bytes here. Since the child starts out sharing the parent's memory, pages of the
+    /// parent's it touches count too, so expect at least a little of the parent's size in here.
+    pub max_rss: u64,
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+impl From<&libc::rusage> for ChildUsage {
+    fn from(usage: &libc::rusage) -> Self {
+        fn duration(time: libc::timeval) -> Duration {
+            Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+        }
+        #[cfg(target_os = "macos")]
+        let max_rss = usage.ru_maxrss as u64;
+        #[cfg(not(target_os = "macos"))]
+        let max_rss = usage.ru_maxrss as u64 * 1024;
+        ChildUsage {
+            user_time: duration(usage.ru_utime),
+            system_time: duration(usage.ru_stime),
+            max_rss,
+        }
+    }
+}
+
+/// When each phase of a run finished, measured from just before `fork()`, so the fields are in
+/// increasing order.
+///
+/// Taking the differences between them tells you where the time went: `forked` is the cost of
+/// `fork()` itself (which grows with the parent's address space), `first_byte` is mostly your
+/// closure plus serializing its result, `eof - first_byte` is moving the result through the pipe,
+/// and `decoded - reaped` is deserializing it in the parent. When [`FORKS`](crate::FORKS) is
+/// `false`, `forked` is zero and everything up to `reaped` is the in-process call.
+///
+/// ```
+/// use fork_map::fork_map_with_stats;
+/// use std::time::Duration;
+///
+/// let (_, stats) = unsafe {
+///     fork_map_with_stats(|| {
+///         std::thread::sleep(Duration::from_millis(50));
+///         Ok(())
+///     })
+///     .unwrap()
+/// };
+/// let t = stats.timings;
+/// assert!(t.forked <= t.first_byte);
+/// assert!(t.first_byte <= t.eof && t.eof <= t.reaped && t.reaped <= t.decoded);
+/// assert!(t.first_byte - t.forked >= Duration::from_millis(50));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ForkTimings {
+    /// `fork()` returned in the parent.
+    pub forked: Duration,
+    /// The first byte of the result arrived. Equal to `eof` if the child sent nothing.
+    pub first_byte: Duration,
+    /// The child closed its end of the pipe, so the whole result has arrived.
+    pub eof: Duration,
+    /// The child was reaped.
+    pub reaped: Duration,
+    /// The result was deserialized.
+    pub decoded: Duration,
+}
+
+/// Everything measured about a run. See
+/// [`fork_map_with_stats`](crate::fork_map_with_stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ForkStats {
+    /// What the child used.
+    pub usage: ChildUsage,
+    /// When each phase finished.
+    pub timings: ForkTimings,
+    /// How many bytes the result pipe could hold, after asking for
+    /// [`pipe_size`](crate::ForkBuilder::pipe_size). `None` where that can't be told, which is
+    /// everywhere but Linux, with a [`Transport`](crate::Transport) other than a pipe, or when
+    /// there's no child.
+    pub pipe_size: Option<usize>,
+    /// The code the child exited with, 0 unless the closure [picked
+    /// another](crate::set_exit_code). Always 0 when there's no child.
+    pub exit_code: i32,
+    /// How many of the child's own descendants were still around when the closure returned, and
+    /// had to be waited for or killed, with
+    /// [`reap_descendants`](crate::ForkBuilder::reap_descendants). Always 0 without it.
+    pub stray_descendants: usize,
+}
@@ -0,0 +1,217 @@
+//! Making sense of the raw status `waitpid` reports.
+
+use std::fmt;
+#[cfg(feature = "serde")]
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// The code a child exits with when it finds, on sending its result, that the parent is gone.
+///
+/// That's only ever seen by whoever reaps it instead of the parent, like a subreaper, since the
+/// parent itself isn't around to. It's what a shell reports for a process killed by `SIGPIPE`,
+/// which is how the child would otherwise have died.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{Fork, EXIT_PARENT_GONE};
+/// # if !fork_map::FORKS || !cfg!(target_os = "linux") { return }
+///
+/// unsafe {
+///     // Orphans end up with us, rather than with init
+///     libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1);
+///     let parent = libc::fork();
+///     if parent == 0 {
+///         let handle = Fork::builder().spawn(|| Ok(vec![7u8; 1 << 20])).unwrap();
+///         // Gone without reading a byte of it
+///         std::mem::forget(handle);
+///         libc::_exit(0);
+///     }
+///     let mut status = 0;
+///     libc::waitpid(parent, &mut status, 0);
+///     // And then its child, once it notices
+///     libc::waitpid(-1, &mut status, 0);
+///     assert!(libc::WIFEXITED(status));
+///     assert_eq!(libc::WEXITSTATUS(status), EXIT_PARENT_GONE);
+/// }
+/// ```
+pub const EXIT_PARENT_GONE: i32 = 141;
+
+/// The code a child exits with when it couldn't send its result for some reason other than the
+/// parent being gone, like the closure having closed the pipe it's sent on. The parent gets a
+/// [`ForkError::ChildFailed`](crate::ForkError::ChildFailed) that says so. It's `EX_IOERR` from
+/// `sysexits.h`.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map, interpret_status, ExitOutcome, ForkError, EXIT_SEND_FAILED};
+/// # if !fork_map::FORKS { return }
+///
+/// let err = unsafe {
+///     fork_map(|| {
+///         // The result pipe among them
+///         for fd in 3..1024 {
+///             libc::close(fd);
+///         }
+///         Ok(42)
+///     })
+/// }
+/// .unwrap_err();
+/// match err.downcast_ref() {
+///     Some(&ForkError::ChildFailed { status, .. }) => {
+///         assert_eq!(interpret_status(status), ExitOutcome::Exited(EXIT_SEND_FAILED));
+///         assert!(err.to_string().ends_with("couldn't send its result"));
+///     }
+///     _ => panic!("unexpected error {:?}", err),
+/// }
+/// ```
+pub const EXIT_SEND_FAILED: i32 = 74;
+
+/// The code a child exits with when the closure returns an error, unless it
+/// [picked another](set_exit_code).
+pub const EXIT_CLOSURE_FAILED: i32 = 1;
+
+/// The code a child exits with when the closure panics, unless it [picked
+/// another](set_exit_code). The same one a Rust program exits with when its main thread panics.
+pub const EXIT_CLOSURE_PANICKED: i32 = 101;
+
+/// What [`set_exit_code`] was last called with, or [`NO_EXIT_CODE`].
+#[cfg(feature = "serde")]
+static EXIT_CODE: AtomicI32 = AtomicI32::new(NO_EXIT_CODE);
+#[cfg(feature = "serde")]
+const NO_EXIT_CODE: i32 = i32::MIN;
+
+/// Called from a closure running in a child, picks the code the child exits with once it has
+/// sent its result, for whatever watches exit codes from outside, like a process supervisor or
+/// monitoring.
+///
+/// Otherwise, a child exits with 0 when the closure succeeds, [`EXIT_CLOSURE_FAILED`] when it
+/// returns an error, and [`EXIT_CLOSURE_PANICKED`] when it panics. Either way, a child that
+/// exits normally after sending its result hasn't failed, whatever the code: the parent gets the
+/// closure's result or error as it was sent, rather than
+/// [`ForkError::ChildFailed`](crate::ForkError::ChildFailed), and a successful result comes with
+/// the code in [`ForkStats::exit_code`](crate::ForkStats::exit_code). Only a child that exits
+/// before sending anything, or is killed, counts as failed.
+///
+/// Exit codes only have 8 bits, so the code is truncated to its lowest 8. Outside of a child,
+/// including when [`FORKS`](crate::FORKS) is `false`, this does nothing.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{set_exit_code, Fork};
+/// # if !fork_map::FORKS { return }
+///
+/// let (result, stats) = unsafe {
+///     Fork::builder()
+///         .run_with_stats(|| {
+///             set_exit_code(42);
+///             Ok("done, with warnings".to_string())
+///         })
+///         .unwrap()
+/// };
+/// assert_eq!(result, "done, with warnings");
+/// assert_eq!(stats.exit_code, 42);
+///
+/// // The error is what's reported, not the code it came with
+/// let err = unsafe {
+///     Fork::builder().run(|| -> anyhow::Result<()> {
+///         set_exit_code(42);
+///         anyhow::bail!("out of widgets")
+///     })
+/// }
+/// .unwrap_err();
+/// assert_eq!(err.to_string(), "out of widgets");
+/// ```
+#[cfg(feature = "serde")]
+pub fn set_exit_code(code: i32) {
+    EXIT_CODE.store(code, Ordering::SeqCst);
+}
+
+/// Runs in the child: forgets any code picked by a closure in the parent, if the parent is a
+/// child itself.
+#[cfg(all(feature = "serde", unix, not(feature = "fallback")))]
+pub(crate) fn reset_exit_code() {
+    EXIT_CODE.store(NO_EXIT_CODE, Ordering::SeqCst);
+}
+
+/// Runs in the child: the code [`set_exit_code`] picked, if it was called.
+#[cfg(all(feature = "serde", unix, not(feature = "fallback")))]
+pub(crate) fn exit_code() -> Option<i32> {
+    let code = EXIT_CODE.load(Ordering::SeqCst);
+    (code != NO_EXIT_CODE).then_some(code)
+}
+
+/// What a raw `waitpid` status says happened to a child. See [`interpret_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExitOutcome {
+    /// The child exited on its own, with this exit code.
+    Exited(i32),
+    /// The child was killed by this signal.
+    Signaled(i32),
+    /// The child was stopped by this signal, and may still be continued. Only reported when
+    /// waiting with `WUNTRACED`.
+    Stopped(i32),
+    /// The child was continued by `SIGCONT`. Only reported when waiting with `WCONTINUED`.
+    Continued,
+}
+
+/// Decodes a raw status from `waitpid` or `wait4`, like the one in
+/// [`ForkError::ChildFailed`](crate::ForkError::ChildFailed), with the platform's own `WIFEXITED`
+/// family of macros.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_bytes, interpret_status, ExitOutcome, ForkError};
+/// # if !fork_map::FORKS { return }
+///
+/// let err = unsafe {
+///     fork_map_bytes(|| {
+///         libc::kill(libc::getpid(), libc::SIGKILL);
+///         vec![]
+///     })
+/// }
+/// .unwrap_err();
+/// let ForkError::ChildFailed { status, .. } = err else {
+///     panic!("unexpected error {:?}", err)
+/// };
+/// assert_eq!(interpret_status(status), ExitOutcome::Signaled(libc::SIGKILL));
+///
+/// let err = unsafe { fork_map_bytes(|| libc::exit(3)) }.unwrap_err();
+/// let ForkError::ChildFailed { status, .. } = err else {
+///     panic!("unexpected error {:?}", err)
+/// };
+/// assert_eq!(interpret_status(status), ExitOutcome::Exited(3));
+/// assert_eq!(interpret_status(status).to_string(), "exited with code 3");
+/// ```
+pub fn interpret_status(status: i32) -> ExitOutcome {
+    #[cfg(unix)]
+    {
+        if libc::WIFEXITED(status) {
+            ExitOutcome::Exited(libc::WEXITSTATUS(status))
+        } else if libc::WIFSIGNALED(status) {
+            ExitOutcome::Signaled(libc::WTERMSIG(status))
+        } else if libc::WIFSTOPPED(status) {
+            ExitOutcome::Stopped(libc::WSTOPSIG(status))
+        } else {
+            ExitOutcome::Continued
+        }
+    }
+    // Nothing here was ever a child, so the status is whatever we made up, which is an exit code
+    #[cfg(not(unix))]
+    {
+        ExitOutcome::Exited(status)
+    }
+}
+
+impl fmt::Display for ExitOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitOutcome::Exited(code) => write!(f, "exited with code {}", code),
+            ExitOutcome::Signaled(signal) => write!(f, "killed by signal {}", signal),
+            ExitOutcome::Stopped(signal) => write!(f, "stopped by signal {}", signal),
+            ExitOutcome::Continued => write!(f, "continued"),
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! Children that send back many items, as they go.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(all(unix, not(feature = "fallback")))]
+use crate::forked::Child;
+use crate::protocol::{self, Tag};
+use crate::{Codec, Compression, Fork, ForkBuilder, ForkError};
+
+/// Forks, and runs `func` in a child process, which can send items back to the parent one at a
+/// time with the [`Yielder`] it's given. Returns right away with an iterator over the items.
+///
+/// Each item is serialized and written to the pipe as soon as it's sent, and decoded by the
+/// parent as soon as you ask for it, so neither side ever holds all of them, and the parent can
+/// get started on the first ones while the child is still working on the rest. A child that gets
+/// ahead of the parent blocks in [`send`](Yielder::send) once the pipe is full.
+///
+/// The iterator yields the items, followed by one error if anything went wrong: `func` returning
+/// an error (wrapped in [`ForkError::Closure`]), an item that fails to decode, or the child
+/// crashing partway through ([`ForkError::ChildFailed`]). Dropping the iterator early closes the
+/// pipe, so the child's next `send` fails, and waits for the child to exit.
+///
+/// Every item the child finished sending before it failed is yielded ahead of the error, even if
+/// it crashed. An item it was partway through sending when it did is never yielded: items only
+/// come out of the pipe once all of their frame is there.
+/// [`into_outcome`](ForkStream::into_outcome) collects both.
+///
+/// When [`FORKS`](crate::FORKS) is `false`, `func` runs to completion in the calling process
+/// before this returns, and the items are buffered in memory.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{fork_map_stream, ForkError};
+///
+/// let stream = unsafe {
+///     fork_map_stream(|yielder| {
+///         for n in 0..100_000u64 {
+///             yielder.send(&n)?;
+///         }
+///         Ok(())
+///     })
+/// }
+/// .unwrap();
+/// let sum: u64 = stream.map(Result::unwrap).sum();
+/// assert_eq!(sum, 100_000 * 99_999 / 2);
+///
+/// # if !fork_map::FORKS { return }
+/// // A crash partway through ends the stream with the error
+/// let stream = unsafe {
+///     fork_map_stream(|yielder| {
+///         yielder.send(&1)?;
+///         yielder.send(&2)?;
+///         libc::kill(libc::getpid(), libc::SIGKILL);
+///         Ok(())
+///     })
+/// }
+/// .unwrap();
+/// let results: Vec<Result<u32, ForkError>> = stream.collect();
+/// assert_eq!(results.len(), 3);
+/// assert!(matches!(results[2], Err(ForkError::ChildFailed { .. })));
+///
+/// // Stopping early stops the child too, even if it would go on forever
+/// let stream = unsafe {
+///     fork_map_stream(|yielder| {
+///         for n in 0u64.. {
+///             yielder.send(&n)?;
+///         }
+///         Ok(())
+///     })
+/// }
+/// .unwrap();
+/// let first: Vec<u64> = stream.take(3).map(Result::unwrap).collect();
+/// assert_eq!(first, [0, 1, 2]);
+/// ```
+///
+/// # Safety
+///
+/// Same as [`fork_map`](crate::fork_map).
+pub unsafe fn fork_map_stream<T, F>(func: F) -> anyhow::Result<ForkStream<T>>
+where
+    F: Fn(&mut Yielder<T>) -> anyhow::Result<()> + Send + Sync,
+    T: Serialize + for<'a> Deserialize<'a> + Send,
+{
+    Fork::builder().stream(func)
+}
+
+impl ForkBuilder {
+    /// Like [`fork_map_stream`], with the child configured like this. A
+    /// [`timeout`](Self::timeout) applies to the whole stream, and
+    /// [`max_result_bytes`](Self::max_result_bytes) to each item.
+    /// [`retries`](Self::retries) don't apply, since items already taken can't be taken back.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn stream<T, F>(self, func: F) -> anyhow::Result<ForkStream<T>>
+    where
+        F: Fn(&mut Yielder<T>) -> anyhow::Result<()> + Send + Sync,
+        T: Serialize + for<'a> Deserialize<'a> + Send,
+    {
+        #[cfg(feature = "testing")]
+        if let Some(error) = crate::testing::take() {
+            return Err(error.into());
+        }
+        #[cfg(all(unix, not(feature = "fallback")))]
+        if !self.inline {
+            let mut yielder = Yielder::new(&self);
+            let child = self.fork_child(|pipe| {
+                yielder.pipe = Some(pipe);
+                func(&mut yielder)
+            })?;
+            return Ok(ForkStream {
+                state: State::Forked(Some(Box::new(child))),
+            });
+        }
+        self.stream_in_process(func)
+    }
+
+    fn stream_in_process<T, F>(self, func: F) -> anyhow::Result<ForkStream<T>>
+    where
+        F: Fn(&mut Yielder<T>) -> anyhow::Result<()>,
+        T: Serialize + for<'a> Deserialize<'a>,
+    {
+        if let Some(feature) = self.child_only_option() {
+            return Err(ForkError::Unsupported { feature }.into());
+        }
+        if self.timeout.is_some() {
+            return Err(ForkError::Unsupported { feature: "timeout" }.into());
+        }
+        let pid = std::process::id();
+        for callback in &self.on_fork.0 {
+            callback(pid);
+        }
+        let mut yielder = Yielder::new(&self);
+        let result = func(&mut yielder);
+        let mut received = yielder.buffer;
+        received.extend_from_slice(&self.encode_result(result));
+
+        // The same frames a child would have sent, decoded the same way
+        let mut results = vec![];
+        let mut rest = &received[..];
+        while let Some(len) = protocol::complete_len(rest) {
+            let (frame, after) = rest.split_at(len);
+            rest = after;
+            if let Some(limit) = self.max_result_bytes {
+                if let Err(e) = protocol::check_size(pid, frame, limit) {
+                    results.push(Err(e));
+                    break;
+                }
+            }
+            if frame[0] != Tag::Item as u8 {
+                if let Err(e) = self.decode_result::<()>(pid, frame) {
+                    results.push(Err(ForkError::from_anyhow(e)));
+                }
+                break;
+            }
+            let item =
+                protocol::parse(pid, frame).and_then(|(_, body)| self.codec.decode(pid, &body));
+            results.push(item);
+        }
+        Ok(ForkStream {
+            state: State::Done(results.into_iter()),
+        })
+    }
+}
+
+/// What a streaming child sends its items with. See [`fork_map_stream`].
+pub struct Yielder<T> {
+    codec: Codec,
+    compression: Compression,
+    /// Write end of the result pipe, in a child.
+    #[cfg(all(unix, not(feature = "fallback")))]
+    pipe: Option<libc::c_int>,
+    /// Frames for the parent otherwise, since it's the same process.
+    buffer: Vec<u8>,
+    _item: PhantomData<fn(&T)>,
+}
+
+impl<T> Yielder<T>
+where
+    T: Serialize,
+{
+    fn new(builder: &ForkBuilder) -> Self {
+        Yielder {
+            codec: builder.codec,
+            compression: builder.compression,
+            #[cfg(all(unix, not(feature = "fallback")))]
+            pipe: None,
+            buffer: vec![],
+            _item: PhantomData,
+        }
+    }
+
+    /// Serializes `item` and sends it to the parent, blocking while the pipe is full. Fails if
+    /// the item can't be serialized, or if the parent has stopped listening, in which case
+    /// there's no point carrying on.
+    pub fn send(&mut self, item: &T) -> anyhow::Result<()> {
+        let frame = protocol::frame(Tag::Item, self.compression, self.codec.encode(item)?)?;
+        #[cfg(all(unix, not(feature = "fallback")))]
+        if let Some(pipe) = self.pipe {
+            return Ok(unsafe { crate::sys::write_all(pipe, &frame) }?);
+        }
+        self.buffer.extend_from_slice(&frame);
+        Ok(())
+    }
+}
+
+impl<T> fmt::Debug for Yielder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Yielder")
+            .field("codec", &self.codec)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Iterator over the items a streaming child sends. See [`fork_map_stream`].
+pub struct ForkStream<T> {
+    state: State<T>,
+}
+
+enum State<T> {
+    /// `None` once the child has been reaped.
+    #[cfg(all(unix, not(feature = "fallback")))]
+    Forked(Option<Box<Child>>),
+    /// Ran in process, so everything is already here.
+    Done(std::vec::IntoIter<Result<T, ForkError>>),
+}
+
+impl<T> Iterator for ForkStream<T>
+where
+    T: for<'a> Deserialize<'a>,
+{
+    type Item = Result<T, ForkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.state {
+            #[cfg(all(unix, not(feature = "fallback")))]
+            State::Forked(child) => next_forked(child),
+            State::Done(results) => results.next(),
+        }
+    }
+}
+
+#[cfg(all(unix, not(feature = "fallback")))]
+fn next_forked<T>(slot: &mut Option<Box<Child>>) -> Option<Result<T, ForkError>>
+where
+    T: for<'a> Deserialize<'a>,
+{
+    let child = slot.as_mut()?;
+    let received = loop {
+        if let Some(item) = child.take_item() {
+            return Some(item);
+        }
+        match child.read_more() {
+            Ok(true) => break Ok(()),
+            Ok(false) => {}
+            Err(e) => break Err(e),
+        }
+    };
+    // All that's left is the closure's result, which says whether it all went well
+    let child = slot.take()?;
+    match child.finish::<()>(received) {
+        Ok(_) => None,
+        Err(e) => Some(Err(ForkError::from_anyhow(e))),
+    }
+}
+
+impl<T> ForkStream<T>
+where
+    T: for<'a> Deserialize<'a>,
+{
+    /// Collects the items, up to the first error, which is kept alongside them rather than
+    /// taking their place.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use fork_map::{fork_map_stream, ForkError};
+    /// use std::time::Duration;
+    /// # if !fork_map::FORKS { return }
+    ///
+    /// let stream = unsafe {
+    ///     fork_map_stream(|yielder| {
+    ///         for n in 0..1000u64 {
+    ///             yielder.send(&vec![n; 4])?;
+    ///         }
+    ///         // Killed while this is stuck halfway into the pipe, with the parent not reading
+    ///         let timer = libc::itimerval {
+    ///             it_interval: libc::timeval { tv_sec: 0, tv_usec: 0 },
+    ///             it_value: libc::timeval { tv_sec: 0, tv_usec: 100_000 },
+    ///         };
+    ///         libc::setitimer(libc::ITIMER_REAL, &timer, std::ptr::null_mut());
+    ///         yielder.send(&vec![u64::MAX; 1 << 20])?;
+    ///         Ok(())
+    ///     })
+    /// }
+    /// .unwrap();
+    /// std::thread::sleep(Duration::from_millis(500));
+    ///
+    /// let outcome = stream.into_outcome();
+    /// assert_eq!(outcome.items.len(), 1000);
+    /// assert!(outcome.items.iter().enumerate().all(|(n, item)| *item == vec![n as u64; 4]));
+    /// assert!(matches!(outcome.error, Some(ForkError::ChildFailed { .. })));
+    /// ```
+    pub fn into_outcome(self) -> StreamOutcome<T> {
+        let mut items = vec![];
+        for item in self {
+            match item {
+                Ok(item) => items.push(item),
+                Err(error) => {
+                    return StreamOutcome {
+                        items,
+                        error: Some(error),
+                    }
+                }
+            }
+        }
+        StreamOutcome { items, error: None }
+    }
+}
+
+/// Everything a streaming child sent, and how it ended. See
+/// [`ForkStream::into_outcome`].
+#[derive(Debug)]
+pub struct StreamOutcome<T> {
+    /// The items, in the order they were sent.
+    pub items: Vec<T>,
+    /// What went wrong after the last of them, if anything.
+    pub error: Option<ForkError>,
+}
+
+impl<T> fmt::Debug for ForkStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ForkStream").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,68 @@
+//! Cleaning up after a child's own children, and theirs. See
+//! [`reap_descendants`](crate::ForkBuilder::reap_descendants).
+//!
+//! A process whose parent exits is reparented to the nearest ancestor that's a subreaper, or init
+//! if there isn't one. So with the child a subreaper, everything it starts stays its descendant,
+//! however the processes in between come and go, and once none of them are left it has none
+//! either: `waitpid(-1)` failing with `ECHILD` is the whole tree gone.
+
+use std::time::{Duration, Instant};
+
+use crate::ForkError;
+
+/// How often the child checks on descendants that are still running.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Runs in the child, before the closure.
+pub(crate) unsafe fn become_subreaper() -> Result<(), ForkError> {
+    if libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) < 0 {
+        return Err(ForkError::last_os_error("prctl"));
+    }
+    Ok(())
+}
+
+/// Runs in the child, after the closure: gives whatever descendants are still around until
+/// `grace` is up to exit, kills the rest, and reaps them all. Returns how many there were.
+pub(crate) unsafe fn reap_descendants(grace: Duration) -> usize {
+    let deadline = Instant::now() + grace;
+    let mut reaped = 0;
+    loop {
+        match libc::waitpid(-1, std::ptr::null_mut(), libc::WNOHANG) {
+            pid if pid > 0 => {
+                reaped += 1;
+                continue;
+            }
+            0 => {}
+            _ if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => {
+                continue
+            }
+            // Nobody left
+            _ => return reaped,
+        }
+        if Instant::now() >= deadline {
+            // Their own children come to us as they go, and are killed the next time round
+            for pid in children() {
+                libc::kill(pid, libc::SIGKILL);
+            }
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// The processes whose parent is this one, going by `/proc`.
+fn children() -> Vec<libc::pid_t> {
+    let me = std::process::id().to_string();
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return vec![];
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let pid = entry.file_name().to_str()?.parse().ok()?;
+            let stat = std::fs::read_to_string(entry.path().join("stat")).ok()?;
+            // The name in parentheses can have anything in it, spaces and parentheses included
+            let ppid = stat.rsplit_once(')')?.1.split_whitespace().nth(1)?;
+            (ppid == me).then_some(pid)
+        })
+        .collect()
+}
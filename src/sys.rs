@@ -0,0 +1,974 @@
+//! Thin wrappers around the libc calls we make, which turn failures into a [`ForkError::Io`]
+//! naming the operation, with errno read portably via [`io::Error::last_os_error`].
+
+use std::cell::Cell;
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::{atfork, forward, limit, ForkError};
+
+pub(crate) fn check(op: &'static str, ret: libc::c_int) -> Result<libc::c_int, ForkError> {
+    if ret < 0 {
+        Err(ForkError::last_os_error(op))
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Replaces `fd` with `/dev/null`, opened with `flags`. Leaves it be if that can't be opened.
+#[cfg(feature = "serde")]
+pub(crate) unsafe fn open_null(fd: libc::c_int, flags: libc::c_int) {
+    let null = libc::open(c"/dev/null".as_ptr(), flags);
+    if null >= 0 && null != fd {
+        libc::dup2(null, fd);
+        libc::close(null);
+    }
+}
+
+/// Returns `[read, write]`.
+pub(crate) unsafe fn pipe() -> Result<[libc::c_int; 2], ForkError> {
+    let mut fds: [libc::c_int; 2] = [0; 2];
+    check("pipe", libc::pipe(fds.as_mut_ptr()))?;
+    Ok(fds)
+}
+
+/// A connected pair of stream sockets, for talking both ways with a worker.
+#[cfg(feature = "serde")]
+pub(crate) unsafe fn socketpair() -> Result<[libc::c_int; 2], ForkError> {
+    let mut fds: [libc::c_int; 2] = [0; 2];
+    check(
+        "socketpair",
+        libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()),
+    )?;
+    // There's no MSG_NOSIGNAL on Apple platforms, the socket has to be told instead
+    #[cfg(target_vendor = "apple")]
+    for fd in fds {
+        let on: libc::c_int = 1;
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_NOSIGPIPE,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+    }
+    Ok(fds)
+}
+
+/// Writes all of `bytes` to a pipe, a chunk at a time if that's all it takes. Waits for room if
+/// it has been made non-blocking.
+pub(crate) unsafe fn write_all(fd: libc::c_int, mut bytes: &[u8]) -> Result<(), ForkError> {
+    while !bytes.is_empty() {
+        let count = libc::write(fd, bytes.as_ptr() as *const libc::c_void, bytes.len());
+        if count >= 0 {
+            bytes = &bytes[count as usize..];
+            continue;
+        }
+        let error = io::Error::last_os_error();
+        match error.kind() {
+            io::ErrorKind::Interrupted => {}
+            io::ErrorKind::WouldBlock => {
+                let mut fds = libc::pollfd {
+                    fd,
+                    events: libc::POLLOUT,
+                    revents: 0,
+                };
+                libc::poll(&mut fds, 1, -1);
+            }
+            _ => {
+                return Err(ForkError::Io {
+                    op: "write",
+                    source: error,
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs in the child: writes its result to the parent and exits with `code`. If the parent has
+/// gone away, exits with [`EXIT_PARENT_GONE`](crate::EXIT_PARENT_GONE) rather than dying of
+/// `SIGPIPE`, or pretending it got its result across, and if writing failed otherwise, with
+/// [`EXIT_SEND_FAILED`](crate::EXIT_SEND_FAILED).
+pub(crate) unsafe fn send_result_and_exit(pipe: libc::c_int, bytes: &[u8], code: i32) -> ! {
+    exit_after_sending(pipe, code, |pipe| write_all(pipe, bytes))
+}
+
+/// Like [`send_result_and_exit`], with `send` doing the writing.
+pub(crate) unsafe fn exit_after_sending(
+    pipe: libc::c_int,
+    code: i32,
+    send: impl FnOnce(libc::c_int) -> Result<(), ForkError>,
+) -> ! {
+    // The closure is done, so there's nobody left to mind
+    libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+    if let Err(ForkError::Io { source, .. }) = send(pipe) {
+        if source.raw_os_error() == Some(libc::EPIPE) {
+            libc::exit(crate::EXIT_PARENT_GONE);
+        }
+        // There's no telling the parent why, but it can tell from this that it didn't work
+        libc::exit(crate::EXIT_SEND_FAILED);
+    }
+    libc::close(pipe);
+    libc::exit(code);
+}
+
+/// A pipe as an [`io::Write`], for serializing into. Remembers the last error, since whatever
+/// it's handed to may not pass it on as it was.
+#[cfg(feature = "serde")]
+pub(crate) struct FdWriter {
+    fd: libc::c_int,
+    pub(crate) error: Option<ForkError>,
+}
+
+#[cfg(feature = "serde")]
+impl FdWriter {
+    pub(crate) fn new(fd: libc::c_int) -> Self {
+        FdWriter { fd, error: None }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl io::Write for FdWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match unsafe { write_all(self.fd, buf) } {
+            Ok(()) => Ok(buf.len()),
+            Err(e) => {
+                let error = match &e {
+                    ForkError::Io { source, .. } => {
+                        io::Error::new(source.kind(), source.to_string())
+                    }
+                    _ => io::ErrorKind::Other.into(),
+                };
+                self.error = Some(e);
+                Err(error)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sends all of `bytes` over a socket from [`socketpair`]. A peer that has gone away is an
+/// `EPIPE` error rather than a `SIGPIPE` that would kill us.
+#[cfg(feature = "serde")]
+pub(crate) unsafe fn send_all(fd: libc::c_int, mut bytes: &[u8]) -> Result<(), ForkError> {
+    #[cfg(target_vendor = "apple")]
+    const FLAGS: libc::c_int = 0;
+    #[cfg(not(target_vendor = "apple"))]
+    const FLAGS: libc::c_int = libc::MSG_NOSIGNAL;
+    while !bytes.is_empty() {
+        let count = libc::send(
+            fd,
+            bytes.as_ptr() as *const libc::c_void,
+            bytes.len(),
+            FLAGS,
+        );
+        if count >= 0 {
+            bytes = &bytes[count as usize..];
+            continue;
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(ForkError::Io {
+                op: "send",
+                source: error,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Fills `buf` from `fd`, returning how much it got, which is less than `buf.len()` only if it
+/// hit EOF.
+#[cfg(feature = "serde")]
+pub(crate) unsafe fn read_exact(fd: libc::c_int, buf: &mut [u8]) -> Result<usize, ForkError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let rest = &mut buf[filled..];
+        let count = libc::read(fd, rest.as_mut_ptr() as *mut libc::c_void, rest.len());
+        if count > 0 {
+            filled += count as usize;
+            continue;
+        }
+        if count == 0 {
+            break;
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(ForkError::Io {
+                op: "read",
+                source: error,
+            });
+        }
+    }
+    Ok(filled)
+}
+
+/// Like [`send_all`], with `passed` sent along as `SCM_RIGHTS`, so the peer gets its own copy
+/// of the file descriptor.
+#[cfg(feature = "serde")]
+pub(crate) unsafe fn send_with_fd(
+    fd: libc::c_int,
+    bytes: &[u8],
+    passed: libc::c_int,
+) -> Result<(), ForkError> {
+    let mut iov = libc::iovec {
+        iov_base: bytes.as_ptr() as *mut libc::c_void,
+        iov_len: bytes.len(),
+    };
+    let mut control =
+        vec![0u8; libc::CMSG_SPACE(std::mem::size_of::<libc::c_int>() as u32) as usize];
+    let mut msg: libc::msghdr = std::mem::zeroed();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    let cmsg = libc::CMSG_FIRSTHDR(&msg);
+    (*cmsg).cmsg_level = libc::SOL_SOCKET;
+    (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+    (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<libc::c_int>() as u32) as _;
+    std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, passed);
+    let sent = loop {
+        let count = libc::sendmsg(fd, &msg, 0);
+        if count >= 0 {
+            break count as usize;
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(ForkError::Io {
+                op: "sendmsg",
+                source: error,
+            });
+        }
+    };
+    // The descriptor went with the first byte, the rest is plain data
+    send_all(fd, &bytes[sent..])
+}
+
+/// Receives what [`send_with_fd`] sent: fills `buf` like [`read_exact`], and returns the file
+/// descriptor that came with it, if any.
+#[cfg(feature = "serde")]
+pub(crate) unsafe fn recv_with_fd(
+    fd: libc::c_int,
+    buf: &mut [u8],
+) -> Result<(usize, Option<libc::c_int>), ForkError> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut control =
+        vec![0u8; libc::CMSG_SPACE(std::mem::size_of::<libc::c_int>() as u32) as usize];
+    let mut msg: libc::msghdr = std::mem::zeroed();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    let received = loop {
+        let count = libc::recvmsg(fd, &mut msg, 0);
+        if count >= 0 {
+            break count as usize;
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(ForkError::Io {
+                op: "recvmsg",
+                source: error,
+            });
+        }
+    };
+    let mut passed = None;
+    let cmsg = libc::CMSG_FIRSTHDR(&msg);
+    if !cmsg.is_null()
+        && (*cmsg).cmsg_level == libc::SOL_SOCKET
+        && (*cmsg).cmsg_type == libc::SCM_RIGHTS
+    {
+        passed = Some(std::ptr::read_unaligned(
+            libc::CMSG_DATA(cmsg) as *const libc::c_int
+        ));
+    }
+    if received == 0 {
+        return Ok((0, passed));
+    }
+    let rest = read_exact(fd, &mut buf[received..])?;
+    Ok((received + rest, passed))
+}
+
+/// Forks, keeping `SIGCHLD` from reaping the child behind our back until it's been waited for,
+/// and adding it to the children [signals are forwarded](forward) to.
+pub(crate) unsafe fn fork() -> Result<libc::pid_t, ForkError> {
+    atfork::register();
+    let mask = forward::block();
+    hold_sigchld();
+    let pid = match libc::fork() {
+        -1 => Err(ForkError::fork_failed()),
+        pid => Ok(pid),
+    };
+    match pid {
+        Ok(0) => {
+            forward::forget_all();
+            limit::forked();
+        }
+        Ok(child) => forward::add(child),
+        Err(_) => {}
+    }
+    if !matches!(pid, Ok(child) if child > 0) {
+        // Failed, or we're the child, which has no child of its own yet
+        release_sigchld();
+    }
+    forward::unblock(mask);
+    pid
+}
+
+/// How many of our children have yet to be waited for, and the `SIGCHLD` disposition that was
+/// in place before the first of them, if it had to be replaced.
+pub(crate) struct SigchldHold {
+    children: usize,
+    saved: Option<libc::sigaction>,
+    /// The process these are the children of. A child starts from its parent's copy of this,
+    /// with none of them its own.
+    owner: u32,
+}
+
+static SIGCHLD_HOLD: Mutex<SigchldHold> = Mutex::new(SigchldHold {
+    children: 0,
+    saved: None,
+    owner: 0,
+});
+
+pub(crate) fn sigchld_hold() -> MutexGuard<'static, SigchldHold> {
+    let mut hold = SIGCHLD_HOLD.lock().unwrap_or_else(|e| e.into_inner());
+    let pid = std::process::id();
+    if hold.owner != pid {
+        *hold = SigchldHold {
+            children: 0,
+            saved: None,
+            owner: pid,
+        };
+    }
+    hold
+}
+
+/// With `SIGCHLD` ignored, or handled with `SA_NOCLDWAIT`, the kernel reaps children itself as
+/// they exit, and there's no status left for us to wait for; `wait4` fails with `ECHILD`. So
+/// while any child of ours is running, it's put back to the default, which leaves them for us.
+unsafe fn hold_sigchld() {
+    let mut hold = sigchld_hold();
+    hold.children += 1;
+    if hold.children > 1 {
+        return;
+    }
+    let mut current: libc::sigaction = std::mem::zeroed();
+    if libc::sigaction(libc::SIGCHLD, std::ptr::null(), &mut current) != 0 {
+        return;
+    }
+    let ignored = current.sa_sigaction == libc::SIG_IGN;
+    if !ignored && current.sa_flags & libc::SA_NOCLDWAIT == 0 {
+        return;
+    }
+    let mut keep = current;
+    if ignored {
+        keep.sa_sigaction = libc::SIG_DFL;
+    }
+    keep.sa_flags &= !libc::SA_NOCLDWAIT;
+    if libc::sigaction(libc::SIGCHLD, &keep, std::ptr::null_mut()) == 0 {
+        hold.saved = Some(current);
+    }
+}
+
+/// Called once for every child [`hold_sigchld`] was, once it's been waited for, and puts back
+/// the disposition it replaced after the last of them.
+fn release_sigchld() {
+    let mut hold = sigchld_hold();
+    hold.children = hold.children.saturating_sub(1);
+    if hold.children > 0 {
+        return;
+    }
+    if let Some(saved) = hold.saved.take() {
+        unsafe {
+            let mut current: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(libc::SIGCHLD, std::ptr::null(), &mut current);
+            // Unless the application has changed it since, in which case that's what it wants
+            let ours = current.sa_flags & libc::SA_NOCLDWAIT == 0
+                && (current.sa_sigaction == saved.sa_sigaction
+                    || current.sa_sigaction == libc::SIG_DFL);
+            if ours {
+                libc::sigaction(libc::SIGCHLD, &saved, std::ptr::null_mut());
+            }
+        }
+    }
+}
+
+/// Runs in the child: puts every signal back to its default action, other than the ones being
+/// [forwarded](crate::install_signal_forwarding), and unblocks them all. See
+/// [`reset_signals`](crate::ForkBuilder::reset_signals).
+#[cfg(feature = "serde")]
+pub(crate) unsafe fn reset_signals() {
+    // One more than the highest signal number, real-time signals included
+    #[cfg(target_os = "linux")]
+    const NSIG: libc::c_int = 65;
+    #[cfg(not(target_os = "linux"))]
+    const NSIG: libc::c_int = 32;
+
+    let default: libc::sigaction = std::mem::zeroed();
+    for signal in 1..NSIG {
+        if forward::forwards(signal) {
+            continue;
+        }
+        // Fails for the ones that can't be caught, which there's nothing to do about anyway
+        libc::sigaction(signal, &default, std::ptr::null_mut());
+    }
+    forward::forward_then_default();
+    let mut empty: libc::sigset_t = std::mem::zeroed();
+    libc::sigemptyset(&mut empty);
+    libc::pthread_sigmask(libc::SIG_SETMASK, &empty, std::ptr::null_mut());
+}
+
+/// How much [`read_some`] reads at a time.
+const CHUNK: usize = 0x10000;
+
+/// Does one `read` into the end of `buf`, returning how many bytes it got (0 at EOF).
+pub(crate) unsafe fn read_some(fd: libc::c_int, buf: &mut Vec<u8>) -> Result<usize, ForkError> {
+    buf.reserve(CHUNK);
+    read_spare(fd, buf, CHUNK)
+}
+
+/// Like [`read_some`], but reads no more than `len` bytes, having made room for exactly that
+/// many, so a buffer that's read into until it's as long as it's going to be is never grown
+/// past that, nor copied as it grows.
+#[cfg(feature = "serde")]
+pub(crate) unsafe fn read_up_to(
+    fd: libc::c_int,
+    buf: &mut Vec<u8>,
+    len: usize,
+) -> Result<usize, ForkError> {
+    reserve_up_to(buf, len);
+    read_spare(fd, buf, len)
+}
+
+/// Makes room for `len` more bytes in `buf`, or for a chunk of them if that's too many to have
+/// room for at once, as it may be when `len` comes from something that's been corrupted.
+#[cfg(feature = "serde")]
+fn reserve_up_to(buf: &mut Vec<u8>, len: usize) {
+    if buf.try_reserve_exact(len).is_err() {
+        buf.reserve(CHUNK.min(len));
+    }
+}
+
+/// Does one `read` of up to `len` bytes into the room already made at the end of `buf`.
+unsafe fn read_spare(fd: libc::c_int, buf: &mut Vec<u8>, len: usize) -> Result<usize, ForkError> {
+    loop {
+        let spare = buf.spare_capacity_mut();
+        let room = spare.len().min(len);
+        let count = libc::read(fd, spare.as_mut_ptr() as *mut libc::c_void, room);
+        if count >= 0 {
+            buf.set_len(buf.len() + count as usize);
+            return Ok(count as usize);
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(ForkError::Io {
+                op: "read",
+                source: error,
+            });
+        }
+    }
+}
+
+/// Like [`read_up_to`], with `recvmsg`, so file descriptors sent along as `SCM_RIGHTS` (see
+/// [`send_with_fd`]) are added to the end of `fds`.
+#[cfg(feature = "serde")]
+pub(crate) unsafe fn recv_up_to(
+    fd: libc::c_int,
+    buf: &mut Vec<u8>,
+    len: usize,
+    fds: &mut Vec<std::os::fd::OwnedFd>,
+) -> Result<usize, ForkError> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    // The kernel stops a read at the first message that carries any, so one is all it takes,
+    // but there's room for a few more in case that's not true everywhere
+    const MAX_FDS: u32 = 8;
+    // Nothing forked later should inherit them
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const FLAGS: libc::c_int = 0;
+
+    reserve_up_to(buf, len);
+    let spare = buf.spare_capacity_mut();
+    let mut iov = libc::iovec {
+        iov_base: spare.as_mut_ptr() as *mut libc::c_void,
+        iov_len: spare.len().min(len),
+    };
+    let mut control =
+        vec![0u8; libc::CMSG_SPACE(MAX_FDS * std::mem::size_of::<libc::c_int>() as u32) as usize];
+    let mut msg: libc::msghdr = std::mem::zeroed();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    let count = loop {
+        let count = libc::recvmsg(fd, &mut msg, FLAGS);
+        if count >= 0 {
+            break count as usize;
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(ForkError::Io {
+                op: "recvmsg",
+                source: error,
+            });
+        }
+    };
+    buf.set_len(buf.len() + count);
+    let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+    while !cmsg.is_null() {
+        if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+            let data = libc::CMSG_DATA(cmsg) as *const libc::c_int;
+            let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+            for i in 0..len / std::mem::size_of::<libc::c_int>() {
+                fds.push(OwnedFd::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+            }
+        }
+        cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    for fd in fds.iter() {
+        use std::os::fd::AsRawFd;
+        libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(ForkError::Io {
+            op: "recvmsg",
+            source: io::Error::new(
+                io::ErrorKind::InvalidData,
+                "more file descriptors were sent than there was room for",
+            ),
+        });
+    }
+    Ok(count)
+}
+
+/// Blocks until at least one of `fds` has an event or `deadline` passes, and fills in their
+/// `revents`. Returns how many have an event, so 0 means the deadline passed.
+#[cfg(feature = "serde")]
+pub(crate) unsafe fn poll(
+    fds: &mut [libc::pollfd],
+    deadline: Option<Instant>,
+) -> Result<usize, ForkError> {
+    loop {
+        let timeout = match deadline {
+            // Rounded up, so we don't wake up just before the deadline and spin
+            Some(deadline) => deadline
+                .saturating_duration_since(Instant::now())
+                .as_micros()
+                .div_ceil(1000)
+                .min(libc::c_int::MAX as u128) as libc::c_int,
+            None => -1,
+        };
+        let count = libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout);
+        if count >= 0 {
+            return Ok(count as usize);
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(ForkError::Io {
+                op: "poll",
+                source: error,
+            });
+        }
+    }
+}
+
+/// Like [`wait4`], but gives up and returns `None` if `pid` is still running at `deadline`.
+pub(crate) unsafe fn wait4_until(
+    pid: libc::pid_t,
+    deadline: Instant,
+) -> Result<Option<(libc::c_int, libc::rusage)>, ForkError> {
+    let mut status = 0;
+    let mut usage: libc::rusage = std::mem::zeroed();
+    let mut nap = Duration::from_millis(1);
+    assert_own_child(pid);
+    loop {
+        // Only reaped once it's no longer forwarded to, so that can't go to a new owner of its pid
+        let reapable = !forward::forwarding() || exited(pid, false)?;
+        if reapable {
+            forward::remove(pid);
+            match libc::wait4(pid, &mut status, libc::WNOHANG, &mut usage) {
+                0 => {}
+                // Stopped or continued under a tracer, which isn't the end of it
+                ret if ret > 0 && !terminated(status) => continue,
+                ret if ret > 0 => {
+                    release_sigchld();
+                    return Ok(Some((status, usage)));
+                }
+                _ => {
+                    let error = io::Error::last_os_error();
+                    if error.kind() != io::ErrorKind::Interrupted {
+                        release_sigchld();
+                        return Err(ForkError::Io {
+                            op: "wait4",
+                            source: error,
+                        });
+                    }
+                    continue;
+                }
+            }
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(None);
+        }
+        // There's no waiting on a pid with a timeout, so check back often at first
+        std::thread::sleep(nap.min(left));
+        nap = (nap * 2).min(Duration::from_millis(50));
+    }
+}
+
+/// Whether `pid` has terminated, without reaping it.
+#[cfg(feature = "serde")]
+pub(crate) unsafe fn has_exited(pid: libc::pid_t) -> Result<bool, ForkError> {
+    exited(pid, false)
+}
+
+/// Whether `pid` has terminated, without reaping it, waiting until it has if `block`. A child
+/// that can't be waited for at all is as good as reaped, and is let go of.
+unsafe fn exited(pid: libc::pid_t, block: bool) -> Result<bool, ForkError> {
+    let mut info: libc::siginfo_t = std::mem::zeroed();
+    assert_own_child(pid);
+    let flags = libc::WEXITED | libc::WNOWAIT | if block { 0 } else { libc::WNOHANG };
+    loop {
+        if libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, flags) == 0 {
+            // Left zeroed if it's still running
+            return Ok(info.si_pid() == pid);
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            forward::remove(pid);
+            release_sigchld();
+            return Err(ForkError::Io {
+                op: "waitid",
+                source: error,
+            });
+        }
+    }
+}
+
+/// Waits for `pid` to terminate and returns its raw wait status and resource usage.
+pub(crate) unsafe fn wait4(pid: libc::pid_t) -> Result<(libc::c_int, libc::rusage), ForkError> {
+    let mut status = 0;
+    let mut usage: libc::rusage = std::mem::zeroed();
+    assert_own_child(pid);
+    if forward::forwarding() {
+        // Not reaped until it's no longer forwarded to, so that can't go to a new owner of its pid
+        exited(pid, true)?;
+        forward::remove(pid);
+    }
+    loop {
+        if libc::wait4(pid, &mut status, 0, &mut usage) >= 0 {
+            if terminated(status) {
+                release_sigchld();
+                return Ok((status, usage));
+            }
+            continue;
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            release_sigchld();
+            return Err(ForkError::Io {
+                op: "wait4",
+                source: error,
+            });
+        }
+    }
+}
+
+/// A child we forked, which is reaped when this is dropped if it hasn't been already, so no
+/// zombie is left behind whichever way the parent gives up on it, panics included. Reaping it
+/// any other way than through this would have it reaped twice.
+pub(crate) struct ChildGuard {
+    pid: libc::pid_t,
+    reaped: bool,
+    /// Whether it's killed outright before being reaped on drop, for while it's still waiting
+    /// on us to finish setting it up.
+    kill_on_drop: bool,
+    #[cfg(feature = "serde")]
+    kill_signal: libc::c_int,
+    #[cfg(feature = "serde")]
+    kill_grace: Duration,
+    /// When a child that was sent a gentler signal than `SIGKILL` runs out of grace.
+    kill_deadline: Cell<Option<Instant>>,
+}
+
+impl ChildGuard {
+    pub(crate) fn new(pid: libc::pid_t) -> Self {
+        ChildGuard {
+            pid,
+            reaped: false,
+            kill_on_drop: false,
+            #[cfg(feature = "serde")]
+            kill_signal: libc::SIGKILL,
+            #[cfg(feature = "serde")]
+            kill_grace: Duration::ZERO,
+            kill_deadline: Cell::new(None),
+        }
+    }
+
+    /// Has [`kill`](Self::kill) send `signal`, and give the child `grace` to exit before it's
+    /// sent `SIGKILL` anyway.
+    #[cfg(feature = "serde")]
+    pub(crate) fn kill_with(mut self, signal: libc::c_int, grace: Duration) -> Self {
+        self.kill_signal = signal;
+        self.kill_grace = grace;
+        self
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn kill_on_drop(&mut self, kill: bool) {
+        self.kill_on_drop = kill;
+    }
+
+    #[cfg(feature = "serde")]
+    /// Sends the child its kill signal, and starts its grace period if it has one. It still has
+    /// to be reaped.
+    pub(crate) fn kill(&self) {
+        if self.reaped {
+            return;
+        }
+        unsafe { libc::kill(self.pid, self.kill_signal) };
+        if self.kill_signal != libc::SIGKILL && !self.kill_grace.is_zero() {
+            // The first signal is what counts, so more of them don't extend it
+            let deadline = self.kill_deadline.get();
+            self.kill_deadline
+                .set(deadline.or_else(|| Some(Instant::now() + self.kill_grace)));
+        }
+    }
+
+    /// Waits for the child to terminate, finishing it off with `SIGKILL` if it was
+    /// [killed](Self::kill) and is still around once its grace period is up, and returns its raw
+    /// wait status and resource usage. It's been reaped once this returns, either way.
+    pub(crate) unsafe fn reap(&mut self) -> Result<(libc::c_int, libc::rusage), ForkError> {
+        assert!(!self.reaped, "child {} reaped twice", self.pid);
+        // Whatever went wrong waiting on it isn't going to go right next time
+        self.reaped = true;
+        if let Some(deadline) = self.kill_deadline.get() {
+            if let Some(waited) = wait4_until(self.pid, deadline)? {
+                return Ok(waited);
+            }
+            libc::kill(self.pid, libc::SIGKILL);
+        }
+        wait4(self.pid)
+    }
+
+    /// Like [`reap`](Self::reap), but gives up and returns `None` if the child is still running
+    /// at `deadline`, in which case it hasn't been reaped.
+    #[cfg(feature = "serde")]
+    pub(crate) unsafe fn reap_until(
+        &mut self,
+        deadline: Instant,
+    ) -> Result<Option<(libc::c_int, libc::rusage)>, ForkError> {
+        let waited = wait4_until(self.pid, deadline);
+        self.reaped = !matches!(waited, Ok(None));
+        waited
+    }
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        if self.reaped {
+            return;
+        }
+        if self.kill_on_drop {
+            unsafe { libc::kill(self.pid, libc::SIGKILL) };
+        }
+        let _ = unsafe { self.reap() };
+    }
+}
+
+/// Whether a status says the child is gone, rather than stopped or continued, which `wait4`
+/// reports for a traced child even without `WUNTRACED`.
+fn terminated(status: libc::c_int) -> bool {
+    libc::WIFEXITED(status) || libc::WIFSIGNALED(status)
+}
+
+/// A pid of 0 or less waits on a whole process group, or any child at all, and could reap a
+/// child the host application is waiting for. Only ever wait on a pid we forked ourselves.
+fn assert_own_child(pid: libc::pid_t) {
+    assert!(pid > 0, "refusing to wait on pid {}", pid);
+}
+
+/// Asks for the pipe `fd` is an end of to buffer `size` bytes, or as many as we're allowed if
+/// that's over `/proc/sys/fs/pipe-max-size`, and returns how many it ended up with. A `size` of 0
+/// leaves it as it is.
+#[cfg(all(feature = "serde", target_os = "linux"))]
+pub(crate) unsafe fn grow_pipe(fd: libc::c_int, size: usize) -> Option<usize> {
+    let size = libc::c_int::try_from(size).unwrap_or(libc::c_int::MAX);
+    if size > 0 && libc::fcntl(fd, libc::F_SETPIPE_SZ, size) < 0 {
+        // Only root can go past the max, and anyone can fail to if they already have too much
+        // in pipes, in which case there's nothing for it
+        let max = std::fs::read_to_string("/proc/sys/fs/pipe-max-size")
+            .ok()
+            .and_then(|max| max.trim().parse::<libc::c_int>().ok());
+        if let Some(max) = max.filter(|&max| max < size) {
+            libc::fcntl(fd, libc::F_SETPIPE_SZ, max);
+        }
+    }
+    usize::try_from(libc::fcntl(fd, libc::F_GETPIPE_SZ)).ok()
+}
+
+/// How many bytes of memory `pid` has resident, or `None` if that can't be found out, like
+/// once it's exited.
+#[cfg(all(feature = "serde", target_os = "linux"))]
+pub(crate) fn resident_size(pid: libc::pid_t) -> Option<u64> {
+    // Sizes in pages: the whole program, then how much of it is resident
+    let statm = std::fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * page as u64)
+}
+
+/// How many bytes of memory `pid` has resident, or `None` if that can't be found out, like
+/// once it's exited.
+#[cfg(all(feature = "serde", target_os = "macos"))]
+pub(crate) fn resident_size(pid: libc::pid_t) -> Option<u64> {
+    let mut info: libc::proc_taskinfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::proc_taskinfo>() as libc::c_int;
+    let ptr = &mut info as *mut libc::proc_taskinfo as *mut libc::c_void;
+    // Fills in however much of it there was room for, which has to be all of it
+    let filled = unsafe { libc::proc_pidinfo(pid, libc::PROC_PIDTASKINFO, 0, ptr, size) };
+    (filled == size).then_some(info.pti_resident_size)
+}
+
+/// A file that only exists as long as something has it open: a `memfd` on Linux, or an
+/// `shm_open` object that's unlinked right away elsewhere. Close-on-exec.
+#[cfg(feature = "serde")]
+pub(crate) unsafe fn anonymous_file() -> Result<std::os::fd::OwnedFd, ForkError> {
+    use std::os::fd::FromRawFd;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let fd = check(
+        "memfd_create",
+        libc::memfd_create(c"fork-map".as_ptr(), libc::MFD_CLOEXEC),
+    )?;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let fd = {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+
+        // Unique enough for the moment it exists under the name
+        let name = format!(
+            "/fork-map-{}-{}\0",
+            libc::getpid(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let fd = check(
+            "shm_open",
+            libc::shm_open(
+                name.as_ptr() as *const libc::c_char,
+                libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+                0o600,
+            ),
+        )?;
+        libc::shm_unlink(name.as_ptr() as *const libc::c_char);
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        fd
+    };
+    Ok(std::os::fd::OwnedFd::from_raw_fd(fd))
+}
+
+/// A file in the temporary directory with no name, so it's gone once nothing has it open, and
+/// takes up disk rather than memory: `O_TMPFILE` where the filesystem supports it, and otherwise
+/// a file from `mkstemp` that's unlinked right away. Close-on-exec.
+#[cfg(feature = "serde")]
+pub(crate) unsafe fn temp_file() -> Result<std::os::fd::OwnedFd, ForkError> {
+    use std::ffi::CString;
+    use std::os::fd::FromRawFd;
+    use std::os::unix::ffi::OsStringExt;
+
+    let dir = std::env::temp_dir().into_os_string().into_vec();
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let path = CString::new(dir.clone()).map_err(|e| ForkError::Io {
+            op: "open",
+            source: io::Error::new(io::ErrorKind::InvalidInput, e),
+        })?;
+        let fd = libc::open(
+            path.as_ptr(),
+            libc::O_TMPFILE | libc::O_RDWR | libc::O_CLOEXEC,
+            0o600,
+        );
+        if fd >= 0 {
+            return Ok(std::os::fd::OwnedFd::from_raw_fd(fd));
+        }
+    }
+    let mut template = dir;
+    template.extend_from_slice(b"/fork-map-XXXXXX");
+    let template = CString::new(template).map_err(|e| ForkError::Io {
+        op: "mkstemp",
+        source: io::Error::new(io::ErrorKind::InvalidInput, e),
+    })?;
+    let template = template.into_raw();
+    let fd = libc::mkstemp(template);
+    let template = CString::from_raw(template);
+    check("mkstemp", fd)?;
+    libc::unlink(template.as_ptr());
+    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    Ok(std::os::fd::OwnedFd::from_raw_fd(fd))
+}
+
+/// The first `len` bytes of a file, mapped read-only, and unmapped when dropped.
+#[cfg(feature = "serde")]
+pub(crate) struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+#[cfg(feature = "serde")]
+impl Mapping {
+    /// Fails with [`ForkError::Truncated`], for child `pid` which wrote it, if the file is
+    /// shorter than `len`, rather than mapping pages that would fault on access.
+    pub(crate) unsafe fn new(pid: u32, fd: libc::c_int, len: usize) -> Result<Self, ForkError> {
+        let mut stat: libc::stat = std::mem::zeroed();
+        check("fstat", libc::fstat(fd, &mut stat))?;
+        let size = usize::try_from(stat.st_size).unwrap_or(0);
+        if size < len || len == 0 {
+            return Err(ForkError::Truncated {
+                pid,
+                expected: len,
+                received: size,
+            });
+        }
+        let ptr = libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            return Err(ForkError::last_os_error("mmap"));
+        }
+        Ok(Mapping { ptr, len })
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
@@ -0,0 +1,205 @@
+//! Fault injection, for testing how your code copes when forking goes wrong.
+//!
+//! Making real forks fail on demand is awkward, so instead you can queue up errors here, and the
+//! next calls into this crate return them without forking at all. The queue is shared by the
+//! whole process, so calls made from other threads (like rayon workers) see it too. Requires the
+//! `testing` feature, which you'll want to enable only in your `[dev-dependencies]`.
+//!
+//! The feature also adds [`ForkBuilder::run_verified`](crate::ForkBuilder::run_verified), for
+//! checking that a closure gives the same result whether it's forked or not, and
+//! [`fork_map_mock`], for checking that a result survives the trip back without forking at all.
+//!
+//! # Example
+//!
+//! ```
+//! use fork_map::{fork_map, testing, ForkError};
+//!
+//! // The code under test: retries crashed children, but only so many times
+//! fn with_retries() -> anyhow::Result<u32> {
+//!     let mut attempts = 0;
+//!     loop {
+//!         attempts += 1;
+//!         match unsafe { fork_map(|| Ok(5)) } {
+//!             Err(e) if attempts < 3 && e.downcast_ref::<ForkError>().is_some() => continue,
+//!             result => return result,
+//!         }
+//!     }
+//! }
+//!
+//! // Simulate a child killed by SIGSEGV
+//! let crash = || ForkError::ChildFailed {
+//!     pid: 0,
+//!     status: libc::SIGSEGV,
+//! };
+//!
+//! testing::fail_next_n(2, crash);
+//! assert_eq!(with_retries().unwrap(), 5);
+//!
+//! testing::fail_next_n(3, crash);
+//! let err = with_retries().unwrap_err();
+//! assert!(matches!(err.downcast_ref(), Some(ForkError::ChildFailed { .. })));
+//! assert_eq!(testing::pending(), 0);
+//! ```
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::ForkError;
+
+type Fault = Box<dyn FnOnce() -> ForkError + Send>;
+
+static FAULTS: Mutex<VecDeque<Fault>> = Mutex::new(VecDeque::new());
+
+/// Makes the next call fail with `error` instead of forking.
+pub fn fail_next(error: ForkError) {
+    push(Box::new(move || error));
+}
+
+/// Makes the next `n` calls fail with errors made by `make` instead of forking.
+pub fn fail_next_n<F>(n: usize, make: F)
+where
+    F: Fn() -> ForkError + Clone + Send + 'static,
+{
+    for _ in 0..n {
+        push(Box::new(make.clone()));
+    }
+}
+
+/// How many injected errors have yet to be returned.
+pub fn pending() -> usize {
+    faults().len()
+}
+
+/// Drops any injected errors that haven't been returned yet.
+pub fn clear() {
+    faults().clear();
+}
+
+/// Takes the next injected error, if any. Called before every fork.
+pub(crate) fn take() -> Option<ForkError> {
+    faults().pop_front().map(|make| make())
+}
+
+fn push(fault: Fault) {
+    faults().push_back(fault);
+}
+
+fn faults() -> std::sync::MutexGuard<'static, VecDeque<Fault>> {
+    // A test that panicked while holding the lock shouldn't take the others down with it
+    FAULTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(feature = "serde")]
+impl crate::ForkBuilder {
+    /// Like [`run`](crate::ForkBuilder::run), but runs `func` in this process too, once the child
+    /// is done, and panics unless the two results are the same. Returns the child's.
+    ///
+    /// This is for checking that code behaves the same on both sides of the fork, and of the
+    /// trip its result takes back: that it doesn't depend on state the child doesn't get a copy
+    /// of, or on which process it's in, and that its result survives being serialized. Running
+    /// `func` here defeats the point of forking it, so it's only for tests.
+    ///
+    /// Errors count as the same when their messages are, down the whole chain. A failure of the
+    /// fork itself, like the child crashing, leaves nothing to compare, and is returned without
+    /// running `func` here.
+    ///
+    /// ```
+    /// use fork_map::Fork;
+    ///
+    /// let sum = unsafe { Fork::builder().run_verified(|| Ok((1..=10).sum::<u32>())) }.unwrap();
+    /// assert_eq!(sum, 55);
+    ///
+    /// # if !fork_map::FORKS { return }
+    /// // Not the same in the child
+    /// let differs = std::panic::catch_unwind(|| unsafe {
+    ///     Fork::builder().run_verified(|| Ok(std::process::id()))
+    /// });
+    /// assert!(differs.is_err());
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// Same as [`fork_map`](crate::fork_map).
+    pub unsafe fn run_verified<F, R>(self, func: F) -> anyhow::Result<R>
+    where
+        F: Fn() -> anyhow::Result<R> + Send + Sync,
+        R: serde::Serialize + for<'a> serde::Deserialize<'a> + Send + PartialEq + std::fmt::Debug,
+    {
+        let forked = self.run(&func);
+        if let Err(error) = &forked {
+            if error.downcast_ref::<ForkError>().is_some() {
+                return forked;
+            }
+        }
+        match (&forked, func()) {
+            (Ok(child), Ok(parent)) => assert_eq!(
+                *child, parent,
+                "the result in the child (left) differs from the one here (right)"
+            ),
+            (Err(child), Err(parent)) => assert_eq!(
+                format!("{:#}", child),
+                format!("{:#}", parent),
+                "the error in the child (left) differs from the one here (right)"
+            ),
+            (Ok(child), Err(parent)) => panic!(
+                "the child returned {:?}, but here it failed with: {:#}",
+                child, parent
+            ),
+            (Err(child), Ok(parent)) => panic!(
+                "the child failed with: {:#}, but here it returned {:?}",
+                child, parent
+            ),
+        }
+        forked
+    }
+}
+
+/// Like [`fork_map`](crate::fork_map), but without forking: runs `func` right here, and sends
+/// its result through the same [`Codec`](crate::Codec) and framing a child's would go through
+/// on its way back, so a test can check that a type makes the trip intact.
+///
+/// That's the part of `fork_map` that's most likely to go wrong for a new result type, and this
+/// checks it quickly, on any platform, and under tools that don't cope with `fork()`, like Miri
+/// or sanitizers. It's the same as [`inline`](crate::ForkBuilder::inline) on a default builder,
+/// which is the way to try other codecs. With nothing forked there's nothing unsafe about it,
+/// and nothing isolated either, so it's no substitute for `fork_map` outside of tests.
+///
+/// ```
+/// use fork_map::testing::fork_map_mock;
+/// use serde::{Deserialize, Serialize};
+/// use std::collections::HashMap;
+///
+/// #[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// struct Report {
+///     lines: usize,
+///     #[serde(skip)]
+///     cached: Option<String>,
+/// }
+///
+/// let report = fork_map_mock(|| {
+///     Ok(Report {
+///         lines: 3,
+///         cached: Some("a\nb\nc".to_string()),
+///     })
+/// })
+/// .unwrap();
+/// // What isn't serialized doesn't come back
+/// assert_eq!(report, Report { lines: 3, cached: None });
+///
+/// // JSON only has strings for keys
+/// let pairs = fork_map_mock(|| Ok(HashMap::from([((1, 2), 3)])));
+/// assert!(pairs.is_err());
+///
+/// // Errors come back as messages
+/// let err = fork_map_mock(|| -> anyhow::Result<()> { anyhow::bail!("no luck") }).unwrap_err();
+/// assert_eq!(err.to_string(), "no luck");
+/// ```
+#[cfg(feature = "serde")]
+pub fn fork_map_mock<F, R>(func: F) -> anyhow::Result<R>
+where
+    F: Fn() -> anyhow::Result<R> + Send + Sync,
+    R: serde::Serialize + for<'a> serde::Deserialize<'a> + Send,
+{
+    // Safety: an inline builder doesn't fork
+    unsafe { crate::Fork::builder().inline(true).run(func) }
+}
@@ -0,0 +1,105 @@
+/// What carries messages between the parent and the child.
+///
+/// By default it's a pipe, which only goes from the child to the parent, with anything else
+/// (like [progress reports](crate::fork_map_with_progress) or
+/// [input](crate::fork_map_with_input)) getting a pipe or socket of its own. With
+/// [`Socket`](Self::Socket), it's one `socketpair(AF_UNIX, SOCK_STREAM)` instead, with those
+/// going over it too: every message is a frame tagged with what it is, and the parent sorts them
+/// out as they come in. That saves a descriptor or two in both processes, and is a unix socket,
+/// which can carry more than bytes.
+///
+/// The choice makes no difference to results. When [`FORKS`](crate::FORKS) is `false` there's
+/// nothing to carry, and it's ignored.
+///
+/// # Example
+///
+/// ```
+/// use fork_map::{Fork, Transport};
+///
+/// let mut seen = vec![];
+/// let big = unsafe {
+///     Fork::builder()
+///         .transport(Transport::Socket)
+///         .run_with_progress(
+///             |progress| {
+///                 for done in 0..=1000 {
+///                     progress.report(done, 1000);
+///                 }
+///                 Ok(vec![7u8; 8 << 20])
+///             },
+///             |update| seen.push(update.done),
+///         )
+///         .unwrap()
+/// };
+/// assert!(big.len() == 8 << 20 && big.iter().all(|&byte| byte == 7));
+/// assert!(seen.windows(2).all(|pair| pair[0] <= pair[1]));
+/// assert_eq!(seen.last(), Some(&1000));
+///
+/// let doubled = unsafe {
+///     Fork::builder()
+///         .transport(Transport::Socket)
+///         .run_with_input(vec![1u64; 1 << 20], |input: Vec<u64>| {
+///             Ok(input.iter().map(|n| n * 2).collect::<Vec<_>>())
+///         })
+///         .unwrap()
+/// };
+/// assert!(doubled.len() == 1 << 20 && doubled.iter().all(|&n| n == 2));
+///
+/// // Both ways get the same result there, one of them with a lot less copying
+/// let big = |transport| unsafe {
+///     Fork::builder()
+///         .transport(transport)
+///         .run(|| Ok(vec![7u8; 8 << 20]))
+///         .unwrap()
+/// };
+/// assert_eq!(big(Transport::SharedMemory), big(Transport::Pipe));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Transport {
+    /// A pipe from the child to the parent.
+    #[default]
+    Pipe,
+    /// A socket both ways, shared by everything.
+    Socket,
+    /// A pipe, like [`Pipe`](Self::Pipe), except that the result goes in shared memory: an
+    /// anonymous file (a `memfd` on Linux) the parent creates before forking and the child
+    /// writes the result to, with only its length going over the pipe. The parent maps it
+    /// read-only and decodes the result straight from the mapping, rather than copying it out
+    /// of the pipe a chunk at a time first, which is worth it for results in the hundreds of
+    /// megabytes and up. The file has no name, so closing it (whether the result was decoded,
+    /// failed to, or the child crashed) is all the cleaning up there is.
+    ///
+    /// If the child can't write the result there, say for lack of room, it sends it over the
+    /// pipe instead, as it does when it exits some other way, like with
+    /// [`fork_map_with_exit_code`](crate::fork_map_with_exit_code).
+    SharedMemory,
+    /// Like [`SharedMemory`](Self::SharedMemory), except that the result goes in a temporary
+    /// file, unnamed (`O_TMPFILE` where the filesystem supports it) so there's nothing to clean
+    /// up, in [`std::env::temp_dir`], for results too big to keep in memory twice over.
+    ///
+    /// Either way, the child is done as soon as it has written the result, rather than when the
+    /// parent has read all of it, so a child from [`spawn`](crate::ForkBuilder::spawn) exits
+    /// (and gives its memory back) without waiting for [`join`](crate::ForkHandle::join):
+    ///
+    /// ```
+    /// use fork_map::{Fork, Transport};
+    /// use std::time::Duration;
+    /// # if !fork_map::FORKS || !cfg!(target_os = "linux") { return }
+    ///
+    /// let handle = unsafe {
+    ///     Fork::builder()
+    ///         .transport(Transport::TempFile)
+    ///         .spawn(|| Ok(vec![7u8; 8 << 20]))
+    ///         .unwrap()
+    /// };
+    /// // Far more than fits in a pipe, but it doesn't need to
+    /// let stat = format!("/proc/{}/stat", handle.pid());
+    /// while !std::fs::read_to_string(&stat).unwrap().contains(") Z ") {
+    ///     std::thread::sleep(Duration::from_millis(10));
+    /// }
+    /// let big = handle.join().unwrap();
+    /// assert!(big.len() == 8 << 20 && big.iter().all(|&byte| byte == 7));
+    /// ```
+    TempFile,
+}